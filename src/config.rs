@@ -0,0 +1,478 @@
+//! Settings for a store conversion read from a TOML file.
+//!
+//! A config file holds the same settings as the flags of
+//! `convert-store`, under the names of the long flags, so that the
+//! settings of a migration can be reviewed and kept along with the rest
+//! of a runbook. On top of that, it can convert some databases or
+//! labels in another mode than the rest, and convert values of some
+//! datatypes as another datatype:
+//!
+//! ```toml
+//! from = "/var/lib/terminusdb/storage"
+//! to = "/var/lib/terminusdb/storage_11"
+//! continue = true
+//!
+//! [databases."admin/legacy"]
+//! naive = true
+//!
+//! [[label-rules]]
+//! pattern = "app/*"
+//! mode = "detect"
+//!
+//! [datatypes]
+//! "http://example.com/schema#Color" = "xsd:string"
+//! ```
+use crate::convert_labels::LabelConflictPolicy;
+use crate::dataconversion::DatatypeMap;
+use crate::disk_space::DEFAULT_MIN_FREE_SPACE_MIB;
+use crate::layer_modes::LabelRule;
+use crate::metrics::DEFAULT_METRICS_INTERVAL_SECS;
+use crate::options::ConversionOptions;
+
+use std::collections::BTreeMap;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+const XSD_PREFIX: &str = "http://www.w3.org/2001/XMLSchema#";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("could not read config file `{path}`: {source}")]
+    Io { path: String, source: io::Error },
+    #[error("invalid config file `{path}`: {source}")]
+    Parse {
+        path: String,
+        source: toml::de::Error,
+    },
+    #[error("no {0} store given, either on the command line or in the config file")]
+    MissingStore(&'static str),
+    #[error("only one of naive, auto and detect-naive can be set")]
+    ConflictingModes,
+    #[error("sync can't be combined with clean or replace")]
+    SyncWithCleanOrReplace,
+}
+
+/// The settings of a conversion as written in a config file. Settings
+/// that are not given are `None`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConversionConfig {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub workdir: Option<String>,
+    pub labels: Option<String>,
+    pub naive: Option<bool>,
+    pub auto: Option<bool>,
+    pub detect_naive: Option<bool>,
+    pub rebuild_all_indexes: Option<bool>,
+    #[serde(rename = "continue")]
+    pub keep_going: Option<bool>,
+    pub verbose: Option<bool>,
+    pub replace: Option<bool>,
+    pub clean: Option<bool>,
+    pub sync: Option<bool>,
+    pub label_conflict: Option<LabelConflictPolicy>,
+    pub label_suffix: Option<String>,
+    pub output_archive: Option<String>,
+    pub backup_dir: Option<String>,
+    /// In MiB, like the flag.
+    pub min_free_space: Option<u64>,
+    pub skip_space_check: Option<bool>,
+    pub check_server_port: Option<u16>,
+    pub force: Option<bool>,
+    pub metrics_file: Option<String>,
+    pub metrics_interval: Option<u64>,
+    /// Rules for the mode of labels without a database setting. The
+    /// first rule matching a label is used.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub label_rules: Vec<LabelRule>,
+    /// Settings for single databases, by name, such as `admin/mydb`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub databases: BTreeMap<String, DatabaseConfig>,
+    /// Datatype IRIs to convert values of other datatype IRIs as. Both
+    /// may start with `xsd:`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub datatypes: DatatypeMap,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Convert this database naively or typed, whatever the mode of the
+    /// rest of the store.
+    pub naive: Option<bool>,
+}
+
+/// Expand a datatype written as `xsd:name` into its full IRI.
+pub fn expand_datatype(datatype: &str) -> String {
+    match datatype.strip_prefix("xsd:") {
+        Some(name) => format!("{XSD_PREFIX}{name}"),
+        None => datatype.to_string(),
+    }
+}
+
+impl ConversionConfig {
+    pub fn parse(contents: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    pub async fn read(path: &str) -> Result<Self, ConfigError> {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|source| ConfigError::Io {
+                path: path.to_string(),
+                source,
+            })?;
+
+        Self::parse(&contents).map_err(|source| ConfigError::Parse {
+            path: path.to_string(),
+            source,
+        })
+    }
+
+    fn sets_mode(&self) -> bool {
+        [self.naive, self.auto, self.detect_naive].contains(&Some(true))
+    }
+
+    /// These settings, falling back to those in `fallback` for anything
+    /// that is not set. Database settings and datatypes are combined.
+    /// Naive, auto and detect-naive are taken together, so that switching
+    /// one of them on switches the others off.
+    pub fn or(self, fallback: Self) -> Self {
+        let (naive, auto, detect_naive) = if self.sets_mode() {
+            (self.naive, self.auto, self.detect_naive)
+        } else {
+            (
+                self.naive.or(fallback.naive),
+                self.auto.or(fallback.auto),
+                self.detect_naive.or(fallback.detect_naive),
+            )
+        };
+        let mut databases = fallback.databases;
+        for (name, database) in self.databases {
+            let fallback = databases.remove(&name).unwrap_or_default();
+            databases.insert(
+                name,
+                DatabaseConfig {
+                    naive: database.naive.or(fallback.naive),
+                },
+            );
+        }
+        let mut datatypes = fallback.datatypes;
+        datatypes.extend(self.datatypes);
+        let mut label_rules = self.label_rules;
+        label_rules.extend(fallback.label_rules);
+
+        Self {
+            from: self.from.or(fallback.from),
+            to: self.to.or(fallback.to),
+            workdir: self.workdir.or(fallback.workdir),
+            labels: self.labels.or(fallback.labels),
+            naive,
+            auto,
+            detect_naive,
+            rebuild_all_indexes: self.rebuild_all_indexes.or(fallback.rebuild_all_indexes),
+            keep_going: self.keep_going.or(fallback.keep_going),
+            verbose: self.verbose.or(fallback.verbose),
+            replace: self.replace.or(fallback.replace),
+            clean: self.clean.or(fallback.clean),
+            sync: self.sync.or(fallback.sync),
+            label_conflict: self.label_conflict.or(fallback.label_conflict),
+            label_suffix: self.label_suffix.or(fallback.label_suffix),
+            output_archive: self.output_archive.or(fallback.output_archive),
+            backup_dir: self.backup_dir.or(fallback.backup_dir),
+            min_free_space: self.min_free_space.or(fallback.min_free_space),
+            skip_space_check: self.skip_space_check.or(fallback.skip_space_check),
+            check_server_port: self.check_server_port.or(fallback.check_server_port),
+            force: self.force.or(fallback.force),
+            metrics_file: self.metrics_file.or(fallback.metrics_file),
+            metrics_interval: self.metrics_interval.or(fallback.metrics_interval),
+            label_rules,
+            databases,
+            datatypes,
+        }
+    }
+
+    /// A workdir that was set explicitly is never removed, whatever
+    /// `clean` says.
+    pub fn ignores_clean(&self) -> bool {
+        self.workdir.is_some() && self.clean == Some(true)
+    }
+
+    /// The settings that are switched on but don't apply to retrying
+    /// failed layers.
+    pub fn ignored_by_retry(&self) -> Vec<&'static str> {
+        [
+            ("replace", self.replace),
+            ("clean", self.clean),
+            ("sync", self.sync),
+        ]
+        .into_iter()
+        .filter(|(_, set)| *set == Some(true))
+        .map(|(name, _)| name)
+        .collect()
+    }
+
+    /// The settings a conversion actually runs with, with defaults
+    /// filled in for everything that has one.
+    pub fn effective(self) -> Result<Self, ConfigError> {
+        let from = self.from.ok_or(ConfigError::MissingStore("source"))?;
+        let to = self.to.ok_or(ConfigError::MissingStore("destination"))?;
+        let naive = self.naive.unwrap_or(false);
+        let auto = self.auto.unwrap_or(false);
+        let detect_naive = self.detect_naive.unwrap_or(false);
+        if [naive, auto, detect_naive]
+            .iter()
+            .filter(|set| **set)
+            .count()
+            > 1
+        {
+            return Err(ConfigError::ConflictingModes);
+        }
+        let sync = self.sync.unwrap_or(false);
+        if sync && (self.clean == Some(true) || self.replace == Some(true)) {
+            return Err(ConfigError::SyncWithCleanOrReplace);
+        }
+        let clean = self.clean.unwrap_or(false) && self.workdir.is_none();
+        // a sync run is expected to move labels it wrote itself earlier
+        let label_conflict = self.label_conflict.unwrap_or(if sync {
+            LabelConflictPolicy::Overwrite
+        } else {
+            LabelConflictPolicy::Fail
+        });
+
+        Ok(Self {
+            workdir: Some(self.workdir.unwrap_or_else(|| format!("{to}/.workdir"))),
+            from: Some(from),
+            to: Some(to),
+            labels: self.labels,
+            naive: Some(naive),
+            auto: Some(auto),
+            detect_naive: Some(detect_naive),
+            rebuild_all_indexes: Some(self.rebuild_all_indexes.unwrap_or(false)),
+            keep_going: Some(self.keep_going.unwrap_or(false)),
+            verbose: Some(self.verbose.unwrap_or(false)),
+            replace: Some(self.replace.unwrap_or(false)),
+            clean: Some(clean),
+            sync: Some(sync),
+            label_conflict: Some(label_conflict),
+            label_suffix: Some(self.label_suffix.unwrap_or_else(|| "_v10".to_string())),
+            output_archive: self.output_archive,
+            backup_dir: self.backup_dir,
+            min_free_space: Some(self.min_free_space.unwrap_or(DEFAULT_MIN_FREE_SPACE_MIB)),
+            skip_space_check: Some(self.skip_space_check.unwrap_or(false)),
+            check_server_port: self.check_server_port,
+            force: Some(self.force.unwrap_or(false)),
+            metrics_file: self.metrics_file,
+            metrics_interval: Some(
+                self.metrics_interval
+                    .unwrap_or(DEFAULT_METRICS_INTERVAL_SECS),
+            ),
+            label_rules: self.label_rules,
+            databases: self
+                .databases
+                .into_iter()
+                .filter(|(_, database)| database.naive.is_some())
+                .collect(),
+            datatypes: self
+                .datatypes
+                .iter()
+                .map(|(from, to)| (expand_datatype(from), expand_datatype(to)))
+                .collect(),
+        })
+    }
+
+    /// The conversion options for these settings. The metrics settings
+    /// are not part of them.
+    pub fn options(&self) -> Result<ConversionOptions, ConfigError> {
+        let config = self.clone().effective()?;
+        let mut options = ConversionOptions::new(config.from.unwrap(), config.to.unwrap())
+            .workdir(config.workdir.unwrap())
+            .naive(config.naive.unwrap())
+            .auto(config.auto.unwrap())
+            .detect_naive(config.detect_naive.unwrap())
+            .rebuild_all_indexes(config.rebuild_all_indexes.unwrap())
+            .keep_going(config.keep_going.unwrap())
+            .verbose(config.verbose.unwrap())
+            .replace(config.replace.unwrap())
+            .clean(config.clean.unwrap())
+            .sync(config.sync.unwrap())
+            .label_conflict(config.label_conflict.unwrap())
+            .label_suffix(config.label_suffix.unwrap())
+            .min_free_space(config.min_free_space.unwrap() << 20)
+            .skip_space_check(config.skip_space_check.unwrap())
+            .force(config.force.unwrap());
+        if let Some(labels) = config.labels {
+            options = options.labels(labels);
+        }
+        if let Some(output_archive) = config.output_archive {
+            options = options.output_archive(output_archive);
+        }
+        if let Some(backup_dir) = config.backup_dir {
+            options = options.backup_dir(backup_dir);
+        }
+        if let Some(port) = config.check_server_port {
+            options = options.check_server_port(port);
+        }
+        for (name, database) in config.databases {
+            if let Some(naive) = database.naive {
+                options = options.naive_override(name, naive);
+            }
+        }
+        for rule in config.label_rules {
+            options = options.label_rule(rule.pattern, rule.mode);
+        }
+        for (from, to) in config.datatypes {
+            options = options.datatype(from, to);
+        }
+
+        Ok(options)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("settings should always be writable as TOML")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer_modes::{LabelMode, ValueMode};
+
+    const CONFIG: &str = r#"
+from = "/data/storage"
+to = "/data/storage_11"
+naive = false
+continue = true
+label-conflict = "rename-suffix"
+min-free-space = 2048
+
+[databases."admin/legacy"]
+naive = true
+
+[[label-rules]]
+pattern = "app/*"
+mode = "detect"
+
+[datatypes]
+"http://example.com/schema#Color" = "xsd:string"
+"#;
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let file = ConversionConfig::parse(CONFIG).unwrap();
+        let cli = ConversionConfig {
+            to: Some("/data/other_11".to_string()),
+            naive: Some(true),
+            ..Default::default()
+        };
+        let options = cli.or(file).options().unwrap();
+        assert_eq!("/data/storage", options.from);
+        assert_eq!("/data/other_11", options.to);
+        assert_eq!("/data/other_11/.workdir", options.workdir);
+        assert!(options.naive);
+        assert!(options.keep_going);
+        assert_eq!(
+            Some(LabelConflictPolicy::RenameSuffix),
+            options.label_conflict
+        );
+        assert_eq!(2048 << 20, options.min_free_space);
+        assert_eq!(Some(&true), options.naive_overrides.get("admin/legacy"));
+        assert_eq!(
+            vec![LabelRule::new("app/*", LabelMode::Detect)],
+            options.label_rules
+        );
+        assert_eq!(
+            Some("http://www.w3.org/2001/XMLSchema#string"),
+            options
+                .datatypes
+                .get("http://example.com/schema#Color")
+                .map(|t| t.as_str())
+        );
+    }
+
+    #[test]
+    fn command_line_switches_settings_off() {
+        let file = ConversionConfig::parse("from = \"a\"\nto = \"b\"\nauto = true\nreplace = true")
+            .unwrap();
+        assert_eq!(vec!["replace"], file.ignored_by_retry());
+
+        let cli = ConversionConfig {
+            naive: Some(false),
+            replace: Some(false),
+            ..Default::default()
+        };
+        let options = cli.or(file.clone()).options().unwrap();
+        assert!(options.auto);
+        assert!(!options.replace);
+
+        let cli = ConversionConfig {
+            auto: Some(false),
+            ..Default::default()
+        };
+        let options = cli.or(file).options().unwrap();
+        assert_eq!(ValueMode::Typed, options.value_mode());
+    }
+
+    #[test]
+    fn effective_settings_read_back_the_same() {
+        let effective = ConversionConfig::parse(CONFIG)
+            .unwrap()
+            .effective()
+            .unwrap();
+        assert_eq!(Some(15), effective.metrics_interval);
+        assert_eq!(Some(false), effective.sync);
+        let written = effective.to_toml();
+        assert_eq!(effective, ConversionConfig::parse(&written).unwrap());
+    }
+
+    #[test]
+    fn one_mode_at_a_time() {
+        let file = ConversionConfig::parse("from = \"a\"\nto = \"b\"\nnaive = true").unwrap();
+        let cli = ConversionConfig {
+            auto: Some(true),
+            ..Default::default()
+        };
+        let options = cli.or(file.clone()).options().unwrap();
+        assert!(options.auto);
+        assert!(!options.naive);
+
+        let both = ConversionConfig {
+            auto: Some(true),
+            ..file
+        };
+        assert!(matches!(
+            both.effective(),
+            Err(ConfigError::ConflictingModes)
+        ));
+    }
+
+    #[test]
+    fn sync_is_refused_with_clean_or_replace() {
+        let file = ConversionConfig::parse("from = \"a\"\nto = \"b\"\nsync = true").unwrap();
+        for cli in [
+            ConversionConfig {
+                clean: Some(true),
+                workdir: Some("w".to_string()),
+                ..Default::default()
+            },
+            ConversionConfig {
+                replace: Some(true),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                cli.or(file.clone()).effective(),
+                Err(ConfigError::SyncWithCleanOrReplace)
+            ));
+        }
+    }
+
+    #[test]
+    fn unknown_settings_are_refused() {
+        assert!(ConversionConfig::parse("continu = true").is_err());
+        assert!(ConversionConfig::parse("[databases.\"admin/db\"]\nnaiv = true").is_err());
+    }
+}
@@ -88,6 +88,81 @@ pub struct Filenames {
     pub rollup: &'static str,
 }
 
+impl Filenames {
+    /// All the file names, in declaration order.
+    pub fn all(&self) -> [&'static str; 67] {
+        [
+            self.node_dictionary_blocks,
+            self.node_dictionary_offsets,
+            self.predicate_dictionary_blocks,
+            self.predicate_dictionary_offsets,
+            self.value_dictionary_types_present,
+            self.value_dictionary_type_offsets,
+            self.value_dictionary_blocks,
+            self.value_dictionary_offsets,
+            self.node_value_idmap_bits,
+            self.node_value_idmap_bit_index_blocks,
+            self.node_value_idmap_bit_index_sblocks,
+            self.predicate_idmap_bits,
+            self.predicate_idmap_bit_index_blocks,
+            self.predicate_idmap_bit_index_sblocks,
+            self.base_subjects,
+            self.base_objects,
+            self.base_s_p_adjacency_list_nums,
+            self.base_s_p_adjacency_list_bits,
+            self.base_s_p_adjacency_list_bit_index_blocks,
+            self.base_s_p_adjacency_list_bit_index_sblocks,
+            self.base_sp_o_adjacency_list_nums,
+            self.base_sp_o_adjacency_list_bits,
+            self.base_sp_o_adjacency_list_bit_index_blocks,
+            self.base_sp_o_adjacency_list_bit_index_sblocks,
+            self.base_o_ps_adjacency_list_nums,
+            self.base_o_ps_adjacency_list_bits,
+            self.base_o_ps_adjacency_list_bit_index_blocks,
+            self.base_o_ps_adjacency_list_bit_index_sblocks,
+            self.pos_subjects,
+            self.pos_objects,
+            self.pos_s_p_adjacency_list_nums,
+            self.pos_s_p_adjacency_list_bits,
+            self.pos_s_p_adjacency_list_bit_index_blocks,
+            self.pos_s_p_adjacency_list_bit_index_sblocks,
+            self.pos_sp_o_adjacency_list_nums,
+            self.pos_sp_o_adjacency_list_bits,
+            self.pos_sp_o_adjacency_list_bit_index_blocks,
+            self.pos_sp_o_adjacency_list_bit_index_sblocks,
+            self.pos_o_ps_adjacency_list_nums,
+            self.pos_o_ps_adjacency_list_bits,
+            self.pos_o_ps_adjacency_list_bit_index_blocks,
+            self.pos_o_ps_adjacency_list_bit_index_sblocks,
+            self.neg_subjects,
+            self.neg_objects,
+            self.neg_s_p_adjacency_list_nums,
+            self.neg_s_p_adjacency_list_bits,
+            self.neg_s_p_adjacency_list_bit_index_blocks,
+            self.neg_s_p_adjacency_list_bit_index_sblocks,
+            self.neg_sp_o_adjacency_list_nums,
+            self.neg_sp_o_adjacency_list_bits,
+            self.neg_sp_o_adjacency_list_bit_index_blocks,
+            self.neg_sp_o_adjacency_list_bit_index_sblocks,
+            self.neg_o_ps_adjacency_list_nums,
+            self.neg_o_ps_adjacency_list_bits,
+            self.neg_o_ps_adjacency_list_bit_index_blocks,
+            self.neg_o_ps_adjacency_list_bit_index_sblocks,
+            self.base_predicate_wavelet_tree_bits,
+            self.base_predicate_wavelet_tree_bit_index_blocks,
+            self.base_predicate_wavelet_tree_bit_index_sblocks,
+            self.pos_predicate_wavelet_tree_bits,
+            self.pos_predicate_wavelet_tree_bit_index_blocks,
+            self.pos_predicate_wavelet_tree_bit_index_sblocks,
+            self.neg_predicate_wavelet_tree_bits,
+            self.neg_predicate_wavelet_tree_bit_index_blocks,
+            self.neg_predicate_wavelet_tree_bit_index_sblocks,
+            self.parent,
+            self.rollup,
+        ]
+    }
+}
+
 pub const V10_FILENAMES: Filenames = Filenames {
     node_dictionary_blocks: "node_dictionary_blocks.pfc",
     node_dictionary_offsets: "node_dictionary_offsets.logarray",
@@ -284,7 +359,7 @@ pub const V11_FILENAMES: Filenames = Filenames {
     rollup: "rollup.hex",
 };
 
-pub const UNCHANGED_FILES: [&'static str; 38] = [
+pub const UNCHANGED_FILES: [&str; 27] = [
     V10_FILENAMES.predicate_idmap_bits,
     V10_FILENAMES.predicate_idmap_bit_index_blocks,
     V10_FILENAMES.predicate_idmap_bit_index_sblocks,
@@ -292,9 +367,6 @@ pub const UNCHANGED_FILES: [&'static str; 38] = [
     V10_FILENAMES.base_s_p_adjacency_list_bits,
     V10_FILENAMES.base_s_p_adjacency_list_bit_index_blocks,
     V10_FILENAMES.base_s_p_adjacency_list_bit_index_sblocks,
-    V10_FILENAMES.base_sp_o_adjacency_list_bits,
-    V10_FILENAMES.base_sp_o_adjacency_list_bit_index_blocks,
-    V10_FILENAMES.base_sp_o_adjacency_list_bit_index_sblocks,
     V10_FILENAMES.base_predicate_wavelet_tree_bits,
     V10_FILENAMES.base_predicate_wavelet_tree_bit_index_blocks,
     V10_FILENAMES.base_predicate_wavelet_tree_bit_index_sblocks,
@@ -305,9 +377,6 @@ pub const UNCHANGED_FILES: [&'static str; 38] = [
     V10_FILENAMES.pos_s_p_adjacency_list_bits,
     V10_FILENAMES.pos_s_p_adjacency_list_bit_index_blocks,
     V10_FILENAMES.pos_s_p_adjacency_list_bit_index_sblocks,
-    V10_FILENAMES.pos_sp_o_adjacency_list_bits,
-    V10_FILENAMES.pos_sp_o_adjacency_list_bit_index_blocks,
-    V10_FILENAMES.pos_sp_o_adjacency_list_bit_index_sblocks,
     V10_FILENAMES.pos_predicate_wavelet_tree_bits,
     V10_FILENAMES.pos_predicate_wavelet_tree_bit_index_blocks,
     V10_FILENAMES.pos_predicate_wavelet_tree_bit_index_sblocks,
@@ -315,32 +384,44 @@ pub const UNCHANGED_FILES: [&'static str; 38] = [
     V10_FILENAMES.neg_s_p_adjacency_list_bits,
     V10_FILENAMES.neg_s_p_adjacency_list_bit_index_blocks,
     V10_FILENAMES.neg_s_p_adjacency_list_bit_index_sblocks,
-    V10_FILENAMES.neg_sp_o_adjacency_list_bits,
-    V10_FILENAMES.neg_sp_o_adjacency_list_bit_index_blocks,
-    V10_FILENAMES.neg_sp_o_adjacency_list_bit_index_sblocks,
     V10_FILENAMES.neg_predicate_wavelet_tree_bits,
     V10_FILENAMES.neg_predicate_wavelet_tree_bit_index_blocks,
     V10_FILENAMES.neg_predicate_wavelet_tree_bit_index_sblocks,
-    V10_FILENAMES.pos_subjects,
-    V10_FILENAMES.neg_subjects,
 ];
 
-pub const BASE_INDEX_FILES: [&'static str; 5] = [
+/// The files that a naive conversion copies from the v10 layer, and a
+/// typed one writes itself. v11 refuses to load a layer that misses any
+/// of them, including the bits of the o_ps adjacency lists.
+pub const BASE_INDEX_FILES: [&str; 9] = [
     V10_FILENAMES.pos_objects,
     V10_FILENAMES.base_sp_o_adjacency_list_nums,
+    V10_FILENAMES.base_sp_o_adjacency_list_bits,
+    V10_FILENAMES.base_sp_o_adjacency_list_bit_index_blocks,
+    V10_FILENAMES.base_sp_o_adjacency_list_bit_index_sblocks,
     V10_FILENAMES.base_o_ps_adjacency_list_nums,
+    V10_FILENAMES.base_o_ps_adjacency_list_bits,
     V10_FILENAMES.base_o_ps_adjacency_list_bit_index_blocks,
     V10_FILENAMES.base_o_ps_adjacency_list_bit_index_sblocks,
 ];
-pub const CHILD_INDEX_FILES: [&'static str; 10] = [
+pub const CHILD_INDEX_FILES: [&str; 20] = [
     V10_FILENAMES.pos_objects,
     V10_FILENAMES.neg_objects,
+    V10_FILENAMES.pos_subjects,
+    V10_FILENAMES.neg_subjects,
     V10_FILENAMES.pos_sp_o_adjacency_list_nums,
+    V10_FILENAMES.pos_sp_o_adjacency_list_bits,
+    V10_FILENAMES.pos_sp_o_adjacency_list_bit_index_blocks,
+    V10_FILENAMES.pos_sp_o_adjacency_list_bit_index_sblocks,
     V10_FILENAMES.neg_sp_o_adjacency_list_nums,
+    V10_FILENAMES.neg_sp_o_adjacency_list_bits,
+    V10_FILENAMES.neg_sp_o_adjacency_list_bit_index_blocks,
+    V10_FILENAMES.neg_sp_o_adjacency_list_bit_index_sblocks,
     V10_FILENAMES.pos_o_ps_adjacency_list_nums,
+    V10_FILENAMES.pos_o_ps_adjacency_list_bits,
     V10_FILENAMES.pos_o_ps_adjacency_list_bit_index_blocks,
     V10_FILENAMES.pos_o_ps_adjacency_list_bit_index_sblocks,
     V10_FILENAMES.neg_o_ps_adjacency_list_nums,
+    V10_FILENAMES.neg_o_ps_adjacency_list_bits,
     V10_FILENAMES.neg_o_ps_adjacency_list_bit_index_blocks,
     V10_FILENAMES.neg_o_ps_adjacency_list_bit_index_sblocks,
 ];
@@ -1,6 +1,6 @@
-use bytes::{Bytes, BytesMut};
-use futures::stream::TryStreamExt;
-use std::collections::HashMap;
+use bytes::{BufMut, Bytes};
+use futures::stream::{Stream, TryStreamExt};
+use std::collections::{BTreeMap, HashMap};
 use std::io;
 use terminus_store_10::storage as storage_10;
 use terminus_store_10::structure::pfc as pfc_10;
@@ -8,21 +8,26 @@ use terminus_store_11::structure::tfc as tfc_11;
 
 use thiserror::*;
 
-use crate::dataconversion::{convert_value_string_to_dict_entry, DataConversionError};
+use crate::dataconversion::{
+    convert_value_string_to_dict_entry_with_datatypes, is_terminusdb_literal, DataConversionError,
+    DatatypeMap,
+};
 
-pub struct UntypedDictionaryOutput {
-    pub offsets: Bytes,
-    pub data: Bytes,
+/// The converted dictionary files are written to the buffers passed in,
+/// which are handed back once everything has been written to them.
+pub struct UntypedDictionaryOutput<B> {
+    pub offsets: B,
+    pub data: B,
 }
 
-pub async fn convert_untyped_dictionary<F: storage_10::FileLoad + 'static>(
+pub async fn convert_untyped_dictionary<F: storage_10::FileLoad + 'static, B: BufMut>(
     from: F,
-) -> io::Result<UntypedDictionaryOutput> {
-    eprintln!("time to convert untyped dict");
+    offsets: B,
+    data: B,
+) -> io::Result<UntypedDictionaryOutput<B>> {
     let mut stream = pfc_10::dict_file_to_indexed_stream(from, 0).await?;
-    eprintln!("opened stream");
 
-    let mut builder = tfc_11::StringDictBufBuilder::new(BytesMut::new(), BytesMut::new());
+    let mut builder = tfc_11::StringDictBufBuilder::new(offsets, data);
     while let Some((_ix, val)) = stream.try_next().await? {
         builder.add(Bytes::copy_from_slice(val.as_bytes()));
     }
@@ -30,28 +35,29 @@ pub async fn convert_untyped_dictionary<F: storage_10::FileLoad + 'static>(
     let (offsets_buf, data_buf) = builder.finalize();
 
     Ok(UntypedDictionaryOutput {
-        offsets: offsets_buf.freeze(),
-        data: data_buf.freeze(),
+        offsets: offsets_buf,
+        data: data_buf,
     })
 }
 
-pub struct NaiveTypedDictionaryOutput {
-    pub types_present: Bytes,
-    pub type_offsets: Bytes,
-    pub offsets: Bytes,
-    pub data: Bytes,
+pub struct TypedDictionaryBuffers<B> {
+    pub types_present: B,
+    pub type_offsets: B,
+    pub offsets: B,
+    pub data: B,
 }
 
-pub async fn convert_naive_typed_dictionary<F: storage_10::FileLoad + 'static>(
+pub async fn convert_naive_typed_dictionary<F: storage_10::FileLoad + 'static, B: BufMut>(
     val_dict: F,
-) -> io::Result<NaiveTypedDictionaryOutput> {
+    output: TypedDictionaryBuffers<B>,
+) -> io::Result<TypedDictionaryBuffers<B>> {
     let mut stream = pfc_10::dict_file_to_stream(val_dict).await?;
 
     let mut builder = tfc_11::TypedDictBufBuilder::new(
-        BytesMut::new(),
-        BytesMut::new(),
-        BytesMut::new(),
-        BytesMut::new(),
+        output.types_present,
+        output.type_offsets,
+        output.offsets,
+        output.data,
     );
 
     while let Some(val) = stream.try_next().await? {
@@ -61,21 +67,33 @@ pub async fn convert_naive_typed_dictionary<F: storage_10::FileLoad + 'static>(
 
     let (types_present_buf, type_offsets_buf, offsets_buf, data_buf) = builder.finalize();
 
-    Ok(NaiveTypedDictionaryOutput {
-        types_present: types_present_buf.freeze(),
-        type_offsets: type_offsets_buf.freeze(),
-        offsets: offsets_buf.freeze(),
-        data: data_buf.freeze(),
+    Ok(TypedDictionaryBuffers {
+        types_present: types_present_buf,
+        type_offsets: type_offsets_buf,
+        offsets: offsets_buf,
+        data: data_buf,
     })
 }
 
-pub struct TypedDictionaryOutput {
-    pub types_present: Bytes,
-    pub type_offsets: Bytes,
-    pub offsets: Bytes,
-    pub data: Bytes,
+pub struct TypedDictionaryOutput<B> {
+    pub files: TypedDictionaryBuffers<B>,
     pub mapping: HashMap<u64, u64>,
     pub offset: u64,
+    /// The number of values that were merged into an equal one, which
+    /// the converted dictionary holds fewer than the v10 one.
+    pub merged: u64,
+    /// The number of values converted to each datatype.
+    pub datatypes: BTreeMap<tfc_11::Datatype, u64>,
+    /// The number of values an auto conversion kept as strings, as they
+    /// were not TerminusDB literals.
+    pub kept_as_strings: u64,
+}
+
+/// The converted value dictionary of a layer below the one being
+/// converted, and the v11 id just before its first value.
+pub struct AncestorDictionary {
+    pub dict: tfc_11::TypedDict,
+    pub before_first: u64,
 }
 
 #[derive(Error, Debug)]
@@ -92,49 +110,254 @@ impl From<io::Error> for DictionaryConversionError {
     }
 }
 
-pub async fn convert_typed_dictionary<F: storage_10::FileLoad + 'static>(
+pub async fn convert_typed_dictionary<F: storage_10::FileLoad + 'static, B: BufMut>(
+    node_dict: F,
+    val_dict: F,
+    offset: u64,
+    shift: u64,
+    ancestors: impl Stream<Item = io::Result<AncestorDictionary>>,
+    datatypes: &DatatypeMap,
+    output: TypedDictionaryBuffers<B>,
+) -> Result<TypedDictionaryOutput<B>, DictionaryConversionError> {
+    convert_value_dictionary(
+        node_dict,
+        val_dict,
+        offset,
+        shift,
+        ancestors,
+        output,
+        |val| convert_value_string_to_dict_entry_with_datatypes(val, datatypes).map(Some),
+    )
+    .await
+}
+
+/// Like [`convert_typed_dictionary`], but values that are not
+/// TerminusDB literals, or that don't convert to their datatype, are
+/// kept as strings rather than failing the conversion.
+pub async fn convert_auto_typed_dictionary<F: storage_10::FileLoad + 'static, B: BufMut>(
+    node_dict: F,
+    val_dict: F,
+    offset: u64,
+    shift: u64,
+    ancestors: impl Stream<Item = io::Result<AncestorDictionary>>,
+    datatypes: &DatatypeMap,
+    output: TypedDictionaryBuffers<B>,
+) -> Result<TypedDictionaryOutput<B>, DictionaryConversionError> {
+    convert_value_dictionary(
+        node_dict,
+        val_dict,
+        offset,
+        shift,
+        ancestors,
+        output,
+        |val| {
+            if !is_terminusdb_literal(val) {
+                return Ok(None);
+            }
+            Ok(convert_value_string_to_dict_entry_with_datatypes(val, datatypes).ok())
+        },
+    )
+    .await
+}
+
+/// Convert every value with `convert`, which gives `None` for values
+/// to keep as strings. The values are sorted by their converted entry,
+/// so they get new ids, which are returned as a mapping from the old
+/// ones. Values that convert to the same entry, such as `foo` and
+/// `"foo"^^'xsd:string'` in an auto conversion, are merged into one.
+/// So are values that convert to an entry one of the `ancestors`
+/// already has, which keep the id it has there.
+///
+/// `offset` is the number of ids in the v10 layers below this one, and
+/// `shift` how many ids fewer those have in v11.
+async fn convert_value_dictionary<
+    F: storage_10::FileLoad + 'static,
+    B: BufMut,
+    C: FnMut(&str) -> Result<Option<tfc_11::TypedDictEntry>, DataConversionError>,
+>(
     node_dict: F,
     val_dict: F,
     offset: u64,
-) -> Result<TypedDictionaryOutput, DictionaryConversionError> {
+    shift: u64,
+    ancestors: impl Stream<Item = io::Result<AncestorDictionary>>,
+    output: TypedDictionaryBuffers<B>,
+    mut convert: C,
+) -> Result<TypedDictionaryOutput<B>, DictionaryConversionError> {
     let node_count = pfc_10::dict_file_get_count(node_dict).await?;
     let val_count = pfc_10::dict_file_get_count(val_dict.clone()).await?;
     let mut stream = pfc_10::dict_file_to_indexed_stream(val_dict, node_count + offset).await?;
 
     let mut converted_vals: Vec<(tfc_11::TypedDictEntry, u64)> =
         Vec::with_capacity(val_count as usize);
+    let mut kept_as_strings = 0;
     while let Some((ix, val)) = stream.try_next().await? {
-        converted_vals.push((
-            convert_value_string_to_dict_entry(&val)
-                .map_err(|e| DictionaryConversionError::DataConversion { id: ix, error: e })?,
-            ix,
-        ));
+        let entry = match convert(&val)
+            .map_err(|e| DictionaryConversionError::DataConversion { id: ix, error: e })?
+        {
+            Some(entry) => entry,
+            None => {
+                kept_as_strings += 1;
+                <String as tfc_11::TdbDataType>::make_entry(&val)
+            }
+        };
+        converted_vals.push((entry, ix));
     }
 
     converted_vals.sort();
 
+    // the ancestors are loaded one at a time, so only one of their
+    // dictionaries is in memory at once
+    let mut existing: Vec<Option<u64>> = vec![None; converted_vals.len()];
+    let mut ancestors = std::pin::pin!(ancestors);
+    while let Some(ancestor) = ancestors.try_next().await? {
+        for ((entry, _), existing) in converted_vals.iter().zip(existing.iter_mut()) {
+            if existing.is_none() {
+                *existing = ancestor
+                    .dict
+                    .id_entry(entry)
+                    .into_option()
+                    .map(|id| ancestor.before_first + id);
+            }
+        }
+    }
+
     let mut builder = tfc_11::TypedDictBufBuilder::new(
-        BytesMut::new(),
-        BytesMut::new(),
-        BytesMut::new(),
-        BytesMut::new(),
+        output.types_present,
+        output.type_offsets,
+        output.offsets,
+        output.data,
     );
     let mut mapping: HashMap<u64, u64> = HashMap::with_capacity(converted_vals.len());
+    let mut datatypes = BTreeMap::new();
 
-    for (new_index, (entry, old_index)) in converted_vals.into_iter().enumerate() {
-        builder.add(entry);
-        let new_index = new_index as u64 + offset + node_count + 1;
+    let last_index = offset - shift + node_count;
+    let mut new_index = last_index;
+    let mut last: Option<tfc_11::TypedDictEntry> = None;
+    for ((entry, old_index), existing) in converted_vals.into_iter().zip(existing) {
+        *datatypes.entry(entry.datatype()).or_insert(0) += 1;
+        if let Some(existing) = existing {
+            mapping.insert(old_index, existing);
+            continue;
+        }
+        if last.as_ref() != Some(&entry) {
+            new_index += 1;
+            builder.add(entry.clone());
+            last = Some(entry);
+        }
         mapping.insert(old_index, new_index);
     }
+    let kept = new_index - last_index;
 
     let (types_present_buf, type_offsets_buf, offsets_buf, data_buf) = builder.finalize();
 
     Ok(TypedDictionaryOutput {
-        types_present: types_present_buf.freeze(),
-        type_offsets: type_offsets_buf.freeze(),
-        offsets: offsets_buf.freeze(),
-        data: data_buf.freeze(),
+        files: TypedDictionaryBuffers {
+            types_present: types_present_buf,
+            type_offsets: type_offsets_buf,
+            offsets: offsets_buf,
+            data: data_buf,
+        },
         mapping,
         offset: offset + node_count + val_count,
+        merged: val_count - kept,
+        datatypes,
+        kept_as_strings,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use terminus_store_10::storage::memory::MemoryBackedStore;
+    use terminus_store_10::storage::FileStore;
+
+    async fn pfc_dictionary(values: &[&str]) -> MemoryBackedStore {
+        let blocks = MemoryBackedStore::new();
+        let offsets = MemoryBackedStore::new();
+        let mut builder = pfc_10::PfcDictFileBuilder::new(
+            blocks.open_write().await.unwrap(),
+            offsets.open_write().await.unwrap(),
+        );
+        for value in values {
+            builder.add(value).await.unwrap();
+        }
+        builder.finalize().await.unwrap();
+
+        blocks
+    }
+
+    fn buffers() -> TypedDictionaryBuffers<Vec<u8>> {
+        TypedDictionaryBuffers {
+            types_present: Vec::new(),
+            type_offsets: Vec::new(),
+            offsets: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn equal_values_are_merged() {
+        let nodes = pfc_dictionary(&["a", "b"]).await;
+        let values = pfc_dictionary(&[
+            "\"foo\"^^'http://www.w3.org/2001/XMLSchema#string'",
+            "bar",
+            "foo",
+        ])
+        .await;
+
+        // ten ids in the layers below, of which three were merged away
+        let output = convert_auto_typed_dictionary(
+            nodes,
+            values,
+            10,
+            3,
+            stream::empty(),
+            &DatatypeMap::new(),
+            buffers(),
+        )
+        .await
+        .unwrap();
+
+        let dict = tfc_11::TypedDict::from_parts(
+            output.files.types_present.into(),
+            output.files.type_offsets.into(),
+            output.files.offsets.into(),
+            output.files.data.into(),
+        );
+        assert_eq!(2, dict.num_entries());
+        assert_eq!(Some("bar".to_string()), dict.get::<String>(1));
+        assert_eq!(Some("foo".to_string()), dict.get::<String>(2));
+
+        assert_eq!(
+            HashMap::from([(13, 11), (14, 10), (15, 11)]),
+            output.mapping
+        );
+        assert_eq!(15, output.offset);
+        assert_eq!(1, output.merged);
+        assert_eq!(Some(&3), output.datatypes.get(&tfc_11::Datatype::String));
+        assert_eq!(2, output.kept_as_strings);
+    }
+
+    #[tokio::test]
+    async fn values_merge_when_their_datatypes_do() {
+        let nodes = pfc_dictionary(&[]).await;
+        let values = pfc_dictionary(&[
+            "\"x\"^^'http://example.com/code'",
+            "\"x\"^^'http://www.w3.org/2001/XMLSchema#string'",
+        ])
+        .await;
+        let datatypes = DatatypeMap::from([(
+            "http://example.com/code".to_string(),
+            "http://www.w3.org/2001/XMLSchema#string".to_string(),
+        )]);
+
+        let output =
+            convert_typed_dictionary(nodes, values, 0, 0, stream::empty(), &datatypes, buffers())
+                .await
+                .unwrap();
+
+        assert_eq!(HashMap::from([(1, 1), (2, 1)]), output.mapping);
+        assert_eq!(1, output.merged);
+    }
+}
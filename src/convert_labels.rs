@@ -0,0 +1,380 @@
+use terminus_store_10::storage as storage_10;
+use terminus_store_10::storage::{name_to_string, string_to_name, Label};
+use tokio::io::AsyncBufReadExt;
+use tokio::io::BufReader;
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::durable::write_file_durably;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+/// What to do when the destination store already has a label with the
+/// same name as one we are converting, but pointing somewhere else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LabelConflictPolicy {
+    /// Refuse to convert anything
+    Fail,
+    /// Leave the existing label alone
+    Skip,
+    /// Replace the existing label
+    Overwrite,
+    /// Write the incoming label under a name with a suffix appended
+    RenameSuffix,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LabelState {
+    pub version: u64,
+    pub layer: Option<[u32; 5]>,
+}
+
+impl fmt::Display for LabelState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.layer {
+            Some(layer) => write!(f, "version {} at {}", self.version, name_to_string(layer)),
+            None => write!(f, "version {} without a layer", self.version),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LabelResolution {
+    Failed,
+    Skipped,
+    Overwritten,
+    Renamed(String),
+}
+
+#[derive(Debug)]
+pub struct LabelConflict {
+    pub label: String,
+    pub existing: LabelState,
+    pub incoming: LabelState,
+    pub resolution: LabelResolution,
+}
+
+impl fmt::Display for LabelConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: destination has {}, source has {}",
+            self.label, self.existing, self.incoming
+        )?;
+        match &self.resolution {
+            LabelResolution::Failed => Ok(()),
+            LabelResolution::Skipped => write!(f, " (skipped)"),
+            LabelResolution::Overwritten => write!(f, " (overwritten)"),
+            LabelResolution::Renamed(name) => write!(f, " (written as {name})"),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct LabelConversionReport {
+    /// Labels that were created or changed in the destination.
+    pub updated: Vec<String>,
+    pub conflicts: Vec<LabelConflict>,
+}
+
+pub fn parse_label(data: &[u8]) -> io::Result<LabelState> {
+    let s = String::from_utf8_lossy(data);
+    let lines: Vec<&str> = s.lines().collect();
+    if lines.len() != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected label file to have two lines. contents were ({lines:?})"),
+        ));
+    }
+    let version = lines[0].parse::<u64>().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "expected first line of label file to be a number but it was {}",
+                lines[0]
+            ),
+        )
+    })?;
+    let layer = if lines[1].is_empty() {
+        None
+    } else {
+        Some(string_to_name(lines[1])?)
+    };
+
+    Ok(LabelState { version, layer })
+}
+
+async fn read_if_exists(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path).await {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn label_path(store: &str, label: &str) -> PathBuf {
+    let mut path = PathBuf::from(store);
+    path.push(format!("{label}.label"));
+    path
+}
+
+/// The names of the labels to convert, either read from a labels file
+/// or found by listing the source store.
+pub async fn label_names<L: storage_10::LabelStore>(
+    label_store: &L,
+    labels: Option<&str>,
+) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    if let Some(labels) = labels {
+        let mut options = tokio::fs::OpenOptions::new();
+        options.create(false);
+        options.read(true);
+        let file = options.open(labels).await?;
+        let buffered = BufReader::new(file);
+        let mut lines = buffered.lines();
+        while let Some(label_name) = lines.next_line().await? {
+            names.push(label_name);
+        }
+    } else {
+        names.extend(
+            storage_10::LabelStore::labels(label_store)
+                .await?
+                .into_iter()
+                .map(|l| l.name),
+        );
+    }
+
+    names.sort();
+    Ok(names)
+}
+
+/// The contents of a label file as the v10 store writes them.
+fn label_contents(label: &Label) -> Vec<u8> {
+    match label.layer {
+        Some(layer) => format!("{}\n{}\n", label.version, name_to_string(layer)).into_bytes(),
+        None => format!("{}\n\n", label.version).into_bytes(),
+    }
+}
+
+async fn read_source_label<L: storage_10::LabelStore>(
+    label_store: &L,
+    label: &str,
+) -> io::Result<Vec<u8>> {
+    storage_10::LabelStore::get_label(label_store, label)
+        .await?
+        .map(|l| label_contents(&l))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("label {label} not found in the source store"),
+            )
+        })
+}
+
+/// A label that already exists in the destination store with a
+/// different version or layer than the label we would write there.
+struct PendingConflict {
+    label: String,
+    existing: Vec<u8>,
+    incoming: Vec<u8>,
+}
+
+impl PendingConflict {
+    fn resolved(self, resolution: LabelResolution) -> io::Result<LabelConflict> {
+        Ok(LabelConflict {
+            label: self.label,
+            existing: parse_label(&self.existing)?,
+            incoming: parse_label(&self.incoming)?,
+            resolution,
+        })
+    }
+}
+
+/// Compare the labels to convert with the destination store, returning
+/// the labels it does not have yet with their contents, and the labels
+/// that conflict with the ones it has.
+async fn compare_labels<L: storage_10::LabelStore>(
+    label_store: &L,
+    to: &str,
+    labels: Option<&str>,
+) -> io::Result<(Vec<(String, Vec<u8>)>, Vec<PendingConflict>)> {
+    let mut new_labels = Vec::new();
+    let mut conflicts = Vec::new();
+    for label in label_names(label_store, labels).await? {
+        let incoming = read_source_label(label_store, &label).await?;
+        match read_if_exists(&label_path(to, &label)).await? {
+            None => new_labels.push((label, incoming)),
+            Some(existing) if existing == incoming => {}
+            Some(existing) => conflicts.push(PendingConflict {
+                label,
+                existing,
+                incoming,
+            }),
+        }
+    }
+
+    Ok((new_labels, conflicts))
+}
+
+/// Find all labels that already exist in the destination store with a
+/// different version or layer than the label we would write there.
+pub async fn find_label_conflicts<L: storage_10::LabelStore>(
+    label_store: &L,
+    to: &str,
+    labels: Option<&str>,
+) -> io::Result<Vec<LabelConflict>> {
+    let (_, conflicts) = compare_labels(label_store, to, labels).await?;
+    conflicts
+        .into_iter()
+        .map(|c| c.resolved(LabelResolution::Failed))
+        .collect()
+}
+
+/// Copy over the label files, resolving any conflicts with labels
+/// already in the destination according to the given policy. All labels
+/// are compared before any is written, so when the policy is to fail,
+/// the destination is left as it was. Label files are written durably,
+/// so a running server never sees a half-written label.
+pub async fn convert_labels<L: storage_10::LabelStore>(
+    label_store: &L,
+    to: &str,
+    labels: Option<&str>,
+    policy: LabelConflictPolicy,
+    suffix: &str,
+) -> io::Result<LabelConversionReport> {
+    let (new_labels, conflicts) = compare_labels(label_store, to, labels).await?;
+
+    let mut report = LabelConversionReport::default();
+    if policy == LabelConflictPolicy::Fail && !conflicts.is_empty() {
+        report.conflicts = conflicts
+            .into_iter()
+            .map(|c| c.resolved(LabelResolution::Failed))
+            .collect::<io::Result<_>>()?;
+        return Ok(report);
+    }
+    if policy == LabelConflictPolicy::RenameSuffix {
+        for conflict in &conflicts {
+            let renamed = format!("{}{suffix}", conflict.label);
+            match read_if_exists(&label_path(to, &renamed)).await? {
+                Some(e) if e != conflict.incoming => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!(
+                            "cannot rename label {}, {renamed} already exists",
+                            conflict.label
+                        ),
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    for (label, incoming) in new_labels {
+        write_file_durably(&label_path(to, &label), &incoming).await?;
+        report.updated.push(label);
+    }
+    for conflict in conflicts {
+        let resolution = match policy {
+            LabelConflictPolicy::Fail => LabelResolution::Failed,
+            LabelConflictPolicy::Skip => LabelResolution::Skipped,
+            LabelConflictPolicy::Overwrite => {
+                write_file_durably(&label_path(to, &conflict.label), &conflict.incoming).await?;
+                report.updated.push(conflict.label.clone());
+                LabelResolution::Overwritten
+            }
+            LabelConflictPolicy::RenameSuffix => {
+                let renamed = format!("{}{suffix}", conflict.label);
+                let renamed_path = label_path(to, &renamed);
+                if read_if_exists(&renamed_path).await?.is_none() {
+                    write_file_durably(&renamed_path, &conflict.incoming).await?;
+                    report.updated.push(renamed.clone());
+                }
+                LabelResolution::Renamed(renamed)
+            }
+        };
+        report.conflicts.push(conflict.resolved(resolution)?);
+    }
+
+    Ok(report)
+}
+
+/// Print the conflicts, and store them in the workdir for later reference.
+pub async fn write_conflict_report(work: &str, conflicts: &[LabelConflict]) -> io::Result<()> {
+    let mut report = String::new();
+    for conflict in conflicts {
+        println!("label conflict: {conflict}");
+        report.push_str(&conflict.to_string());
+        report.push('\n');
+    }
+    let mut path = PathBuf::from(work);
+    fs::create_dir_all(&path).await?;
+    path.push("label_conflicts.log");
+    write_file_durably(&path, report.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_store::TestStore;
+    use terminus_store_10::storage::directory as directory_10;
+
+    #[test]
+    fn parse_label_files() {
+        let state = parse_label(b"3\nc79ce872a1851b569f9d81da5f7b916d4e6fde1e\n").unwrap();
+        assert_eq!(3, state.version);
+        assert_eq!(
+            "c79ce872a1851b569f9d81da5f7b916d4e6fde1e",
+            name_to_string(state.layer.unwrap())
+        );
+
+        let empty = parse_label(b"0\n\n").unwrap();
+        assert_eq!(None, empty.layer);
+
+        let label = Label {
+            name: "admin%2fdb".to_string(),
+            version: state.version,
+            layer: state.layer,
+        };
+        assert_eq!(
+            b"3\nc79ce872a1851b569f9d81da5f7b916d4e6fde1e\n".to_vec(),
+            label_contents(&label)
+        );
+
+        assert!(parse_label(b"zero\n\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn failing_on_a_conflict_writes_no_labels() {
+        let store = TestStore::new("label_conflicts");
+        let (v10, v11) = (store.path("v10"), store.path("v11"));
+        let layer = "c79ce872a1851b569f9d81da5f7b916d4e6fde1e";
+        for label in ["a", "b", "c"] {
+            std::fs::write(label_path(&v10, label), format!("1\n{layer}\n")).unwrap();
+        }
+        std::fs::write(label_path(&v11, "b"), "2\n\n").unwrap();
+        let label_store = directory_10::DirectoryLabelStore::new(&v10);
+
+        let report = convert_labels(&label_store, &v11, None, LabelConflictPolicy::Fail, "_v10")
+            .await
+            .unwrap();
+        assert!(report.updated.is_empty());
+        assert_eq!(1, report.conflicts.len());
+        assert!(!label_path(&v11, "a").exists());
+        assert!(!label_path(&v11, "c").exists());
+
+        let report = convert_labels(&label_store, &v11, None, LabelConflictPolicy::Skip, "_v10")
+            .await
+            .unwrap();
+        assert_eq!(vec!["a", "c"], report.updated);
+        assert_eq!(
+            b"2\n\n".to_vec(),
+            std::fs::read(label_path(&v11, "b")).unwrap()
+        );
+    }
+}
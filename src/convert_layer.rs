@@ -1,41 +1,61 @@
 use terminus_store_10::storage as storage_10;
 use terminus_store_10::storage::directory as directory_10;
+use terminus_store_10::structure::pfc as pfc_10;
 use terminus_store_11::layer::builder as builder_11;
 use terminus_store_11::storage as storage_11;
 use terminus_store_11::storage::archive as archive_11;
-use terminus_store_11::storage::consts as consts_11;
 use terminus_store_11::storage::{name_to_string, string_to_name};
+use terminus_store_11::structure::{self as structure_11, tfc as tfc_11};
 use tokio::io::AsyncReadExt;
 
 use crate::consts::*;
 use crate::convert_dict::*;
 use crate::convert_triples::*;
+use crate::dataconversion::DatatypeMap;
+use crate::durable::write_file_durably;
+use crate::index_rebuild::rebuild_all_indexes;
+use crate::layer_modes::ValueMode;
+use crate::observer::*;
+use crate::staged_layer::*;
 
 use std::collections::HashMap;
+use std::future::Future;
 use std::io;
 use std::path::PathBuf;
+use std::time::Instant;
 
 use bytes::Bytes;
-
+use futures::stream::{self, Stream};
 use serde::{Deserialize, Serialize};
 
 use tokio::io::AsyncWriteExt;
 
 use thiserror::Error;
 
+/// How to convert a layer.
+#[derive(Clone, Copy, Debug)]
+pub struct LayerSettings<'a> {
+    pub mode: ValueMode,
+    /// Changes the datatypes of values in a typed or auto conversion.
+    pub datatypes: &'a DatatypeMap,
+    /// Build every index of the layer anew from its triples rather than
+    /// copying it, and tell the observer about rebuilt files that differ
+    /// from the v10 ones.
+    pub rebuild_all_indexes: bool,
+}
+
 pub async fn convert_layer(
     from: &str,
     to: &str,
     work: &str,
-    naive: bool,
-    verbose: bool,
+    settings: LayerSettings<'_>,
     id_string: &str,
+    observer: &dyn ConversionObserver,
 ) -> Result<(), LayerConversionError> {
     let v10_store = directory_10::DirectoryLayerStore::new(from);
-    let v11_store = archive_11::ArchiveLayerStore::new(to);
     let id = string_to_name(id_string).unwrap();
 
-    convert_layer_with_stores(&v10_store, &v11_store, work, naive, verbose, id).await
+    convert_layer_with_stores(&v10_store, to, work, settings, id, observer).await
 }
 
 #[derive(Debug, Error)]
@@ -74,6 +94,25 @@ pub enum InnerLayerConversionError {
     NodeValueRemapExists,
 }
 
+impl InnerLayerConversionError {
+    /// A short name for the kind of error, for the status log.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::DictionaryConversion(_) => "dictionary_conversion",
+            Self::LayerAlreadyConverted => "layer_already_converted",
+            Self::FileCopyError { .. } => "file_copy",
+            Self::ParentMapError(_) => "parent_map",
+            Self::TripleConversionError(_) => "triple_conversion",
+            Self::RebuildIndexError(_) => "rebuild_index",
+            Self::FinalizationError(_) => "finalization",
+            Self::RollupFileCopyError(_) => "rollup_file_copy",
+            Self::ParentMapWriteError(_) => "parent_map_write",
+            Self::Io(_) => "io",
+            Self::NodeValueRemapExists => "node_value_remap_exists",
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 #[error("Failed to convert layer {}: {source}", name_to_string(self.layer))]
 pub struct LayerConversionError {
@@ -88,22 +127,57 @@ impl LayerConversionError {
             source: source.into(),
         }
     }
+
+    pub fn layer(&self) -> [u32; 5] {
+        self.layer
+    }
+
+    pub fn kind(&self) -> &'static str {
+        self.source.kind()
+    }
+
+    /// What went wrong, without the layer name.
+    pub fn message(&self) -> String {
+        self.source.to_string()
+    }
 }
 
-pub async fn convert_layer_with_stores(
-    v10_store: &directory_10::DirectoryLayerStore,
-    v11_store: &archive_11::ArchiveLayerStore,
+/// Convert a single layer into the v11 store in `to`. The files of the
+/// new layer are staged in the workdir and only written to the store
+/// as a whole once they are all there.
+pub async fn convert_layer_with_stores<S: storage_10::PersistentLayerStore>(
+    v10_store: &S,
+    to: &str,
+    work: &str,
+    settings: LayerSettings<'_>,
+    id: [u32; 5],
+    observer: &dyn ConversionObserver,
+) -> Result<(), LayerConversionError> {
+    observer.layer_started(id);
+    let start = Instant::now();
+    let result = convert_layer_inner(v10_store, to, work, settings, id, observer).await;
+    match &result {
+        Ok(()) => observer.layer_completed(id, start.elapsed()),
+        Err(e) => observer.layer_failed(id, e),
+    }
+
+    result
+}
+
+async fn convert_layer_inner<S: storage_10::PersistentLayerStore>(
+    v10_store: &S,
+    to: &str,
     work: &str,
-    naive: bool,
-    verbose: bool,
+    settings: LayerSettings<'_>,
     id: [u32; 5],
+    observer: &dyn ConversionObserver,
 ) -> Result<(), LayerConversionError> {
-    println!("converting layer {}", name_to_string(id));
     let is_child = storage_10::PersistentLayerStore::layer_has_parent(v10_store, id)
         .await
         .map_err(|e| LayerConversionError::new(id, e))?;
 
-    if storage_11::PersistentLayerStore::directory_exists(v11_store, id)
+    let v11_store = archive_11::ArchiveLayerStore::new(to);
+    if storage_11::PersistentLayerStore::directory_exists(&v11_store, id)
         .await
         .map_err(|e| LayerConversionError::new(id, e))?
     {
@@ -113,70 +187,33 @@ pub async fn convert_layer_with_stores(
         ));
     }
 
-    storage_11::PersistentLayerStore::create_named_directory(v11_store, id)
+    assert_no_remap_exists(v10_store, id)
         .await
         .map_err(|e| LayerConversionError::new(id, e))?;
 
-    assert_no_remap_exists(v10_store, id)
+    let staged = StagedLayer::create(work, id)
         .await
         .map_err(|e| LayerConversionError::new(id, e))?;
-
-    let map;
-    let offset;
-    if naive {
-        naive_convert_dictionaries(v10_store, v11_store, id)
-            .await
-            .map_err(|e| LayerConversionError::new(id, e))?;
-        if verbose {
-            println!("dictionaries converted");
-        }
-        copy_unchanged_files(v10_store, v11_store, id).await?;
-        copy_indexes(v10_store, v11_store, id, is_child).await?;
-        map = None;
-        offset = None;
-    } else {
-        let (mut mapping, offset_1) = get_mapping_and_offset(work, v10_store, id)
-            .await
-            .map_err(|e| LayerConversionError::new(id, e))?;
-        if verbose {
-            println!("parent mappings retrieved");
-        }
-        let (mapping_addition, offset_1) = convert_dictionaries(v10_store, v11_store, id, offset_1)
-            .await
-            .map_err(|e| LayerConversionError::new(id, e))?;
-        mapping.extend(mapping_addition);
-        if verbose {
-            println!("dictionaries converted");
-        }
-        convert_triples(v10_store, v11_store, id, is_child, &mapping)
-            .await
-            .map_err(|e| {
-                LayerConversionError::new(id, InnerLayerConversionError::TripleConversionError(e))
-            })?;
-        if verbose {
-            println!("triples converted");
-        }
-        copy_unchanged_files(v10_store, v11_store, id).await?;
-        if verbose {
-            println!("files copied");
-        }
-        rebuild_indexes(v11_store, id, is_child)
-            .await
-            .map_err(|e| {
-                LayerConversionError::new(id, InnerLayerConversionError::RebuildIndexError(e))
-            })?;
-        if verbose {
-            println!("indexes rebuilt");
+    let parent_map = match stage_layer(
+        v10_store, &staged, to, work, settings, id, is_child, observer,
+    )
+    .await
+    {
+        Ok(parent_map) => parent_map,
+        Err(e) => {
+            // best effort, it gets cleared on the next attempt anyway
+            let _ = staged.remove().await;
+            return Err(e);
         }
-        map = Some(mapping);
-        offset = Some(offset_1);
-    }
+    };
 
-    storage_11::PersistentLayerStore::finalize(v11_store, id)
-        .await
-        .map_err(|e| {
-            LayerConversionError::new(id, InnerLayerConversionError::FinalizationError(e))
-        })?;
+    phase(observer, id, LayerPhase::Finalize, async {
+        staged.finalize(to).await?;
+        observer.bytes_written(id, layer_file_size(to, id).await?);
+        Ok(())
+    })
+    .await
+    .map_err(|e| LayerConversionError::new(id, InnerLayerConversionError::FinalizationError(e)))?;
 
     /*
     // we copy the rollup only after finalizing, as rollups are not
@@ -188,18 +225,166 @@ pub async fn convert_layer_with_stores(
         })?;
     */
 
-    if !naive {
-        write_parent_map(&work, id, map.unwrap(), offset.unwrap())
+    if let Some((mapping, offset)) = parent_map {
+        phase(
+            observer,
+            id,
+            LayerPhase::WriteParentMap,
+            write_parent_map(work, id, mapping, offset),
+        )
+        .await
+        .map_err(|e| {
+            LayerConversionError::new(id, InnerLayerConversionError::ParentMapWriteError(e))
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Write all files of the converted layer to the staging directory.
+/// For a typed or auto conversion, this returns the mapping and offset to
+/// write to the parent map of the layer.
+#[allow(clippy::too_many_arguments)]
+async fn stage_layer<S: storage_10::PersistentLayerStore>(
+    v10_store: &S,
+    staged: &StagedLayer,
+    to: &str,
+    work: &str,
+    settings: LayerSettings<'_>,
+    id: [u32; 5],
+    is_child: bool,
+    observer: &dyn ConversionObserver,
+) -> Result<Option<(IdMapping, u64)>, LayerConversionError> {
+    let LayerSettings {
+        mode,
+        datatypes,
+        rebuild_all_indexes,
+    } = settings;
+    if mode == ValueMode::Naive {
+        phase(
+            observer,
+            id,
+            LayerPhase::ConvertDictionaries,
+            naive_convert_dictionaries(v10_store, staged, id, observer),
+        )
+        .await
+        .map_err(|e| LayerConversionError::new(id, e))?;
+        phase(observer, id, LayerPhase::CopyFiles, async {
+            copy_unchanged_files(v10_store, staged, id, observer).await?;
+            copy_indexes(v10_store, staged, id, is_child, observer).await
+        })
+        .await?;
+        if rebuild_all_indexes {
+            phase(
+                observer,
+                id,
+                LayerPhase::RebuildIndexes,
+                rebuild_and_compare_indexes(staged, id, is_child, true, observer),
+            )
             .await
             .map_err(|e| {
-                LayerConversionError::new(id, InnerLayerConversionError::ParentMapWriteError(e))
+                LayerConversionError::new(id, InnerLayerConversionError::RebuildIndexError(e))
             })?;
-        if verbose {
-            println!("written parent map to workdir");
         }
+
+        Ok(None)
+    } else {
+        let (mut mapping, offset) = phase(
+            observer,
+            id,
+            LayerPhase::ReadParentMap,
+            get_mapping_and_offset(work, v10_store, id),
+        )
+        .await
+        .map_err(|e| LayerConversionError::new(id, e))?;
+        let shift = mapping.shift();
+        let (mapping_addition, offset, merged) = phase(
+            observer,
+            id,
+            LayerPhase::ConvertDictionaries,
+            convert_dictionaries(
+                v10_store, staged, to, id, offset, shift, mode, datatypes, observer,
+            ),
+        )
+        .await
+        .map_err(|e| LayerConversionError::new(id, e))?;
+        mapping.values.extend(mapping_addition);
+        mapping.add_shift(offset, shift + merged);
+        phase(
+            observer,
+            id,
+            LayerPhase::ConvertTriples,
+            convert_triples(v10_store, staged, id, is_child, &mapping, observer),
+        )
+        .await
+        .map_err(|e| {
+            LayerConversionError::new(id, InnerLayerConversionError::TripleConversionError(e))
+        })?;
+        phase(
+            observer,
+            id,
+            LayerPhase::CopyFiles,
+            copy_unchanged_files(v10_store, staged, id, observer),
+        )
+        .await?;
+        phase(observer, id, LayerPhase::RebuildIndexes, async {
+            if rebuild_all_indexes {
+                rebuild_and_compare_indexes(staged, id, is_child, false, observer).await
+            } else {
+                rebuild_indexes(staged, is_child).await
+            }
+        })
+        .await
+        .map_err(|e| {
+            LayerConversionError::new(id, InnerLayerConversionError::RebuildIndexError(e))
+        })?;
+
+        Ok(Some((mapping, offset)))
     }
+}
 
-    Ok(())
+/// Run a phase of a layer conversion, telling the observer when it
+/// starts and when it is done.
+async fn phase<T, E, F: Future<Output = Result<T, E>>>(
+    observer: &dyn ConversionObserver,
+    id: [u32; 5],
+    phase: LayerPhase,
+    future: F,
+) -> Result<T, E> {
+    observer.phase_started(id, phase);
+    let start = Instant::now();
+    let result = future.await;
+    if result.is_ok() {
+        observer.phase_finished(id, phase, start.elapsed());
+    }
+
+    result
+}
+
+/// The size of the layer file a layer was finalized into.
+async fn layer_file_size(to: &str, id: [u32; 5]) -> io::Result<u64> {
+    let name = name_to_string(id);
+    let mut path = PathBuf::from(to);
+    path.push(&name[..3]);
+    path.push(format!("{name}.larch"));
+
+    Ok(tokio::fs::metadata(path).await?.len())
+}
+
+/// Get a file of the v10 layer, telling the observer its size if it
+/// exists.
+async fn read_file<S: storage_10::PersistentLayerStore>(
+    v10_store: &S,
+    id: [u32; 5],
+    name: &str,
+    observer: &dyn ConversionObserver,
+) -> io::Result<S::File> {
+    let file = storage_10::PersistentLayerStore::get_file(v10_store, id, name).await?;
+    if storage_10::FileLoad::exists(&file).await? {
+        observer.source_file_opened(id, storage_10::FileLoad::size(&file).await? as u64);
+    }
+
+    Ok(file)
 }
 
 #[derive(Error, Debug)]
@@ -236,9 +421,19 @@ impl ParentMapError {
 struct ParentMap {
     offset: u64,
     mapping: Vec<(u64, u64)>,
+    shifts: Vec<(u64, u64)>,
 }
 
-fn path_for_parent_map(workdir: &str, parent: [u32; 5]) -> PathBuf {
+/// A parent map written before values could be merged, when v10 and v11
+/// ids only differed for values.
+#[derive(Deserialize)]
+struct LegacyParentMap {
+    offset: u64,
+    mapping: Vec<(u64, u64)>,
+}
+
+/// Where the parent map of a converted layer is kept in a workdir.
+pub fn path_for_parent_map(workdir: &str, parent: [u32; 5]) -> PathBuf {
     let parent_string = name_to_string(parent);
     let prefix = &parent_string[..3];
     let mut pathbuf = PathBuf::from(workdir);
@@ -251,7 +446,7 @@ fn path_for_parent_map(workdir: &str, parent: [u32; 5]) -> PathBuf {
 async fn get_mapping_and_offset_from_parent(
     workdir: &str,
     parent: [u32; 5],
-) -> Result<(HashMap<u64, u64>, u64), ParentMapError> {
+) -> Result<(IdMapping, u64), ParentMapError> {
     let pathbuf = path_for_parent_map(workdir, parent);
     let file = tokio::fs::File::open(pathbuf).await;
     if file.is_err() && file.as_ref().unwrap_err().kind() == io::ErrorKind::NotFound {
@@ -268,284 +463,385 @@ async fn get_mapping_and_offset_from_parent(
     let ParentMap {
         offset,
         mapping: mapping_vec,
-    } = postcard::from_bytes(&bytes).map_err(|e| ParentMapError::new(parent, e))?;
-    let mut mapping = HashMap::with_capacity(mapping_vec.len());
-    mapping.extend(mapping_vec);
+        shifts,
+    } = match postcard::from_bytes(&bytes) {
+        Ok(parent_map) => parent_map,
+        Err(e) => match postcard::from_bytes::<LegacyParentMap>(&bytes) {
+            Ok(LegacyParentMap { offset, mapping }) => ParentMap {
+                offset,
+                mapping,
+                shifts: Vec::new(),
+            },
+            Err(_) => return Err(ParentMapError::new(parent, e)),
+        },
+    };
+    let mut mapping = IdMapping {
+        values: HashMap::with_capacity(mapping_vec.len()),
+        shifts,
+    };
+    mapping.values.extend(mapping_vec);
 
     Ok((mapping, offset))
 }
 
-async fn get_mapping_and_offset(
+async fn get_mapping_and_offset<S: storage_10::PersistentLayerStore>(
     workdir: &str,
-    store: &directory_10::DirectoryLayerStore,
+    store: &S,
     id: [u32; 5],
-) -> Result<(HashMap<u64, u64>, u64), ParentMapError> {
+) -> Result<(IdMapping, u64), ParentMapError> {
     // look up parent id if applicable
     if let Some(parent) = storage_10::LayerStore::get_layer_parent_name(store, id)
         .await
-        .map_err(|e| ParentMapError::Io(e))?
+        .map_err(ParentMapError::Io)?
     {
         get_mapping_and_offset_from_parent(workdir, parent).await
     } else {
-        Ok((HashMap::with_capacity(0), 0))
+        Ok((IdMapping::default(), 0))
     }
 }
 
-async fn naive_convert_dictionaries(
-    v10_store: &directory_10::DirectoryLayerStore,
-    v11_store: &archive_11::ArchiveLayerStore,
+async fn naive_convert_dictionaries<S: storage_10::PersistentLayerStore>(
+    v10_store: &S,
+    staged: &StagedLayer,
     id: [u32; 5],
+    observer: &dyn ConversionObserver,
 ) -> io::Result<()> {
-    let node_dict_pfc = storage_10::PersistentLayerStore::get_file(
+    convert_untyped_dictionaries(v10_store, staged, id, observer).await?;
+
+    let value_dict_pfc = read_file(
         v10_store,
         id,
-        V10_FILENAMES.node_dictionary_blocks,
+        V10_FILENAMES.value_dictionary_blocks,
+        observer,
     )
     .await?;
-    let UntypedDictionaryOutput { offsets, data } =
-        convert_untyped_dictionary(node_dict_pfc.clone()).await?;
+    // a naive conversion turns every value into a string
+    let values = pfc_10::dict_file_get_count(value_dict_pfc.clone()).await?;
+    let output =
+        convert_naive_typed_dictionary(value_dict_pfc, value_dictionary_buffers(staged)?).await?;
+    finish_value_dictionary(output)?;
+    observer.values_converted(id, tfc_11::Datatype::String, values);
 
-    write_bytes_to_file(v11_store, id, V11_FILENAMES.node_dictionary_blocks, data);
-    write_bytes_to_file(
-        v11_store,
-        id,
-        V11_FILENAMES.node_dictionary_offsets,
-        offsets,
-    );
+    Ok(())
+}
 
-    let predicate_dict_pfc = storage_10::PersistentLayerStore::get_file(
+/// Convert the dictionaries of a typed or auto conversion. This returns
+/// the new ids of the values, the number of ids in the v10 layers up to
+/// and including this one, and how many values were merged.
+#[allow(clippy::too_many_arguments)]
+async fn convert_dictionaries<S: storage_10::PersistentLayerStore>(
+    v10_store: &S,
+    staged: &StagedLayer,
+    to: &str,
+    id: [u32; 5],
+    offset: u64,
+    shift: u64,
+    mode: ValueMode,
+    datatypes: &DatatypeMap,
+    observer: &dyn ConversionObserver,
+) -> Result<(HashMap<u64, u64>, u64, u64), DictionaryConversionError> {
+    convert_untyped_dictionaries(v10_store, staged, id, observer).await?;
+
+    // only used for its count, and already read for the untyped
+    // dictionaries
+    let node_dict_pfc = storage_10::PersistentLayerStore::get_file(
         v10_store,
         id,
-        V10_FILENAMES.predicate_dictionary_blocks,
+        V10_FILENAMES.node_dictionary_blocks,
     )
     .await?;
-    let UntypedDictionaryOutput { offsets, data } =
-        convert_untyped_dictionary(predicate_dict_pfc).await?;
-
-    write_bytes_to_file(
-        v11_store,
-        id,
-        V11_FILENAMES.predicate_dictionary_blocks,
-        data,
-    );
-    write_bytes_to_file(
-        v11_store,
-        id,
-        V11_FILENAMES.predicate_dictionary_offsets,
-        offsets,
-    );
-
-    let value_dict_pfc = storage_10::PersistentLayerStore::get_file(
+    let value_dict_pfc = read_file(
         v10_store,
         id,
         V10_FILENAMES.value_dictionary_blocks,
+        observer,
     )
     .await?;
-    let NaiveTypedDictionaryOutput {
-        types_present,
-        type_offsets,
-        offsets,
-        data,
-    } = convert_naive_typed_dictionary(value_dict_pfc).await?;
-
-    write_bytes_to_file(
-        v11_store,
-        id,
-        V11_FILENAMES.value_dictionary_types_present,
-        types_present,
-    );
-    write_bytes_to_file(
-        v11_store,
-        id,
-        V11_FILENAMES.value_dictionary_type_offsets,
-        type_offsets,
-    );
-    write_bytes_to_file(v11_store, id, V11_FILENAMES.value_dictionary_blocks, data);
-    write_bytes_to_file(
-        v11_store,
-        id,
-        V11_FILENAMES.value_dictionary_offsets,
-        offsets,
-    );
+    let buffers = value_dictionary_buffers(staged)?;
+    let ancestors = ancestor_dictionaries(v10_store, to, id, offset - shift);
+    let TypedDictionaryOutput {
+        files,
+        mapping,
+        offset,
+        merged,
+        datatypes: converted,
+        kept_as_strings,
+    } = if mode == ValueMode::Auto {
+        convert_auto_typed_dictionary(
+            node_dict_pfc,
+            value_dict_pfc,
+            offset,
+            shift,
+            ancestors,
+            datatypes,
+            buffers,
+        )
+        .await?
+    } else {
+        convert_typed_dictionary(
+            node_dict_pfc,
+            value_dict_pfc,
+            offset,
+            shift,
+            ancestors,
+            datatypes,
+            buffers,
+        )
+        .await?
+    };
 
-    Ok(())
+    finish_value_dictionary(files)?;
+    if mode == ValueMode::Auto {
+        let total: u64 = converted.values().sum();
+        observer.values_auto_converted(id, total - kept_as_strings, kept_as_strings);
+    }
+    for (datatype, values) in converted {
+        observer.values_converted(id, datatype, values);
+    }
+
+    Ok((mapping, offset, merged))
 }
 
-async fn convert_dictionaries(
-    v10_store: &directory_10::DirectoryLayerStore,
+/// The converted value dictionaries of the ancestors of a layer, parent
+/// first, along with the v11 id before their first value. `ids_below`
+/// is the number of v11 ids in all ancestors. A dictionary is only read
+/// from the v11 store when the stream gets to it.
+fn ancestor_dictionaries<'a, S: storage_10::PersistentLayerStore>(
+    v10_store: &'a S,
+    to: &str,
+    id: [u32; 5],
+    ids_below: u64,
+) -> impl Stream<Item = io::Result<AncestorDictionary>> + 'a {
+    let v11_store = archive_11::ArchiveLayerStore::new(to);
+    stream::try_unfold((Some(id), ids_below), move |(layer, ids_below)| {
+        let v11_store = v11_store.clone();
+        async move {
+            let Some(layer) = layer else {
+                return Ok(None);
+            };
+            let Some(parent) =
+                storage_10::LayerStore::get_layer_parent_name(v10_store, layer).await?
+            else {
+                return Ok(None);
+            };
+
+            let node_dict = storage_10::PersistentLayerStore::get_file(
+                v10_store,
+                parent,
+                V10_FILENAMES.node_dictionary_blocks,
+            )
+            .await?;
+            let nodes = pfc_10::dict_file_get_count(node_dict).await?;
+            let dict = tfc_11::TypedDict::from_parts(
+                read_v11_file(
+                    &v11_store,
+                    parent,
+                    V11_FILENAMES.value_dictionary_types_present,
+                )
+                .await?,
+                read_v11_file(
+                    &v11_store,
+                    parent,
+                    V11_FILENAMES.value_dictionary_type_offsets,
+                )
+                .await?,
+                read_v11_file(&v11_store, parent, V11_FILENAMES.value_dictionary_offsets).await?,
+                read_v11_file(&v11_store, parent, V11_FILENAMES.value_dictionary_blocks).await?,
+            );
+            let before_first = ids_below - dict.num_entries() as u64;
+            let ancestor = AncestorDictionary { dict, before_first };
+
+            Ok(Some((ancestor, (Some(parent), before_first - nodes))))
+        }
+    })
+}
+
+/// Read a file of a layer in the v11 store. Only the part of the layer
+/// archive that holds the file is read.
+async fn read_v11_file(
     v11_store: &archive_11::ArchiveLayerStore,
     id: [u32; 5],
-    offset: u64,
-) -> Result<(HashMap<u64, u64>, u64), DictionaryConversionError> {
-    let node_dict_pfc = storage_10::PersistentLayerStore::get_file(
+    name: &str,
+) -> io::Result<Bytes> {
+    let file = storage_11::PersistentLayerStore::get_file(v11_store, id, name).await?;
+    let mut reader = storage_11::FileLoad::open_read(&file).await?;
+    // the reader of an archive slice miscounts when given a buffer that
+    // is partly filled, as `read_to_end` does, so read into an empty one
+    let mut contents = Vec::new();
+    let mut chunk = vec![0; 1 << 16];
+    loop {
+        let read = reader.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        contents.extend_from_slice(&chunk[..read]);
+    }
+
+    Ok(contents.into())
+}
+
+/// Convert the node and predicate dictionaries, which are the same for
+/// typed and naive conversions.
+async fn convert_untyped_dictionaries<S: storage_10::PersistentLayerStore>(
+    v10_store: &S,
+    staged: &StagedLayer,
+    id: [u32; 5],
+    observer: &dyn ConversionObserver,
+) -> io::Result<()> {
+    let node_dict_pfc = read_file(
         v10_store,
         id,
         V10_FILENAMES.node_dictionary_blocks,
+        observer,
     )
     .await?;
-    let UntypedDictionaryOutput { offsets, data } =
-        convert_untyped_dictionary(node_dict_pfc.clone()).await?;
-
-    write_bytes_to_file(v11_store, id, V11_FILENAMES.node_dictionary_blocks, data);
-    write_bytes_to_file(
-        v11_store,
-        id,
-        V11_FILENAMES.node_dictionary_offsets,
-        offsets,
-    );
+    let UntypedDictionaryOutput { offsets, data } = convert_untyped_dictionary(
+        node_dict_pfc,
+        staged.buffer(V11_FILENAMES.node_dictionary_offsets)?,
+        staged.buffer(V11_FILENAMES.node_dictionary_blocks)?,
+    )
+    .await?;
+    offsets.finish()?;
+    data.finish()?;
 
-    let predicate_dict_pfc = storage_10::PersistentLayerStore::get_file(
+    let predicate_dict_pfc = read_file(
         v10_store,
         id,
         V10_FILENAMES.predicate_dictionary_blocks,
+        observer,
     )
     .await?;
-    let UntypedDictionaryOutput { offsets, data } =
-        convert_untyped_dictionary(predicate_dict_pfc).await?;
-
-    write_bytes_to_file(
-        v11_store,
-        id,
-        V11_FILENAMES.predicate_dictionary_blocks,
-        data,
-    );
-    write_bytes_to_file(
-        v11_store,
-        id,
-        V11_FILENAMES.predicate_dictionary_offsets,
-        offsets,
-    );
-
-    let value_dict_pfc = storage_10::PersistentLayerStore::get_file(
-        v10_store,
-        id,
-        V10_FILENAMES.value_dictionary_blocks,
+    let UntypedDictionaryOutput { offsets, data } = convert_untyped_dictionary(
+        predicate_dict_pfc,
+        staged.buffer(V11_FILENAMES.predicate_dictionary_offsets)?,
+        staged.buffer(V11_FILENAMES.predicate_dictionary_blocks)?,
     )
     .await?;
-    let TypedDictionaryOutput {
-        types_present,
-        type_offsets,
-        offsets,
-        data,
-        mapping,
-        offset,
-    } = convert_typed_dictionary(node_dict_pfc, value_dict_pfc, offset).await?;
+    offsets.finish()?;
+    data.finish()
+}
 
-    write_bytes_to_file(
-        v11_store,
-        id,
-        V11_FILENAMES.value_dictionary_types_present,
-        types_present,
-    );
-    write_bytes_to_file(
-        v11_store,
-        id,
-        V11_FILENAMES.value_dictionary_type_offsets,
-        type_offsets,
-    );
-    write_bytes_to_file(v11_store, id, V11_FILENAMES.value_dictionary_blocks, data);
-    write_bytes_to_file(
-        v11_store,
-        id,
-        V11_FILENAMES.value_dictionary_offsets,
-        offsets,
-    );
+fn value_dictionary_buffers(staged: &StagedLayer) -> io::Result<TypedDictionaryBuffers<FileBuf>> {
+    Ok(TypedDictionaryBuffers {
+        types_present: staged.buffer(V11_FILENAMES.value_dictionary_types_present)?,
+        type_offsets: staged.buffer(V11_FILENAMES.value_dictionary_type_offsets)?,
+        offsets: staged.buffer(V11_FILENAMES.value_dictionary_offsets)?,
+        data: staged.buffer(V11_FILENAMES.value_dictionary_blocks)?,
+    })
+}
 
-    Ok((mapping, offset))
+fn finish_value_dictionary(buffers: TypedDictionaryBuffers<FileBuf>) -> io::Result<()> {
+    buffers.types_present.finish()?;
+    buffers.type_offsets.finish()?;
+    buffers.offsets.finish()?;
+    buffers.data.finish()
 }
 
-async fn convert_triples(
-    v10_store: &directory_10::DirectoryLayerStore,
-    v11_store: &archive_11::ArchiveLayerStore,
+async fn convert_triples<S: storage_10::PersistentLayerStore>(
+    v10_store: &S,
+    staged: &StagedLayer,
     id: [u32; 5],
     is_child: bool,
-    mapping: &HashMap<u64, u64>,
+    mapping: &IdMapping,
+    observer: &dyn ConversionObserver,
 ) -> io::Result<()> {
     if is_child {
-        let pos_bits = storage_10::PersistentLayerStore::get_file(
-            v10_store,
-            id,
-            V10_FILENAMES.pos_sp_o_adjacency_list_bits,
-        )
-        .await?;
-        let pos_nums = storage_10::PersistentLayerStore::get_file(
-            v10_store,
-            id,
-            V10_FILENAMES.pos_sp_o_adjacency_list_nums,
-        )
-        .await?;
-
-        let output_nums = convert_sp_o_nums(pos_bits, pos_nums, mapping).await?;
-        write_bytes_to_file(
-            v11_store,
-            id,
-            V11_FILENAMES.pos_sp_o_adjacency_list_nums,
-            output_nums,
-        );
-
-        let neg_bits = storage_10::PersistentLayerStore::get_file(
-            v10_store,
-            id,
-            V10_FILENAMES.neg_sp_o_adjacency_list_bits,
-        )
-        .await?;
-        let neg_nums = storage_10::PersistentLayerStore::get_file(
-            v10_store,
-            id,
-            V10_FILENAMES.neg_sp_o_adjacency_list_nums,
-        )
-        .await?;
-
-        let output_nums = convert_sp_o_nums(neg_bits, neg_nums, mapping).await?;
-        write_bytes_to_file(
-            v11_store,
-            id,
-            V11_FILENAMES.neg_sp_o_adjacency_list_nums,
-            output_nums,
-        );
+        for (files, subjects) in [
+            (
+                [
+                    V10_FILENAMES.pos_sp_o_adjacency_list_nums,
+                    V10_FILENAMES.pos_sp_o_adjacency_list_bits,
+                    V10_FILENAMES.pos_sp_o_adjacency_list_bit_index_blocks,
+                    V10_FILENAMES.pos_sp_o_adjacency_list_bit_index_sblocks,
+                ],
+                V10_FILENAMES.pos_subjects,
+            ),
+            (
+                [
+                    V10_FILENAMES.neg_sp_o_adjacency_list_nums,
+                    V10_FILENAMES.neg_sp_o_adjacency_list_bits,
+                    V10_FILENAMES.neg_sp_o_adjacency_list_bit_index_blocks,
+                    V10_FILENAMES.neg_sp_o_adjacency_list_bit_index_sblocks,
+                ],
+                V10_FILENAMES.neg_subjects,
+            ),
+        ] {
+            convert_adjacency_list(v10_store, staged, id, files, mapping, observer).await?;
+            let input = read_file(v10_store, id, subjects, observer).await?;
+            convert_subjects(input, mapping, staged.buffer(subjects)?)
+                .await?
+                .finish()?;
+        }
     } else {
-        let base_bits = storage_10::PersistentLayerStore::get_file(
-            v10_store,
-            id,
-            V10_FILENAMES.base_sp_o_adjacency_list_bits,
-        )
-        .await?;
-        let base_nums = storage_10::PersistentLayerStore::get_file(
+        convert_adjacency_list(
             v10_store,
+            staged,
             id,
-            V10_FILENAMES.base_sp_o_adjacency_list_nums,
+            [
+                V10_FILENAMES.base_sp_o_adjacency_list_nums,
+                V10_FILENAMES.base_sp_o_adjacency_list_bits,
+                V10_FILENAMES.base_sp_o_adjacency_list_bit_index_blocks,
+                V10_FILENAMES.base_sp_o_adjacency_list_bit_index_sblocks,
+            ],
+            mapping,
+            observer,
         )
         .await?;
-
-        let output_nums = convert_sp_o_nums(base_bits, base_nums, mapping).await?;
-        write_bytes_to_file(
-            v11_store,
-            id,
-            V11_FILENAMES.base_sp_o_adjacency_list_nums,
-            output_nums,
-        );
     }
 
     Ok(())
 }
 
-async fn copy_unchanged_files(
-    from: &directory_10::DirectoryLayerStore,
-    to: &archive_11::ArchiveLayerStore,
+/// Convert an sp_o adjacency list, given as its nums, bits, bit index
+/// blocks and bit index sblocks, and build the index of its bits.
+async fn convert_adjacency_list<S: storage_10::PersistentLayerStore>(
+    v10_store: &S,
+    staged: &StagedLayer,
+    id: [u32; 5],
+    [nums, bits, blocks, sblocks]: [&str; 4],
+    mapping: &IdMapping,
+    observer: &dyn ConversionObserver,
+) -> io::Result<()> {
+    let bits_input = read_file(v10_store, id, bits, observer).await?;
+    let nums_input = read_file(v10_store, id, nums, observer).await?;
+    let (nums_output, bits_output) = convert_sp_o(
+        bits_input,
+        nums_input,
+        mapping,
+        staged.buffer(nums)?,
+        staged.buffer(bits)?,
+    )
+    .await?;
+    nums_output.finish()?;
+    bits_output.finish()?;
+
+    structure_11::build_bitindex(
+        storage_11::FileLoad::open_read(&staged.file(bits)).await?,
+        storage_11::FileStore::open_write(&staged.file(blocks)).await?,
+        storage_11::FileStore::open_write(&staged.file(sblocks)).await?,
+    )
+    .await
+}
+
+async fn copy_unchanged_files<S: storage_10::PersistentLayerStore>(
+    from: &S,
+    to: &StagedLayer,
     id: [u32; 5],
+    observer: &dyn ConversionObserver,
 ) -> Result<(), LayerConversionError> {
     for filename in UNCHANGED_FILES.iter() {
-        copy_file(from, to, id, filename).await?;
+        copy_file(from, to, id, filename, observer).await?;
     }
 
     Ok(())
 }
 
-async fn copy_indexes(
-    from: &directory_10::DirectoryLayerStore,
-    to: &archive_11::ArchiveLayerStore,
+async fn copy_indexes<S: storage_10::PersistentLayerStore>(
+    from: &S,
+    to: &StagedLayer,
     id: [u32; 5],
     is_child: bool,
+    observer: &dyn ConversionObserver,
 ) -> Result<(), LayerConversionError> {
     let iter = if is_child {
         CHILD_INDEX_FILES.iter()
@@ -553,50 +849,25 @@ async fn copy_indexes(
         BASE_INDEX_FILES.iter()
     };
     for filename in iter {
-        copy_file(from, to, id, filename).await?;
+        copy_file(from, to, id, filename, observer).await?;
     }
 
     Ok(())
 }
 
-async fn rebuild_indexes(
-    store: &archive_11::ArchiveLayerStore,
-    id: [u32; 5],
-    is_child: bool,
-) -> io::Result<()> {
+async fn rebuild_indexes(staged: &StagedLayer, is_child: bool) -> io::Result<()> {
     let pos_objects_file = if is_child {
-        Some(
-            storage_11::PersistentLayerStore::get_file(store, id, V11_FILENAMES.pos_objects)
-                .await?,
-        )
+        Some(staged.file(V11_FILENAMES.pos_objects))
     } else {
         None
     };
 
-    let pos_sp_o_nums = storage_11::PersistentLayerStore::get_file(
-        store,
-        id,
-        V11_FILENAMES.pos_sp_o_adjacency_list_nums,
-    )
-    .await?;
-    let pos_sp_o_bits = storage_11::PersistentLayerStore::get_file(
-        store,
-        id,
-        V11_FILENAMES.pos_sp_o_adjacency_list_bits,
-    )
-    .await?;
-    let pos_sp_o_bit_index_blocks = storage_11::PersistentLayerStore::get_file(
-        store,
-        id,
-        V11_FILENAMES.pos_sp_o_adjacency_list_bit_index_blocks,
-    )
-    .await?;
-    let pos_sp_o_bit_index_sblocks = storage_11::PersistentLayerStore::get_file(
-        store,
-        id,
-        V11_FILENAMES.pos_sp_o_adjacency_list_bit_index_sblocks,
-    )
-    .await?;
+    let pos_sp_o_nums = staged.file(V11_FILENAMES.pos_sp_o_adjacency_list_nums);
+    let pos_sp_o_bits = staged.file(V11_FILENAMES.pos_sp_o_adjacency_list_bits);
+    let pos_sp_o_bit_index_blocks =
+        staged.file(V11_FILENAMES.pos_sp_o_adjacency_list_bit_index_blocks);
+    let pos_sp_o_bit_index_sblocks =
+        staged.file(V11_FILENAMES.pos_sp_o_adjacency_list_bit_index_sblocks);
 
     let pos_sp_o_files = storage_11::AdjacencyListFiles {
         bitindex_files: storage_11::BitIndexFiles {
@@ -607,30 +878,12 @@ async fn rebuild_indexes(
         nums_file: pos_sp_o_nums,
     };
 
-    let pos_o_ps_nums = storage_11::PersistentLayerStore::get_file(
-        store,
-        id,
-        V11_FILENAMES.pos_o_ps_adjacency_list_nums,
-    )
-    .await?;
-    let pos_o_ps_bits = storage_11::PersistentLayerStore::get_file(
-        store,
-        id,
-        V11_FILENAMES.pos_o_ps_adjacency_list_bits,
-    )
-    .await?;
-    let pos_o_ps_bit_index_blocks = storage_11::PersistentLayerStore::get_file(
-        store,
-        id,
-        V11_FILENAMES.pos_o_ps_adjacency_list_bit_index_blocks,
-    )
-    .await?;
-    let pos_o_ps_bit_index_sblocks = storage_11::PersistentLayerStore::get_file(
-        store,
-        id,
-        V11_FILENAMES.pos_o_ps_adjacency_list_bit_index_sblocks,
-    )
-    .await?;
+    let pos_o_ps_nums = staged.file(V11_FILENAMES.pos_o_ps_adjacency_list_nums);
+    let pos_o_ps_bits = staged.file(V11_FILENAMES.pos_o_ps_adjacency_list_bits);
+    let pos_o_ps_bit_index_blocks =
+        staged.file(V11_FILENAMES.pos_o_ps_adjacency_list_bit_index_blocks);
+    let pos_o_ps_bit_index_sblocks =
+        staged.file(V11_FILENAMES.pos_o_ps_adjacency_list_bit_index_sblocks);
 
     let pos_o_ps_files = storage_11::AdjacencyListFiles {
         bitindex_files: storage_11::BitIndexFiles {
@@ -644,35 +897,14 @@ async fn rebuild_indexes(
     builder_11::build_object_index(pos_sp_o_files, pos_o_ps_files, pos_objects_file).await?;
 
     if is_child {
-        let neg_objects_file = Some(
-            storage_11::PersistentLayerStore::get_file(store, id, V11_FILENAMES.neg_objects)
-                .await?,
-        );
+        let neg_objects_file = Some(staged.file(V11_FILENAMES.neg_objects));
 
-        let neg_sp_o_nums = storage_11::PersistentLayerStore::get_file(
-            store,
-            id,
-            V11_FILENAMES.neg_sp_o_adjacency_list_nums,
-        )
-        .await?;
-        let neg_sp_o_bits = storage_11::PersistentLayerStore::get_file(
-            store,
-            id,
-            V11_FILENAMES.neg_sp_o_adjacency_list_bits,
-        )
-        .await?;
-        let neg_sp_o_bit_index_blocks = storage_11::PersistentLayerStore::get_file(
-            store,
-            id,
-            V11_FILENAMES.neg_sp_o_adjacency_list_bit_index_blocks,
-        )
-        .await?;
-        let neg_sp_o_bit_index_sblocks = storage_11::PersistentLayerStore::get_file(
-            store,
-            id,
-            V11_FILENAMES.neg_sp_o_adjacency_list_bit_index_sblocks,
-        )
-        .await?;
+        let neg_sp_o_nums = staged.file(V11_FILENAMES.neg_sp_o_adjacency_list_nums);
+        let neg_sp_o_bits = staged.file(V11_FILENAMES.neg_sp_o_adjacency_list_bits);
+        let neg_sp_o_bit_index_blocks =
+            staged.file(V11_FILENAMES.neg_sp_o_adjacency_list_bit_index_blocks);
+        let neg_sp_o_bit_index_sblocks =
+            staged.file(V11_FILENAMES.neg_sp_o_adjacency_list_bit_index_sblocks);
 
         let neg_sp_o_files = storage_11::AdjacencyListFiles {
             bitindex_files: storage_11::BitIndexFiles {
@@ -683,30 +915,12 @@ async fn rebuild_indexes(
             nums_file: neg_sp_o_nums,
         };
 
-        let neg_o_ps_nums = storage_11::PersistentLayerStore::get_file(
-            store,
-            id,
-            V11_FILENAMES.neg_o_ps_adjacency_list_nums,
-        )
-        .await?;
-        let neg_o_ps_bits = storage_11::PersistentLayerStore::get_file(
-            store,
-            id,
-            V11_FILENAMES.neg_o_ps_adjacency_list_bits,
-        )
-        .await?;
-        let neg_o_ps_bit_index_blocks = storage_11::PersistentLayerStore::get_file(
-            store,
-            id,
-            V11_FILENAMES.neg_o_ps_adjacency_list_bit_index_blocks,
-        )
-        .await?;
-        let neg_o_ps_bit_index_sblocks = storage_11::PersistentLayerStore::get_file(
-            store,
-            id,
-            V11_FILENAMES.neg_o_ps_adjacency_list_bit_index_sblocks,
-        )
-        .await?;
+        let neg_o_ps_nums = staged.file(V11_FILENAMES.neg_o_ps_adjacency_list_nums);
+        let neg_o_ps_bits = staged.file(V11_FILENAMES.neg_o_ps_adjacency_list_bits);
+        let neg_o_ps_bit_index_blocks =
+            staged.file(V11_FILENAMES.neg_o_ps_adjacency_list_bit_index_blocks);
+        let neg_o_ps_bit_index_sblocks =
+            staged.file(V11_FILENAMES.neg_o_ps_adjacency_list_bit_index_sblocks);
 
         let neg_o_ps_files = storage_11::AdjacencyListFiles {
             bitindex_files: storage_11::BitIndexFiles {
@@ -723,80 +937,83 @@ async fn rebuild_indexes(
     Ok(())
 }
 
+/// Rebuild every index of the layer with the v11 builders, and report
+/// the files that differ from what was copied from the v10 layer.
+async fn rebuild_and_compare_indexes(
+    staged: &StagedLayer,
+    id: [u32; 5],
+    is_child: bool,
+    object_index_copied: bool,
+    observer: &dyn ConversionObserver,
+) -> io::Result<()> {
+    for file in rebuild_all_indexes(staged, is_child, object_index_copied).await? {
+        observer.index_diverged(id, file);
+    }
+
+    Ok(())
+}
+
 async fn write_parent_map(
     workdir: &str,
     id: [u32; 5],
-    mapping: HashMap<u64, u64>,
+    mapping: IdMapping,
     offset: u64,
 ) -> io::Result<()> {
     let pathbuf = path_for_parent_map(workdir, id);
     tokio::fs::create_dir_all(pathbuf.parent().unwrap()).await?;
 
-    let mut options = tokio::fs::OpenOptions::new();
-    options.create(true).write(true);
-
-    let mut file = options.open(pathbuf).await?;
-
-    let mut map_vec: Vec<_> = mapping.into_iter().collect();
+    let mut map_vec: Vec<_> = mapping.values.into_iter().collect();
     map_vec.sort();
 
     let parent_map = ParentMap {
         mapping: map_vec,
         offset,
+        shifts: mapping.shifts,
     };
 
     let v = postcard::to_allocvec(&parent_map).unwrap();
-    file.write_all(&v).await?;
-    file.flush().await
-}
-
-fn write_bytes_to_file(
-    store: &archive_11::ArchiveLayerStore,
-    id: [u32; 5],
-    file: &str,
-    bytes: Bytes,
-) {
-    let file = consts_11::FILENAME_ENUM_MAP[file];
-    store.write_bytes(id, file, bytes);
+    // children of this layer rely on the parent map once the layer is
+    // marked as completed, so it has to be on disk by then
+    write_file_durably(&pathbuf, &v).await
 }
 
-async fn copy_file(
-    from: &directory_10::DirectoryLayerStore,
-    to: &archive_11::ArchiveLayerStore,
+async fn copy_file<S: storage_10::PersistentLayerStore>(
+    from: &S,
+    to: &StagedLayer,
     id: [u32; 5],
     file: &str,
+    observer: &dyn ConversionObserver,
 ) -> Result<(), LayerConversionError> {
-    inner_copy_file(from, to, id, file).await.map_err(|e| {
-        LayerConversionError::new(
-            id,
-            InnerLayerConversionError::FileCopyError {
-                name: file.to_string(),
-                source: e,
-            },
-        )
-    })
+    inner_copy_file(from, to, id, file, observer)
+        .await
+        .map_err(|e| {
+            LayerConversionError::new(
+                id,
+                InnerLayerConversionError::FileCopyError {
+                    name: file.to_string(),
+                    source: e,
+                },
+            )
+        })
 }
-async fn inner_copy_file(
-    from: &directory_10::DirectoryLayerStore,
-    to: &archive_11::ArchiveLayerStore,
+async fn inner_copy_file<S: storage_10::PersistentLayerStore>(
+    from: &S,
+    to: &StagedLayer,
     id: [u32; 5],
     file: &str,
+    observer: &dyn ConversionObserver,
 ) -> io::Result<()> {
     // this assumes that the file name is the same in from and to,
     // which should be correct for everythning that is not a
     // dictionary. At this point, we've already copied over the
     // dictionaries.
-    let input = storage_10::PersistentLayerStore::get_file(from, id, file).await?;
-    if let Some(map) = storage_10::FileLoad::map_if_exists(&input).await? {
-        write_bytes_to_file(to, id, file, map);
-    }
-
-    Ok(())
+    let input = read_file(from, id, file, observer).await?;
+    to.copy_from(file, &input).await
 }
 
 #[allow(unused)]
-async fn copy_rollup_file(
-    from: &directory_10::DirectoryLayerStore,
+async fn copy_rollup_file<S: storage_10::PersistentLayerStore>(
+    from: &S,
     to: &archive_11::ArchiveLayerStore,
     id: [u32; 5],
 ) -> io::Result<()> {
@@ -814,8 +1031,8 @@ async fn copy_rollup_file(
     Ok(())
 }
 
-async fn assert_no_remap_exists(
-    store: &directory_10::DirectoryLayerStore,
+async fn assert_no_remap_exists<S: storage_10::PersistentLayerStore>(
+    store: &S,
     id: [u32; 5],
 ) -> Result<(), InnerLayerConversionError> {
     if storage_10::PersistentLayerStore::file_exists(
@@ -830,3 +1047,170 @@ async fn assert_no_remap_exists(
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_store::TestStore;
+    use terminus_store_10::layer::{Layer as _, StringTriple};
+    use terminus_store_11::layer::Layer as _;
+
+    #[tokio::test]
+    async fn merged_values_move_the_ids_of_child_layers() {
+        let store = TestStore::new("merged_values");
+        let (base, child) = store.layers_with_equal_values().await;
+        store.convert(base, ValueMode::Auto).await;
+        store.convert(child, ValueMode::Auto).await;
+
+        let triple = |s: &str, p: &str, o: &str| (s.to_string(), p.to_string(), o.to_string());
+        assert_eq!(
+            vec![
+                triple("a", "p", "\"foo\""),
+                triple("a", "q", "\"bar\""),
+                triple("b", "p", "a"),
+            ],
+            store.v11_triples(base).await
+        );
+        assert_eq!(
+            vec![
+                triple("a", "p", "\"foo\""),
+                triple("b", "p", "a"),
+                triple("c", "p", "\"foo\""),
+                triple("c", "q", "b"),
+            ],
+            store.v11_triples(child).await
+        );
+    }
+
+    #[tokio::test]
+    async fn values_equal_to_those_of_ancestors_take_their_ids() {
+        let store = TestStore::new("values_of_ancestors");
+        let xsd_string =
+            |value: &str| format!("\"{value}\"^^'http://www.w3.org/2001/XMLSchema#string'");
+        let mut layers = Vec::new();
+        let mut layer: Option<terminus_store_10::store::StoreLayer> = None;
+        for triples in [
+            vec![StringTriple::new_value("a", "p", "foo")],
+            vec![
+                StringTriple::new_value("c", "p", "baz"),
+                StringTriple::new_node("c", "q", "a"),
+            ],
+            vec![
+                StringTriple::new_value("e", "p", &xsd_string("foo")),
+                StringTriple::new_value("f", "p", &xsd_string("baz")),
+                StringTriple::new_node("f", "q", "c"),
+            ],
+        ] {
+            let builder = match &layer {
+                Some(parent) => parent.open_write().await.unwrap(),
+                None => store.v10().create_base_layer().await.unwrap(),
+            };
+            for triple in triples {
+                builder.add_string_triple(triple).unwrap();
+            }
+            let committed = builder.commit().await.unwrap();
+            layers.push(committed.name());
+            layer = Some(committed);
+        }
+        for id in &layers {
+            store.convert(*id, ValueMode::Auto).await;
+        }
+
+        let triple = |s: &str, p: &str, o: &str| (s.to_string(), p.to_string(), o.to_string());
+        assert_eq!(
+            vec![
+                triple("a", "p", "\"foo\""),
+                triple("c", "p", "\"baz\""),
+                triple("c", "q", "a"),
+                triple("e", "p", "\"foo\""),
+                triple("f", "p", "\"baz\""),
+                triple("f", "q", "c"),
+            ],
+            store.v11_triples(layers[2]).await
+        );
+        let layer = store.v11_layer(layers[2]).await;
+        for (value, expected) in [("foo", ["a", "e"]), ("baz", ["c", "f"])] {
+            let id = layer
+                .object_value_id(&<String as tfc_11::TdbDataType>::make_entry(&value))
+                .unwrap();
+            let subjects: Vec<_> = layer
+                .triples_o(id)
+                .map(|triple| layer.id_subject(triple.subject).unwrap())
+                .collect();
+            assert_eq!(expected.to_vec(), subjects, "{value}");
+        }
+    }
+
+    #[tokio::test]
+    async fn large_ancestor_dictionaries_are_read_whole() {
+        let store = TestStore::new("large_ancestor_dictionaries");
+        let values: Vec<String> = (0..200u64)
+            .map(|i| format!("{:016x}", i.wrapping_mul(0x9e3779b97f4a7c15)))
+            .collect();
+        let values: Vec<&str> = values.iter().map(|v| &v[..]).collect();
+        let base = store.value_layer(None, &values).await;
+        let child = store.value_layer(Some(base), &values[..1]).await;
+        for id in [base, child] {
+            store.convert(id, ValueMode::Auto).await;
+        }
+
+        assert_eq!(
+            store.v11_triples(base).await,
+            store.v11_triples(child).await
+        );
+    }
+
+    #[tokio::test]
+    async fn naive_layers_load_in_v11() {
+        let store = TestStore::new("naive_layers");
+        let (base, child) = store.layers_with_equal_values().await;
+        store.convert(base, ValueMode::Naive).await;
+        store.convert(child, ValueMode::Naive).await;
+
+        let layer = store.v11_layer(child).await;
+        // looking triples up by object goes through the object index,
+        // which is copied from the v10 layer
+        let foo = layer
+            .object_value_id(&<String as tfc_11::TdbDataType>::make_entry(&"foo"))
+            .unwrap();
+        let subjects: Vec<_> = layer
+            .triples_o(foo)
+            .map(|triple| layer.id_subject(triple.subject).unwrap())
+            .collect();
+        assert_eq!(vec!["a", "c"], subjects);
+        assert_eq!(5, store.v11_triples(child).await.len());
+    }
+
+    /// Collects the files whose rebuilt index differs from the v10 one.
+    #[derive(Default)]
+    struct DivergenceObserver(std::sync::Mutex<Vec<String>>);
+
+    impl ConversionObserver for DivergenceObserver {
+        fn index_diverged(&self, layer: [u32; 5], file: &str) {
+            let layer = name_to_string(layer);
+            self.0.lock().unwrap().push(format!("{layer}/{file}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn rebuilt_indexes_of_clean_layers_do_not_diverge() {
+        for mode in [ValueMode::Naive, ValueMode::Auto] {
+            let store = TestStore::new(&format!("rebuilt_indexes_{mode:?}"));
+            let (base, child) = store.layers_with_equal_values().await;
+            let settings = LayerSettings {
+                mode,
+                datatypes: &DatatypeMap::new(),
+                rebuild_all_indexes: true,
+            };
+            let observer = DivergenceObserver::default();
+            store.convert_with(base, settings, &observer).await;
+            store.convert_with(child, settings, &observer).await;
+
+            assert_eq!(
+                Vec::<String>::new(),
+                *observer.0.lock().unwrap(),
+                "{mode:?}"
+            );
+        }
+    }
+}
@@ -1,74 +1,446 @@
-use chrono::Local;
+use terminus_store_10::storage as storage_10;
 use terminus_store_10::storage::directory as directory_10;
 use terminus_store_10::storage::name_to_string;
-use terminus_store_10::storage::string_to_name;
-use terminus_store_11::storage::archive as archive_11;
 use tokio::fs::OpenOptions;
-use tokio::io::AsyncBufReadExt;
 use tokio::io::AsyncWriteExt;
-use tokio::io::BufReader;
 
+use crate::convert_labels::*;
 use crate::convert_layer::*;
+use crate::disk_space::*;
+use crate::durable::*;
+use crate::failure_impact::*;
+use crate::interrupt::{defer_interrupts, interrupted};
+use crate::layer_modes::*;
+use crate::migration::*;
+use crate::observer::ConversionObserver;
+use crate::options::ConversionOptions;
+use crate::plan::parent_map;
 use crate::reachable::*;
+use crate::replace::*;
+use crate::run_manifest::*;
+use crate::status_log::*;
+use crate::storage_version::*;
+use crate::store_archive::*;
+use crate::store_lock::*;
+use crate::store_manifest::*;
+use crate::tar_store::*;
 
 use std::collections::HashMap;
 use std::io;
 use std::path::PathBuf;
-use std::str::FromStr;
+use std::time::Instant;
 
+use async_trait::async_trait;
 use tokio::fs;
 
 use thiserror::*;
 
 #[derive(Error, Debug)]
 #[error(transparent)]
+#[non_exhaustive]
 pub enum StoreConversionError {
     LayerConversion(#[from] LayerConversionError),
+    #[error(
+        "Conversion interrupted after converting {0} layers. Run convert-store again to resume"
+    )]
+    Interrupted(usize),
     #[error("Some layer conversions failed")]
     LayerConversionsFailed(Vec<[u32; 5]>),
+    #[error("Cannot sync: {0} does not exist. Run a full conversion first")]
+    NothingToSync(String),
+    #[error("Cannot sync and also clean or replace: a sync run reuses the workdir and the converted store")]
+    SyncWithCleanOrReplace,
+    #[error("Cannot retry: {0} does not exist. Run a conversion first")]
+    NothingToRetry(String),
+    #[error("Cannot replace {0}: the source store is a tar archive")]
+    CannotReplaceArchive(String),
+    #[error("Cannot replace {0}: it is converted through more than one storage version")]
+    CannotReplaceIntermediate(String),
+    #[error("{} labels conflict with labels already in the destination store", .0.len())]
+    LabelConflicts(Vec<LabelConflict>),
+    StoreArchive(#[from] StoreArchiveError),
+    Replace(#[from] ReplaceError),
+    Lock(#[from] LockError),
+    Manifest(#[from] ManifestError),
+    RunManifest(#[from] RunManifestError),
+    DiskSpace(#[from] DiskSpaceError),
+    LayerMode(#[from] LayerModeError),
+    StorageVersion(#[from] StorageVersionError),
     Io(#[from] io::Error),
 }
 
+/// Convert a store into a store of the latest version, as described by
+/// `options`. The version of the source is found first, and stores that
+/// can't be converted are refused before anything is written.
 pub async fn convert_store(
+    options: &ConversionOptions,
+    observer: &dyn ConversionObserver,
+) -> Result<(), StoreConversionError> {
+    if options.sync && (options.clean || options.replace) {
+        return Err(StoreConversionError::SyncWithCleanOrReplace);
+    }
+    let from = options.from.as_str();
+    // archives are only read as v10 stores, and their layout is checked
+    // once they are indexed
+    let source = if is_tar_archive(from) {
+        StorageVersion::V10
+    } else {
+        detect_storage_version(from).await?
+    };
+    let steps = migration_path(source, StorageVersion::LATEST)?;
+    if steps.is_empty() {
+        return Err(StorageVersionError::AlreadyConverted {
+            path: from.to_string(),
+            version: source,
+        }
+        .into());
+    }
+
+    run_migration(&steps, options, observer).await
+}
+
+/// The conversion of a TerminusDB 10 store into a TerminusDB 11 store.
+pub struct V10ToV11;
+
+#[async_trait]
+impl MigrationStep for V10ToV11 {
+    fn source_version(&self) -> StorageVersion {
+        StorageVersion::V10
+    }
+
+    fn target_version(&self) -> StorageVersion {
+        StorageVersion::V11
+    }
+
+    async fn convert(
+        &self,
+        options: &ConversionOptions,
+        observer: &dyn ConversionObserver,
+    ) -> Result<(), StoreConversionError> {
+        convert_v10_store(options, observer).await
+    }
+}
+
+async fn convert_v10_store(
+    options: &ConversionOptions,
+    observer: &dyn ConversionObserver,
+) -> Result<(), StoreConversionError> {
+    let from = options.from.as_str();
+    if is_tar_archive(from) {
+        if options.replace {
+            return Err(StoreConversionError::CannotReplaceArchive(from.to_string()));
+        }
+        if options.verbose {
+            println!("indexing archive `{from}`");
+        }
+        let (v10_layer_store, v10_label_store) = open_tar_store(from).await?;
+        check_v10_archive(from, &v10_layer_store)?;
+        convert_store_from(&v10_layer_store, &v10_label_store, options, observer).await
+    } else {
+        let v10_layer_store = directory_10::DirectoryLayerStore::new(from);
+        let v10_label_store = directory_10::DirectoryLabelStore::new(from);
+        convert_store_from(&v10_layer_store, &v10_label_store, options, observer).await
+    }
+}
+
+async fn convert_store_from<S: storage_10::PersistentLayerStore, L: storage_10::LabelStore>(
+    v10_layer_store: &S,
+    v10_label_store: &L,
+    options: &ConversionOptions,
+    observer: &dyn ConversionObserver,
+) -> Result<(), StoreConversionError> {
+    let (from, to, work) = (&options.from[..], &options.to[..], &options.workdir[..]);
+    let (labels, verbose, sync) = (options.labels.as_deref(), options.verbose, options.sync);
+    if sync {
+        check_sync_target(to, work).await?;
+    }
+    if let Some(port) = options.server_port {
+        check_server_port(port)?;
+    }
+    let locks = lock_conversion(from, to, work).await?;
+    let settings = RunManifest::new(options).await?;
+    let recorded = read_run_manifest(work).await?;
+    check_run_manifest(work, &settings, true, options.force).await?;
+
+    // a sync run is expected to move labels it wrote itself earlier
+    let label_conflict = options.label_conflict.unwrap_or(if sync {
+        LabelConflictPolicy::Overwrite
+    } else {
+        LabelConflictPolicy::Fail
+    });
+    if label_conflict == LabelConflictPolicy::Fail {
+        let conflicts = find_label_conflicts(v10_label_store, to, labels).await?;
+        if !conflicts.is_empty() {
+            write_conflict_report(work, &conflicts).await?;
+            return Err(StoreConversionError::LabelConflicts(conflicts));
+        }
+    }
+
+    let roots = find_label_roots(v10_layer_store, v10_label_store, labels, verbose).await?;
+    let reachable = find_reachable_layers_from_roots(v10_layer_store, &roots, verbose).await?;
+    let modes = find_layer_modes(v10_layer_store, &roots, options).await?;
+
+    let mut status_log = StatusLog::open(work).await?;
+    let pending: Vec<_> = conversion_order(&reachable)
+        .into_iter()
+        .filter(|l| status_log.status(*l) != Some(ConversionStatus::Completed))
+        .collect();
+    check_mode_changes(
+        v10_layer_store,
+        &roots,
+        &reachable,
+        &pending,
+        &modes,
+        options,
+        recorded.as_ref(),
+        &status_log,
+    )
+    .await?;
+    let space_guard = SpaceGuard::new(to, work, options.min_free_space);
+    if !options.skip_space_check {
+        let estimate =
+            estimate_required_space(v10_layer_store, &pending, &parent_map(&reachable), &modes)
+                .await?;
+        println!(
+            "Estimated space needed: {} for the converted layers, {} in the workdir",
+            format_size(estimate.output),
+            format_size(estimate.workdir)
+        );
+        space_guard.check(&estimate)?;
+    }
+
+    status_log
+        .run_started(conversion_order(&reachable).len())
+        .await?;
+    let result = convert_reachable_layers(
+        v10_layer_store,
+        options,
+        &mut status_log,
+        &reachable,
+        &modes,
+        (!options.skip_space_check).then_some(&space_guard),
+        observer,
+    )
+    .await;
+    let failed = failed_layers(&result);
+    if !failed.is_empty() {
+        report_failure_impact(v10_layer_store, &roots, &reachable, work, &failed).await?;
+    }
+    let (converted, failures) = result?;
+
+    let label_report = convert_labels(
+        v10_label_store,
+        to,
+        labels,
+        label_conflict,
+        &options.label_suffix,
+    )
+    .await?;
+    if !label_report.conflicts.is_empty() {
+        write_conflict_report(work, &label_report.conflicts).await?;
+        if label_conflict == LabelConflictPolicy::Fail {
+            return Err(StoreConversionError::LabelConflicts(label_report.conflicts));
+        }
+    }
+    write_version_file(to, StorageVersion::V11).await?;
+
+    if sync {
+        println!("Synced {} new layers", converted.len());
+        for label in label_report.updated.iter() {
+            println!("label updated: {label}");
+        }
+    }
+
+    if !failures.is_empty() {
+        Err(StoreConversionError::LayerConversionsFailed(failures))
+    } else {
+        let manifest = write_store_manifest(
+            v10_layer_store,
+            from,
+            to,
+            &conversion_order(&reachable),
+            &converted,
+        )
+        .await?;
+        if verbose {
+            println!(
+                "{STORE_MANIFEST} with {} layers written to `{to}`",
+                manifest.layers.len()
+            );
+        }
+        if let Some(output_archive) = options.output_archive.as_deref() {
+            let manifest = write_store_archive(to, output_archive).await?;
+            println!(
+                "Archive with {} layers and {} labels written to `{output_archive}`",
+                manifest.layers.len(),
+                manifest.labels.len()
+            );
+        }
+        if labels.is_none() {
+            if options.clean {
+                clean_workdir(work).await?;
+                if verbose {
+                    println!("Workdir `{work}` removed");
+                }
+            }
+            if options.replace {
+                locks.release()?;
+                let replaced =
+                    replace_storage_directory(from, to, options.backup_dir.as_deref()).await?;
+                println!("Version 11 Store now available");
+                println!("Backup storage directory is in `{}`", replaced.backup);
+                if let Some(leftover) = replaced.leftover {
+                    eprintln!("Warning: {leftover}. Remove `{to}` by hand");
+                }
+            } else if is_tar_archive(from) {
+                println!("Your version 11 Store is converted in `{to}`, you will need to manually move it to the target storage location");
+            } else {
+                println!("Your version 11 Store is converted in `{to}`, you will need to manually move it to the target storage location: `{from}`");
+            }
+            println!("Conversion completed!");
+            if !options.clean {
+                println!("You can now remove your workdir: `{work}`");
+            }
+        } else {
+            println!("Partial conversion of your store is now complete.");
+        }
+        Ok(())
+    }
+}
+
+/// Lock the stores and workdir of a conversion, creating the
+/// destination and workdir if needed.
+pub async fn lock_conversion(
     from: &str,
     to: &str,
     work: &str,
-    labels: Option<&str>,
-    naive: bool,
-    keep_going: bool,
-    verbose: bool,
-    replace: bool,
-    clean: bool,
+) -> Result<StoreLocks, StoreConversionError> {
+    fs::create_dir_all(to).await?;
+    fs::create_dir_all(work).await?;
+    // a tar archive can't be moved by --replace, so it needs no lock
+    let source = fs::metadata(from)
+        .await
+        .map(|m| m.is_dir())
+        .unwrap_or(false)
+        .then_some(from);
+
+    Ok(StoreLocks::acquire(source, &[to, work])?)
+}
+
+/// Refuse to convert any of the `pending` layers in another mode than
+/// the one its parent was converted in. Layers whose status log entry
+/// doesn't say what mode they were converted in are taken to have been
+/// converted with the settings `recorded` in the workdir.
+#[allow(clippy::too_many_arguments)]
+pub async fn check_mode_changes<S: storage_10::PersistentLayerStore>(
+    v10_layer_store: &S,
+    roots: &[LabelRoots],
+    reachable: &HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>,
+    pending: &[[u32; 5]],
+    modes: &LayerModes,
+    options: &ConversionOptions,
+    recorded: Option<&RunManifest>,
+    status_log: &StatusLog,
 ) -> Result<(), StoreConversionError> {
-    let v10_layer_store = directory_10::DirectoryLayerStore::new(from);
-    let v10_label_store = directory_10::DirectoryLabelStore::new(from);
-    let v11_layer_store = archive_11::ArchiveLayerStore::new(to);
+    let parents = parent_map(reachable);
+    let is_completed = |layer| status_log.status(layer) == Some(ConversionStatus::Completed);
+    let unrecorded = pending
+        .iter()
+        .filter_map(|layer| parents.get(layer))
+        .any(|parent| is_completed(*parent) && status_log.converted_mode(*parent).is_none());
+    let recorded_modes = match recorded.map(|recorded| recorded.with_modes(options)) {
+        Some(recorded_options) if unrecorded && recorded_options != *options => Some(
+            find_layer_modes(
+                v10_layer_store,
+                roots,
+                &ConversionOptions {
+                    verbose: false,
+                    ..recorded_options
+                },
+            )
+            .await?,
+        ),
+        _ => None,
+    };
+    let converted_mode = |layer| {
+        is_completed(layer).then(|| {
+            status_log
+                .converted_mode(layer)
+                .unwrap_or_else(|| recorded_modes.as_ref().unwrap_or(modes).mode(layer))
+        })
+    };
+    check_converted_parents(pending, &parents, modes, converted_mode)?;
+
+    Ok(())
+}
 
-    let reachable =
-        find_reachable_layers(&v10_layer_store, &v10_label_store, labels, verbose).await?;
+/// The layers a run converted, and the layers that failed.
+pub type LayersConverted = Result<(Vec<[u32; 5]>, Vec<[u32; 5]>), StoreConversionError>;
 
-    let mut options = OpenOptions::new();
-    options.create(true);
-    options.write(true);
+/// Convert every reachable layer, parents before children, skipping
+/// those the status log marks as completed. Returns the layers
+/// converted, and the layers that failed if `keep_going` was set.
+/// When interrupted, stops before the next layer.
+pub async fn convert_reachable_layers<S: storage_10::PersistentLayerStore>(
+    v10_layer_store: &S,
+    options: &ConversionOptions,
+    status_log: &mut StatusLog,
+    reachable: &HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>,
+    modes: &LayerModes,
+    space_guard: Option<&SpaceGuard>,
+    observer: &dyn ConversionObserver,
+) -> LayersConverted {
+    let order = conversion_order(reachable);
+    let completed = order
+        .iter()
+        .filter(|l| status_log.status(**l) == Some(ConversionStatus::Completed))
+        .count();
+    observer.run_started(order.len(), completed);
+    let deferred = defer_interrupts();
+    let result = visit_reachable_layers(
+        v10_layer_store,
+        options,
+        status_log,
+        reachable,
+        modes,
+        space_guard,
+        observer,
+    )
+    .await;
+    drop(deferred);
+    observer.run_finished();
+
+    result
+}
+
+async fn visit_reachable_layers<S: storage_10::PersistentLayerStore>(
+    v10_layer_store: &S,
+    options: &ConversionOptions,
+    status_log: &mut StatusLog,
+    reachable: &HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>,
+    modes: &LayerModes,
+    space_guard: Option<&SpaceGuard>,
+    observer: &dyn ConversionObserver,
+) -> LayersConverted {
+    let (to, work) = (&options.to[..], &options.workdir[..]);
+    let mut open_options = OpenOptions::new();
+    open_options.create(true);
+    open_options.write(true);
     let mut error_path = PathBuf::from(work);
     std::fs::create_dir_all(&error_path)?;
     error_path.push("error.log");
-    let mut error_log = options.open(error_path).await?;
-    let status_hashmap = get_status_hashmap(work).await?;
-    let mut status_log = status_log(work).await?;
+    let mut error_log = open_options.open(error_path).await?;
 
     let mut visit_queue = Vec::new();
     visit_queue.extend(reachable[&None].clone());
 
     let mut failures = Vec::new();
+    let mut converted = Vec::new();
 
     while let Some(layer) = visit_queue.pop() {
-        let status = status_hashmap.get(&layer);
-        match status {
+        match status_log.status(layer) {
             Some(ConversionStatus::Completed) => {
-                if verbose {
-                    println!("skipping: {}", name_to_string(layer))
-                };
+                observer.layer_skipped(layer);
                 // even though we skip this layer, its children still
                 // might need to be converted, so here they are added
                 // to the visit queue.
@@ -80,28 +452,41 @@ pub async fn convert_store(
             Some(_) => layer_cleanup(to, layer).await?,
             None => (),
         }
-        write_status(&mut status_log, layer, ConversionStatus::Started).await?;
+        if let Some(space_guard) = space_guard {
+            space_guard.wait_for_space(v10_layer_store, layer).await?;
+        }
+        if interrupted() {
+            status_log.interrupted(converted.len()).await?;
+            return Err(StoreConversionError::Interrupted(converted.len()));
+        }
+        status_log.layer_started(layer).await?;
+        let start = Instant::now();
         let result = convert_layer_with_stores(
-            &v10_layer_store,
-            &v11_layer_store,
+            v10_layer_store,
+            to,
             work,
-            naive,
-            verbose,
+            options.layer_settings(modes.mode(layer)),
             layer,
+            observer,
         )
         .await;
         if let Ok(()) = result {
-            write_status(&mut status_log, layer, ConversionStatus::Completed).await?;
+            sync_layer_directory(to, layer).await?;
+            status_log
+                .layer_completed(layer, start.elapsed(), modes.mode(layer))
+                .await?;
+            converted.push(layer);
             if let Some(children) = reachable.get(&Some(layer)) {
                 visit_queue.extend(children.clone());
             }
         } else if let Err(e) = result {
-            write_status(&mut status_log, layer, ConversionStatus::Error).await?;
-            eprintln!("ERROR: {e}");
+            status_log
+                .layer_failed(layer, start.elapsed(), e.kind(), e.message())
+                .await?;
             error_log.write_all(e.to_string().as_bytes()).await?;
             error_log.write_all(b"\n").await?;
             error_log.flush().await?;
-            if keep_going {
+            if options.keep_going {
                 failures.push(layer);
             } else {
                 return Err(e.into());
@@ -109,162 +494,91 @@ pub async fn convert_store(
         }
     }
 
-    convert_labels(from, to, labels).await?;
-    write_version_file(to).await?;
-
-    if !failures.is_empty() {
-        Err(StoreConversionError::LayerConversionsFailed(failures))
-    } else {
-        if labels.is_none() {
-            if clean {
-                clean_workdir(work).await?;
-                if verbose {
-                    println!("Workdir `{work}` removed");
-                }
-            }
-            if replace {
-                let backup_path = replace_storage_directory(from, to).await?;
-                println!("Version 11 Store now available");
-                println!("Backup storage directory is in `{backup_path}`");
-            } else {
-                println!("Your version 11 Store is converted in `{to}`, you will need to manually move it to the target storage location: `{from}`");
-            }
-            println!("Conversion completed!");
-            if !clean {
-                println!("You can now remove your workdir: `{work}`");
-            }
-        } else {
-            println!("Partial conversion of your store is now complete.");
-        }
-        Ok(())
-    }
-}
-
-pub enum ConversionStatus {
-    Error,
-    Completed,
-    Started,
+    Ok((converted, failures))
 }
 
-impl ToString for ConversionStatus {
-    fn to_string(&self) -> String {
-        match self {
-            ConversionStatus::Error => "Error".to_string(),
-            ConversionStatus::Completed => "Completed".to_string(),
-            ConversionStatus::Started => "Started".to_string(),
-        }
+/// The layers that failed to convert, whether the conversion kept going
+/// after a failure or stopped at the first one.
+pub fn failed_layers(result: &LayersConverted) -> Vec<[u32; 5]> {
+    match result {
+        Ok((_, failures)) => failures.clone(),
+        Err(StoreConversionError::LayerConversion(e)) => vec![e.layer()],
+        Err(_) => Vec::new(),
     }
 }
 
-impl FromStr for ConversionStatus {
-    type Err = io::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "Error" => Ok(ConversionStatus::Error),
-            "Completed" => Ok(ConversionStatus::Completed),
-            "Started" => Ok(ConversionStatus::Started),
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Unknown conversion status encountered",
-            )),
+/// The order in which `convert_store` visits the reachable layers,
+/// assuming every conversion succeeds. Parents always come before
+/// their children.
+pub fn conversion_order(reachable: &HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>) -> Vec<[u32; 5]> {
+    let mut order = Vec::new();
+    let mut visit_queue = Vec::new();
+    if let Some(roots) = reachable.get(&None) {
+        visit_queue.extend(roots.clone());
+    }
+    while let Some(layer) = visit_queue.pop() {
+        order.push(layer);
+        if let Some(children) = reachable.get(&Some(layer)) {
+            visit_queue.extend(children.clone());
         }
     }
+
+    order
 }
 
-pub async fn get_status_hashmap(work: &str) -> io::Result<HashMap<[u32; 5], ConversionStatus>> {
-    let mut status_options = OpenOptions::new();
-    status_options.read(true);
-    status_options.create(false);
-    let mut status_path = PathBuf::from(work);
-    status_path.push("status.log");
-    let status_log = status_options.open(status_path).await;
-    match status_log {
-        Ok(status_log) => {
-            let mut hashmap = HashMap::new();
-            let status_log_buf = BufReader::new(status_log);
-            let mut lines = status_log_buf.lines();
-            while let Some(line) = lines.next_line().await? {
-                let elts = line.split(' ').collect::<Vec<&str>>();
-                let layer = string_to_name(elts[0])?;
-                let status = ConversionStatus::from_str(elts[1])?;
-                hashmap.insert(layer, status);
-            }
-            Ok(hashmap)
-        }
-        Err(e) => {
-            if e.kind() == io::ErrorKind::NotFound {
-                Ok(HashMap::with_capacity(0))
-            } else {
-                Err(e)
-            }
-        }
+fn require_v10(path: &str, version: StorageVersion) -> Result<(), StorageVersionError> {
+    match version {
+        StorageVersion::V10 => Ok(()),
+        version => Err(StorageVersionError::AlreadyConverted {
+            path: path.to_string(),
+            version,
+        }),
     }
 }
 
-pub async fn write_status(
-    f: &mut fs::File,
-    layer: [u32; 5],
-    s: ConversionStatus,
-) -> Result<(), io::Error> {
-    f.write_all(format!("{} {}\n", name_to_string(layer), s.to_string(),).as_bytes())
-        .await?;
-    f.flush().await?;
-    Ok(())
+/// Refuse storage directories of anything but a v10 store, for the
+/// commands that only read v10 stores.
+pub async fn check_v10_directory(path: &str) -> Result<(), StorageVersionError> {
+    require_v10(path, detect_storage_version(path).await?)
 }
 
-pub async fn status_log(work: &str) -> io::Result<fs::File> {
-    let mut completed_options = OpenOptions::new();
-    completed_options.create(true);
-    completed_options.append(true);
-    let mut completed_path = PathBuf::from(work);
-    std::fs::create_dir_all(&completed_path)?;
-    completed_path.push("status.log");
-    let completed_log = completed_options.open(completed_path).await?;
-    Ok(completed_log)
+/// Refuse archives of anything but a v10 store, which would otherwise
+/// look like a store without any layers.
+pub fn check_v10_archive(path: &str, store: &TarLayerStore) -> Result<(), StorageVersionError> {
+    require_v10(path, store.layout().version(path)?)
 }
 
-pub async fn convert_labels(from: &str, to: &str, labels: Option<&str>) -> io::Result<()> {
-    let v11_store_path = PathBuf::from(to);
-    if let Some(labels) = labels {
-        let mut options = tokio::fs::OpenOptions::new();
-        options.create(false);
-        options.read(true);
-        let v10_store_path = PathBuf::from(from);
-        let file = options.open(labels).await?;
-        let buffered = BufReader::new(file);
-        let mut lines = buffered.lines();
-        while let Some(label_name) = lines.next_line().await? {
-            let mut from_path = v10_store_path.clone();
-            from_path.push(format!("{label_name}.label"));
-            let mut to_path = v11_store_path.clone();
-            to_path.push(format!("{label_name}.label"));
-
-            fs::copy(from_path, to_path).await?;
-        }
-    } else {
-        let mut stream = fs::read_dir(from).await?;
-        while let Some(direntry) = stream.next_entry().await? {
-            if direntry.file_type().await?.is_file() {
-                let os_name = direntry.file_name();
-                let name = os_name.to_str().ok_or_else(|| {
-                    io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "unexpected non-utf8 directory name",
-                    )
-                })?;
-                if name.ends_with(".label") {
-                    let mut to_path = v11_store_path.clone();
-                    to_path.push(name);
-                    fs::copy(direntry.path(), to_path).await?;
-                }
+/// A sync run builds on a previous conversion, so both its output and
+/// its status log have to be there already.
+async fn check_sync_target(to: &str, work: &str) -> Result<(), StoreConversionError> {
+    let mut version_path = PathBuf::from(to);
+    version_path.push(STORAGE_VERSION_FILE);
+    let mut status_path = PathBuf::from(work);
+    status_path.push(STATUS_LOG);
+    for path in [version_path, status_path] {
+        if let Err(e) = fs::metadata(&path).await {
+            if e.kind() == io::ErrorKind::NotFound {
+                return Err(StoreConversionError::NothingToSync(
+                    path.to_string_lossy().into_owned(),
+                ));
             }
+            return Err(e.into());
         }
     }
 
     Ok(())
 }
 
+/// `finalize` syncs the layer file itself, but not the directories it
+/// was created in.
+pub async fn sync_layer_directory(to: &str, layer: [u32; 5]) -> io::Result<()> {
+    let name = name_to_string(layer);
+    let mut path = PathBuf::from(to);
+    path.push(&name[..3]);
+    sync_directory(&path).await?;
+    sync_parent_directory(&path).await
+}
+
 pub async fn layer_cleanup(to: &str, layer: [u32; 5]) -> Result<(), io::Error> {
     let name = name_to_string(layer);
     println!("layer cleanup: {name}");
@@ -292,26 +606,6 @@ pub async fn layer_cleanup(to: &str, layer: [u32; 5]) -> Result<(), io::Error> {
     Ok(())
 }
 
-pub async fn write_version_file(to: &str) -> Result<(), io::Error> {
-    let mut options = OpenOptions::new();
-    options.create(true);
-    options.write(true);
-
-    let mut path = PathBuf::from(to);
-    path.push("STORAGE_VERSION");
-    let mut file = options.open(path).await?;
-    file.write_all(b"2").await?;
-    file.flush().await
-}
-
-pub async fn replace_storage_directory(from: &str, to: &str) -> Result<String, io::Error> {
-    let date = Local::now().format("%+");
-    let backup = format!("{from}.{date}.backup");
-    fs::rename(from, &backup).await?;
-    fs::rename(to, from).await?;
-    Ok(backup)
-}
-
 pub async fn clean_workdir(work: &str) -> Result<(), io::Error> {
     fs::remove_dir_all(work).await?;
     Ok(())
@@ -3,22 +3,99 @@ use terminus_store_10::storage as storage_10;
 use terminus_store_10::structure as structure_10;
 use terminus_store_11::structure::LogArrayBufBuilder;
 
-use bytes::{Bytes, BytesMut};
+use bytes::BufMut;
 use futures::stream::TryStreamExt;
 
 use std::io;
 
-pub async fn convert_sp_o_nums<F: storage_10::FileLoad + 'static>(
+/// How the ids of a stack of v10 layers map to v11 ids. Values get new
+/// ids as they are sorted by their converted entries, and the ids of
+/// nodes move down when equal values were merged in a layer below them.
+#[derive(Default)]
+pub struct IdMapping {
+    pub values: HashMap<u64, u64>,
+    /// For every layer that moves its nodes, the last v10 id before its
+    /// own and how many ids fewer there are in v11 from there on.
+    pub shifts: Vec<(u64, u64)>,
+}
+
+impl IdMapping {
+    pub fn map(&self, id: u64) -> u64 {
+        if let Some(mapped) = self.values.get(&id) {
+            return *mapped;
+        }
+        match self.shifts.partition_point(|(after, _)| *after < id) {
+            0 => id,
+            ix => id - self.shifts[ix - 1].1,
+        }
+    }
+
+    /// How many ids fewer there are in v11 after the last layer.
+    pub fn shift(&self) -> u64 {
+        self.shifts.last().map(|(_, shift)| *shift).unwrap_or(0)
+    }
+
+    /// Move the ids after `after` down by `shift`.
+    pub fn add_shift(&mut self, after: u64, shift: u64) {
+        if shift != self.shift() {
+            self.shifts.push((after, shift));
+        }
+    }
+}
+
+/// Writes a bit array in the v11 format, which is a sequence of 64 bit
+/// words followed by the number of bits.
+struct BitArrayBufBuilder<B> {
+    buf: B,
+    current: u64,
+    count: u64,
+}
+
+impl<B: BufMut> BitArrayBufBuilder<B> {
+    fn new(buf: B) -> Self {
+        Self {
+            buf,
+            current: 0,
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, bit: bool) {
+        if bit {
+            self.current |= 0x8000_0000_0000_0000 >> (self.count & 0b11_1111);
+        }
+        self.count += 1;
+        if self.count & 0b11_1111 == 0 {
+            self.buf.put_u64(self.current);
+            self.current = 0;
+        }
+    }
+
+    fn finalize(mut self) -> B {
+        if self.count & 0b11_1111 != 0 {
+            self.buf.put_u64(self.current);
+        }
+        self.buf.put_u64(self.count);
+        self.buf
+    }
+}
+
+/// Map the objects of an sp_o adjacency list to their v11 ids. Objects
+/// that end up the same within an (s, p) pair, as their values were
+/// merged, are only kept once, so the bits are written anew as well.
+pub async fn convert_sp_o<F: storage_10::FileLoad + 'static, B: BufMut>(
     bits: F,
     nums: F,
-    mapping: &HashMap<u64, u64>,
-) -> io::Result<Bytes> {
+    mapping: &IdMapping,
+    mut nums_output: B,
+    bits_output: B,
+) -> io::Result<(B, B)> {
     let (_len, width) = structure_10::logarray_file_get_length_and_width(nums.clone()).await?;
     let mut bits_stream = structure_10::bitarray_stream_bits(bits).await?;
     let mut nums_stream = structure_10::logarray_stream_entries(nums).await?;
 
-    let mut buf = BytesMut::new();
-    let mut builder = LogArrayBufBuilder::new(&mut buf, width);
+    let mut builder = LogArrayBufBuilder::new(&mut nums_output, width);
+    let mut bits_builder = BitArrayBufBuilder::new(bits_output);
 
     let mut tally = 0;
     while let Some(b) = bits_stream.try_next().await? {
@@ -28,11 +105,15 @@ pub async fn convert_sp_o_nums<F: storage_10::FileLoad + 'static>(
             let mut v = Vec::with_capacity(tally);
             for _ in 0..tally {
                 let unmapped = nums_stream.try_next().await?.unwrap();
-                let mapped = mapping.get(&unmapped).cloned().unwrap_or(unmapped);
-                v.push(mapped);
+                v.push(mapping.map(unmapped));
             }
             v.sort();
+            v.dedup();
 
+            for _ in 1..v.len() {
+                bits_builder.push(false);
+            }
+            bits_builder.push(true);
             builder.push_vec(v);
             tally = 0;
         }
@@ -40,5 +121,61 @@ pub async fn convert_sp_o_nums<F: storage_10::FileLoad + 'static>(
 
     builder.finalize();
 
-    Ok(buf.freeze())
+    Ok((nums_output, bits_builder.finalize()))
+}
+
+/// Map the subjects of a child layer to their v11 ids, which keeps them
+/// in order.
+pub async fn convert_subjects<F: storage_10::FileLoad + 'static, B: BufMut>(
+    subjects: F,
+    mapping: &IdMapping,
+    mut output: B,
+) -> io::Result<B> {
+    let (_len, width) = structure_10::logarray_file_get_length_and_width(subjects.clone()).await?;
+    let mut stream = structure_10::logarray_stream_entries(subjects).await?;
+
+    let mut builder = LogArrayBufBuilder::new(&mut output, width);
+    while let Some(subject) = stream.try_next().await? {
+        builder.push(mapping.map(subject));
+    }
+    builder.finalize();
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_move_down_after_merged_values() {
+        let mut mapping = IdMapping::default();
+        mapping.values.extend([(4, 5), (5, 4), (6, 4)]);
+        // the base layer has ids up to 6, of which one was merged
+        mapping.add_shift(6, 1);
+        mapping.values.extend([(9, 8)]);
+        // the child has ids up to 9, and merged nothing
+        mapping.add_shift(9, 1);
+        mapping.add_shift(12, 3);
+
+        assert_eq!(vec![(6, 1), (12, 3)], mapping.shifts);
+        assert_eq!(
+            vec![1, 5, 4, 4, 6, 8, 9, 11, 10],
+            [1, 4, 5, 6, 7, 9, 10, 12, 13].map(|id| mapping.map(id))
+        );
+    }
+
+    #[test]
+    fn bit_arrays_end_with_their_length() {
+        let mut builder = BitArrayBufBuilder::new(Vec::new());
+        for i in 0..65 {
+            builder.push(i % 2 == 0);
+        }
+        let buf = builder.finalize();
+
+        assert_eq!(24, buf.len());
+        assert_eq!(0xaaaa_aaaa_aaaa_aaaa_u64.to_be_bytes(), buf[..8]);
+        assert_eq!(0x8000_0000_0000_0000_u64.to_be_bytes(), buf[8..16]);
+        assert_eq!(65_u64.to_be_bytes(), buf[16..]);
+    }
 }
@@ -4,12 +4,14 @@ use regex::Regex;
 use rug::Integer;
 use std::{
     borrow::Cow,
+    collections::BTreeMap,
     io::{Cursor, Read},
 };
 
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum DataConversionError {
     #[error("value string in store had unexpected format `{0}`")]
     ValueStringHadUnexpectedFormat(String),
@@ -43,7 +45,7 @@ pub enum LangOrType<'a> {
     Type(&'a str, &'a str),
 }
 
-pub fn value_string_to_slices(s: &str) -> Result<LangOrType> {
+pub fn value_string_to_slices(s: &str) -> Result<LangOrType<'_>> {
     if s.is_empty() {
         return Err(DataConversionError::ValueStringHadUnexpectedFormat(
             s.to_string(),
@@ -101,8 +103,46 @@ pub fn value_string_to_slices(s: &str) -> Result<LangOrType> {
     }
 }
 
+/// Whether a value string is a literal the way TerminusDB writes them,
+/// either `"value"^^'datatype'` or `"value"@lang`. Numbers and booleans
+/// are written without quotes, as in `42^^'datatype'`.
+pub fn is_terminusdb_literal(s: &str) -> bool {
+    if let Some(pos) = s.rfind("^^'") {
+        let (value, datatype) = (&s[..pos], &s[pos + 3..]);
+        let quoted = value.len() > 1 && value.starts_with('"') && value.ends_with('"');
+        let bare = !value.is_empty() && !value.contains(|c: char| c == '"' || c.is_whitespace());
+        return (quoted || bare) && datatype.len() > 1 && datatype.ends_with('\'');
+    }
+    if !s.starts_with('"') {
+        return false;
+    }
+    match s.rfind("\"@") {
+        Some(pos) if pos > 0 => {
+            let lang = s[pos + 2..].trim_matches('\'');
+            !lang.is_empty() && lang.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        }
+        _ => false,
+    }
+}
+
+/// Datatype IRIs to convert values of other datatype IRIs as, such as
+/// a custom datatype that should just become a string.
+pub type DatatypeMap = BTreeMap<String, String>;
+
 pub fn convert_value_string_to_dict_entry(value: &str) -> Result<tfc_11::TypedDictEntry> {
-    let res = value_string_to_slices(value)?;
+    convert_value_string_to_dict_entry_with_datatypes(value, &DatatypeMap::new())
+}
+
+/// Convert a value string, first replacing its datatype if `datatypes`
+/// maps it to another one.
+pub fn convert_value_string_to_dict_entry_with_datatypes(
+    value: &str,
+    datatypes: &DatatypeMap,
+) -> Result<tfc_11::TypedDictEntry> {
+    let res = match value_string_to_slices(value)? {
+        LangOrType::Type(s, t) => LangOrType::Type(s, datatypes.get(t).map_or(t, |t| t.as_str())),
+        res => res,
+    };
     Ok(match res {
         LangOrType::Lang(s, l) => {
             let converted = prolog_string_to_string(s);
@@ -548,7 +588,7 @@ fn parse_duration(s: &str) -> Result<Duration> {
     })
 }
 
-pub fn normalize_decimal(s: &str) -> std::result::Result<Cow<str>, DecimalValidationError> {
+pub fn normalize_decimal(s: &str) -> std::result::Result<Cow<'_, str>, DecimalValidationError> {
     lazy_static! {
         static ref NORMALIZED_RE: Regex = Regex::new(r"^-?\d+(\.\d+)?$").unwrap();
         static ref SCIENTIFIC_RE: Regex =
@@ -587,7 +627,7 @@ const SWIPL_CONTROL_CHAR_B: char = 8 as char;
 const SWIPL_CONTROL_CHAR_F: char = 12 as char;
 const SWIPL_CONTROL_CHAR_V: char = 11 as char;
 
-fn prolog_string_to_string(s: &str) -> Cow<str> {
+fn prolog_string_to_string(s: &str) -> Cow<'_, str> {
     let mut result: Option<String> = None;
     let mut escaping = false;
     let mut characters = s.char_indices();
@@ -658,4 +698,40 @@ mod tests {
         check_decimal("1.03432e-10", "0.000000000103432");
         check_decimal("1.03432e+2", "103.432");
     }
+
+    #[test]
+    fn terminusdb_literals() {
+        assert!(is_terminusdb_literal(
+            "\"1\"^^'http://www.w3.org/2001/XMLSchema#integer'"
+        ));
+        assert!(is_terminusdb_literal(
+            "42^^'http://www.w3.org/2001/XMLSchema#integer'"
+        ));
+        assert!(is_terminusdb_literal("\"hallo\"@nl"));
+        assert!(is_terminusdb_literal("\"hallo\"@'nl'"));
+        assert!(!is_terminusdb_literal("someone@example.com"));
+        assert!(!is_terminusdb_literal("\"quoted\""));
+        assert!(!is_terminusdb_literal("plain"));
+        assert!(!is_terminusdb_literal("two words^^'http://example.com/t'"));
+    }
+
+    #[test]
+    fn mapped_datatypes() {
+        let value = "\"red\"^^'http://example.com/schema#Color'";
+        assert!(matches!(
+            convert_value_string_to_dict_entry(value),
+            Err(DataConversionError::UnrecognizedType { .. })
+        ));
+
+        let datatypes = DatatypeMap::from([(
+            "http://example.com/schema#Color".to_string(),
+            "http://www.w3.org/2001/XMLSchema#string".to_string(),
+        )]);
+        let entry = convert_value_string_to_dict_entry_with_datatypes(value, &datatypes).unwrap();
+        assert_eq!(tfc_11::Datatype::String, entry.datatype());
+        assert_eq!(
+            <String as tfc_11::TdbDataType>::make_entry(&"red".to_string()),
+            entry
+        );
+    }
 }
@@ -0,0 +1,277 @@
+//! Checking that there's enough disk space for a conversion, both
+//! before starting and in between layers.
+use terminus_store_10::storage as storage_10;
+use terminus_store_10::storage::name_to_string;
+
+use crate::interrupt::*;
+use crate::layer_modes::{LayerModes, ValueMode};
+use crate::plan::{layer_size, layer_value_count};
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use thiserror::Error;
+
+/// Roughly how many bytes an entry in a parent map takes up. Parent
+/// maps are pairs of varint-encoded ids, which are rarely above 2^35.
+const BYTES_PER_MAPPING_ENTRY: u64 = 10;
+
+/// The default amount of space to leave free, in MiB.
+pub const DEFAULT_MIN_FREE_SPACE_MIB: u64 = 1024;
+
+/// How long to wait before checking free space again while paused.
+const PAUSE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum DiskSpaceError {
+    #[error(
+        "Not enough free space on the filesystem of `{path}`: {} needed, {} available",
+        format_size(*.needed),
+        format_size(*.available)
+    )]
+    Insufficient {
+        path: String,
+        needed: u64,
+        available: u64,
+    },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// The space a conversion is expected to take up.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SpaceEstimate {
+    /// The converted layers, which are about as big as the originals.
+    pub output: u64,
+    /// The parent maps written to the workdir for typed conversions.
+    pub workdir: u64,
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} bytes")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// Estimate the space needed to convert the given layers. Layers that
+/// were already converted should not be included. `parents` maps each
+/// layer to its parent, which is needed because every parent map holds
+/// the mappings of all its ancestors too.
+pub async fn estimate_required_space<S: storage_10::PersistentLayerStore>(
+    store: &S,
+    order: &[[u32; 5]],
+    parents: &HashMap<[u32; 5], [u32; 5]>,
+    modes: &LayerModes,
+) -> io::Result<SpaceEstimate> {
+    let mut estimate = SpaceEstimate::default();
+    let mut cumulative_values: HashMap<[u32; 5], u64> = HashMap::new();
+    for layer in order.iter() {
+        estimate.output += layer_size(store, *layer).await?;
+        if modes.mode(*layer) != ValueMode::Naive {
+            let mut values = layer_value_count(store, *layer).await?;
+            if let Some(parent) = parents.get(layer) {
+                values += ancestor_value_count(store, *parent, &mut cumulative_values).await?;
+            }
+            cumulative_values.insert(*layer, values);
+            estimate.workdir += values * BYTES_PER_MAPPING_ENTRY;
+        }
+    }
+
+    Ok(estimate)
+}
+
+/// The number of values in a layer and all its ancestors. `counts`
+/// holds the numbers found before, and gets the ones found now, so that
+/// every chain is only walked once.
+async fn ancestor_value_count<S: storage_10::PersistentLayerStore>(
+    store: &S,
+    layer: [u32; 5],
+    counts: &mut HashMap<[u32; 5], u64>,
+) -> io::Result<u64> {
+    let mut count = 0;
+    let mut uncounted = Vec::new();
+    let mut current = Some(layer);
+    while let Some(layer) = current {
+        if let Some(known) = counts.get(&layer) {
+            count = *known;
+            break;
+        }
+        uncounted.push(layer);
+        current = storage_10::LayerStore::get_layer_parent_name(store, layer).await?;
+    }
+    for layer in uncounted.into_iter().rev() {
+        count += layer_value_count(store, layer).await?;
+        counts.insert(layer, count);
+    }
+
+    Ok(count)
+}
+
+/// The path itself if it exists, or else its closest existing ancestor.
+/// Free space can only be looked up for existing paths, and the output
+/// and workdir are usually created by the conversion.
+fn existing_ancestor(path: &Path) -> PathBuf {
+    let mut path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        let mut cwd = std::env::current_dir().unwrap_or_default();
+        cwd.push(path);
+        cwd
+    };
+    while !path.exists() && path.pop() {}
+
+    path
+}
+
+#[cfg(unix)]
+fn same_filesystem(path1: &Path, path2: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(std::fs::metadata(path1)?.dev() == std::fs::metadata(path2)?.dev())
+}
+
+#[cfg(not(unix))]
+fn same_filesystem(_path1: &Path, _path2: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Watches the free space on the filesystems of the output and workdir.
+pub struct SpaceGuard {
+    output: PathBuf,
+    work: PathBuf,
+    /// Space to leave free on both filesystems.
+    min_free: u64,
+}
+
+impl SpaceGuard {
+    pub fn new(to: &str, work: &str, min_free: u64) -> Self {
+        Self {
+            output: PathBuf::from(to),
+            work: PathBuf::from(work),
+            min_free,
+        }
+    }
+
+    /// Check whether the estimated space is available on top of the
+    /// minimum amount of free space.
+    pub fn check(&self, estimate: &SpaceEstimate) -> Result<(), DiskSpaceError> {
+        let output_path = existing_ancestor(&self.output);
+        let work_path = existing_ancestor(&self.work);
+        let needs = if same_filesystem(&output_path, &work_path)? {
+            vec![(output_path, estimate.output + estimate.workdir)]
+        } else {
+            vec![
+                (output_path, estimate.output),
+                (work_path, estimate.workdir),
+            ]
+        };
+        for (path, needed) in needs {
+            let available = fs2::available_space(&path)?;
+            let needed = needed + self.min_free;
+            if available < needed {
+                return Err(DiskSpaceError::Insufficient {
+                    path: path.to_string_lossy().into_owned(),
+                    needed,
+                    available,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait until there's room to convert the given layer. This is
+    /// called in between layers, so while paused, nothing is
+    /// half-written.
+    pub async fn wait_for_space<S: storage_10::PersistentLayerStore>(
+        &self,
+        store: &S,
+        layer: [u32; 5],
+    ) -> Result<(), DiskSpaceError> {
+        let estimate = SpaceEstimate {
+            output: layer_size(store, layer).await?,
+            workdir: 0,
+        };
+        let mut paused = false;
+        loop {
+            if interrupted() {
+                // the conversion stops before this layer anyway
+                return Ok(());
+            }
+            match self.check(&estimate) {
+                Ok(()) => break,
+                Err(e @ DiskSpaceError::Insufficient { .. }) => {
+                    if !paused {
+                        eprintln!(
+                            "{e}. Pausing before converting layer {}, free up space to continue",
+                            name_to_string(layer)
+                        );
+                        paused = true;
+                    }
+                    interruptible_sleep(PAUSE_INTERVAL).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if paused {
+            println!("Enough free space available again, continuing");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_store::TestStore;
+    use terminus_store_10::storage::directory as directory_10;
+
+    #[test]
+    fn format_sizes() {
+        assert_eq!("512 bytes", format_size(512));
+        assert_eq!("1.5 KiB", format_size(1536));
+        assert_eq!("2.0 GiB", format_size(2 << 30));
+    }
+
+    #[tokio::test]
+    async fn converted_ancestors_count_towards_parent_maps() {
+        let store = TestStore::new("space_estimate");
+        let base = store.value_layer(None, &["a", "b"]).await;
+        let child = store.value_layer(Some(base), &["c"]).await;
+        let left = store.value_layer(Some(child), &["d"]).await;
+        let right = store.value_layer(Some(child), &["e", "f"]).await;
+        let layer_store = directory_10::DirectoryLayerStore::new(store.path("v10"));
+        let parents = HashMap::from([(child, base), (left, child), (right, child)]);
+
+        // base and child were converted already
+        let estimate = estimate_required_space(
+            &layer_store,
+            &[left, right],
+            &parents,
+            &LayerModes::uniform(ValueMode::Typed),
+        )
+        .await
+        .unwrap();
+        assert_eq!((4 + 5) * BYTES_PER_MAPPING_ENTRY, estimate.workdir);
+
+        let mut counts = HashMap::new();
+        assert_eq!(
+            3,
+            ancestor_value_count(&layer_store, child, &mut counts)
+                .await
+                .unwrap()
+        );
+        assert_eq!(HashMap::from([(base, 2), (child, 3)]), counts);
+    }
+}
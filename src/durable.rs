@@ -0,0 +1,55 @@
+//! Helpers for making sure that what we write is on disk before we
+//! rely on it, so a power loss can't leave a half-written file behind
+//! that a resumed run would trust.
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// Sync a directory, making the creation, removal and renaming of the
+/// entries in it durable. This blocks, see [`sync_directory`] for use
+/// in async code.
+pub fn sync_directory_blocking(path: &Path) -> io::Result<()> {
+    std::fs::File::open(path)?.sync_all()
+}
+
+/// Sync the directory that `path` is in.
+pub fn sync_parent_directory_blocking(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => sync_directory_blocking(parent),
+        _ => sync_directory_blocking(Path::new(".")),
+    }
+}
+
+pub async fn sync_directory(path: &Path) -> io::Result<()> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || sync_directory_blocking(&path))
+        .await
+        .unwrap()
+}
+
+pub async fn sync_parent_directory(path: &Path) -> io::Result<()> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || sync_parent_directory_blocking(&path))
+        .await
+        .unwrap()
+}
+
+/// Replace the contents of a file. The contents are written to a
+/// temporary file which is synced and then renamed into place, so
+/// readers see either the old or the new contents, never a mix.
+pub async fn write_file_durably(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp_name: OsString = path.file_name().unwrap().to_owned();
+    tmp_name.push(".tmp");
+    let mut tmp_path = PathBuf::from(path);
+    tmp_path.set_file_name(tmp_name);
+
+    let mut file = fs::File::create(&tmp_path).await?;
+    file.write_all(contents).await?;
+    file.flush().await?;
+    file.sync_all().await?;
+    fs::rename(&tmp_path, path).await?;
+    sync_parent_directory(path).await
+}
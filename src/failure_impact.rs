@@ -0,0 +1,303 @@
+//! Working out which databases, branches and commits depend on the
+//! layers that failed to convert.
+//!
+//! A layer that failed takes every layer built on top of it down with
+//! it. So for every label, repository and commit, the layers it points
+//! at are followed up their parent chains, and if a failed layer is
+//! found, that is reported along with what depends on it.
+use terminus_store_10::storage as storage_10;
+use terminus_store_10::storage::name_to_string;
+
+use crate::durable::write_file_durably;
+use crate::plan::{decode_label_name, parent_map};
+use crate::reachable::*;
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+pub const FAILURE_IMPACT_JSON: &str = "failure_impact.json";
+pub const FAILURE_IMPACT_TABLE: &str = "failure_impact.txt";
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImpactedGraph {
+    /// The layer a label points at: the metadata graph of a data
+    /// product, or a system graph.
+    Label,
+    CommitGraph,
+    Instance,
+    Schema,
+}
+
+impl ImpactedGraph {
+    fn name(self) -> &'static str {
+        match self {
+            ImpactedGraph::Label => "label",
+            ImpactedGraph::CommitGraph => "commit graph",
+            ImpactedGraph::Instance => "instance",
+            ImpactedGraph::Schema => "schema",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ImpactEntry {
+    /// The data product, as `organization/database`, or the name of a
+    /// system label.
+    pub database: String,
+    pub repository: Option<String>,
+    pub commit: Option<String>,
+    /// The branches with this commit as their head.
+    pub branches: Vec<String>,
+    pub graph: ImpactedGraph,
+    /// The layer of the graph.
+    pub layer: String,
+    /// The failed layer it was built on, which may be the layer itself.
+    pub failed_layer: String,
+}
+
+/// The first failed layer found going up the parent chain of a layer.
+fn failed_ancestor(
+    parents: &HashMap<[u32; 5], [u32; 5]>,
+    failed: &HashSet<[u32; 5]>,
+    layer: [u32; 5],
+) -> Option<[u32; 5]> {
+    let mut current = Some(layer);
+    while let Some(layer) = current {
+        if failed.contains(&layer) {
+            return Some(layer);
+        }
+        current = parents.get(&layer).cloned();
+    }
+
+    None
+}
+
+/// Find everything that depends on the failed layers in the labels
+/// selected for conversion, given the roots of those labels and the
+/// layers they reach.
+pub async fn find_failure_impact<S: storage_10::PersistentLayerStore>(
+    layer_store: &S,
+    roots: &[LabelRoots],
+    reachable: &HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>,
+    failed: &[[u32; 5]],
+) -> io::Result<Vec<ImpactEntry>> {
+    let parents = parent_map(reachable);
+    let failed: HashSet<[u32; 5]> = failed.iter().cloned().collect();
+
+    let mut entries = Vec::new();
+    for root in roots.iter() {
+        let database = decode_label_name(&root.label);
+        let entry = |repository: Option<&str>,
+                     commit: Option<&str>,
+                     branches: Vec<String>,
+                     graph,
+                     layer,
+                     failed_layer| ImpactEntry {
+            database: database.clone(),
+            repository: repository.map(|r| r.to_string()),
+            commit: commit.map(|c| c.to_string()),
+            branches,
+            graph,
+            layer: name_to_string(layer),
+            failed_layer: name_to_string(failed_layer),
+        };
+
+        if let Some(failed_layer) = failed_ancestor(&parents, &failed, root.head) {
+            entries.push(entry(
+                None,
+                None,
+                Vec::new(),
+                ImpactedGraph::Label,
+                root.head,
+                failed_layer,
+            ));
+        }
+        if !root.data_product {
+            continue;
+        }
+        for repository in find_repositories(layer_store, root.head).await? {
+            let name = Some(repository.name.as_str());
+            if let Some(failed_layer) = failed_ancestor(&parents, &failed, repository.commit_graph)
+            {
+                entries.push(entry(
+                    name,
+                    None,
+                    Vec::new(),
+                    ImpactedGraph::CommitGraph,
+                    repository.commit_graph,
+                    failed_layer,
+                ));
+            }
+            let commit_graph = read_commit_graph(layer_store, repository.commit_graph).await?;
+            for commit in commit_graph.commits.iter() {
+                let graphs = [
+                    (ImpactedGraph::Instance, commit.instance),
+                    (ImpactedGraph::Schema, commit.schema),
+                ];
+                for (graph, layer) in graphs {
+                    let Some(layer) = layer else { continue };
+                    let Some(failed_layer) = failed_ancestor(&parents, &failed, layer) else {
+                        continue;
+                    };
+                    let branches = commit_graph
+                        .branches
+                        .iter()
+                        .filter(|b| b.head.as_ref() == Some(&commit.id))
+                        .map(|b| b.name.clone())
+                        .collect();
+                    entries.push(entry(
+                        name,
+                        Some(&commit.id),
+                        branches,
+                        graph,
+                        layer,
+                        failed_layer,
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Render the impact of failures as a table, one line per graph that
+/// depends on a failed layer.
+pub fn render_impact_table(entries: &[ImpactEntry]) -> String {
+    let header = [
+        "DATABASE",
+        "REPOSITORY",
+        "COMMIT",
+        "BRANCHES",
+        "GRAPH",
+        "FAILED LAYER",
+    ];
+    let rows: Vec<[String; 6]> = entries
+        .iter()
+        .map(|e| {
+            [
+                e.database.clone(),
+                e.repository.clone().unwrap_or_else(|| "-".to_string()),
+                e.commit.clone().unwrap_or_else(|| "-".to_string()),
+                if e.branches.is_empty() {
+                    "-".to_string()
+                } else {
+                    e.branches.join(",")
+                },
+                e.graph.name().to_string(),
+                e.failed_layer.clone(),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(|h| h.len());
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut table = String::new();
+    let header = header.map(|h| h.to_string());
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        table.push_str(line.join("  ").trim_end());
+        table.push('\n');
+    }
+
+    table
+}
+
+/// Report what depends on the failed layers, both on the terminal and
+/// in the workdir. Not being able to work this out is only a warning,
+/// as it shouldn't hide the failures themselves.
+pub async fn report_failure_impact<S: storage_10::PersistentLayerStore>(
+    layer_store: &S,
+    roots: &[LabelRoots],
+    reachable: &HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>,
+    work: &str,
+    failed: &[[u32; 5]],
+) -> io::Result<()> {
+    let entries = match find_failure_impact(layer_store, roots, reachable, failed).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("could not work out what depends on the failed layers: {e}");
+            return Ok(());
+        }
+    };
+
+    let table = render_impact_table(&entries);
+    let databases: HashSet<&str> = entries.iter().map(|e| e.database.as_str()).collect();
+    eprintln!(
+        "{} failed layers affect {} databases:",
+        failed.len(),
+        databases.len()
+    );
+    eprint!("{table}");
+
+    let mut json_path = PathBuf::from(work);
+    json_path.push(FAILURE_IMPACT_JSON);
+    write_file_durably(&json_path, &serde_json::to_vec_pretty(&entries)?).await?;
+    let mut table_path = PathBuf::from(work);
+    table_path.push(FAILURE_IMPACT_TABLE);
+    write_file_durably(&table_path, table.as_bytes()).await?;
+    eprintln!(
+        "This report was written to `{}` and `{}`",
+        table_path.display(),
+        json_path.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_are_found_up_the_parent_chain() {
+        let parents = HashMap::from([([2; 5], [1; 5]), ([3; 5], [2; 5]), ([5; 5], [4; 5])]);
+        let failed = HashSet::from([[2; 5]]);
+        assert_eq!(Some([2; 5]), failed_ancestor(&parents, &failed, [3; 5]));
+        assert_eq!(Some([2; 5]), failed_ancestor(&parents, &failed, [2; 5]));
+        assert_eq!(None, failed_ancestor(&parents, &failed, [1; 5]));
+        assert_eq!(None, failed_ancestor(&parents, &failed, [5; 5]));
+    }
+
+    #[test]
+    fn impact_table_columns_line_up() {
+        let entries = vec![
+            ImpactEntry {
+                database: "admin/db1".to_string(),
+                repository: Some("local".to_string()),
+                commit: Some("c2".to_string()),
+                branches: vec!["main".to_string()],
+                graph: ImpactedGraph::Instance,
+                layer: name_to_string([3; 5]),
+                failed_layer: name_to_string([2; 5]),
+            },
+            ImpactEntry {
+                database: "admin/db1".to_string(),
+                repository: None,
+                commit: None,
+                branches: Vec::new(),
+                graph: ImpactedGraph::Label,
+                layer: name_to_string([2; 5]),
+                failed_layer: name_to_string([2; 5]),
+            },
+        ];
+        let table = render_impact_table(&entries);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(3, lines.len());
+        let column = lines[0].find("GRAPH").unwrap();
+        assert_eq!(Some(column), lines[1].find("instance"));
+        assert_eq!(Some(column), lines[2].find("label"));
+    }
+}
@@ -0,0 +1,660 @@
+use terminus_store_10::layer as layer_10;
+use terminus_store_10::storage as storage_10;
+use terminus_store_10::storage::directory as directory_10;
+use terminus_store_11::layer as layer_11;
+use terminus_store_11::storage as storage_11;
+use terminus_store_11::storage::archive as archive_11;
+use terminus_store_11::store as store_11;
+use terminus_store_11::structure::TdbDataType;
+
+use crate::convert_labels::*;
+use crate::convert_layer::path_for_parent_map;
+use crate::convert_store::*;
+use crate::dataconversion::{convert_value_string_to_dict_entry, DataConversionError};
+use crate::disk_space::*;
+use crate::durable::write_file_durably;
+use crate::layer_modes::{find_layer_modes, LayerModes, ValueMode};
+use crate::observer::ConversionObserver;
+use crate::options::ConversionOptions;
+use crate::plan::{decode_label_name, parent_map};
+use crate::reachable::*;
+use crate::run_manifest::*;
+use crate::status_log::*;
+use crate::storage_version::{StorageVersion, STORAGE_VERSION_FILE};
+use crate::store_lock::*;
+
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use thiserror::*;
+
+const SYSTEM_DATA_LABEL: &str = "terminusdb%3a%2f%2f%2fsystem%2fdata";
+const SYSTEM_NAME: &str = "http://terminusdb.com/schema/system#name";
+const SYSTEM_DATABASE: &str = "http://terminusdb.com/schema/system#database";
+const SYSTEM_ORGANIZATION: &str = "http://terminusdb.com/schema/system#Organization";
+const RDF_TYPE: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#type";
+const XSD_STRING: &str = "http://www.w3.org/2001/XMLSchema#string";
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum ImportError {
+    #[error(transparent)]
+    StoreConversion(#[from] StoreConversionError),
+    #[error(transparent)]
+    Lock(#[from] LockError),
+    #[error(transparent)]
+    RunManifest(#[from] RunManifestError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("`{0}` is not a version 11 store")]
+    NotAV11Store(String),
+    #[error("database name `{0}` should be of the form `organization/database`")]
+    InvalidDatabaseName(String),
+    #[error("database `{0}` was not found in the source store")]
+    DatabaseNotFound(String),
+    #[error("database `{0}` already exists in the destination store")]
+    DatabaseAlreadyExists(String),
+    #[error("store `{0}` has no system graph")]
+    SystemGraphNotFound(String),
+    #[error("could not convert system graph entry: {0}")]
+    SystemGraphConversion(#[from] DataConversionError),
+    #[error("the system graph of the destination store changed during the import")]
+    SystemGraphChanged,
+    #[error("a triple about `{0}` in the system graph of the source could not be read")]
+    SystemGraphUnreadable(String),
+}
+
+/// Convert a single database from a v10 store into an existing v11
+/// store which may already hold other databases. The labels file,
+/// sync and keep going settings of `options` are not used, and
+/// conflicting labels fail the import unless another policy is set.
+/// A database whose label is renamed is registered under the new name,
+/// and one whose label is skipped is converted but not registered.
+pub async fn import_database(
+    options: &ConversionOptions,
+    database: &str,
+    observer: &dyn ConversionObserver,
+) -> Result<(), ImportError> {
+    // the database is only registered if all of its layers converted
+    let options = &ConversionOptions {
+        labels: None,
+        keep_going: false,
+        ..options.clone()
+    };
+    let (from, to, work) = (&options.from[..], &options.to[..], &options.workdir[..]);
+    let verbose = options.verbose;
+    let label_conflict = options.label_conflict.unwrap_or(LabelConflictPolicy::Fail);
+    let (organization, database_name) = database
+        .split_once('/')
+        .ok_or_else(|| ImportError::InvalidDatabaseName(database.to_string()))?;
+    let label = format!("{organization}%2f{database_name}");
+
+    check_v10_directory(from)
+        .await
+        .map_err(StoreConversionError::from)?;
+    check_v11_store(to).await?;
+    if let Some(port) = options.server_port {
+        check_server_port(port)?;
+    }
+    tokio::fs::create_dir_all(work).await?;
+    let _locks = StoreLocks::acquire(Some(from), &[to, work])?;
+    // every import picks its own database, so a workdir shared by
+    // several imports keeps the label selection it was first used with
+    let mut settings = RunManifest::new(options).await?;
+    settings.labels = read_run_manifest(work).await?.and_then(|m| m.labels);
+    check_run_manifest(work, &settings, true, options.force).await?;
+
+    let v10_layer_store = directory_10::DirectoryLayerStore::new(from);
+    let v10_label_store = directory_10::DirectoryLabelStore::new(from);
+    let v11_store = store_11::open_archive_store(to);
+
+    let head = storage_10::LabelStore::get_label(&v10_label_store, &label)
+        .await?
+        .and_then(|l| l.layer)
+        .ok_or_else(|| ImportError::DatabaseNotFound(database.to_string()))?;
+
+    // Before converting anything, make sure we'll be able to register
+    // the database with the destination server.
+    let entry = system_graph_entry(
+        &v10_layer_store,
+        &v10_label_store,
+        organization,
+        database_name,
+    )
+    .await?
+    .ok_or_else(|| ImportError::DatabaseNotFound(database.to_string()))?;
+    let labels_path = write_labels_file(work, &label).await?;
+    let labels_path = labels_path.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("workdir `{work}` is not valid UTF-8"),
+        )
+    })?;
+    let conflicts = find_label_conflicts(&v10_label_store, to, Some(labels_path)).await?;
+    if label_conflict == LabelConflictPolicy::Fail && !conflicts.is_empty() {
+        write_conflict_report(work, &conflicts).await?;
+        return Err(StoreConversionError::LabelConflicts(conflicts).into());
+    }
+    // a skipped label leaves the database in the destination alone
+    let register = label_conflict != LabelConflictPolicy::Skip || conflicts.is_empty();
+    let (system_graph, system_layer) = open_system_graph(&v11_store, to).await?;
+    if register && layer_11::Layer::subject_id(&system_layer, &entry.database_node).is_some() {
+        return Err(ImportError::DatabaseAlreadyExists(database.to_string()));
+    }
+
+    let roots = [find_data_product_roots(&v10_layer_store, label.clone(), head).await?];
+    let reachable = find_reachable_layers_from_roots(&v10_layer_store, &roots, verbose).await?;
+    let modes = find_layer_modes(&v10_layer_store, &roots, options)
+        .await
+        .map_err(StoreConversionError::from)?;
+    let order = conversion_order(&reachable);
+    adopt_converted_layers(to, work, &order, &modes).await?;
+    let mut status_log = StatusLog::open(work).await?;
+    let space_guard = SpaceGuard::new(to, work, options.min_free_space);
+    if !options.skip_space_check {
+        let pending: Vec<_> = order
+            .iter()
+            .copied()
+            .filter(|l| status_log.status(*l) != Some(ConversionStatus::Completed))
+            .collect();
+        let estimate =
+            estimate_required_space(&v10_layer_store, &pending, &parent_map(&reachable), &modes)
+                .await?;
+        println!(
+            "Estimated space needed: {} for the converted layers, {} in the workdir",
+            format_size(estimate.output),
+            format_size(estimate.workdir)
+        );
+        space_guard
+            .check(&estimate)
+            .map_err(StoreConversionError::from)?;
+    }
+    status_log.run_started(order.len()).await?;
+    convert_reachable_layers(
+        &v10_layer_store,
+        options,
+        &mut status_log,
+        &reachable,
+        &modes,
+        (!options.skip_space_check).then_some(&space_guard),
+        observer,
+    )
+    .await?;
+
+    let label_report = convert_labels(
+        &v10_label_store,
+        to,
+        Some(labels_path),
+        label_conflict,
+        &options.label_suffix,
+    )
+    .await?;
+    if !label_report.conflicts.is_empty() {
+        write_conflict_report(work, &label_report.conflicts).await?;
+    }
+
+    let resolution = label_report
+        .conflicts
+        .iter()
+        .find(|c| c.label == label)
+        .map(|c| &c.resolution);
+    match resolution {
+        Some(LabelResolution::Skipped) => {
+            println!(
+                "Database `{database}` converted into `{to}`, but not registered, as its label was skipped"
+            );
+        }
+        Some(LabelResolution::Renamed(renamed)) => {
+            let renamed = decode_label_name(renamed);
+            let name = renamed
+                .split_once('/')
+                .map(|(_, name)| name)
+                .unwrap_or(&renamed);
+            add_system_graph_entry(&system_graph, &system_layer, entry.renamed(name)).await?;
+            println!("Database `{database}` imported into `{to}` as `{renamed}`");
+        }
+        _ => {
+            add_system_graph_entry(&system_graph, &system_layer, entry).await?;
+            println!("Database `{database}` imported into `{to}`");
+        }
+    }
+
+    Ok(())
+}
+
+/// The default workdir of an import of `database` into `to`. Every
+/// database gets its own, so that importing one doesn't get in the way
+/// of resuming the import of another.
+pub fn import_workdir(to: &str, database: &str) -> String {
+    format!("{to}/.import/{}", database.replacen('/', "%2f", 1))
+}
+
+/// Mark the layers that are already in the destination as converted,
+/// such as those a database shares with one imported earlier. Their
+/// parent maps are copied over from the workdir of the earlier import
+/// or conversion, if it is still around, so their children can be
+/// converted in a typed or auto mode.
+async fn adopt_converted_layers(
+    to: &str,
+    work: &str,
+    order: &[[u32; 5]],
+    modes: &LayerModes,
+) -> io::Result<()> {
+    let mut status_log = StatusLog::open(work).await?;
+    let v11_layer_store = archive_11::ArchiveLayerStore::new(to);
+    let workdirs = other_workdirs(to, work).await?;
+    for layer in order.iter().copied() {
+        if status_log.status(layer).is_some()
+            || !storage_11::PersistentLayerStore::directory_exists(&v11_layer_store, layer).await?
+        {
+            continue;
+        }
+        let parent_map = path_for_parent_map(work, layer);
+        if modes.mode(layer) != ValueMode::Naive && !parent_map.exists() {
+            for other in workdirs.iter() {
+                if let Ok(contents) = tokio::fs::read(path_for_parent_map(other, layer)).await {
+                    tokio::fs::create_dir_all(parent_map.parent().unwrap()).await?;
+                    write_file_durably(&parent_map, &contents).await?;
+                    break;
+                }
+            }
+        }
+        status_log
+            .layer_completed(layer, Duration::ZERO, modes.mode(layer))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// The default workdirs of a conversion into `to` and of imports into
+/// it, other than `work`.
+async fn other_workdirs(to: &str, work: &str) -> io::Result<Vec<String>> {
+    let mut workdirs = vec![format!("{to}/.workdir")];
+    match tokio::fs::read_dir(format!("{to}/.import")).await {
+        Ok(mut entries) => {
+            while let Some(entry) = entries.next_entry().await? {
+                if let Some(path) = entry.path().to_str() {
+                    workdirs.push(path.to_string());
+                }
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let work = std::fs::canonicalize(work)?;
+    workdirs.retain(|dir| std::fs::canonicalize(dir).ok().as_ref() != Some(&work));
+
+    Ok(workdirs)
+}
+
+async fn check_v11_store(to: &str) -> Result<(), ImportError> {
+    let mut path = PathBuf::from(to);
+    path.push(STORAGE_VERSION_FILE);
+    match tokio::fs::read_to_string(path).await {
+        Ok(version) if StorageVersion::from_version_file(&version) == Some(StorageVersion::V11) => {
+            Ok(())
+        }
+        Ok(_) => Err(ImportError::NotAV11Store(to.to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Err(ImportError::NotAV11Store(to.to_string()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `convert_labels` works on a labels file, so we write one for the
+/// single label we're importing.
+async fn write_labels_file(work: &str, label: &str) -> io::Result<PathBuf> {
+    let mut path = PathBuf::from(work);
+    tokio::fs::create_dir_all(&path).await?;
+    path.push("import_labels");
+    tokio::fs::write(&path, format!("{label}\n")).await?;
+
+    Ok(path)
+}
+
+/// The triples describing a database in the system graph.
+struct SystemGraphEntry {
+    database_node: String,
+    organization_node: String,
+    /// Triples with the database as their subject
+    database_triples: Vec<layer_11::ValueTriple>,
+    /// Triples describing the organization, minus the links to its
+    /// other databases. These are only needed when the organization
+    /// does not exist in the destination yet.
+    organization_triples: Vec<layer_11::ValueTriple>,
+}
+
+impl SystemGraphEntry {
+    /// The entry for the database under another name.
+    fn renamed(mut self, name: &str) -> Self {
+        for triple in self.database_triples.iter_mut() {
+            if triple.predicate == SYSTEM_NAME {
+                triple.object =
+                    layer_11::ObjectType::Value(<String as TdbDataType>::make_entry(&name));
+            }
+        }
+
+        self
+    }
+}
+
+async fn system_graph_entry(
+    layer_store: &directory_10::DirectoryLayerStore,
+    label_store: &directory_10::DirectoryLabelStore,
+    organization: &str,
+    database: &str,
+) -> Result<Option<SystemGraphEntry>, ImportError> {
+    let system_layer = storage_10::LabelStore::get_label(label_store, SYSTEM_DATA_LABEL)
+        .await?
+        .and_then(|l| l.layer)
+        .ok_or_else(|| ImportError::SystemGraphNotFound("source".to_string()))?;
+    let layer = storage_10::LayerStore::get_layer(layer_store, system_layer)
+        .await?
+        .ok_or_else(|| ImportError::SystemGraphNotFound("source".to_string()))?;
+
+    let organization_node = match find_organization(&*layer, organization) {
+        Some(node) => node,
+        None => return Ok(None),
+    };
+    let database_node = match find_database(&*layer, &organization_node, database) {
+        Some(node) => node,
+        None => return Ok(None),
+    };
+
+    let database_triples = convert_subject_triples(&*layer, &database_node, |_| true)?;
+    let organization_triples = convert_subject_triples(&*layer, &organization_node, |t| {
+        t.predicate != SYSTEM_DATABASE
+            || t.object == layer_10::ObjectType::Node(database_node.clone())
+    })?;
+
+    Ok(Some(SystemGraphEntry {
+        database_node,
+        organization_node,
+        database_triples,
+        organization_triples,
+    }))
+}
+
+fn find_organization(layer: &dyn layer_10::Layer, name: &str) -> Option<String> {
+    let type_predicate = layer.predicate_id(RDF_TYPE)?;
+    let organization_type = layer.object_node_id(SYSTEM_ORGANIZATION)?;
+    find_named_subjects(layer, name)
+        .into_iter()
+        .find(|s| layer.triple_exists(*s, type_predicate, organization_type))
+        .and_then(|s| layer.id_subject(s))
+}
+
+fn find_database(layer: &dyn layer_10::Layer, organization: &str, name: &str) -> Option<String> {
+    let organization = layer.subject_id(organization)?;
+    let database_predicate = layer.predicate_id(SYSTEM_DATABASE)?;
+    find_named_subjects(layer, name)
+        .into_iter()
+        .filter_map(|s| layer.id_subject(s))
+        // the database node is linked to from its organization
+        .find(|s| {
+            layer
+                .object_node_id(s)
+                .map(|o| layer.triple_exists(organization, database_predicate, o))
+                .unwrap_or(false)
+        })
+}
+
+fn find_named_subjects(layer: &dyn layer_10::Layer, name: &str) -> Vec<u64> {
+    let name_predicate = match layer.predicate_id(SYSTEM_NAME) {
+        Some(p) => p,
+        None => return Vec::with_capacity(0),
+    };
+    let name_value = match layer.object_value_id(&format!("\"{name}\"^^'{XSD_STRING}'")) {
+        Some(v) => v,
+        None => return Vec::with_capacity(0),
+    };
+
+    layer
+        .triples_o(name_value)
+        .filter(|t| t.predicate == name_predicate)
+        .map(|t| t.subject)
+        .collect()
+}
+
+fn convert_subject_triples<P: Fn(&layer_10::StringTriple) -> bool>(
+    layer: &dyn layer_10::Layer,
+    subject: &str,
+    predicate: P,
+) -> Result<Vec<layer_11::ValueTriple>, ImportError> {
+    let subject_id = match layer.subject_id(subject) {
+        Some(id) => id,
+        None => return Ok(Vec::with_capacity(0)),
+    };
+    let mut result = Vec::new();
+    for triple in layer.triples_s(subject_id) {
+        let triple = layer
+            .id_triple_to_string(&triple)
+            .ok_or_else(|| ImportError::SystemGraphUnreadable(subject.to_string()))?;
+        if !predicate(&triple) {
+            continue;
+        }
+        let converted = match triple.object {
+            layer_10::ObjectType::Node(node) => {
+                layer_11::ValueTriple::new_node(&triple.subject, &triple.predicate, &node)
+            }
+            layer_10::ObjectType::Value(value) => layer_11::ValueTriple::new_value(
+                &triple.subject,
+                &triple.predicate,
+                convert_value_string_to_dict_entry(&value)?,
+            ),
+        };
+        result.push(converted);
+    }
+
+    Ok(result)
+}
+
+async fn open_system_graph(
+    store: &store_11::Store,
+    to: &str,
+) -> Result<(store_11::NamedGraph, store_11::StoreLayer), ImportError> {
+    let graph = store
+        .open(SYSTEM_DATA_LABEL)
+        .await?
+        .ok_or_else(|| ImportError::SystemGraphNotFound(to.to_string()))?;
+    let layer = graph
+        .head()
+        .await?
+        .ok_or_else(|| ImportError::SystemGraphNotFound(to.to_string()))?;
+
+    Ok((graph, layer))
+}
+
+async fn add_system_graph_entry(
+    graph: &store_11::NamedGraph,
+    layer: &store_11::StoreLayer,
+    entry: SystemGraphEntry,
+) -> Result<(), ImportError> {
+    let builder = layer.open_write().await?;
+    if layer_11::Layer::subject_id(layer, &entry.organization_node).is_none() {
+        for triple in entry.organization_triples {
+            builder.add_value_triple(triple)?;
+        }
+    } else {
+        builder.add_value_triple(layer_11::ValueTriple::new_node(
+            &entry.organization_node,
+            SYSTEM_DATABASE,
+            &entry.database_node,
+        ))?;
+    }
+    for triple in entry.database_triples {
+        builder.add_value_triple(triple)?;
+    }
+    let new_layer = builder.commit().await?;
+    if !graph.set_head(&new_layer).await? {
+        return Err(ImportError::SystemGraphChanged);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observer::SilentObserver;
+    use crate::storage_version::write_version_file;
+    use crate::test_store::TestStore;
+
+    const ORGANIZATION: &str = "terminusdb:///system/data/Organization/admin";
+
+    fn name(name: &str) -> String {
+        format!("\"{name}\"^^'{XSD_STRING}'")
+    }
+
+    /// A v10 store with the databases `admin/mydb` and `admin/other`,
+    /// which shares the layers of `admin/mydb` and adds one on top, and
+    /// a v11 store that already has a database named `mydb`.
+    async fn stores_with_database(test: &str) -> (TestStore, store_11::NamedGraph) {
+        let store = TestStore::new(test);
+        let (_, child) = store.layers_with_equal_values().await;
+        let v10 = store.v10();
+        let database = "terminusdb:///system/data/UserDatabase/source";
+        let other = "terminusdb:///system/data/UserDatabase/other";
+        let builder = v10.create_base_layer().await.unwrap();
+        for triple in [
+            layer_10::StringTriple::new_node(ORGANIZATION, RDF_TYPE, SYSTEM_ORGANIZATION),
+            layer_10::StringTriple::new_value(ORGANIZATION, SYSTEM_NAME, &name("admin")),
+            layer_10::StringTriple::new_node(ORGANIZATION, SYSTEM_DATABASE, database),
+            layer_10::StringTriple::new_value(database, SYSTEM_NAME, &name("mydb")),
+            layer_10::StringTriple::new_node(ORGANIZATION, SYSTEM_DATABASE, other),
+            layer_10::StringTriple::new_value(other, SYSTEM_NAME, &name("other")),
+        ] {
+            builder.add_string_triple(triple).unwrap();
+        }
+        let system_layer = builder.commit().await.unwrap();
+        let system_graph = v10.create(SYSTEM_DATA_LABEL).await.unwrap();
+        system_graph.set_head(&system_layer).await.unwrap();
+        let data_layer = v10.get_layer_from_id(child).await.unwrap().unwrap();
+        let data = v10.create("admin%2fmydb").await.unwrap();
+        data.set_head(&data_layer).await.unwrap();
+        let other_layer = store.value_layer(Some(child), &["baz"]).await;
+        let other_layer = v10.get_layer_from_id(other_layer).await.unwrap().unwrap();
+        let data = v10.create("admin%2fother").await.unwrap();
+        data.set_head(&other_layer).await.unwrap();
+
+        let v11 = terminus_store_11::open_archive_store(store.dir.join("v11"));
+        let database = "terminusdb:///system/data/UserDatabase/destination";
+        let builder = v11.create_base_layer().await.unwrap();
+        for triple in [
+            layer_11::ValueTriple::new_node(ORGANIZATION, SYSTEM_DATABASE, database),
+            layer_11::ValueTriple::new_string_value(database, SYSTEM_NAME, "mydb"),
+        ] {
+            builder.add_value_triple(triple).unwrap();
+        }
+        let system_layer = builder.commit().await.unwrap();
+        let system_graph = v11.create(SYSTEM_DATA_LABEL).await.unwrap();
+        system_graph.set_head(&system_layer).await.unwrap();
+        let data = v11.create("admin%2fmydb").await.unwrap();
+        data.set_head(&system_layer).await.unwrap();
+        write_version_file(&store.path("v11"), StorageVersion::V11)
+            .await
+            .unwrap();
+
+        (store, system_graph)
+    }
+
+    fn options(store: &TestStore, policy: LabelConflictPolicy) -> ConversionOptions {
+        ConversionOptions::new(store.path("v10"), store.path("v11"))
+            .workdir(store.path("work"))
+            .auto(true)
+            .label_conflict(policy)
+            .label_suffix("_v10")
+            .skip_space_check(true)
+    }
+
+    /// The names of the databases of the organization in the system
+    /// graph.
+    async fn database_names(system_graph: &store_11::NamedGraph) -> Vec<String> {
+        let layer = system_graph.head().await.unwrap().unwrap();
+        let ids = (
+            layer_11::Layer::subject_id(&layer, ORGANIZATION).unwrap(),
+            layer_11::Layer::predicate_id(&layer, SYSTEM_DATABASE).unwrap(),
+            layer_11::Layer::predicate_id(&layer, SYSTEM_NAME).unwrap(),
+        );
+        let mut names: Vec<_> = layer_11::Layer::triples_sp(&layer, ids.0, ids.1)
+            .flat_map(|t| layer_11::Layer::triples_sp(&layer, t.object, ids.2))
+            .map(
+                |t| match layer_11::Layer::id_object(&layer, t.object).unwrap() {
+                    layer_11::ObjectType::Value(value) => value.as_val::<String, String>(),
+                    layer_11::ObjectType::Node(node) => node,
+                },
+            )
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn renamed_labels_register_the_database_under_their_name() {
+        let (store, system_graph) = stores_with_database("import_renamed").await;
+        let options = options(&store, LabelConflictPolicy::RenameSuffix);
+        import_database(&options, "admin/mydb", &SilentObserver)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            vec!["mydb", "mydb_v10"],
+            database_names(&system_graph).await
+        );
+        assert!(store.dir.join("v11/admin%2fmydb_v10.label").exists());
+    }
+
+    #[tokio::test]
+    async fn skipped_labels_are_not_registered() {
+        let (store, system_graph) = stores_with_database("import_skipped").await;
+        let options = options(&store, LabelConflictPolicy::Skip);
+        import_database(&options, "admin/mydb", &SilentObserver)
+            .await
+            .unwrap();
+
+        assert_eq!(vec!["mydb"], database_names(&system_graph).await);
+        assert!(!store.dir.join("v11/admin%2fmydb_v10.label").exists());
+    }
+
+    #[tokio::test]
+    async fn databases_sharing_layers_are_imported_one_by_one() {
+        let (store, system_graph) = stores_with_database("import_shared").await;
+        let to = store.path("v11");
+        for database in ["admin/mydb", "admin/other"] {
+            let options = options(&store, LabelConflictPolicy::RenameSuffix)
+                .workdir(import_workdir(&to, database));
+            import_database(&options, database, &SilentObserver)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            vec!["mydb", "mydb_v10", "other"],
+            database_names(&system_graph).await
+        );
+        let head = terminus_store_11::open_archive_store(&to)
+            .open("admin%2fother")
+            .await
+            .unwrap()
+            .unwrap()
+            .head()
+            .await
+            .unwrap()
+            .unwrap();
+        let triple = |s: &str, p: &str, o: &str| (s.to_string(), p.to_string(), o.to_string());
+        assert_eq!(
+            vec![
+                triple("a", "p", "\"foo\""),
+                triple("b", "p", "a"),
+                triple("c", "p", "\"foo\""),
+                triple("c", "q", "b"),
+                triple("s", "p", "\"baz\""),
+            ],
+            store.v11_triples(layer_11::Layer::name(&head)).await
+        );
+        assert!(store.dir.join("v11/.import/admin%2fother").exists());
+    }
+}
@@ -0,0 +1,370 @@
+//! Rebuilding every index of a converted layer from its triples.
+//!
+//! A conversion normally keeps the indexes of the v10 layer. Only when
+//! it changes the ids of values does it write the sp_o adjacency lists
+//! and subject lists itself, and build the object index anew. To
+//! check those copies, the adjacency lists, their bit indexes, the
+//! predicate wavelet trees and the subject and object lists can all be
+//! built again with the v11 builders, from the triples of the staged
+//! layer. The files they replace are set aside first, and every file
+//! that was copied from the v10 layer is compared with its rebuilt
+//! counterpart.
+use terminus_store_11::layer::builder as builder_11;
+use terminus_store_11::layer::{open_base_triple_stream, open_child_triple_stream};
+use terminus_store_11::storage::directory::FileBackedStore;
+use terminus_store_11::storage::{self as storage_11, FileLoad};
+use terminus_store_11::structure::logarray_stream_entries;
+
+use crate::consts::V11_FILENAMES;
+use crate::staged_layer::StagedLayer;
+
+use std::io;
+
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use tokio::io::AsyncReadExt;
+
+const COMPARE_BUFFER_SIZE: usize = 1 << 16;
+
+/// The files holding the triples of a base layer, or the additions or
+/// removals of a child layer. Adjacency lists are given as nums, bits,
+/// bit index blocks and bit index sblocks, and the wavelet tree as
+/// bits, blocks and sblocks.
+struct TripleFiles {
+    subjects: &'static str,
+    objects: &'static str,
+    s_p: [&'static str; 4],
+    sp_o: [&'static str; 4],
+    o_ps: [&'static str; 4],
+    wavelet_tree: [&'static str; 3],
+}
+
+const BASE_FILES: TripleFiles = TripleFiles {
+    subjects: V11_FILENAMES.base_subjects,
+    objects: V11_FILENAMES.base_objects,
+    s_p: [
+        V11_FILENAMES.base_s_p_adjacency_list_nums,
+        V11_FILENAMES.base_s_p_adjacency_list_bits,
+        V11_FILENAMES.base_s_p_adjacency_list_bit_index_blocks,
+        V11_FILENAMES.base_s_p_adjacency_list_bit_index_sblocks,
+    ],
+    sp_o: [
+        V11_FILENAMES.base_sp_o_adjacency_list_nums,
+        V11_FILENAMES.base_sp_o_adjacency_list_bits,
+        V11_FILENAMES.base_sp_o_adjacency_list_bit_index_blocks,
+        V11_FILENAMES.base_sp_o_adjacency_list_bit_index_sblocks,
+    ],
+    o_ps: [
+        V11_FILENAMES.base_o_ps_adjacency_list_nums,
+        V11_FILENAMES.base_o_ps_adjacency_list_bits,
+        V11_FILENAMES.base_o_ps_adjacency_list_bit_index_blocks,
+        V11_FILENAMES.base_o_ps_adjacency_list_bit_index_sblocks,
+    ],
+    wavelet_tree: [
+        V11_FILENAMES.base_predicate_wavelet_tree_bits,
+        V11_FILENAMES.base_predicate_wavelet_tree_bit_index_blocks,
+        V11_FILENAMES.base_predicate_wavelet_tree_bit_index_sblocks,
+    ],
+};
+
+const POS_FILES: TripleFiles = TripleFiles {
+    subjects: V11_FILENAMES.pos_subjects,
+    objects: V11_FILENAMES.pos_objects,
+    s_p: [
+        V11_FILENAMES.pos_s_p_adjacency_list_nums,
+        V11_FILENAMES.pos_s_p_adjacency_list_bits,
+        V11_FILENAMES.pos_s_p_adjacency_list_bit_index_blocks,
+        V11_FILENAMES.pos_s_p_adjacency_list_bit_index_sblocks,
+    ],
+    sp_o: [
+        V11_FILENAMES.pos_sp_o_adjacency_list_nums,
+        V11_FILENAMES.pos_sp_o_adjacency_list_bits,
+        V11_FILENAMES.pos_sp_o_adjacency_list_bit_index_blocks,
+        V11_FILENAMES.pos_sp_o_adjacency_list_bit_index_sblocks,
+    ],
+    o_ps: [
+        V11_FILENAMES.pos_o_ps_adjacency_list_nums,
+        V11_FILENAMES.pos_o_ps_adjacency_list_bits,
+        V11_FILENAMES.pos_o_ps_adjacency_list_bit_index_blocks,
+        V11_FILENAMES.pos_o_ps_adjacency_list_bit_index_sblocks,
+    ],
+    wavelet_tree: [
+        V11_FILENAMES.pos_predicate_wavelet_tree_bits,
+        V11_FILENAMES.pos_predicate_wavelet_tree_bit_index_blocks,
+        V11_FILENAMES.pos_predicate_wavelet_tree_bit_index_sblocks,
+    ],
+};
+
+const NEG_FILES: TripleFiles = TripleFiles {
+    subjects: V11_FILENAMES.neg_subjects,
+    objects: V11_FILENAMES.neg_objects,
+    s_p: [
+        V11_FILENAMES.neg_s_p_adjacency_list_nums,
+        V11_FILENAMES.neg_s_p_adjacency_list_bits,
+        V11_FILENAMES.neg_s_p_adjacency_list_bit_index_blocks,
+        V11_FILENAMES.neg_s_p_adjacency_list_bit_index_sblocks,
+    ],
+    sp_o: [
+        V11_FILENAMES.neg_sp_o_adjacency_list_nums,
+        V11_FILENAMES.neg_sp_o_adjacency_list_bits,
+        V11_FILENAMES.neg_sp_o_adjacency_list_bit_index_blocks,
+        V11_FILENAMES.neg_sp_o_adjacency_list_bit_index_sblocks,
+    ],
+    o_ps: [
+        V11_FILENAMES.neg_o_ps_adjacency_list_nums,
+        V11_FILENAMES.neg_o_ps_adjacency_list_bits,
+        V11_FILENAMES.neg_o_ps_adjacency_list_bit_index_blocks,
+        V11_FILENAMES.neg_o_ps_adjacency_list_bit_index_sblocks,
+    ],
+    wavelet_tree: [
+        V11_FILENAMES.neg_predicate_wavelet_tree_bits,
+        V11_FILENAMES.neg_predicate_wavelet_tree_bit_index_blocks,
+        V11_FILENAMES.neg_predicate_wavelet_tree_bit_index_sblocks,
+    ],
+};
+
+impl TripleFiles {
+    /// Every file that is rebuilt. Base layers have no subject and
+    /// object lists.
+    fn rebuilt(&self, is_child: bool) -> Vec<&'static str> {
+        let mut files = Vec::new();
+        if is_child {
+            files.extend([self.subjects, self.objects]);
+        }
+        files.extend(self.s_p);
+        files.extend(self.sp_o);
+        files.extend(self.o_ps);
+        files.extend(self.wavelet_tree);
+        files
+    }
+
+    /// Whether a staged file is a copy of the v10 one. Unless the
+    /// conversion kept the ids of values, sp_o, the subjects and
+    /// everything in the object index were written by the conversion.
+    fn is_copied(&self, name: &str, object_index_copied: bool) -> bool {
+        object_index_copied
+            || !(self.sp_o.contains(&name)
+                || name == self.subjects
+                || name == self.objects
+                || self.o_ps.contains(&name))
+    }
+}
+
+fn adjacency_list_files(
+    files: &[&str; 4],
+    open: impl Fn(&str) -> FileBackedStore,
+) -> storage_11::AdjacencyListFiles<FileBackedStore> {
+    storage_11::AdjacencyListFiles {
+        bitindex_files: storage_11::BitIndexFiles {
+            bits_file: open(files[1]),
+            blocks_file: open(files[2]),
+            sblocks_file: open(files[3]),
+        },
+        nums_file: open(files[0]),
+    }
+}
+
+/// The triples in the set aside files, in order.
+async fn set_aside_triples(
+    staged: &StagedLayer,
+    files: &TripleFiles,
+    is_child: bool,
+) -> io::Result<BoxStream<'static, io::Result<(u64, u64, u64)>>> {
+    let open = |name: &str| staged.set_aside_file(name);
+    let s_p_files = adjacency_list_files(&files.s_p, open);
+    let sp_o_files = adjacency_list_files(&files.sp_o, open);
+    if is_child {
+        Ok(
+            open_child_triple_stream(open(files.subjects), s_p_files, sp_o_files)
+                .await?
+                .boxed(),
+        )
+    } else {
+        Ok(open_base_triple_stream(s_p_files, sp_o_files)
+            .await?
+            .boxed())
+    }
+}
+
+/// Whether a set aside file holds the same as the one rebuilt in its
+/// place. Log arrays are compared by their entries, as the builders
+/// may pick a different width for them.
+async fn same_contents(
+    name: &str,
+    copied: FileBackedStore,
+    rebuilt: FileBackedStore,
+) -> io::Result<bool> {
+    if !rebuilt.exists().await? {
+        return Ok(false);
+    }
+    if !name.ends_with(".logarray") {
+        return same_bytes(copied, rebuilt).await;
+    }
+
+    let mut copied = logarray_stream_entries(copied).await?;
+    let mut rebuilt = logarray_stream_entries(rebuilt).await?;
+    loop {
+        match (copied.try_next().await?, rebuilt.try_next().await?) {
+            (None, None) => return Ok(true),
+            (a, b) if a == b => {}
+            _ => return Ok(false),
+        }
+    }
+}
+
+/// Whether two files hold the same bytes. They are read a chunk at a
+/// time, so large files are never loaded whole.
+async fn same_bytes(copied: FileBackedStore, rebuilt: FileBackedStore) -> io::Result<bool> {
+    if copied.size().await? != rebuilt.size().await? {
+        return Ok(false);
+    }
+
+    let mut copied = copied.open_read().await?;
+    let mut rebuilt = rebuilt.open_read().await?;
+    let mut copied_buf = vec![0; COMPARE_BUFFER_SIZE];
+    let mut rebuilt_buf = vec![0; COMPARE_BUFFER_SIZE];
+    loop {
+        let count = copied.read(&mut copied_buf).await?;
+        if count == 0 {
+            return Ok(true);
+        }
+        rebuilt.read_exact(&mut rebuilt_buf[..count]).await?;
+        if copied_buf[..count] != rebuilt_buf[..count] {
+            return Ok(false);
+        }
+    }
+}
+
+async fn rebuild_triple_files(
+    staged: &StagedLayer,
+    files: &TripleFiles,
+    is_child: bool,
+    object_index_copied: bool,
+) -> io::Result<Vec<&'static str>> {
+    let mut set_aside = Vec::new();
+    for name in files.rebuilt(is_child) {
+        if staged.set_aside(name).await? {
+            set_aside.push(name);
+        }
+    }
+
+    // the widths of the adjacency lists depend on the greatest ids in
+    // them, so the triples are read twice
+    let (mut greatest_predicate, mut greatest_object) = (0, 0);
+    let mut triples = set_aside_triples(staged, files, is_child).await?;
+    while let Some((_, predicate, object)) = triples.try_next().await? {
+        greatest_predicate = greatest_predicate.max(predicate);
+        greatest_object = greatest_object.max(object);
+    }
+
+    let open = |name: &str| staged.file(name);
+    let mut builder = builder_11::TripleFileBuilder::new(
+        adjacency_list_files(&files.s_p, open),
+        adjacency_list_files(&files.sp_o, open),
+        greatest_object as usize,
+        greatest_predicate as usize,
+        0,
+        is_child.then(|| open(files.subjects)),
+    )
+    .await?;
+    let mut triples = set_aside_triples(staged, files, is_child).await?;
+    while let Some((subject, predicate, object)) = triples.try_next().await? {
+        builder.add_triple(subject, predicate, object).await?;
+    }
+    builder.finalize().await?;
+
+    builder_11::build_indexes(
+        adjacency_list_files(&files.s_p, open),
+        adjacency_list_files(&files.sp_o, open),
+        adjacency_list_files(&files.o_ps, open),
+        is_child.then(|| open(files.objects)),
+        storage_11::BitIndexFiles {
+            bits_file: open(files.wavelet_tree[0]),
+            blocks_file: open(files.wavelet_tree[1]),
+            sblocks_file: open(files.wavelet_tree[2]),
+        },
+    )
+    .await?;
+
+    let mut diverged = Vec::new();
+    for name in set_aside {
+        if files.is_copied(name, object_index_copied)
+            && !same_contents(name, staged.set_aside_file(name), open(name)).await?
+        {
+            diverged.push(name);
+        }
+    }
+
+    Ok(diverged)
+}
+
+/// Rebuild the triple files and all indexes of a staged layer from its
+/// triples, and return the names of the files copied from the v10 layer
+/// that came out different. `object_index_copied` is whether the objects
+/// of sp_o and the object index are v10 copies as well, as they are in a
+/// naive conversion.
+pub async fn rebuild_all_indexes(
+    staged: &StagedLayer,
+    is_child: bool,
+    object_index_copied: bool,
+) -> io::Result<Vec<&'static str>> {
+    let mut diverged = Vec::new();
+    if is_child {
+        for files in [&POS_FILES, &NEG_FILES] {
+            diverged.extend(rebuild_triple_files(staged, files, true, object_index_copied).await?);
+        }
+    } else {
+        diverged = rebuild_triple_files(staged, &BASE_FILES, false, object_index_copied).await?;
+    }
+    staged.remove_set_aside().await?;
+
+    Ok(diverged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copied_files() {
+        let base = BASE_FILES.rebuilt(false);
+        assert_eq!(15, base.len());
+        assert!(!base.contains(&BASE_FILES.subjects));
+        assert_eq!(17, POS_FILES.rebuilt(true).len());
+
+        let compared: Vec<_> = NEG_FILES
+            .rebuilt(true)
+            .into_iter()
+            .filter(|name| NEG_FILES.is_copied(name, false))
+            .collect();
+        assert_eq!(7, compared.len());
+        assert!(compared.contains(&V11_FILENAMES.neg_s_p_adjacency_list_bits));
+        assert!(!compared.contains(&V11_FILENAMES.neg_subjects));
+        assert!(!compared.contains(&V11_FILENAMES.neg_sp_o_adjacency_list_bits));
+        assert!(!compared.contains(&V11_FILENAMES.neg_sp_o_adjacency_list_nums));
+        assert!(!compared.contains(&V11_FILENAMES.neg_objects));
+        assert!(NEG_FILES
+            .rebuilt(true)
+            .iter()
+            .all(|name| NEG_FILES.is_copied(name, true)));
+    }
+
+    #[tokio::test]
+    async fn files_are_compared_in_chunks() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("index_rebuild_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let contents = vec![7u8; COMPARE_BUFFER_SIZE * 2 + 5];
+        let mut changed = contents.clone();
+        *changed.last_mut().unwrap() = 8;
+        for (name, bytes) in [("a", &contents), ("b", &contents), ("c", &changed)] {
+            std::fs::write(dir.join(name), bytes).unwrap();
+        }
+        let file = |name| FileBackedStore::new(dir.join(name));
+
+        assert!(same_bytes(file("a"), file("b")).await.unwrap());
+        assert!(!same_bytes(file("a"), file("c")).await.unwrap());
+        std::fs::write(dir.join("b"), &contents[..10]).unwrap();
+        assert!(!same_bytes(file("a"), file("b")).await.unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Stopping a conversion cleanly on SIGINT or SIGTERM.
+//!
+//! On the first signal during the layer loop, the layer that is being
+//! converted is finished, but no new layers are started, and the status
+//! log gets a checkpoint entry. A second signal, or any signal outside
+//! of the layer loop, exits right away. The layer in progress is
+//! then cleaned up and converted again when the conversion is resumed.
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+static STATE: InterruptState = InterruptState::new();
+
+/// The exit code of a process killed by SIGINT.
+const FORCED_EXIT_CODE: i32 = 130;
+
+/// Whether a signal was received, and whether signals are deferred
+/// until the layer in progress is finished. Outside of the layer loop,
+/// a signal exits right away, as it would without a handler.
+struct InterruptState {
+    interrupted: AtomicBool,
+    deferring: AtomicBool,
+}
+
+impl InterruptState {
+    const fn new() -> Self {
+        Self {
+            interrupted: AtomicBool::new(false),
+            deferring: AtomicBool::new(false),
+        }
+    }
+
+    fn defer(&self) -> DeferredInterrupts<'_> {
+        self.deferring.store(true, Ordering::SeqCst);
+        DeferredInterrupts(self)
+    }
+
+    /// Record a signal. Returns whether to exit immediately.
+    fn signal(&self) -> bool {
+        let interrupted = self.interrupted.swap(true, Ordering::SeqCst);
+        interrupted || !self.deferring.load(Ordering::SeqCst)
+    }
+}
+
+/// While this is held, the first signal only asks the conversion to
+/// stop after the layer in progress.
+pub struct DeferredInterrupts<'a>(&'a InterruptState);
+
+impl Drop for DeferredInterrupts<'_> {
+    fn drop(&mut self) {
+        self.0.deferring.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Whether the conversion was asked to stop.
+pub fn interrupted() -> bool {
+    STATE.interrupted.load(Ordering::SeqCst)
+}
+
+/// Defer signals until the returned guard is dropped. Used around the
+/// layer loop, as a layer is best finished before stopping.
+pub fn defer_interrupts() -> DeferredInterrupts<'static> {
+    STATE.defer()
+}
+
+/// Start listening for SIGINT and SIGTERM.
+pub fn install_signal_handlers() -> io::Result<()> {
+    #[cfg(unix)]
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::spawn(async move {
+        loop {
+            #[cfg(unix)]
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            #[cfg(not(unix))]
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+
+            if STATE.signal() {
+                eprintln!("Interrupted, exiting immediately");
+                std::process::exit(FORCED_EXIT_CODE);
+            }
+            eprintln!(
+                "Interrupted, finishing the layer in progress. Interrupt again to exit immediately"
+            );
+        }
+    });
+
+    Ok(())
+}
+
+/// Sleep for the given time, or until the conversion is interrupted.
+pub async fn interruptible_sleep(duration: Duration) {
+    let step = Duration::from_secs(1);
+    let mut remaining = duration;
+    while !interrupted() && !remaining.is_zero() {
+        let sleep = remaining.min(step);
+        tokio::time::sleep(sleep).await;
+        remaining -= sleep;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_exit_unless_deferred() {
+        let state = InterruptState::new();
+        assert!(state.signal());
+
+        let state = InterruptState::new();
+        {
+            let _deferred = state.defer();
+            assert!(!state.signal());
+            assert!(state.interrupted.load(Ordering::SeqCst));
+            assert!(state.signal());
+        }
+        assert!(!state.deferring.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn signals_after_the_layer_loop_exit() {
+        let state = InterruptState::new();
+        drop(state.defer());
+        assert!(state.signal());
+    }
+
+    #[tokio::test]
+    async fn sleeps_last_their_time_when_not_interrupted() {
+        let start = std::time::Instant::now();
+        interruptible_sleep(Duration::from_millis(10)).await;
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        assert!(!interrupted());
+    }
+}
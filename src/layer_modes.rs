@@ -0,0 +1,524 @@
+//! Which layers are converted naively and which typed.
+//!
+//! The mode is chosen per label, and holds for every layer the label
+//! reaches, all the way up to the base layer. A typed layer needs the
+//! parent map its parent wrote in the workdir, which a naive conversion
+//! never writes, so all layers of a chain have to share a mode.
+//!
+//! A label gets its mode from the naive overrides in the options, or
+//! else from the first label rule whose pattern matches its name, or
+//! else from the mode of the whole conversion. With [`LabelMode::Detect`]
+//! the mode is picked by looking at the values in the layers of the
+//! label: stores that TerminusDB wrote hold nothing but literals like
+//! `"1"^^'http://www.w3.org/2001/XMLSchema#integer'`, while other users
+//! of terminus-store usually store plain strings. Labels with both are
+//! converted in auto mode.
+use terminus_store_10::storage as storage_10;
+use terminus_store_10::storage::name_to_string;
+use terminus_store_10::structure::pfc as pfc_10;
+
+use crate::consts::V10_FILENAMES;
+use crate::dataconversion::is_terminusdb_literal;
+use crate::options::ConversionOptions;
+use crate::plan::decode_label_name;
+use crate::reachable::LabelRoots;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// The number of values looked at in every layer to detect its mode.
+const DETECTION_SAMPLE_SIZE: usize = 1000;
+
+#[derive(Error, Debug)]
+pub enum LayerModeError {
+    #[error("layer {} is reached by `{label}`, which is converted in {mode} mode, and by `{other_label}`, which is converted in {other_mode} mode. Convert both the same way", name_to_string(*.layer))]
+    MixedModes {
+        layer: [u32; 5],
+        label: String,
+        mode: ValueMode,
+        other_label: String,
+        other_mode: ValueMode,
+    },
+    #[error("layer {} would be converted in {mode} mode, but its parent {} was already converted in {parent_mode} mode. Convert it in {parent_mode} mode, or start over with a new workdir", name_to_string(*.layer), name_to_string(*.parent))]
+    ConvertedParent {
+        layer: [u32; 5],
+        mode: ValueMode,
+        parent: [u32; 5],
+        parent_mode: ValueMode,
+    },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// How the values of a layer are converted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ValueMode {
+    /// Every value becomes a string.
+    Naive,
+    /// Every value is a TerminusDB literal, and is converted to its
+    /// datatype.
+    Typed,
+    /// Values that are TerminusDB literals are converted to their
+    /// datatype, and all others become strings.
+    Auto,
+}
+
+impl fmt::Display for ValueMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ValueMode::Naive => "naive",
+            ValueMode::Typed => "typed",
+            ValueMode::Auto => "auto",
+        })
+    }
+}
+
+/// How to convert the layers of a label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LabelMode {
+    Naive,
+    Typed,
+    Auto,
+    /// Typed if the values in its layers are TerminusDB literals, naive
+    /// if they are not, and auto if they are a mix.
+    Detect,
+}
+
+impl From<ValueMode> for LabelMode {
+    fn from(mode: ValueMode) -> Self {
+        match mode {
+            ValueMode::Naive => LabelMode::Naive,
+            ValueMode::Typed => LabelMode::Typed,
+            ValueMode::Auto => LabelMode::Auto,
+        }
+    }
+}
+
+/// Convert the labels whose name matches `pattern` in `mode`. Patterns
+/// are matched against the decoded label name, such as `admin/mydb`,
+/// where `*` matches any number of characters and `?` matches one.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabelRule {
+    pub pattern: String,
+    pub mode: LabelMode,
+}
+
+impl LabelRule {
+    pub fn new(pattern: impl Into<String>, mode: LabelMode) -> Self {
+        Self {
+            pattern: pattern.into(),
+            mode,
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        glob_matches(&self.pattern, name)
+    }
+}
+
+/// Match a name against a pattern with `*` and `?` wildcards.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // where the last `*` was, and the part of the name it matches so far
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // let the `*` match one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// The conversion mode of each layer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayerModes {
+    mode: ValueMode,
+    layers: HashMap<[u32; 5], ValueMode>,
+}
+
+impl LayerModes {
+    /// Every layer converted in the same mode.
+    pub fn uniform(mode: ValueMode) -> Self {
+        Self {
+            mode,
+            layers: HashMap::new(),
+        }
+    }
+
+    pub fn mode(&self, layer: [u32; 5]) -> ValueMode {
+        *self.layers.get(&layer).unwrap_or(&self.mode)
+    }
+}
+
+/// How many of the sampled values of a layer were TerminusDB literals.
+#[derive(Clone, Copy, Debug, Default)]
+struct LiteralSample {
+    literals: u64,
+    others: u64,
+}
+
+async fn sample_literals<S: storage_10::PersistentLayerStore>(
+    layer_store: &S,
+    layer: [u32; 5],
+) -> io::Result<LiteralSample> {
+    let file = storage_10::PersistentLayerStore::get_file(
+        layer_store,
+        layer,
+        V10_FILENAMES.value_dictionary_blocks,
+    )
+    .await?;
+    let mut stream = pfc_10::dict_file_to_stream(file).await?;
+    let mut sample = LiteralSample::default();
+    for _ in 0..DETECTION_SAMPLE_SIZE {
+        match stream.try_next().await? {
+            Some(value) if is_terminusdb_literal(&value) => sample.literals += 1,
+            Some(_) => sample.others += 1,
+            None => break,
+        }
+    }
+
+    Ok(sample)
+}
+
+/// Finds the layers of labels and what their values look like, looking
+/// every layer up only once.
+struct ChainWalker<'a, S> {
+    layer_store: &'a S,
+    parents: HashMap<[u32; 5], Option<[u32; 5]>>,
+    samples: HashMap<[u32; 5], LiteralSample>,
+}
+
+impl<'a, S: storage_10::PersistentLayerStore> ChainWalker<'a, S> {
+    fn new(layer_store: &'a S) -> Self {
+        Self {
+            layer_store,
+            parents: HashMap::new(),
+            samples: HashMap::new(),
+        }
+    }
+
+    /// The layers of a label along with all their ancestors.
+    async fn chain(&mut self, root: &LabelRoots) -> io::Result<Vec<[u32; 5]>> {
+        let mut chain = Vec::new();
+        let mut seen = HashSet::new();
+        for layer in root.layers.iter() {
+            let mut current = Some(*layer);
+            while let Some(layer) = current {
+                if !seen.insert(layer) {
+                    break;
+                }
+                chain.push(layer);
+                current = match self.parents.get(&layer) {
+                    Some(parent) => *parent,
+                    None => {
+                        let parent =
+                            storage_10::LayerStore::get_layer_parent_name(self.layer_store, layer)
+                                .await?;
+                        self.parents.insert(layer, parent);
+                        parent
+                    }
+                };
+            }
+        }
+
+        Ok(chain)
+    }
+
+    async fn sample(&mut self, chain: &[[u32; 5]]) -> io::Result<LiteralSample> {
+        let mut total = LiteralSample::default();
+        for layer in chain {
+            let sample = match self.samples.get(layer) {
+                Some(sample) => *sample,
+                None => {
+                    let sample = sample_literals(self.layer_store, *layer).await?;
+                    self.samples.insert(*layer, sample);
+                    sample
+                }
+            };
+            total.literals += sample.literals;
+            total.others += sample.others;
+        }
+
+        Ok(total)
+    }
+}
+
+/// Pick the mode of a label from the values in its layers. Labels
+/// without values get the mode of the whole conversion.
+fn detected_mode(label: &str, sample: LiteralSample, options: &ConversionOptions) -> ValueMode {
+    let mode = match (sample.literals, sample.others) {
+        (0, 0) => options.value_mode(),
+        (_, 0) => ValueMode::Typed,
+        (0, _) => ValueMode::Naive,
+        (_, _) => ValueMode::Auto,
+    };
+    if options.verbose {
+        println!(
+            "`{}` detected as {mode} ({} of {} values sampled are TerminusDB literals)",
+            decode_label_name(label),
+            sample.literals,
+            sample.literals + sample.others
+        );
+    }
+
+    mode
+}
+
+/// Find the mode of every layer the labels reach, as set by the naive
+/// overrides and label rules in `options`. Fails if a layer is reached
+/// by labels with different modes.
+pub async fn find_layer_modes<S: storage_10::PersistentLayerStore>(
+    layer_store: &S,
+    roots: &[LabelRoots],
+    options: &ConversionOptions,
+) -> Result<LayerModes, LayerModeError> {
+    let default = options.value_mode();
+    let label_modes: Vec<LabelMode> = roots.iter().map(|r| options.label_mode(&r.label)).collect();
+    if label_modes
+        .iter()
+        .all(|mode| *mode == LabelMode::from(default))
+    {
+        return Ok(LayerModes::uniform(default));
+    }
+
+    let mut walker = ChainWalker::new(layer_store);
+    let mut modes = LayerModes::uniform(default);
+    // the label each layer got its mode from, to report conflicts with
+    let mut reached_by: HashMap<[u32; 5], &str> = HashMap::new();
+    for (root, mode) in roots.iter().zip(label_modes) {
+        let chain = walker.chain(root).await?;
+        let mode = match mode {
+            LabelMode::Naive => ValueMode::Naive,
+            LabelMode::Typed => ValueMode::Typed,
+            LabelMode::Auto => ValueMode::Auto,
+            LabelMode::Detect => detected_mode(&root.label, walker.sample(&chain).await?, options),
+        };
+        for layer in chain {
+            match reached_by.get(&layer) {
+                Some(_) if modes.layers[&layer] == mode => {}
+                Some(label) => {
+                    return Err(LayerModeError::MixedModes {
+                        layer,
+                        label: decode_label_name(label),
+                        mode: modes.layers[&layer],
+                        other_label: decode_label_name(&root.label),
+                        other_mode: mode,
+                    });
+                }
+                None => {
+                    modes.layers.insert(layer, mode);
+                    reached_by.insert(layer, &root.label);
+                }
+            }
+        }
+    }
+
+    Ok(modes)
+}
+
+/// Check that none of the `pending` layers is converted in another
+/// mode than its parent, if that was converted already. A typed layer
+/// needs the parent map its parent wrote, which a naive conversion
+/// never writes, and a naive layer would keep the value ids its typed
+/// parent remapped. `converted_mode` gives the mode of a converted
+/// layer, and `None` for layers that are not converted yet.
+pub fn check_converted_parents(
+    pending: &[[u32; 5]],
+    parents: &HashMap<[u32; 5], [u32; 5]>,
+    modes: &LayerModes,
+    converted_mode: impl Fn([u32; 5]) -> Option<ValueMode>,
+) -> Result<(), LayerModeError> {
+    for layer in pending {
+        let Some(parent) = parents.get(layer) else {
+            continue;
+        };
+        match converted_mode(*parent) {
+            Some(parent_mode) if parent_mode != modes.mode(*layer) => {
+                return Err(LayerModeError::ConvertedParent {
+                    layer: *layer,
+                    mode: modes.mode(*layer),
+                    parent: *parent,
+                    parent_mode,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_store::TestStore;
+    use terminus_store_10::storage::directory as directory_10;
+
+    const LITERAL: &str = "\"1\"^^'http://www.w3.org/2001/XMLSchema#integer'";
+
+    fn roots(label: &str, layer: [u32; 5]) -> LabelRoots {
+        LabelRoots {
+            label: label.to_string(),
+            data_product: true,
+            head: layer,
+            layers: vec![layer],
+        }
+    }
+
+    fn default_options() -> ConversionOptions {
+        ConversionOptions::new("/data/storage", "/data/storage_11")
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_matches("admin/*", "admin/mydb"));
+        assert!(glob_matches("*/legacy_?", "acme/legacy_2"));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(glob_matches("admin/mydb", "admin/mydb"));
+        assert!(!glob_matches("admin/*", "acme/mydb"));
+        assert!(!glob_matches("*/legacy_?", "acme/legacy_10"));
+        assert!(!glob_matches("admin/mydb", "admin/mydb2"));
+    }
+
+    #[test]
+    fn mode_changes_below_converted_layers() {
+        // 1 -> 2 -> 3 and 4 -> 5, where 1 and 2 are converted typed
+        let parents = HashMap::from([([2; 5], [1; 5]), ([3; 5], [2; 5]), ([5; 5], [4; 5])]);
+        let converted = HashMap::from([([1; 5], ValueMode::Typed), ([2; 5], ValueMode::Typed)]);
+        let converted_mode = |layer| converted.get(&layer).copied();
+
+        let typed = LayerModes::uniform(ValueMode::Typed);
+        assert!(check_converted_parents(&[[3; 5]], &parents, &typed, converted_mode).is_ok());
+
+        // a chain nothing of which is converted yet can change its mode
+        let naive = LayerModes::uniform(ValueMode::Naive);
+        assert!(
+            check_converted_parents(&[[4; 5], [5; 5]], &parents, &naive, converted_mode).is_ok()
+        );
+
+        assert!(matches!(
+            check_converted_parents(&[[3; 5]], &parents, &naive, converted_mode),
+            Err(LayerModeError::ConvertedParent {
+                layer: [3, 3, 3, 3, 3],
+                mode: ValueMode::Naive,
+                parent: [2, 2, 2, 2, 2],
+                parent_mode: ValueMode::Typed,
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn modes_are_detected_from_values() {
+        let store = TestStore::new("detected_modes");
+        let typed = store.value_layer(None, &[LITERAL]).await;
+        let plain = store.value_layer(None, &["foo"]).await;
+        let (mixed, _) = store.layers_with_equal_values().await;
+        let empty = store.value_layer(None, &[]).await;
+        let layer_store = directory_10::DirectoryLayerStore::new(store.path("v10"));
+
+        let roots = [
+            roots("typed", typed),
+            roots("plain", plain),
+            roots("mixed", mixed),
+            roots("empty", empty),
+        ];
+        let options = default_options()
+            .naive(true)
+            .label_rule("*", LabelMode::Detect);
+        let modes = find_layer_modes(&layer_store, &roots, &options)
+            .await
+            .unwrap();
+        assert_eq!(ValueMode::Typed, modes.mode(typed));
+        assert_eq!(ValueMode::Naive, modes.mode(plain));
+        assert_eq!(ValueMode::Auto, modes.mode(mixed));
+        // without values, the mode of the whole conversion is used
+        assert_eq!(ValueMode::Naive, modes.mode(empty));
+    }
+
+    #[tokio::test]
+    async fn modes_hold_up_to_the_base_layer() {
+        let store = TestStore::new("shared_ancestors");
+        let base = store.value_layer(None, &[LITERAL]).await;
+        let left = store.value_layer(Some(base), &[LITERAL]).await;
+        let top = store.value_layer(Some(left), &[LITERAL]).await;
+        let right = store.value_layer(Some(base), &[LITERAL]).await;
+        let other = store.value_layer(None, &[LITERAL]).await;
+        let layer_store = directory_10::DirectoryLayerStore::new(store.path("v10"));
+
+        let roots = [
+            roots("top", top),
+            roots("right", right),
+            roots("other", other),
+        ];
+        let options = default_options()
+            .label_rule("top", LabelMode::Naive)
+            .label_rule("right", LabelMode::Naive);
+        let modes = find_layer_modes(&layer_store, &roots, &options)
+            .await
+            .unwrap();
+        for layer in [base, left, top, right] {
+            assert_eq!(ValueMode::Naive, modes.mode(layer));
+        }
+        assert_eq!(ValueMode::Typed, modes.mode(other));
+    }
+
+    #[tokio::test]
+    async fn labels_sharing_layers_need_the_same_mode() {
+        let store = TestStore::new("mixed_modes");
+        let base = store.value_layer(None, &[LITERAL]).await;
+        let left = store.value_layer(Some(base), &[LITERAL]).await;
+        let right = store.value_layer(Some(base), &["foo"]).await;
+        let layer_store = directory_10::DirectoryLayerStore::new(store.path("v10"));
+
+        let roots = [roots("left", left), roots("right", right)];
+        let options = default_options().label_rule("left", LabelMode::Naive);
+        let result = find_layer_modes(&layer_store, &roots, &options).await;
+        assert!(
+            matches!(
+                &result,
+                Err(LayerModeError::MixedModes {
+                    layer,
+                    label,
+                    mode: ValueMode::Naive,
+                    other_label,
+                    other_mode: ValueMode::Typed,
+                }) if *layer == base && label == "left" && other_label == "right"
+            ),
+            "{result:?}"
+        );
+
+        // detecting the modes of both gives the same trouble
+        let options = default_options().label_rule("*", LabelMode::Detect);
+        let result = find_layer_modes(&layer_store, &roots, &options).await;
+        assert!(matches!(
+            result,
+            Err(LayerModeError::MixedModes {
+                mode: ValueMode::Typed,
+                other_mode: ValueMode::Auto,
+                ..
+            })
+        ));
+    }
+}
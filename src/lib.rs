@@ -0,0 +1,95 @@
+//! Converts a TerminusDB v10 store into a v11 store.
+//!
+//! This is the library behind the `terminusdb-10-to-11` command line
+//! tool. A whole store is converted with [`convert_store`], which takes
+//! its settings as [`ConversionOptions`], and reports its progress to a
+//! [`ConversionObserver`]:
+//!
+//! ```no_run
+//! use terminusdb_10_to_11::{convert_store, ConversionOptions, LogObserver};
+//!
+//! # async fn example() -> Result<(), terminusdb_10_to_11::StoreConversionError> {
+//! let options = ConversionOptions::new("/data/storage", "/data/storage_11").keep_going(true);
+//! convert_store(&options, &LogObserver::new(false)).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Single layers are converted with [`convert_layer_with_stores`],
+//! which reads the layer from a store opened with terminus-store v10
+//! (re-exported as [`terminus_store_10`], with v11 as
+//! [`terminus_store_11`]) and writes it into the v11 store directory
+//! `to`. It also takes a workdir, where the files of the new layer are
+//! staged and where the parent maps of converted layers are kept, so the
+//! parent of a layer must have been converted with the same workdir.
+//! [`find_reachable_layers`] finds the layers a conversion goes through,
+//! and [`dataconversion`] turns v10 value strings into v11 dictionary
+//! entries.
+mod config;
+mod consts;
+mod convert_dict;
+mod convert_labels;
+mod convert_layer;
+mod convert_store;
+mod convert_triples;
+pub mod dataconversion;
+mod disk_space;
+mod durable;
+mod failure_impact;
+mod import_database;
+mod index_rebuild;
+mod interrupt;
+mod layer_modes;
+mod metrics;
+mod migration;
+pub mod observer;
+pub mod options;
+mod plan;
+mod reachable;
+mod replace;
+mod retry_failed;
+mod run_manifest;
+mod staged_layer;
+mod status_log;
+mod storage_version;
+mod store_archive;
+mod store_lock;
+mod store_manifest;
+mod tar_store;
+#[cfg(test)]
+mod test_store;
+
+pub use terminus_store_10;
+pub use terminus_store_11;
+
+pub use convert_labels::{LabelConflict, LabelConflictPolicy, LabelResolution, LabelState};
+pub use convert_layer::{convert_layer_with_stores, LayerConversionError, LayerSettings};
+pub use convert_store::{convert_store, StoreConversionError};
+pub use dataconversion::{convert_value_string_to_dict_entry, DataConversionError};
+pub use disk_space::DiskSpaceError;
+pub use layer_modes::{LabelMode, LabelRule, LayerModeError, ValueMode};
+pub use observer::{ConversionObserver, LayerPhase, LogObserver, MultiObserver, SilentObserver};
+pub use options::ConversionOptions;
+pub use reachable::find_reachable_layers;
+pub use replace::ReplaceError;
+pub use run_manifest::RunManifestError;
+pub use storage_version::{StorageVersion, StorageVersionError};
+pub use store_archive::StoreArchiveError;
+pub use store_lock::LockError;
+pub use store_manifest::ManifestError;
+
+/// What the command line tool uses besides the library API. This is not
+/// meant for other users, and may change in any release.
+#[doc(hidden)]
+pub mod cli {
+    pub use crate::config::{ConfigError, ConversionConfig};
+    pub use crate::convert_layer::convert_layer;
+    pub use crate::import_database::{import_database, import_workdir, ImportError};
+    pub use crate::interrupt::install_signal_handlers;
+    pub use crate::metrics::{MetricsObserver, DEFAULT_METRICS_INTERVAL_SECS};
+    pub use crate::plan::plan_store;
+    pub use crate::retry_failed::retry_failed;
+    pub use crate::status_log::show_status;
+    pub use crate::store_archive::unpack_store_archive;
+    pub use crate::store_manifest::check_store_manifest;
+}
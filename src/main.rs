@@ -1,16 +1,15 @@
-mod consts;
-mod convert_dict;
-mod convert_layer;
-mod convert_store;
-mod convert_triples;
-mod dataconversion;
-mod reachable;
+mod progress;
 
-use convert_layer::*;
-use convert_store::*;
+use terminusdb_10_to_11::cli::*;
+use terminusdb_10_to_11::dataconversion::DatatypeMap;
+use terminusdb_10_to_11::*;
+
+use progress::ProgressObserver;
 
 use clap::*;
-use std::io;
+use std::io::{self, IsTerminal};
+use std::sync::Arc;
+use std::time::Duration;
 
 use thiserror::*;
 
@@ -35,6 +34,12 @@ enum Commands {
         /// Convert the layer assuming all values are strings
         #[arg(long = "naive")]
         naive: bool,
+        /// Convert values that are TerminusDB literals to their datatype, and keep all others as strings
+        #[arg(long = "auto", conflicts_with = "naive")]
+        auto: bool,
+        /// Build every index of the layer anew from its triples rather than copying the v10 ones, and warn about files that differ
+        #[arg(long = "rebuild-all-indexes")]
+        rebuild_all_indexes: bool,
         /// The layer id to convert
         id: String,
         /// Verbose reporting
@@ -42,43 +47,343 @@ enum Commands {
         verbose: bool,
     },
     /// convert a store from a 10 store and an 11 store
-    ConvertStore {
+    ConvertStore(ConvertStoreArgs),
+    /// retry the layers that failed in an earlier convert-store run, along with the layers below them
+    RetryFailed(RetryFailedArgs),
+    /// print the settings convert-store would run with, combining the config file and the flags given
+    PrintConfig(ConvertStoreArgs),
+    /// import a single database from a 10 store into an existing 11 store
+    ImportDatabase {
         /// The storage dir from v10
         from: String,
-        /// The storage dir for v11
+        /// The storage dir of the existing v11 store
         to: String,
-        /// The workdir to store mappings in
+        /// The database to import, as `organization/database`
+        database: String,
+        /// The workdir to store mappings in [default: <to>/.import/<organization>%2f<database>]
         #[arg(short = 'w', long = "workdir")]
         workdir: Option<String>,
-        /// Path to a file with a list of labels to convert
-        #[arg(long = "labels")]
-        labels: Option<String>,
-        /// Convert the store assuming all values are strings
+        /// Convert the database assuming all values are strings
         #[arg(long = "naive")]
         naive: bool,
-        /// Keep going with other layers if a layer does not convert
-        #[arg(short = 'c', long = "continue")]
-        keep_going: bool,
+        /// Convert values that are TerminusDB literals to their datatype, and keep all others as strings
+        #[arg(long = "auto", conflicts_with = "naive")]
+        auto: bool,
+        /// Build every index of the converted layers anew from their triples rather than copying the v10 ones, and warn about files that differ
+        #[arg(long = "rebuild-all-indexes")]
+        rebuild_all_indexes: bool,
+        /// Verbose reporting
+        #[arg(short = 'v', long = "verbose")]
+        verbose: bool,
+        /// What to do when the database label already exists in the destination with a different layer
+        #[arg(long = "label-conflict", value_enum, default_value = "fail")]
+        label_conflict: LabelConflictPolicy,
+        /// The suffix to append to a conflicting label name with --label-conflict=rename-suffix
+        #[arg(long = "label-suffix", default_value = "_v10")]
+        label_suffix: String,
+        /// Resume even if the workdir was used with different settings
+        #[arg(long = "force")]
+        force: bool,
+        /// Free space in MiB to leave on the filesystems of the destination store and the workdir [default: 1024]
+        #[arg(long = "min-free-space")]
+        min_free_space: Option<u64>,
+        /// Don't check for free disk space before or during the import
+        #[arg(long = "skip-space-check")]
+        skip_space_check: bool,
+        /// Refuse to run while something is listening on this port on localhost, such as a running TerminusDB server [default port: 6363]
+        #[arg(long = "check-server-port", num_args = 0..=1, default_missing_value = "6363")]
+        check_server_port: Option<u16>,
+    },
+    /// export the layer graph of a 10 store along with the order it would be converted in
+    Plan {
+        /// The storage dir from v10
+        from: String,
+        /// Path to a file with a list of labels to include
+        #[arg(long = "labels")]
+        labels: Option<String>,
+        /// Write the layer graph as Graphviz DOT to this file
+        #[arg(long = "dot")]
+        dot: Option<String>,
+        /// Write the layer plan as JSON to this file
+        #[arg(long = "json")]
+        json: Option<String>,
+        /// Verbose reporting
+        #[arg(short = 'v', long = "verbose")]
+        verbose: bool,
+    },
+    /// check a converted 11 store against the MANIFEST.json written by convert-store
+    CheckManifest {
+        /// The storage dir for v11
+        store: String,
+        /// Also check the layer files in this v10 storage dir or archive
+        #[arg(long = "source")]
+        source: Option<String>,
+        /// Verbose reporting
+        #[arg(short = 'v', long = "verbose")]
+        verbose: bool,
+    },
+    /// show the progress of a running or aborted store conversion
+    Status {
+        /// The storage dir for v11
+        to: String,
+        /// The workdir of the conversion [default: <to>/.workdir]
+        #[arg(short = 'w', long = "workdir")]
+        workdir: Option<String>,
+        /// The number of slowest layers to list
+        #[arg(long = "slowest", default_value_t = 5)]
+        slowest: usize,
+        /// Print the report as JSON
+        #[arg(long = "json")]
+        json: bool,
+    },
+    /// extract a store archive written with --output-archive and check it against its manifest
+    Unpack {
+        /// The archive to extract
+        archive: String,
+        /// The storage dir to extract into. Should be empty or not exist yet
+        to: String,
         /// Verbose reporting
         #[arg(short = 'v', long = "verbose")]
         verbose: bool,
-        /// Replace original directory with converted directory
-        #[arg(short = 'r', long = "replace")]
-        replace: bool,
-        /// Cleanup work directory after successful run
-        #[arg(short = 'k', long = "clean")]
-        clean: bool,
     },
 }
 
+/// The flags convert-store and retry-failed share.
+#[derive(Args)]
+struct CommonArgs {
+    /// The storage dir from v10
+    from: Option<String>,
+    /// The storage dir for v11
+    to: Option<String>,
+    /// A TOML file with settings for the conversion. Flags given on the command line take precedence
+    #[arg(long = "config")]
+    config: Option<String>,
+    /// The workdir to store mappings in [default: <to>/.workdir]
+    #[arg(short = 'w', long = "workdir")]
+    workdir: Option<String>,
+    /// Path to a file with a list of labels to convert
+    #[arg(long = "labels")]
+    labels: Option<String>,
+    /// Convert assuming all values are strings
+    #[arg(long = "naive", overrides_with = "no_naive")]
+    naive: bool,
+    /// Don't convert assuming all values are strings, even if the config file says so
+    #[arg(long = "no-naive", overrides_with = "naive")]
+    no_naive: bool,
+    /// Convert values that are TerminusDB literals to their datatype, and keep all others as strings
+    #[arg(long = "auto", conflicts_with = "naive", overrides_with = "no_auto")]
+    auto: bool,
+    /// Don't convert in auto mode, even if the config file says so
+    #[arg(long = "no-auto", overrides_with = "auto")]
+    no_auto: bool,
+    /// Convert labels naively, typed or in auto mode depending on how many of their values are TerminusDB literals
+    #[arg(long = "detect-naive", conflicts_with_all = ["naive", "auto"], overrides_with = "no_detect_naive")]
+    detect_naive: bool,
+    /// Don't detect the mode of labels, even if the config file says so
+    #[arg(long = "no-detect-naive", overrides_with = "detect_naive")]
+    no_detect_naive: bool,
+    /// Build every index of the converted layers anew from their triples rather than copying the v10 ones, and warn about files that differ
+    #[arg(
+        long = "rebuild-all-indexes",
+        overrides_with = "no_rebuild_all_indexes"
+    )]
+    rebuild_all_indexes: bool,
+    /// Copy the indexes that can be copied, even if the config file says to rebuild them all
+    #[arg(
+        long = "no-rebuild-all-indexes",
+        overrides_with = "rebuild_all_indexes"
+    )]
+    no_rebuild_all_indexes: bool,
+    /// Keep going with other layers if a layer does not convert
+    #[arg(short = 'c', long = "continue", overrides_with = "no_keep_going")]
+    keep_going: bool,
+    /// Stop at the first layer that does not convert, even if the config file says to continue
+    #[arg(long = "no-continue", overrides_with = "keep_going")]
+    no_keep_going: bool,
+    /// Verbose reporting
+    #[arg(short = 'v', long = "verbose", overrides_with = "no_verbose")]
+    verbose: bool,
+    /// No verbose reporting, even if the config file asks for it
+    #[arg(long = "no-verbose", overrides_with = "verbose")]
+    no_verbose: bool,
+    /// Resume even if the workdir was used with different settings
+    #[arg(long = "force", overrides_with = "no_force")]
+    force: bool,
+    /// Don't resume when the workdir was used with different settings, even if the config file says to force it
+    #[arg(long = "no-force", overrides_with = "force")]
+    no_force: bool,
+    /// Write conversion metrics in the Prometheus text format to this file, for node_exporter's textfile collector
+    #[arg(long = "metrics-file")]
+    metrics_file: Option<String>,
+    /// Seconds between writes of the metrics file [default: 15]
+    #[arg(long = "metrics-interval")]
+    metrics_interval: Option<u64>,
+}
+
+#[derive(Args)]
+struct ConvertStoreArgs {
+    #[command(flatten)]
+    common: CommonArgs,
+    /// Replace original directory with converted directory
+    #[arg(short = 'r', long = "replace", overrides_with = "no_replace")]
+    replace: bool,
+    /// Don't replace the original directory, even if the config file says so
+    #[arg(long = "no-replace", overrides_with = "replace")]
+    no_replace: bool,
+    /// Cleanup work directory after successful run
+    #[arg(short = 'k', long = "clean", overrides_with = "no_clean")]
+    clean: bool,
+    /// Keep the work directory, even if the config file says to clean it up
+    #[arg(long = "no-clean", overrides_with = "clean")]
+    no_clean: bool,
+    /// Reuse the output of a previous run, converting only new layers and updating changed labels
+    #[arg(long = "sync", conflicts_with_all = ["clean", "replace"], overrides_with = "no_sync")]
+    sync: bool,
+    /// Run a full conversion, even if the config file says to sync
+    #[arg(long = "no-sync", overrides_with = "sync")]
+    no_sync: bool,
+    /// What to do with labels that already exist in the destination with a different layer [default: fail, or overwrite with --sync]
+    #[arg(long = "label-conflict", value_enum)]
+    label_conflict: Option<LabelConflictPolicy>,
+    /// The suffix to append to conflicting label names with --label-conflict=rename-suffix [default: _v10]
+    #[arg(long = "label-suffix")]
+    label_suffix: Option<String>,
+    /// Also write the converted store to this zstd-compressed tar archive
+    #[arg(long = "output-archive")]
+    output_archive: Option<String>,
+    /// The directory to move the original store to with --replace [default: next to the original store]
+    #[arg(long = "backup-dir")]
+    backup_dir: Option<String>,
+    /// Free space in MiB to leave on the filesystems of the new store and the workdir. The conversion pauses in between layers when this is reached [default: 1024]
+    #[arg(long = "min-free-space")]
+    min_free_space: Option<u64>,
+    /// Don't check for free disk space before or during the conversion
+    #[arg(long = "skip-space-check", overrides_with = "no_skip_space_check")]
+    skip_space_check: bool,
+    /// Check for free disk space, even if the config file says to skip it
+    #[arg(long = "no-skip-space-check", overrides_with = "skip_space_check")]
+    no_skip_space_check: bool,
+    /// Refuse to run while something is listening on this port on localhost, such as a running TerminusDB server [default port: 6363]
+    #[arg(long = "check-server-port", num_args = 0..=1, default_missing_value = "6363")]
+    check_server_port: Option<u16>,
+}
+
+/// The flags of retry-failed. They are those of convert-store that
+/// apply to the layers it retries, with the same meaning.
+#[derive(Args)]
+struct RetryFailedArgs {
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+/// The config file, if one was given.
+async fn read_config(path: Option<&str>) -> Result<ConversionConfig, ConfigError> {
+    match path {
+        Some(path) => ConversionConfig::read(path).await,
+        None => Ok(ConversionConfig::default()),
+    }
+}
+
+/// A setting switched on with a flag or off with its `--no-` flag, or
+/// `None` if neither was given.
+fn switch(on: bool, off: bool) -> Option<bool> {
+    if on {
+        Some(true)
+    } else if off {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+impl CommonArgs {
+    /// The settings given with the shared flags, on top of those in the
+    /// config file. Flags that are not given don't override anything.
+    async fn config(self, flags: ConversionConfig) -> Result<ConversionConfig, ConfigError> {
+        let file = read_config(self.config.as_deref()).await?;
+        let flags = ConversionConfig {
+            from: self.from,
+            to: self.to,
+            workdir: self.workdir,
+            labels: self.labels,
+            naive: switch(self.naive, self.no_naive),
+            auto: switch(self.auto, self.no_auto),
+            detect_naive: switch(self.detect_naive, self.no_detect_naive),
+            rebuild_all_indexes: switch(self.rebuild_all_indexes, self.no_rebuild_all_indexes),
+            keep_going: switch(self.keep_going, self.no_keep_going),
+            verbose: switch(self.verbose, self.no_verbose),
+            force: switch(self.force, self.no_force),
+            metrics_file: self.metrics_file,
+            metrics_interval: self.metrics_interval,
+            ..flags
+        };
+
+        Ok(flags.or(file))
+    }
+}
+
+impl ConvertStoreArgs {
+    async fn config(self) -> Result<ConversionConfig, ConfigError> {
+        let flags = ConversionConfig {
+            replace: switch(self.replace, self.no_replace),
+            clean: switch(self.clean, self.no_clean),
+            sync: switch(self.sync, self.no_sync),
+            label_conflict: self.label_conflict,
+            label_suffix: self.label_suffix,
+            output_archive: self.output_archive,
+            backup_dir: self.backup_dir,
+            min_free_space: self.min_free_space,
+            skip_space_check: switch(self.skip_space_check, self.no_skip_space_check),
+            check_server_port: self.check_server_port,
+            ..Default::default()
+        };
+
+        self.common.config(flags).await
+    }
+}
+
+impl RetryFailedArgs {
+    async fn config(self) -> Result<ConversionConfig, ConfigError> {
+        self.common.config(ConversionConfig::default()).await
+    }
+}
+
 #[derive(Error, Debug)]
 #[error(transparent)]
 pub enum CliError {
     StoreConversion(#[from] StoreConversionError),
     LayerConversion(#[from] LayerConversionError),
+    Import(#[from] ImportError),
+    StoreArchive(#[from] StoreArchiveError),
+    Manifest(#[from] ManifestError),
+    Config(#[from] ConfigError),
     Io(#[from] io::Error),
 }
 
+/// A progress bar when running in a terminal, and a line per layer
+/// otherwise, so that logs stay readable.
+fn progress_observer(verbose: bool) -> Arc<dyn ConversionObserver> {
+    if io::stderr().is_terminal() {
+        Arc::new(ProgressObserver::new(verbose))
+    } else {
+        Arc::new(LogObserver::new(verbose))
+    }
+}
+
+/// Progress output, along with metrics if a metrics file was asked for.
+fn conversion_observer(config: &ConversionConfig) -> MultiObserver {
+    let mut observers = vec![progress_observer(config.verbose.unwrap_or(false))];
+    if let Some(metrics_file) = config.metrics_file.as_deref() {
+        let interval = config
+            .metrics_interval
+            .unwrap_or(DEFAULT_METRICS_INTERVAL_SECS);
+        let interval = Duration::from_secs(interval.max(1));
+        observers.push(MetricsObserver::start(metrics_file, interval));
+    }
+
+    MultiObserver(observers)
+}
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
     let result = inner_main().await;
@@ -98,50 +403,140 @@ async fn inner_main() -> Result<(), CliError> {
             to,
             workdir,
             naive,
+            auto,
+            rebuild_all_indexes,
             id,
             verbose,
         } => {
+            let mode = if auto {
+                ValueMode::Auto
+            } else if naive {
+                ValueMode::Naive
+            } else {
+                ValueMode::Typed
+            };
             convert_layer(
                 &from,
                 &to,
                 workdir
                     .as_deref()
                     .unwrap_or("/tmp/terminusdb_10_to_11_workdir/"),
-                naive,
-                verbose,
+                LayerSettings {
+                    mode,
+                    datatypes: &DatatypeMap::new(),
+                    rebuild_all_indexes,
+                },
                 &id,
+                &LogObserver::new(verbose),
             )
             .await?;
         }
-        Commands::ConvertStore {
+        Commands::ConvertStore(args) => {
+            install_signal_handlers()?;
+            let config = args.config().await?;
+            if config.ignores_clean() {
+                println!("Clean flag was specified, but ignored as we will not remove manually specified work directories");
+            };
+            let options = config.options()?;
+            let observer = conversion_observer(&config);
+            convert_store(&options, &observer).await?;
+        }
+        Commands::RetryFailed(args) => {
+            install_signal_handlers()?;
+            let config = args.config().await?;
+            let ignored = config.ignored_by_retry();
+            if !ignored.is_empty() {
+                println!(
+                    "{} specified in the config file, but ignored when retrying failed layers",
+                    ignored.join(", ")
+                );
+            }
+            let observer = conversion_observer(&config);
+            retry_failed(&config.options()?, &observer).await?;
+        }
+        Commands::PrintConfig(args) => {
+            print!("{}", args.config().await?.effective()?.to_toml());
+        }
+        Commands::ImportDatabase {
             from,
             to,
+            database,
             workdir,
-            labels,
             naive,
-            keep_going,
+            auto,
+            rebuild_all_indexes,
             verbose,
-            replace,
-            mut clean,
+            label_conflict,
+            label_suffix,
+            force,
+            min_free_space,
+            skip_space_check,
+            check_server_port,
         } => {
-            if workdir.is_some() && clean {
-                println!("Clean flag was specified, but ignored as we will not remove manually specified work directories");
-                clean = false;
-            };
-            let default_workdir = format!("{to}/.workdir");
-            convert_store(
+            install_signal_handlers()?;
+            let workdir = workdir.unwrap_or_else(|| import_workdir(&to, &database));
+            let mut options = ConversionOptions::new(from, to)
+                .workdir(workdir)
+                .naive(naive)
+                .auto(auto)
+                .rebuild_all_indexes(rebuild_all_indexes)
+                .verbose(verbose)
+                .label_conflict(label_conflict)
+                .label_suffix(label_suffix)
+                .skip_space_check(skip_space_check)
+                .force(force);
+            if let Some(min_free_space) = min_free_space {
+                options = options.min_free_space(min_free_space << 20);
+            }
+            if let Some(port) = check_server_port {
+                options = options.check_server_port(port);
+            }
+            import_database(&options, &database, progress_observer(verbose).as_ref()).await?;
+        }
+        Commands::Plan {
+            from,
+            labels,
+            dot,
+            json,
+            verbose,
+        } => {
+            plan_store(
                 &from,
-                &to,
-                workdir.as_deref().unwrap_or(&default_workdir),
                 labels.as_deref(),
-                naive,
-                keep_going,
+                dot.as_deref(),
+                json.as_deref(),
                 verbose,
-                replace,
-                clean,
             )
             .await?;
         }
+        Commands::CheckManifest {
+            store,
+            source,
+            verbose,
+        } => {
+            check_store_manifest(&store, source.as_deref(), verbose).await?;
+        }
+        Commands::Status {
+            to,
+            workdir,
+            slowest,
+            json,
+        } => {
+            let default_workdir = format!("{to}/.workdir");
+            show_status(
+                workdir.as_deref().unwrap_or(&default_workdir),
+                slowest,
+                json,
+            )
+            .await?;
+        }
+        Commands::Unpack {
+            archive,
+            to,
+            verbose,
+        } => {
+            unpack_store_archive(&archive, &to, verbose).await?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,307 @@
+//! Conversion metrics in the Prometheus text format.
+//!
+//! The metrics are written to a file which node_exporter's textfile
+//! collector picks up, so a long conversion can be followed on the
+//! same dashboards as everything else. The file is written every so
+//! often while the conversion runs, and once more when it is done. It
+//! is replaced as a whole each time, so the collector never sees a
+//! partially written file.
+use terminus_store_11::structure::tfc::Datatype;
+
+use crate::convert_layer::LayerConversionError;
+use crate::observer::*;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The default time between writes of the metrics file, in seconds.
+pub const DEFAULT_METRICS_INTERVAL_SECS: u64 = 15;
+
+const PREFIX: &str = "terminusdb_conversion";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    /// Whether a run is going on.
+    pub running: bool,
+    /// The layers the current run goes through.
+    pub layers: u64,
+    /// The layers converted, including those converted by earlier runs.
+    pub layers_completed: u64,
+    pub layers_failed: u64,
+    pub values_converted: BTreeMap<Datatype, u64>,
+    pub opened_file_bytes: u64,
+    pub bytes_written: u64,
+    /// The time spent in each phase of converting a layer, in seconds.
+    pub phase_seconds: BTreeMap<LayerPhase, f64>,
+}
+
+/// The amount of memory the process has resident, in bytes.
+#[cfg(target_os = "linux")]
+pub fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn resident_memory() -> Option<u64> {
+    None
+}
+
+fn write_metric(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: &[(String, String)],
+) {
+    let _ = writeln!(out, "# HELP {PREFIX}_{name} {help}");
+    let _ = writeln!(out, "# TYPE {PREFIX}_{name} {kind}");
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{PREFIX}_{name}{labels} {value}");
+    }
+}
+
+/// Render the metrics in the Prometheus text format.
+pub fn render_metrics(metrics: &Metrics, resident_memory: Option<u64>, timestamp: f64) -> String {
+    let single = |value: String| vec![(String::new(), value)];
+    let mut out = String::new();
+    write_metric(
+        &mut out,
+        "running",
+        "gauge",
+        "Whether a conversion run is going on.",
+        &single((metrics.running as u8).to_string()),
+    );
+    write_metric(
+        &mut out,
+        "layers",
+        "gauge",
+        "Layers the current conversion run goes through.",
+        &single(metrics.layers.to_string()),
+    );
+    write_metric(
+        &mut out,
+        "layers_completed",
+        "gauge",
+        "Layers converted, including those converted by earlier runs.",
+        &single(metrics.layers_completed.to_string()),
+    );
+    write_metric(
+        &mut out,
+        "layers_failed",
+        "gauge",
+        "Layers that failed to convert.",
+        &single(metrics.layers_failed.to_string()),
+    );
+    let values: Vec<_> = metrics
+        .values_converted
+        .iter()
+        .map(|(datatype, values)| (format!("{{datatype=\"{datatype:?}\"}}"), values.to_string()))
+        .collect();
+    write_metric(
+        &mut out,
+        "values_converted_total",
+        "counter",
+        "Values converted, by the datatype they were converted to.",
+        &values,
+    );
+    write_metric(
+        &mut out,
+        "opened_file_bytes_total",
+        "counter",
+        "Total size of the layer files of the original store opened for conversion.",
+        &single(metrics.opened_file_bytes.to_string()),
+    );
+    write_metric(
+        &mut out,
+        "written_bytes_total",
+        "counter",
+        "Bytes written to layer files in the new store.",
+        &single(metrics.bytes_written.to_string()),
+    );
+    let phases: Vec<_> = metrics
+        .phase_seconds
+        .iter()
+        .map(|(phase, seconds)| {
+            (
+                format!("{{phase=\"{}\"}}", phase.name().replace(' ', "_")),
+                format!("{seconds:.3}"),
+            )
+        })
+        .collect();
+    write_metric(
+        &mut out,
+        "phase_seconds_total",
+        "counter",
+        "Time spent in each phase of converting a layer.",
+        &phases,
+    );
+    if let Some(resident_memory) = resident_memory {
+        write_metric(
+            &mut out,
+            "resident_memory_bytes",
+            "gauge",
+            "Resident memory of the conversion process.",
+            &single(resident_memory.to_string()),
+        );
+    }
+    write_metric(
+        &mut out,
+        "last_update_timestamp_seconds",
+        "gauge",
+        "When these metrics were written.",
+        &single(format!("{timestamp:.3}")),
+    );
+
+    out
+}
+
+/// Collects metrics from a conversion and writes them to a file.
+pub struct MetricsObserver {
+    path: PathBuf,
+    metrics: Mutex<Metrics>,
+}
+
+impl MetricsObserver {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            metrics: Mutex::new(Metrics::default()),
+        }
+    }
+
+    /// Create an observer that writes its metrics file every
+    /// `interval`, for as long as it is around.
+    pub fn start(path: impl Into<PathBuf>, interval: Duration) -> Arc<Self> {
+        let observer = Arc::new(Self::new(path));
+        let weak = Arc::downgrade(&observer);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match weak.upgrade() {
+                    Some(observer) => observer.write_or_warn(),
+                    None => return,
+                }
+            }
+        });
+
+        observer
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    /// Write the metrics file, replacing the one written before.
+    pub fn write(&self) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let rendered = render_metrics(&self.metrics(), resident_memory(), timestamp);
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        std::fs::write(&tmp_path, rendered)?;
+        std::fs::rename(&tmp_path, &self.path)
+    }
+
+    fn write_or_warn(&self) {
+        if let Err(e) = self.write() {
+            eprintln!(
+                "WARNING: could not write metrics to `{}`: {e}",
+                self.path.display()
+            );
+        }
+    }
+
+    fn update<F: FnOnce(&mut Metrics)>(&self, f: F) {
+        f(&mut self.metrics.lock().unwrap());
+    }
+}
+
+impl ConversionObserver for MetricsObserver {
+    fn run_started(&self, layers: usize, completed: usize) {
+        self.update(|m| {
+            m.running = true;
+            m.layers = layers as u64;
+            m.layers_completed = completed as u64;
+            m.layers_failed = 0;
+        });
+        self.write_or_warn();
+    }
+
+    fn run_finished(&self) {
+        self.update(|m| m.running = false);
+        self.write_or_warn();
+    }
+
+    fn phase_finished(&self, _layer: [u32; 5], phase: LayerPhase, elapsed: Duration) {
+        self.update(|m| *m.phase_seconds.entry(phase).or_insert(0.0) += elapsed.as_secs_f64());
+    }
+
+    fn source_file_opened(&self, _layer: [u32; 5], size: u64) {
+        self.update(|m| m.opened_file_bytes += size);
+    }
+
+    fn values_converted(&self, _layer: [u32; 5], datatype: Datatype, values: u64) {
+        self.update(|m| *m.values_converted.entry(datatype).or_insert(0) += values);
+    }
+
+    fn bytes_written(&self, _layer: [u32; 5], bytes: u64) {
+        self.update(|m| m.bytes_written += bytes);
+    }
+
+    fn layer_completed(&self, _layer: [u32; 5], _elapsed: Duration) {
+        self.update(|m| m.layers_completed += 1);
+    }
+
+    fn layer_failed(&self, _layer: [u32; 5], _error: &LayerConversionError) {
+        self.update(|m| m.layers_failed += 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_in_text_format() {
+        let metrics = Metrics {
+            running: true,
+            layers: 11,
+            layers_completed: 4,
+            layers_failed: 1,
+            values_converted: BTreeMap::from([(Datatype::String, 20), (Datatype::Decimal, 3)]),
+            opened_file_bytes: 2048,
+            bytes_written: 1024,
+            phase_seconds: BTreeMap::from([(LayerPhase::ConvertDictionaries, 1.5)]),
+        };
+        let rendered = render_metrics(&metrics, Some(4096), 1700000000.0);
+        let samples: Vec<&str> = rendered.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            vec![
+                "terminusdb_conversion_running 1",
+                "terminusdb_conversion_layers 11",
+                "terminusdb_conversion_layers_completed 4",
+                "terminusdb_conversion_layers_failed 1",
+                "terminusdb_conversion_values_converted_total{datatype=\"String\"} 20",
+                "terminusdb_conversion_values_converted_total{datatype=\"Decimal\"} 3",
+                "terminusdb_conversion_opened_file_bytes_total 2048",
+                "terminusdb_conversion_written_bytes_total 1024",
+                "terminusdb_conversion_phase_seconds_total{phase=\"convert_dictionaries\"} 1.500",
+                "terminusdb_conversion_resident_memory_bytes 4096",
+                "terminusdb_conversion_last_update_timestamp_seconds 1700000000.000",
+            ],
+            samples
+        );
+        assert!(rendered.contains("# TYPE terminusdb_conversion_opened_file_bytes_total counter\n"));
+    }
+}
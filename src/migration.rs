@@ -0,0 +1,137 @@
+//! Converting a store through the storage versions in between.
+//!
+//! Every hop from one storage version to the next is a
+//! [`MigrationStep`], and [`MIGRATION_STEPS`] lists them oldest first.
+//! A conversion finds the version of its source, and runs the steps
+//! from there up to [`StorageVersion::LATEST`] one after the other, so
+//! that converting from a new version only takes a step from the one
+//! before it. All steps but the last write their store to the workdir,
+//! where the next step picks it up.
+use crate::convert_store::{StoreConversionError, V10ToV11};
+use crate::observer::ConversionObserver;
+use crate::options::ConversionOptions;
+use crate::storage_version::*;
+
+use async_trait::async_trait;
+
+/// The conversion of a whole store from one storage version to the
+/// next.
+#[async_trait]
+pub trait MigrationStep: Sync {
+    fn source_version(&self) -> StorageVersion;
+
+    fn target_version(&self) -> StorageVersion;
+
+    /// Convert the store in `options.from` into a store of the target
+    /// version in `options.to`.
+    async fn convert(
+        &self,
+        options: &ConversionOptions,
+        observer: &dyn ConversionObserver,
+    ) -> Result<(), StoreConversionError>;
+}
+
+/// Every step this tool can take, oldest first.
+pub static MIGRATION_STEPS: &[&dyn MigrationStep] = &[&V10ToV11];
+
+/// The steps that convert a store of version `from` into one of version
+/// `to`, in the order to take them. There are none if the versions are
+/// the same.
+pub fn migration_path(
+    from: StorageVersion,
+    to: StorageVersion,
+) -> Result<Vec<&'static dyn MigrationStep>, StorageVersionError> {
+    let mut path = Vec::new();
+    let mut current = from;
+    while current != to {
+        let step = MIGRATION_STEPS
+            .iter()
+            .find(|step| step.source_version() == current && step.target_version() <= to)
+            .ok_or(StorageVersionError::NoMigration { from, to })?;
+        path.push(*step);
+        current = step.target_version();
+    }
+
+    Ok(path)
+}
+
+/// The settings for a step that converts into the workdir rather than
+/// the destination. The store it writes is only an intermediate one, so
+/// it is never moved into place, archived or synced.
+fn intermediate_options(
+    options: &ConversionOptions,
+    from: String,
+    step: &dyn MigrationStep,
+) -> ConversionOptions {
+    let (source, target) = (step.source_version(), step.target_version());
+    ConversionOptions {
+        from,
+        to: format!("{}/storage_{target}", options.workdir),
+        workdir: format!("{}/{source}_to_{target}", options.workdir),
+        replace: false,
+        clean: false,
+        sync: false,
+        output_archive: None,
+        ..options.clone()
+    }
+}
+
+/// Run the steps in order, feeding the store each step writes to the
+/// next.
+pub async fn run_migration(
+    steps: &[&dyn MigrationStep],
+    options: &ConversionOptions,
+    observer: &dyn ConversionObserver,
+) -> Result<(), StoreConversionError> {
+    if steps.len() > 1 && options.replace {
+        return Err(StoreConversionError::CannotReplaceIntermediate(
+            options.from.clone(),
+        ));
+    }
+
+    let mut from = options.from.clone();
+    for (i, step) in steps.iter().enumerate() {
+        if i + 1 == steps.len() {
+            let options = ConversionOptions {
+                from,
+                ..options.clone()
+            };
+            return step.convert(&options, observer).await;
+        }
+        let step_options = intermediate_options(options, from, *step);
+        if options.verbose {
+            println!(
+                "converting to TerminusDB {} in `{}`",
+                step.target_version(),
+                step_options.to
+            );
+        }
+        step.convert(&step_options, observer).await?;
+        from = step_options.to;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_between_versions() {
+        let path = migration_path(StorageVersion::V10, StorageVersion::V11).unwrap();
+        let hops: Vec<_> = path
+            .iter()
+            .map(|step| (step.source_version(), step.target_version()))
+            .collect();
+        assert_eq!(vec![(StorageVersion::V10, StorageVersion::V11)], hops);
+
+        assert!(migration_path(StorageVersion::V11, StorageVersion::V11)
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            migration_path(StorageVersion::V11, StorageVersion::V10),
+            Err(StorageVersionError::NoMigration { .. })
+        ));
+    }
+}
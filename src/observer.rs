@@ -0,0 +1,236 @@
+//! Following along with a conversion.
+//!
+//! The conversion reports its progress to a [`ConversionObserver`]
+//! rather than printing it, so that embedding applications can show it
+//! in their own way. [`LogObserver`] prints it the way the command line
+//! tool always has.
+use terminus_store_10::storage::name_to_string;
+use terminus_store_11::structure::tfc::Datatype;
+
+use crate::convert_layer::LayerConversionError;
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The steps a layer goes through while it is converted. Naive
+/// conversions skip reading the parent map, converting triples and
+/// rebuilding indexes, as they only change the dictionaries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LayerPhase {
+    /// Reading the parent map the parent layer left in the workdir.
+    ReadParentMap,
+    ConvertDictionaries,
+    ConvertTriples,
+    CopyFiles,
+    RebuildIndexes,
+    /// Writing the layer file into the destination store.
+    Finalize,
+    /// Writing the parent map for the children of this layer.
+    WriteParentMap,
+}
+
+impl LayerPhase {
+    pub fn name(self) -> &'static str {
+        match self {
+            LayerPhase::ReadParentMap => "read parent map",
+            LayerPhase::ConvertDictionaries => "convert dictionaries",
+            LayerPhase::ConvertTriples => "convert triples",
+            LayerPhase::CopyFiles => "copy files",
+            LayerPhase::RebuildIndexes => "rebuild indexes",
+            LayerPhase::Finalize => "finalize",
+            LayerPhase::WriteParentMap => "write parent map",
+        }
+    }
+}
+
+impl fmt::Display for LayerPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Callbacks for the progress of a conversion. Every method does
+/// nothing by default, so implementations only need to pick the ones
+/// they are interested in. Callbacks are made from the task doing the
+/// conversion and should return quickly.
+pub trait ConversionObserver: Send + Sync {
+    /// A run over `layers` layers started, of which `completed` were
+    /// already converted by an earlier run.
+    fn run_started(&self, _layers: usize, _completed: usize) {}
+
+    /// The run ended, either because all layers were visited, or
+    /// because it stopped early.
+    fn run_finished(&self) {}
+
+    /// A layer was already converted by an earlier run.
+    fn layer_skipped(&self, _layer: [u32; 5]) {}
+
+    fn layer_started(&self, _layer: [u32; 5]) {}
+
+    fn phase_started(&self, _layer: [u32; 5], _phase: LayerPhase) {}
+
+    fn phase_finished(&self, _layer: [u32; 5], _phase: LayerPhase, _elapsed: Duration) {}
+
+    /// A file of the v10 layer was opened for reading. `size` is the
+    /// size of the whole file, reported once when it is opened rather than
+    /// as it is read.
+    fn source_file_opened(&self, _layer: [u32; 5], _size: u64) {}
+
+    /// `values` values in the value dictionary of the layer were
+    /// converted to `datatype`. Naive conversions turn every value into
+    /// a string.
+    fn values_converted(&self, _layer: [u32; 5], _datatype: Datatype, _values: u64) {}
+
+    /// An auto conversion converted `typed` values of the layer to their
+    /// datatype and kept `strings` values as strings, because they were
+    /// not TerminusDB literals.
+    fn values_auto_converted(&self, _layer: [u32; 5], _typed: u64, _strings: u64) {}
+
+    /// A file of the layer that was rebuilt with `rebuild_all_indexes`
+    /// differs from the one in the v10 layer.
+    fn index_diverged(&self, _layer: [u32; 5], _file: &str) {}
+
+    /// The converted layer was written to a layer file of `bytes` bytes.
+    fn bytes_written(&self, _layer: [u32; 5], _bytes: u64) {}
+
+    fn layer_completed(&self, _layer: [u32; 5], _elapsed: Duration) {}
+
+    fn layer_failed(&self, _layer: [u32; 5], _error: &LayerConversionError) {}
+}
+
+/// An observer that ignores everything.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SilentObserver;
+
+impl ConversionObserver for SilentObserver {}
+
+/// An observer that prints a line for every layer, and with `verbose`,
+/// for every phase as well. Failures are printed to stderr.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogObserver {
+    pub verbose: bool,
+}
+
+impl LogObserver {
+    pub fn new(verbose: bool) -> Self {
+        Self { verbose }
+    }
+}
+
+impl ConversionObserver for LogObserver {
+    fn layer_skipped(&self, layer: [u32; 5]) {
+        if self.verbose {
+            println!("skipping: {}", name_to_string(layer));
+        }
+    }
+
+    fn layer_started(&self, layer: [u32; 5]) {
+        println!("converting layer {}", name_to_string(layer));
+    }
+
+    fn phase_finished(&self, _layer: [u32; 5], phase: LayerPhase, elapsed: Duration) {
+        if self.verbose {
+            println!("{phase}: done in {:.1}s", elapsed.as_secs_f64());
+        }
+    }
+
+    fn values_auto_converted(&self, _layer: [u32; 5], typed: u64, strings: u64) {
+        println!("{typed} values typed, {strings} kept as strings");
+    }
+
+    fn index_diverged(&self, layer: [u32; 5], file: &str) {
+        eprintln!(
+            "WARNING: the rebuilt `{file}` of layer {} differs from the v10 copy",
+            name_to_string(layer)
+        );
+    }
+
+    fn layer_failed(&self, _layer: [u32; 5], error: &LayerConversionError) {
+        eprintln!("ERROR: {error}");
+    }
+}
+
+/// Passes everything on to each of a number of observers.
+#[derive(Default)]
+pub struct MultiObserver(pub Vec<Arc<dyn ConversionObserver>>);
+
+impl ConversionObserver for MultiObserver {
+    fn run_started(&self, layers: usize, completed: usize) {
+        for o in self.0.iter() {
+            o.run_started(layers, completed);
+        }
+    }
+
+    fn run_finished(&self) {
+        for o in self.0.iter() {
+            o.run_finished();
+        }
+    }
+
+    fn layer_skipped(&self, layer: [u32; 5]) {
+        for o in self.0.iter() {
+            o.layer_skipped(layer);
+        }
+    }
+
+    fn layer_started(&self, layer: [u32; 5]) {
+        for o in self.0.iter() {
+            o.layer_started(layer);
+        }
+    }
+
+    fn phase_started(&self, layer: [u32; 5], phase: LayerPhase) {
+        for o in self.0.iter() {
+            o.phase_started(layer, phase);
+        }
+    }
+
+    fn phase_finished(&self, layer: [u32; 5], phase: LayerPhase, elapsed: Duration) {
+        for o in self.0.iter() {
+            o.phase_finished(layer, phase, elapsed);
+        }
+    }
+
+    fn source_file_opened(&self, layer: [u32; 5], size: u64) {
+        for o in self.0.iter() {
+            o.source_file_opened(layer, size);
+        }
+    }
+
+    fn values_converted(&self, layer: [u32; 5], datatype: Datatype, values: u64) {
+        for o in self.0.iter() {
+            o.values_converted(layer, datatype, values);
+        }
+    }
+
+    fn values_auto_converted(&self, layer: [u32; 5], typed: u64, strings: u64) {
+        for o in self.0.iter() {
+            o.values_auto_converted(layer, typed, strings);
+        }
+    }
+
+    fn index_diverged(&self, layer: [u32; 5], file: &str) {
+        for o in self.0.iter() {
+            o.index_diverged(layer, file);
+        }
+    }
+
+    fn bytes_written(&self, layer: [u32; 5], bytes: u64) {
+        for o in self.0.iter() {
+            o.bytes_written(layer, bytes);
+        }
+    }
+
+    fn layer_completed(&self, layer: [u32; 5], elapsed: Duration) {
+        for o in self.0.iter() {
+            o.layer_completed(layer, elapsed);
+        }
+    }
+
+    fn layer_failed(&self, layer: [u32; 5], error: &LayerConversionError) {
+        for o in self.0.iter() {
+            o.layer_failed(layer, error);
+        }
+    }
+}
@@ -0,0 +1,314 @@
+//! The settings of a store conversion.
+use crate::convert_labels::LabelConflictPolicy;
+use crate::convert_layer::LayerSettings;
+use crate::dataconversion::DatatypeMap;
+use crate::disk_space::DEFAULT_MIN_FREE_SPACE_MIB;
+use crate::layer_modes::{LabelMode, LabelRule, ValueMode};
+use crate::plan::decode_label_name;
+
+use std::collections::BTreeMap;
+
+/// How to convert a store. Start from [`ConversionOptions::new`], which
+/// gives the defaults of the command line tool, and change what you
+/// need with the builder methods.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ConversionOptions {
+    /// The v10 store, either a storage dir or a tar archive.
+    pub from: String,
+    /// The storage dir for the v11 store.
+    pub to: String,
+    /// The workdir to store the status log and parent maps in.
+    pub workdir: String,
+    /// A file listing the labels to convert. All labels are converted
+    /// if not given.
+    pub labels: Option<String>,
+    /// Convert assuming all values are strings.
+    pub naive: bool,
+    /// Convert the values that are TerminusDB literals to their
+    /// datatype, and keep all other values as strings.
+    pub auto: bool,
+    /// Databases, or other labels, to convert naively (`true`) or typed
+    /// (`false`) regardless of `naive`, by their name, such as
+    /// `admin/mydb`.
+    pub naive_overrides: BTreeMap<String, bool>,
+    /// Rules for the mode of labels that have no naive override. The
+    /// first rule that matches a label is used.
+    pub label_rules: Vec<LabelRule>,
+    /// Detect the mode of labels that have no naive override or rule,
+    /// rather than using `naive`.
+    pub detect_naive: bool,
+    /// The datatypes to convert values of other datatypes as, in typed
+    /// conversions.
+    pub datatypes: DatatypeMap,
+    /// Build every index of the converted layers from their triples,
+    /// rather than copying the v10 ones, and report where they differ.
+    pub rebuild_all_indexes: bool,
+    /// Keep going with other layers if a layer does not convert.
+    pub keep_going: bool,
+    pub verbose: bool,
+    /// Move the converted store into the place of the original one.
+    pub replace: bool,
+    /// Remove the workdir after a successful conversion.
+    pub clean: bool,
+    /// Reuse the output of a previous run, converting only new layers
+    /// and updating changed labels.
+    pub sync: bool,
+    /// What to do with labels that already exist in the destination.
+    /// Defaults to failing, or to overwriting when syncing.
+    pub label_conflict: Option<LabelConflictPolicy>,
+    /// The suffix for conflicting labels with
+    /// [`LabelConflictPolicy::RenameSuffix`].
+    pub label_suffix: String,
+    /// Also write the converted store to this archive.
+    pub output_archive: Option<String>,
+    /// Where to move the original store with `replace`.
+    pub backup_dir: Option<String>,
+    /// Free space in bytes to leave on the filesystems of the new store
+    /// and the workdir.
+    pub min_free_space: u64,
+    pub skip_space_check: bool,
+    /// Refuse to run while something listens on this port on localhost.
+    pub server_port: Option<u16>,
+    /// Resume even if the workdir was used with different settings.
+    pub force: bool,
+}
+
+impl ConversionOptions {
+    /// Convert `from` into `to`, using `<to>/.workdir` as the workdir.
+    pub fn new(from: impl Into<String>, to: impl Into<String>) -> Self {
+        let to = to.into();
+        Self {
+            from: from.into(),
+            workdir: format!("{to}/.workdir"),
+            to,
+            labels: None,
+            naive: false,
+            auto: false,
+            naive_overrides: BTreeMap::new(),
+            label_rules: Vec::new(),
+            detect_naive: false,
+            datatypes: DatatypeMap::new(),
+            rebuild_all_indexes: false,
+            keep_going: false,
+            verbose: false,
+            replace: false,
+            clean: false,
+            sync: false,
+            label_conflict: None,
+            label_suffix: "_v10".to_string(),
+            output_archive: None,
+            backup_dir: None,
+            min_free_space: DEFAULT_MIN_FREE_SPACE_MIB << 20,
+            skip_space_check: false,
+            server_port: None,
+            force: false,
+        }
+    }
+
+    pub fn workdir(mut self, workdir: impl Into<String>) -> Self {
+        self.workdir = workdir.into();
+        self
+    }
+
+    pub fn labels(mut self, labels: impl Into<String>) -> Self {
+        self.labels = Some(labels.into());
+        self
+    }
+
+    pub fn naive(mut self, naive: bool) -> Self {
+        self.naive = naive;
+        self
+    }
+
+    pub fn auto(mut self, auto: bool) -> Self {
+        self.auto = auto;
+        self
+    }
+
+    /// Convert the database or label `name` naively or typed, whatever
+    /// the mode of the rest of the store.
+    pub fn naive_override(mut self, name: impl Into<String>, naive: bool) -> Self {
+        self.naive_overrides.insert(name.into(), naive);
+        self
+    }
+
+    /// Convert the labels matching `pattern` in `mode`, unless an
+    /// earlier rule matches them.
+    pub fn label_rule(mut self, pattern: impl Into<String>, mode: LabelMode) -> Self {
+        self.label_rules.push(LabelRule::new(pattern, mode));
+        self
+    }
+
+    pub fn detect_naive(mut self, detect_naive: bool) -> Self {
+        self.detect_naive = detect_naive;
+        self
+    }
+
+    /// Convert values of the datatype IRI `from` as if they were of the
+    /// datatype IRI `to`.
+    pub fn datatype(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.datatypes.insert(from.into(), to.into());
+        self
+    }
+
+    /// How the layers of a label are converted. `label` is the name of
+    /// the label as stored, such as `admin%2fmydb`.
+    pub fn label_mode(&self, label: &str) -> LabelMode {
+        let name = decode_label_name(label);
+        if let Some(naive) = self.naive_overrides.get(&name) {
+            return if *naive {
+                LabelMode::Naive
+            } else {
+                LabelMode::Typed
+            };
+        }
+        if let Some(rule) = self.label_rules.iter().find(|r| r.matches(&name)) {
+            return rule.mode;
+        }
+
+        if self.detect_naive {
+            LabelMode::Detect
+        } else {
+            self.value_mode().into()
+        }
+    }
+
+    /// The mode of the conversion as a whole, which labels get unless
+    /// they have a mode of their own.
+    pub fn value_mode(&self) -> ValueMode {
+        if self.auto {
+            ValueMode::Auto
+        } else if self.naive {
+            ValueMode::Naive
+        } else {
+            ValueMode::Typed
+        }
+    }
+
+    /// How to convert a layer in the given mode.
+    pub fn layer_settings(&self, mode: ValueMode) -> LayerSettings<'_> {
+        LayerSettings {
+            mode,
+            datatypes: &self.datatypes,
+            rebuild_all_indexes: self.rebuild_all_indexes,
+        }
+    }
+
+    pub fn rebuild_all_indexes(mut self, rebuild_all_indexes: bool) -> Self {
+        self.rebuild_all_indexes = rebuild_all_indexes;
+        self
+    }
+
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    pub fn replace(mut self, replace: bool) -> Self {
+        self.replace = replace;
+        self
+    }
+
+    pub fn clean(mut self, clean: bool) -> Self {
+        self.clean = clean;
+        self
+    }
+
+    pub fn sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    pub fn label_conflict(mut self, label_conflict: LabelConflictPolicy) -> Self {
+        self.label_conflict = Some(label_conflict);
+        self
+    }
+
+    pub fn label_suffix(mut self, label_suffix: impl Into<String>) -> Self {
+        self.label_suffix = label_suffix.into();
+        self
+    }
+
+    pub fn output_archive(mut self, output_archive: impl Into<String>) -> Self {
+        self.output_archive = Some(output_archive.into());
+        self
+    }
+
+    pub fn backup_dir(mut self, backup_dir: impl Into<String>) -> Self {
+        self.backup_dir = Some(backup_dir.into());
+        self
+    }
+
+    /// The free space to leave, in bytes.
+    pub fn min_free_space(mut self, min_free_space: u64) -> Self {
+        self.min_free_space = min_free_space;
+        self
+    }
+
+    pub fn skip_space_check(mut self, skip_space_check: bool) -> Self {
+        self.skip_space_check = skip_space_check;
+        self
+    }
+
+    pub fn check_server_port(mut self, port: u16) -> Self {
+        self.server_port = Some(port);
+        self
+    }
+
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_match_the_command_line() {
+        let options = ConversionOptions::new("/data/storage", "/data/storage_11");
+        assert_eq!("/data/storage_11/.workdir", options.workdir);
+        assert_eq!(None, options.label_conflict);
+        assert_eq!("_v10", options.label_suffix);
+        assert_eq!(1 << 30, options.min_free_space);
+
+        let options = options
+            .workdir("/tmp/workdir")
+            .labels("labels.txt")
+            .label_conflict(LabelConflictPolicy::Skip);
+        assert_eq!("/tmp/workdir", options.workdir);
+        assert_eq!(Some("labels.txt"), options.labels.as_deref());
+        assert_eq!(Some(LabelConflictPolicy::Skip), options.label_conflict);
+    }
+
+    #[test]
+    fn label_modes() {
+        let options = ConversionOptions::new("/data/storage", "/data/storage_11")
+            .naive_override("admin/legacy", true);
+        assert_eq!(LabelMode::Naive, options.label_mode("admin%2flegacy"));
+        assert_eq!(LabelMode::Typed, options.label_mode("admin%2fmydb"));
+
+        let options = options
+            .naive(true)
+            .naive_override("admin/mydb", false)
+            .label_rule("app/*", LabelMode::Detect)
+            .label_rule("*", LabelMode::Typed);
+        assert_eq!(LabelMode::Typed, options.label_mode("admin%2fmydb"));
+        assert_eq!(LabelMode::Detect, options.label_mode("app%2fgraph"));
+        assert_eq!(
+            LabelMode::Typed,
+            options.label_mode("terminusdb%3a%2f%2f%2fsystem%2fdata")
+        );
+
+        let options =
+            ConversionOptions::new("/data/storage", "/data/storage_11").detect_naive(true);
+        assert_eq!(LabelMode::Detect, options.label_mode("admin%2fmydb"));
+    }
+}
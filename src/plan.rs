@@ -0,0 +1,343 @@
+use terminus_store_10::storage as storage_10;
+use terminus_store_10::storage::directory as directory_10;
+use terminus_store_10::storage::name_to_string;
+use terminus_store_10::structure::pfc as pfc_10;
+
+use crate::consts::*;
+use crate::convert_store::conversion_order;
+use crate::reachable::*;
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Write as FmtWrite;
+use std::io;
+
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct LayerPlan {
+    pub id: String,
+    pub parent: Option<String>,
+    /// Size in bytes of the v10 layer directory.
+    pub size: u64,
+    pub value_count: u64,
+    /// Number of ancestors this layer has. Base layers have depth 0.
+    pub depth: usize,
+    /// Position of this layer in the conversion order.
+    pub order: usize,
+    /// Labels pointing directly at this layer or one of its descendants.
+    pub labels: Vec<String>,
+    /// Databases whose metadata or commit graphs depend on this layer.
+    pub databases: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct LabelPlan {
+    pub name: String,
+    pub layer: String,
+}
+
+#[derive(Serialize)]
+pub struct StorePlan {
+    pub total_size: u64,
+    pub total_value_count: u64,
+    pub layers: Vec<LayerPlan>,
+    pub labels: Vec<LabelPlan>,
+    pub order: Vec<String>,
+}
+
+pub async fn plan_store(
+    from: &str,
+    labels: Option<&str>,
+    dot: Option<&str>,
+    json: Option<&str>,
+    verbose: bool,
+) -> io::Result<()> {
+    let plan = build_plan(from, labels, verbose).await?;
+
+    if let Some(dot) = dot {
+        tokio::fs::write(dot, render_dot(&plan)).await?;
+        println!("Layer graph written to `{dot}`");
+    }
+    if let Some(json) = json {
+        let serialized = serde_json::to_vec_pretty(&plan)?;
+        tokio::fs::write(json, serialized).await?;
+        println!("Layer plan written to `{json}`");
+    }
+
+    println!(
+        "{} layers reachable from {} labels, {} bytes in total, {} values",
+        plan.layers.len(),
+        plan.labels.len(),
+        plan.total_size,
+        plan.total_value_count
+    );
+
+    Ok(())
+}
+
+pub async fn build_plan(from: &str, labels: Option<&str>, verbose: bool) -> io::Result<StorePlan> {
+    let v10_layer_store = directory_10::DirectoryLayerStore::new(from);
+    let v10_label_store = directory_10::DirectoryLabelStore::new(from);
+
+    let roots = find_label_roots(&v10_layer_store, &v10_label_store, labels, verbose).await?;
+    let reachable = find_reachable_layers_from_roots(&v10_layer_store, &roots, verbose).await?;
+
+    let parents = parent_map(&reachable);
+    let depths = layer_depths(&reachable);
+    let order = conversion_order(&reachable);
+
+    let mut reaching_labels: HashMap<[u32; 5], BTreeSet<String>> = HashMap::new();
+    let mut reaching_databases: HashMap<[u32; 5], BTreeSet<String>> = HashMap::new();
+    for root in roots.iter() {
+        for layer in ancestors(&parents, [root.head]) {
+            reaching_labels
+                .entry(layer)
+                .or_default()
+                .insert(root.label.clone());
+        }
+        if root.data_product {
+            let database = decode_label_name(&root.label);
+            for layer in ancestors(&parents, root.layers.iter().cloned()) {
+                reaching_databases
+                    .entry(layer)
+                    .or_default()
+                    .insert(database.clone());
+            }
+        }
+    }
+
+    let mut layers = Vec::with_capacity(order.len());
+    let mut total_size = 0;
+    let mut total_value_count = 0;
+    for (position, layer) in order.iter().cloned().enumerate() {
+        let size = layer_size(&v10_layer_store, layer).await?;
+        let value_count = layer_value_count(&v10_layer_store, layer).await?;
+        total_size += size;
+        total_value_count += value_count;
+
+        layers.push(LayerPlan {
+            id: name_to_string(layer),
+            parent: parents.get(&layer).map(|p| name_to_string(*p)),
+            size,
+            value_count,
+            depth: depths[&layer],
+            order: position,
+            labels: reaching_labels
+                .remove(&layer)
+                .map(|l| l.into_iter().collect())
+                .unwrap_or_default(),
+            databases: reaching_databases
+                .remove(&layer)
+                .map(|d| d.into_iter().collect())
+                .unwrap_or_default(),
+        });
+    }
+
+    let mut labels: Vec<_> = roots
+        .into_iter()
+        .map(|r| LabelPlan {
+            name: r.label,
+            layer: name_to_string(r.head),
+        })
+        .collect();
+    labels.sort_by(|l1, l2| l1.name.cmp(&l2.name));
+
+    Ok(StorePlan {
+        total_size,
+        total_value_count,
+        layers,
+        labels,
+        order: order.into_iter().map(name_to_string).collect(),
+    })
+}
+
+/// Escape a string for use within double quotes in DOT.
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+pub fn render_dot(plan: &StorePlan) -> String {
+    let mut dot = String::new();
+    writeln!(dot, "digraph layers {{").unwrap();
+    writeln!(dot, "  rankdir=LR;").unwrap();
+    for layer in plan.layers.iter() {
+        writeln!(
+            dot,
+            "  \"{}\" [label=\"{}\\n#{} depth {}\\n{} bytes, {} values\"];",
+            layer.id,
+            &layer.id[..8],
+            layer.order,
+            layer.depth,
+            layer.size,
+            layer.value_count
+        )
+        .unwrap();
+        if let Some(parent) = layer.parent.as_ref() {
+            writeln!(dot, "  \"{}\" -> \"{}\";", parent, layer.id).unwrap();
+        }
+    }
+    for label in plan.labels.iter() {
+        let name = escape_dot(&label.name);
+        writeln!(dot, "  \"label:{name}\" [shape=box, label=\"{name}\"];").unwrap();
+        writeln!(dot, "  \"label:{name}\" -> \"{}\";", label.layer).unwrap();
+    }
+    writeln!(dot, "}}").unwrap();
+
+    dot
+}
+
+pub fn parent_map(
+    reachable: &HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>,
+) -> HashMap<[u32; 5], [u32; 5]> {
+    let mut parents = HashMap::new();
+    for (parent, children) in reachable.iter() {
+        if let Some(parent) = parent {
+            for child in children {
+                parents.insert(*child, *parent);
+            }
+        }
+    }
+
+    parents
+}
+
+fn layer_depths(reachable: &HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>) -> HashMap<[u32; 5], usize> {
+    let mut depths = HashMap::new();
+    let mut visit_queue: Vec<_> = reachable
+        .get(&None)
+        .map(|roots| roots.iter().map(|r| (*r, 0)).collect())
+        .unwrap_or_default();
+    while let Some((layer, depth)) = visit_queue.pop() {
+        depths.insert(layer, depth);
+        if let Some(children) = reachable.get(&Some(layer)) {
+            visit_queue.extend(children.iter().map(|c| (*c, depth + 1)));
+        }
+    }
+
+    depths
+}
+
+/// All the given layers together with all their ancestors.
+fn ancestors<I: IntoIterator<Item = [u32; 5]>>(
+    parents: &HashMap<[u32; 5], [u32; 5]>,
+    layers: I,
+) -> HashSet<[u32; 5]> {
+    let mut result = HashSet::new();
+    for mut layer in layers {
+        while result.insert(layer) {
+            match parents.get(&layer) {
+                Some(parent) => layer = *parent,
+                None => break,
+            }
+        }
+    }
+
+    result
+}
+
+/// Size in bytes of all the files of a v10 layer.
+pub async fn layer_size<S: storage_10::PersistentLayerStore>(
+    store: &S,
+    layer: [u32; 5],
+) -> io::Result<u64> {
+    let mut size = 0;
+    for file in V10_FILENAMES.all() {
+        if storage_10::PersistentLayerStore::file_exists(store, layer, file).await? {
+            let file = storage_10::PersistentLayerStore::get_file(store, layer, file).await?;
+            size += storage_10::FileLoad::size(&file).await? as u64;
+        }
+    }
+
+    Ok(size)
+}
+
+pub async fn layer_value_count<S: storage_10::PersistentLayerStore>(
+    store: &S,
+    layer: [u32; 5],
+) -> io::Result<u64> {
+    let value_dict_pfc = storage_10::PersistentLayerStore::get_file(
+        store,
+        layer,
+        V10_FILENAMES.value_dictionary_blocks,
+    )
+    .await?;
+    pfc_10::dict_file_get_count(value_dict_pfc).await
+}
+
+/// Turn an url-encoded label name like `admin%2fmydb` into `admin/mydb`.
+pub fn decode_label_name(name: &str) -> String {
+    let mut result = Vec::with_capacity(name.len());
+    let bytes = name.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).unwrap_or_default();
+            if hex.len() == 2 && hex.iter().all(u8::is_ascii_hexdigit) {
+                // two ASCII hex digits are always valid UTF-8
+                let hex = std::str::from_utf8(hex).unwrap();
+                result.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+                continue;
+            }
+        }
+        result.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depths_and_order_follow_parent_chains() {
+        let base = [0, 0, 0, 0, 1];
+        let child = [0, 0, 0, 0, 2];
+        let grandchild = [0, 0, 0, 0, 3];
+        let other_base = [0, 0, 0, 0, 4];
+        let reachable = HashMap::from([
+            (None, vec![base, other_base]),
+            (Some(base), vec![child]),
+            (Some(child), vec![grandchild]),
+        ]);
+
+        let depths = layer_depths(&reachable);
+        assert_eq!(0, depths[&base]);
+        assert_eq!(1, depths[&child]);
+        assert_eq!(2, depths[&grandchild]);
+        assert_eq!(0, depths[&other_base]);
+
+        let order = conversion_order(&reachable);
+        assert_eq!(vec![other_base, base, child, grandchild], order);
+
+        let parents = parent_map(&reachable);
+        let found = ancestors(&parents, [grandchild]);
+        assert_eq!(HashSet::from([base, child, grandchild]), found);
+    }
+
+    #[test]
+    fn decode_label_names() {
+        assert_eq!("admin/mydb", decode_label_name("admin%2fmydb"));
+        assert_eq!("100%", decode_label_name("100%"));
+        assert_eq!("%zz%2", decode_label_name("%zz%2"));
+        assert_eq!("%é/%+1", decode_label_name("%é%2f%+1"));
+    }
+
+    #[test]
+    fn dot_names_are_escaped() {
+        let plan = StorePlan {
+            total_size: 0,
+            total_value_count: 0,
+            layers: Vec::new(),
+            labels: vec![LabelPlan {
+                name: "a\"b\\c".to_string(),
+                layer: "c79ce872a1851b569f9d81da5f7b916d4e6fde1e".to_string(),
+            }],
+            order: Vec::new(),
+        };
+        assert!(render_dot(&plan)
+            .contains("  \"label:a\\\"b\\\\c\" [shape=box, label=\"a\\\"b\\\\c\"];\n"));
+    }
+}
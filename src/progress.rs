@@ -0,0 +1,164 @@
+//! The progress bar shown by the command line tool while converting.
+use terminusdb_10_to_11::observer::*;
+use terminusdb_10_to_11::terminus_store_10::storage::name_to_string;
+use terminusdb_10_to_11::LayerConversionError;
+
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const TEMPLATE: &str =
+    "{spinner} [{elapsed_precise}] [{bar:40}] {pos}/{len} layers, ETA {eta} {wide_msg}";
+
+/// Shows how many layers were converted, how long the rest will take
+/// and what is being worked on. With `verbose`, the phases of every
+/// layer are printed above the bar.
+pub struct ProgressObserver {
+    bar: Mutex<Option<ProgressBar>>,
+    bytes: AtomicU64,
+    converted: AtomicU64,
+    failed: AtomicU64,
+    /// Values an auto conversion kept as strings.
+    kept_as_strings: AtomicU64,
+    /// Rebuilt files that differ from their v10 copy.
+    diverged: AtomicU64,
+    verbose: bool,
+}
+
+impl ProgressObserver {
+    pub fn new(verbose: bool) -> Self {
+        Self {
+            bar: Mutex::new(None),
+            bytes: AtomicU64::new(0),
+            converted: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            kept_as_strings: AtomicU64::new(0),
+            diverged: AtomicU64::new(0),
+            verbose,
+        }
+    }
+
+    fn with_bar<F: FnOnce(&ProgressBar)>(&self, f: F) {
+        if let Some(bar) = self.bar.lock().unwrap().as_ref() {
+            f(bar);
+        }
+    }
+
+    fn show_layer(&self, layer: [u32; 5], phase: Option<LayerPhase>) {
+        let name = name_to_string(layer);
+        let bytes = HumanBytes(self.bytes.load(Ordering::Relaxed));
+        let message = match phase {
+            Some(phase) => format!("({bytes} opened) {}: {phase}", &name[..8]),
+            None => format!("({bytes} opened) {}", &name[..8]),
+        };
+        self.with_bar(|bar| bar.set_message(message));
+    }
+}
+
+impl ConversionObserver for ProgressObserver {
+    fn run_started(&self, layers: usize, completed: usize) {
+        let bar = ProgressBar::new(layers as u64)
+            .with_style(
+                ProgressStyle::with_template(TEMPLATE)
+                    .unwrap()
+                    .progress_chars("=> "),
+            )
+            .with_position(completed as u64);
+        bar.reset_eta();
+        bar.enable_steady_tick(Duration::from_millis(200));
+        self.bytes.store(0, Ordering::Relaxed);
+        self.converted.store(0, Ordering::Relaxed);
+        self.failed.store(0, Ordering::Relaxed);
+        self.kept_as_strings.store(0, Ordering::Relaxed);
+        self.diverged.store(0, Ordering::Relaxed);
+        *self.bar.lock().unwrap() = Some(bar);
+    }
+
+    fn run_finished(&self) {
+        if let Some(bar) = self.bar.lock().unwrap().take() {
+            bar.finish_and_clear();
+            let failed = match self.failed.load(Ordering::Relaxed) {
+                0 => String::new(),
+                failed => format!(", {failed} failed"),
+            };
+            let kept = match self.kept_as_strings.load(Ordering::Relaxed) {
+                0 => String::new(),
+                kept => format!(", {kept} values kept as strings"),
+            };
+            let diverged = match self.diverged.load(Ordering::Relaxed) {
+                0 => String::new(),
+                diverged => format!(", {diverged} rebuilt files differ from the v10 copies"),
+            };
+            eprintln!(
+                "Converted {} layers in {}{failed}, {} of layer files opened{kept}{diverged}",
+                self.converted.load(Ordering::Relaxed),
+                HumanDuration(bar.elapsed()),
+                HumanBytes(self.bytes.load(Ordering::Relaxed))
+            );
+        }
+    }
+
+    fn layer_skipped(&self, layer: [u32; 5]) {
+        if self.verbose {
+            self.with_bar(|bar| bar.println(format!("skipping: {}", name_to_string(layer))));
+        }
+    }
+
+    fn layer_started(&self, layer: [u32; 5]) {
+        if self.verbose {
+            self.with_bar(|bar| bar.println(format!("converting layer {}", name_to_string(layer))));
+        }
+        self.show_layer(layer, None);
+    }
+
+    fn phase_started(&self, layer: [u32; 5], phase: LayerPhase) {
+        self.show_layer(layer, Some(phase));
+    }
+
+    fn phase_finished(&self, _layer: [u32; 5], phase: LayerPhase, elapsed: Duration) {
+        if self.verbose {
+            self.with_bar(|bar| {
+                bar.println(format!("{phase}: done in {:.1}s", elapsed.as_secs_f64()))
+            });
+        }
+    }
+
+    fn source_file_opened(&self, _layer: [u32; 5], size: u64) {
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn values_auto_converted(&self, _layer: [u32; 5], typed: u64, strings: u64) {
+        self.kept_as_strings.fetch_add(strings, Ordering::Relaxed);
+        if self.verbose {
+            self.with_bar(|bar| {
+                bar.println(format!("{typed} values typed, {strings} kept as strings"))
+            });
+        }
+    }
+
+    fn index_diverged(&self, layer: [u32; 5], file: &str) {
+        self.diverged.fetch_add(1, Ordering::Relaxed);
+        self.with_bar(|bar| {
+            bar.println(format!(
+                "WARNING: the rebuilt `{file}` of layer {} differs from the v10 copy",
+                name_to_string(layer)
+            ))
+        });
+    }
+
+    fn layer_completed(&self, _layer: [u32; 5], _elapsed: Duration) {
+        self.converted.fetch_add(1, Ordering::Relaxed);
+        self.with_bar(|bar| bar.inc(1));
+    }
+
+    fn layer_failed(&self, _layer: [u32; 5], error: &LayerConversionError) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        self.with_bar(|bar| {
+            bar.println(format!("ERROR: {error}"));
+            // failed layers are done with as far as this run goes
+            bar.inc(1)
+        });
+    }
+}
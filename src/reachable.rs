@@ -1,6 +1,5 @@
 use terminus_store_10::layer as layer_10;
 use terminus_store_10::storage as storage_10;
-use terminus_store_10::storage::directory as directory_10;
 
 use itertools::*;
 use tokio::io::AsyncBufReadExt;
@@ -10,14 +9,54 @@ use std::collections::{HashMap, HashSet};
 use std::io;
 use std::io::Write;
 
-pub async fn find_reachable_layers(
-    layer_store: &directory_10::DirectoryLayerStore,
-    label_store: &directory_10::DirectoryLabelStore,
+/// The layers a single label makes reachable, before walking up the
+/// parent chains.
+pub struct LabelRoots {
+    pub label: String,
+    /// Whether this label is a data product, as opposed to one of the
+    /// special system labels.
+    pub data_product: bool,
+    /// The layer the label points at.
+    pub head: [u32; 5],
+    pub layers: Vec<[u32; 5]>,
+}
+
+pub async fn find_reachable_layers<
+    S: storage_10::PersistentLayerStore,
+    L: storage_10::LabelStore,
+>(
+    layer_store: &S,
+    label_store: &L,
     labels: Option<&str>,
     verbose: bool,
 ) -> io::Result<HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>> {
-    let mut data_product_layers: Vec<[u32; 5]>;
-    let special_layers: Vec<[u32; 5]>;
+    let roots = find_label_roots(layer_store, label_store, labels, verbose).await?;
+    find_reachable_layers_from_roots(layer_store, &roots, verbose).await
+}
+
+pub async fn find_reachable_layers_from_roots<S: storage_10::PersistentLayerStore>(
+    layer_store: &S,
+    roots: &[LabelRoots],
+    verbose: bool,
+) -> io::Result<HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>> {
+    let mut layers: Vec<[u32; 5]> = roots
+        .iter()
+        .flat_map(|r| r.layers.iter().cloned())
+        .collect();
+    layers.sort();
+    layers.dedup();
+
+    collect_parents(layer_store, layers, verbose).await
+}
+
+pub async fn find_label_roots<S: storage_10::PersistentLayerStore, L: storage_10::LabelStore>(
+    layer_store: &S,
+    label_store: &L,
+    labels: Option<&str>,
+    verbose: bool,
+) -> io::Result<Vec<LabelRoots>> {
+    let mut data_product_labels: Vec<(String, [u32; 5])>;
+    let special_labels: Vec<(String, [u32; 5])>;
     if let Some(labels) = labels {
         // we got a labels file, so read that out and use that instead of a full file listing
         let mut options = tokio::fs::OpenOptions::new();
@@ -27,7 +66,7 @@ pub async fn find_reachable_layers(
         let file = options.open(labels).await?;
         let buffered = BufReader::new(file);
         let mut lines = buffered.lines();
-        data_product_layers = Vec::new();
+        data_product_labels = Vec::new();
         while let Some(label_name) = lines.next_line().await? {
             let label = storage_10::LabelStore::get_label(label_store, &label_name).await?;
             if label.is_none() {
@@ -35,13 +74,13 @@ pub async fn find_reachable_layers(
             }
             let label = label.unwrap();
             if let Some(layer) = label.layer {
-                data_product_layers.push(layer);
+                data_product_labels.push((label_name, layer));
             }
         }
 
-        special_layers = Vec::with_capacity(0);
+        special_labels = Vec::with_capacity(0);
     } else {
-        let special_labels: HashSet<&'static str> = HashSet::from([
+        let special_label_names: HashSet<&'static str> = HashSet::from([
             "http%3a%2f%2fterminusdb.com%2fschema%2fref",
             "http%3a%2f%2fterminusdb.com%2fschema%2frepository",
             "http%3a%2f%2fterminusdb.com%2fschema%2fwoql",
@@ -53,25 +92,33 @@ pub async fn find_reachable_layers(
             println!("starting label retrieval");
         }
         let labels = storage_10::LabelStore::labels(label_store).await?;
-        special_layers = labels
+        special_labels = labels
             .iter()
-            .filter(|l| special_labels.contains(l.name.as_str()))
-            .map(|l| *l.layer.as_ref().unwrap())
+            .filter(|l| special_label_names.contains(l.name.as_str()))
+            .map(|l| (l.name.clone(), *l.layer.as_ref().unwrap()))
             .collect();
-        data_product_layers = labels
+        data_product_labels = labels
             .into_iter()
-            .filter(|l| !special_labels.contains(l.name.as_str()))
-            .map(|l| l.layer.unwrap())
+            .filter(|l| !special_label_names.contains(l.name.as_str()))
+            .map(|l| (l.name, l.layer.unwrap()))
             .collect();
         if verbose {
             println!("labels retrieved");
         }
     }
 
-    data_product_layers.sort();
-    data_product_layers.dedup();
-    let mut layers = data_product_layers.clone();
-    layers.extend(special_layers.clone());
+    data_product_labels.sort();
+    data_product_labels.dedup();
+
+    let mut result = Vec::with_capacity(data_product_labels.len() + special_labels.len());
+    for (label, layer) in special_labels {
+        result.push(LabelRoots {
+            label,
+            data_product: false,
+            head: layer,
+            layers: vec![layer],
+        });
+    }
 
     // now we need to go into the system graph (?)
     // discover all data products in use, treat their labels as metadata graphs.
@@ -79,23 +126,43 @@ pub async fn find_reachable_layers(
     //
     // The metadata graphs will tell us where all the commit graphs are.
     // we need to traverse those commit graphs to find the actual data and schema layers.
-    let mut commit_layers = HashSet::new();
-    for data_product in data_product_layers.iter().cloned() {
-        let commit_layers_for_data_product =
-            discover_layers_in_meta_graph(layer_store, data_product).await?;
-        commit_layers.extend(commit_layers_for_data_product.clone());
-        layers.extend(commit_layers_for_data_product);
+    for (label, data_product) in data_product_labels {
+        result.push(find_data_product_roots(layer_store, label, data_product).await?);
     }
 
-    for commit in commit_layers {
+    Ok(result)
+}
+
+/// Find the layers of a data product by going through its metadata
+/// graph into its commit graphs.
+pub async fn find_data_product_roots<S: storage_10::PersistentLayerStore>(
+    layer_store: &S,
+    label: String,
+    head: [u32; 5],
+) -> io::Result<LabelRoots> {
+    let mut layers = vec![head];
+    let commit_layers = discover_layers_in_meta_graph(layer_store, head).await?;
+    for commit in commit_layers.iter().cloned() {
         layers.extend(discover_layers_in_meta_graph(layer_store, commit).await?);
     }
-
+    layers.extend(commit_layers);
     layers.sort();
     layers.dedup();
 
-    let mut discovered: HashSet<_> =
-        HashSet::with_capacity(data_product_layers.len() + special_layers.len());
+    Ok(LabelRoots {
+        label,
+        data_product: true,
+        head,
+        layers,
+    })
+}
+
+async fn collect_parents<S: storage_10::PersistentLayerStore>(
+    layer_store: &S,
+    mut layers: Vec<[u32; 5]>,
+    verbose: bool,
+) -> io::Result<HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>> {
+    let mut discovered: HashSet<_> = HashSet::with_capacity(layers.len());
     discovered.extend(layers.clone());
 
     let mut final_list = Vec::with_capacity(layers.len());
@@ -131,9 +198,7 @@ pub async fn find_reachable_layers(
         println!("reachable layers retrieved");
     }
     final_list.sort();
-    let group_iter = final_list
-        .into_iter()
-        .group_by(|(parent, _)| parent.clone());
+    let group_iter = final_list.into_iter().group_by(|(parent, _)| *parent);
     let final_map: HashMap<Option<[u32; 5]>, Vec<[u32; 5]>> = group_iter
         .into_iter()
         .map(|(k, g)| {
@@ -151,8 +216,8 @@ pub async fn find_reachable_layers(
     Ok(final_map)
 }
 
-async fn discover_layers_in_meta_graph(
-    store: &directory_10::DirectoryLayerStore,
+async fn discover_layers_in_meta_graph<S: storage_10::PersistentLayerStore>(
+    store: &S,
     id: [u32; 5],
 ) -> io::Result<Vec<[u32; 5]>> {
     let meta_layer = storage_10::LayerStore::get_layer(store, id)
@@ -178,7 +243,151 @@ async fn discover_layers_in_meta_graph(
     Ok(result)
 }
 
-const STRING_SUFFIX: &'static str = "\"^^'http://www.w3.org/2001/XMLSchema#string'";
+const LAYER_IDENTIFIER: &str = "http://terminusdb.com/schema/layer#identifier";
+const REPOSITORY_NAME: &str = "http://terminusdb.com/schema/repository#name";
+const REPOSITORY_HEAD: &str = "http://terminusdb.com/schema/repository#head";
+const REF_NAME: &str = "http://terminusdb.com/schema/ref#name";
+const REF_HEAD: &str = "http://terminusdb.com/schema/ref#head";
+const REF_IDENTIFIER: &str = "http://terminusdb.com/schema/ref#identifier";
+const REF_INSTANCE: &str = "http://terminusdb.com/schema/ref#instance";
+const REF_SCHEMA: &str = "http://terminusdb.com/schema/ref#schema";
+
+/// A repository in the metadata graph of a data product.
+pub struct Repository {
+    pub name: String,
+    pub commit_graph: [u32; 5],
+}
+
+pub struct Commit {
+    pub id: String,
+    pub instance: Option<[u32; 5]>,
+    pub schema: Option<[u32; 5]>,
+}
+
+pub struct Branch {
+    pub name: String,
+    /// The id of the head commit, if the branch has any commits.
+    pub head: Option<String>,
+}
+
+pub struct CommitGraph {
+    pub commits: Vec<Commit>,
+    pub branches: Vec<Branch>,
+}
+
+async fn get_layer<S: storage_10::PersistentLayerStore>(
+    store: &S,
+    id: [u32; 5],
+) -> io::Result<std::sync::Arc<layer_10::InternalLayer>> {
+    storage_10::LayerStore::get_layer(store, id)
+        .await?
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("layer {} not found", storage_10::name_to_string(id)),
+            )
+        })
+}
+
+/// The string value of a property of a node.
+fn string_property(
+    layer: &dyn layer_10::Layer,
+    subject: u64,
+    predicate: Option<u64>,
+) -> Option<String> {
+    let triple = layer_10::Layer::single_triple_sp(layer, subject, predicate?)?;
+    let value = layer_10::Layer::id_object_value(layer, triple.object)?;
+    let slice = value.strip_prefix('"')?.strip_suffix(STRING_SUFFIX)?;
+    Some(slice.to_string())
+}
+
+/// The layer id a node points at through a layer object.
+fn layer_property(
+    layer: &dyn layer_10::Layer,
+    subject: u64,
+    predicate: Option<u64>,
+) -> Option<[u32; 5]> {
+    let triple = layer_10::Layer::single_triple_sp(layer, subject, predicate?)?;
+    let identifier = layer_10::Layer::predicate_id(layer, LAYER_IDENTIFIER);
+    let id = string_property(layer, triple.object, identifier)?;
+    storage_10::string_to_name(&id).ok()
+}
+
+/// The repositories in the metadata graph of a data product, with the
+/// layers of their commit graphs.
+pub async fn find_repositories<S: storage_10::PersistentLayerStore>(
+    store: &S,
+    meta: [u32; 5],
+) -> io::Result<Vec<Repository>> {
+    let meta_layer = get_layer(store, meta).await?;
+    let meta_layer: &dyn layer_10::Layer = &*meta_layer;
+    let head = layer_10::Layer::predicate_id(meta_layer, REPOSITORY_HEAD);
+    let name = layer_10::Layer::predicate_id(meta_layer, REPOSITORY_NAME);
+    let mut repositories = Vec::new();
+    if let Some(head) = head {
+        for triple in layer_10::Layer::triples_p(meta_layer, head) {
+            if let Some(commit_graph) = layer_property(meta_layer, triple.subject, Some(head)) {
+                repositories.push(Repository {
+                    name: string_property(meta_layer, triple.subject, name)
+                        .unwrap_or_else(|| "?".to_string()),
+                    commit_graph,
+                });
+            }
+        }
+    }
+    repositories.sort_by(|r1, r2| r1.name.cmp(&r2.name));
+
+    Ok(repositories)
+}
+
+/// The commits and branches in a commit graph.
+pub async fn read_commit_graph<S: storage_10::PersistentLayerStore>(
+    store: &S,
+    id: [u32; 5],
+) -> io::Result<CommitGraph> {
+    let commit_layer = get_layer(store, id).await?;
+    let commit_layer: &dyn layer_10::Layer = &*commit_layer;
+    let predicate = |name| layer_10::Layer::predicate_id(commit_layer, name);
+    let (identifier, instance, schema) = (
+        predicate(REF_IDENTIFIER),
+        predicate(REF_INSTANCE),
+        predicate(REF_SCHEMA),
+    );
+
+    let mut commits = Vec::new();
+    if let Some(identifier) = identifier {
+        for triple in layer_10::Layer::triples_p(commit_layer, identifier) {
+            if let Some(id) = string_property(commit_layer, triple.subject, Some(identifier)) {
+                commits.push(Commit {
+                    id,
+                    instance: layer_property(commit_layer, triple.subject, instance),
+                    schema: layer_property(commit_layer, triple.subject, schema),
+                });
+            }
+        }
+    }
+    commits.sort_by(|c1, c2| c1.id.cmp(&c2.id));
+
+    let mut branches = Vec::new();
+    let (name, head) = (predicate(REF_NAME), predicate(REF_HEAD));
+    if let Some(name) = name {
+        for triple in layer_10::Layer::triples_p(commit_layer, name) {
+            let head_commit = head.and_then(|head| {
+                layer_10::Layer::single_triple_sp(commit_layer, triple.subject, head)
+            });
+            branches.push(Branch {
+                name: string_property(commit_layer, triple.subject, Some(name))
+                    .unwrap_or_else(|| "?".to_string()),
+                head: head_commit.and_then(|t| string_property(commit_layer, t.object, identifier)),
+            });
+        }
+    }
+    branches.sort_by(|b1, b2| b1.name.cmp(&b2.name));
+
+    Ok(CommitGraph { commits, branches })
+}
+
+const STRING_SUFFIX: &str = "\"^^'http://www.w3.org/2001/XMLSchema#string'";
 fn layer_id_value_to_id(val: &str) -> [u32; 5] {
     assert_eq!("\"", &val[0..1]);
     let slice = &val[1..val.len() - STRING_SUFFIX.len()];
@@ -186,3 +395,94 @@ fn layer_id_value_to_id(val: &str) -> [u32; 5] {
 
     storage_10::string_to_name(slice).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_store::TestStore;
+    use terminus_store_10::layer::{Layer as _, StringTriple};
+    use terminus_store_10::storage::directory as directory_10;
+    use terminus_store_10::storage::name_to_string;
+
+    fn string(s: &str) -> String {
+        format!("\"{s}\"^^'http://www.w3.org/2001/XMLSchema#string'")
+    }
+
+    /// `subject` pointing at a layer through a layer object.
+    fn layer_triples(subject: &str, predicate: &str, layer: [u32; 5]) -> [StringTriple; 2] {
+        let id = name_to_string(layer);
+        let object = format!("layer_{id}");
+        [
+            StringTriple::new_node(subject, predicate, &object),
+            StringTriple::new_value(&object, LAYER_IDENTIFIER, &string(&id)),
+        ]
+    }
+
+    async fn layer(store: &TestStore, triples: Vec<StringTriple>) -> [u32; 5] {
+        let builder = store.v10().create_base_layer().await.unwrap();
+        for triple in triples {
+            builder.add_string_triple(triple).unwrap();
+        }
+        builder.commit().await.unwrap().name()
+    }
+
+    #[tokio::test]
+    async fn repositories_commits_and_branches() {
+        let store = TestStore::new("commit_graphs");
+        let instance = store.value_layer(None, &["foo"]).await;
+        let schema = store.value_layer(None, &["bar"]).await;
+
+        let mut triples = vec![
+            StringTriple::new_value("c1", REF_IDENTIFIER, &string("abc")),
+            StringTriple::new_value("c2", REF_IDENTIFIER, &string("def")),
+            StringTriple::new_value("main", REF_NAME, &string("main")),
+            StringTriple::new_node("main", REF_HEAD, "c1"),
+            StringTriple::new_value("dev", REF_NAME, &string("dev")),
+        ];
+        triples.extend(layer_triples("c1", REF_INSTANCE, instance));
+        triples.extend(layer_triples("c1", REF_SCHEMA, schema));
+        triples.extend(layer_triples("c2", REF_INSTANCE, instance));
+        let commit_graph = layer(&store, triples).await;
+
+        let mut triples = vec![StringTriple::new_value(
+            "local",
+            REPOSITORY_NAME,
+            &string("local"),
+        )];
+        triples.extend(layer_triples("local", REPOSITORY_HEAD, commit_graph));
+        let meta = layer(&store, triples).await;
+        let layer_store = directory_10::DirectoryLayerStore::new(store.path("v10"));
+
+        let repositories = find_repositories(&layer_store, meta).await.unwrap();
+        assert_eq!(1, repositories.len());
+        assert_eq!("local", repositories[0].name);
+        assert_eq!(commit_graph, repositories[0].commit_graph);
+
+        let graph = read_commit_graph(&layer_store, commit_graph).await.unwrap();
+        let commits: Vec<_> = graph
+            .commits
+            .iter()
+            .map(|c| (c.id.as_str(), c.instance, c.schema))
+            .collect();
+        assert_eq!(
+            vec![
+                ("abc", Some(instance), Some(schema)),
+                ("def", Some(instance), None)
+            ],
+            commits
+        );
+        let branches: Vec<_> = graph
+            .branches
+            .iter()
+            .map(|b| (b.name.as_str(), b.head.as_deref()))
+            .collect();
+        assert_eq!(vec![("dev", None), ("main", Some("abc"))], branches);
+
+        let roots = find_data_product_roots(&layer_store, "admin%2fdb".to_string(), meta)
+            .await
+            .unwrap();
+        let mut layers = vec![meta, commit_graph, instance, schema];
+        layers.sort();
+        assert_eq!(layers, roots.layers);
+    }
+}
@@ -0,0 +1,280 @@
+//! Replacing the original storage directory with the converted one.
+//!
+//! This is done in two moves: the original store is moved to a backup
+//! location, and then the converted store is moved into its place. A
+//! move is a rename where possible, and a copy followed by a removal
+//! of the original where the two paths are on different filesystems.
+//! If the second move fails, the original store is moved back, unless
+//! the converted store was copied into place and only removing it from
+//! its old location failed.
+use crate::durable::{sync_directory_blocking, sync_parent_directory_blocking};
+
+use chrono::Local;
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+const COPY_BUFFER_SIZE: usize = 1 << 16;
+
+#[derive(Error, Debug)]
+pub enum ReplaceError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("copy of `{0}` does not match the original")]
+    VerificationFailed(String),
+    #[error("`{src}` was copied to `{dst}`, but could not be removed afterwards: {source}")]
+    SourceNotRemoved {
+        src: String,
+        dst: String,
+        source: io::Error,
+    },
+    #[error("replacing the store failed, the original store was put back in place: {0}")]
+    RolledBack(Box<ReplaceError>),
+    #[error("replacing the store failed ({error}), and so did putting back the original store from `{backup}` ({rollback})")]
+    RollbackFailed {
+        error: Box<ReplaceError>,
+        rollback: Box<ReplaceError>,
+        backup: String,
+    },
+}
+
+/// A store that was put in place by [`replace_storage_directory`].
+#[derive(Debug)]
+pub struct Replaced {
+    /// Where the original store was moved to.
+    pub backup: String,
+    /// The converted store was copied into place, but could not be
+    /// removed from its old location afterwards. It should be removed
+    /// by hand.
+    pub leftover: Option<ReplaceError>,
+}
+
+/// Move the original store in `from` to a backup location and move the
+/// converted store in `to` into its place.
+pub async fn replace_storage_directory(
+    from: &str,
+    to: &str,
+    backup_dir: Option<&str>,
+) -> Result<Replaced, ReplaceError> {
+    let from = PathBuf::from(from);
+    let to = PathBuf::from(to);
+    let date = Local::now().format("%+").to_string();
+    let backup = backup_path(&from, backup_dir.map(Path::new), &date);
+    tokio::task::spawn_blocking(move || replace_with(&from, &to, &backup, move_directory))
+        .await
+        .unwrap()
+}
+
+fn replace_with(
+    from: &Path,
+    to: &Path,
+    backup: &Path,
+    move_directory: impl Fn(&Path, &Path) -> Result<(), ReplaceError>,
+) -> Result<Replaced, ReplaceError> {
+    if let Some(parent) = backup.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    move_directory(from, backup)?;
+    let leftover = match move_directory(to, from) {
+        Ok(()) => None,
+        // The converted store is in place, only its old copy is left.
+        Err(error @ ReplaceError::SourceNotRemoved { .. }) => Some(error),
+        Err(error) => {
+            return match move_directory(backup, from) {
+                Ok(()) => Err(ReplaceError::RolledBack(Box::new(error))),
+                Err(rollback) => Err(ReplaceError::RollbackFailed {
+                    error: Box::new(error),
+                    rollback: Box::new(rollback),
+                    backup: backup.to_string_lossy().into_owned(),
+                }),
+            };
+        }
+    };
+
+    Ok(Replaced {
+        backup: backup.to_string_lossy().into_owned(),
+        leftover,
+    })
+}
+
+/// Where to move the original store to. Without a backup dir, this is
+/// next to the original store.
+fn backup_path(from: &Path, backup_dir: Option<&Path>, date: &str) -> PathBuf {
+    let name = from
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "storage".to_string());
+    let mut path = match backup_dir {
+        Some(dir) => dir.to_path_buf(),
+        None => from.parent().map(Path::to_path_buf).unwrap_or_default(),
+    };
+    path.push(format!("{name}.{date}.backup"));
+
+    path
+}
+
+fn is_cross_device(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EXDEV)
+}
+
+/// Move a directory. If `src` and `dst` are on different filesystems,
+/// `src` is copied, the copy is synced to disk and compared with the
+/// original, and only then is the original removed. If removing it
+/// fails, `dst` is a complete copy, `src` may be partly removed, and
+/// [`ReplaceError::SourceNotRemoved`] is returned. On any other
+/// failure, `src` is left as it was.
+fn move_directory(src: &Path, dst: &Path) -> Result<(), ReplaceError> {
+    match fs::rename(src, dst) {
+        Ok(()) => {
+            sync_parent_directory_blocking(dst)?;
+            return Ok(());
+        }
+        Err(e) if is_cross_device(&e) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let copied = copy_directory(src, dst)
+        .map_err(ReplaceError::from)
+        .and_then(|_| verify_directory(src, dst));
+    if let Err(e) = copied {
+        // best effort, the original is still there
+        let _ = fs::remove_dir_all(dst);
+        return Err(e);
+    }
+    sync_parent_directory_blocking(dst)?;
+
+    fs::remove_dir_all(src).map_err(|source| ReplaceError::SourceNotRemoved {
+        src: src.to_string_lossy().into_owned(),
+        dst: dst.to_string_lossy().into_owned(),
+        source,
+    })
+}
+
+fn copy_directory(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::create_dir(dst)?;
+    for direntry in fs::read_dir(src)? {
+        let direntry = direntry?;
+        let mut dst_path = dst.to_path_buf();
+        dst_path.push(direntry.file_name());
+        let file_type = direntry.file_type()?;
+        if file_type.is_dir() {
+            copy_directory(&direntry.path(), &dst_path)?;
+        } else if file_type.is_file() {
+            fs::copy(direntry.path(), &dst_path)?;
+            File::open(&dst_path)?.sync_all()?;
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("cannot copy `{}`", direntry.path().to_string_lossy()),
+            ));
+        }
+    }
+
+    sync_directory_blocking(dst)
+}
+
+/// Check that every file in `src` has an identical copy in `dst`.
+fn verify_directory(src: &Path, dst: &Path) -> Result<(), ReplaceError> {
+    for direntry in fs::read_dir(src)? {
+        let direntry = direntry?;
+        let mut dst_path = dst.to_path_buf();
+        dst_path.push(direntry.file_name());
+        if direntry.file_type()?.is_dir() {
+            verify_directory(&direntry.path(), &dst_path)?;
+        } else if !files_equal(&direntry.path(), &dst_path)? {
+            return Err(ReplaceError::VerificationFailed(
+                direntry.path().to_string_lossy().into_owned(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn files_equal(path1: &Path, path2: &Path) -> io::Result<bool> {
+    let mut file1 = File::open(path1)?;
+    let mut file2 = File::open(path2)?;
+    if file1.metadata()?.len() != file2.metadata()?.len() {
+        return Ok(false);
+    }
+
+    let mut buf1 = vec![0; COPY_BUFFER_SIZE];
+    let mut buf2 = vec![0; COPY_BUFFER_SIZE];
+    loop {
+        let count = file1.read(&mut buf1)?;
+        if count == 0 {
+            return Ok(true);
+        }
+        file2.read_exact(&mut buf2[..count])?;
+        if buf1[..count] != buf2[..count] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backup_paths() {
+        let date = "2023-01-01T00:00:00+00:00";
+        assert_eq!(
+            PathBuf::from("/data/storage.2023-01-01T00:00:00+00:00.backup"),
+            backup_path(Path::new("/data/storage/"), None, date)
+        );
+        assert_eq!(
+            PathBuf::from("/backups/storage.2023-01-01T00:00:00+00:00.backup"),
+            backup_path(
+                Path::new("/data/storage"),
+                Some(Path::new("/backups")),
+                date
+            )
+        );
+    }
+
+    #[test]
+    fn a_converted_store_that_is_not_removed_is_still_in_place() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("replace_leftover_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (from, to, backup) = (dir.join("storage"), dir.join("new"), dir.join("backup"));
+        fs::create_dir_all(&from).unwrap();
+        fs::write(from.join("STORAGE_VERSION"), "1").unwrap();
+        fs::create_dir_all(&to).unwrap();
+        fs::write(to.join("STORAGE_VERSION"), "2").unwrap();
+
+        // Moves the converted store like a copy across filesystems whose
+        // source can't be removed afterwards.
+        let replaced = replace_with(&from, &to, &backup, |src, dst| {
+            if src != to.as_path() {
+                return Ok(fs::rename(src, dst)?);
+            }
+            copy_directory(src, dst)?;
+            Err(ReplaceError::SourceNotRemoved {
+                src: src.to_string_lossy().into_owned(),
+                dst: dst.to_string_lossy().into_owned(),
+                source: io::Error::from(io::ErrorKind::PermissionDenied),
+            })
+        })
+        .unwrap();
+
+        assert!(matches!(
+            replaced.leftover,
+            Some(ReplaceError::SourceNotRemoved { .. })
+        ));
+        assert_eq!(backup.to_string_lossy(), replaced.backup);
+        assert_eq!(
+            "2",
+            fs::read_to_string(from.join("STORAGE_VERSION")).unwrap()
+        );
+        assert_eq!(
+            "1",
+            fs::read_to_string(backup.join("STORAGE_VERSION")).unwrap()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+//! Retrying the layers that failed in an earlier conversion.
+//!
+//! Rather than going through the whole store again, a retry only goes
+//! through the failed layers and the layers below them. Layers below a
+//! failed layer could not be converted without it, so they were never
+//! attempted.
+use terminus_store_10::storage as storage_10;
+use terminus_store_10::storage::directory as directory_10;
+
+use crate::convert_labels::*;
+use crate::convert_store::*;
+use crate::disk_space::*;
+use crate::failure_impact::*;
+use crate::layer_modes::find_layer_modes;
+use crate::observer::ConversionObserver;
+use crate::options::ConversionOptions;
+use crate::reachable::*;
+use crate::run_manifest::*;
+use crate::status_log::*;
+use crate::storage_version::*;
+use crate::store_manifest::*;
+use crate::tar_store::*;
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+
+/// The part of the layer graph a retry goes through: the failed layers
+/// that are not below another failed layer as roots, and everything
+/// below them.
+pub fn retry_graph(
+    reachable: &HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>,
+    statuses: &HashMap<[u32; 5], ConversionStatus>,
+) -> HashMap<Option<[u32; 5]>, Vec<[u32; 5]>> {
+    let failed: HashSet<[u32; 5]> = statuses
+        .iter()
+        .filter(|(_, status)| **status == ConversionStatus::Error)
+        .map(|(layer, _)| *layer)
+        .collect();
+
+    let mut graph = HashMap::new();
+    let mut roots = Vec::new();
+    let mut visit_queue: Vec<([u32; 5], bool)> = Vec::new();
+    if let Some(top) = reachable.get(&None) {
+        visit_queue.extend(top.iter().map(|l| (*l, false)));
+    }
+    while let Some((layer, below_failed)) = visit_queue.pop() {
+        let is_failed = failed.contains(&layer);
+        if is_failed && !below_failed {
+            roots.push(layer);
+        }
+        if let Some(children) = reachable.get(&Some(layer)) {
+            if below_failed || is_failed {
+                graph.insert(Some(layer), children.clone());
+            }
+            visit_queue.extend(children.iter().map(|l| (*l, below_failed || is_failed)));
+        }
+    }
+    roots.sort();
+    graph.insert(None, roots);
+
+    graph
+}
+
+/// Retry the layers that failed in an earlier conversion into
+/// `options.to`, using the status log in its workdir.
+pub async fn retry_failed(
+    options: &ConversionOptions,
+    observer: &dyn ConversionObserver,
+) -> Result<(), StoreConversionError> {
+    let from = options.from.as_str();
+    if is_tar_archive(from) {
+        let (v10_layer_store, v10_label_store) = open_tar_store(from).await?;
+        check_v10_archive(from, &v10_layer_store)?;
+        retry_failed_from(&v10_layer_store, &v10_label_store, options, observer).await
+    } else {
+        check_v10_directory(from).await?;
+        let v10_layer_store = directory_10::DirectoryLayerStore::new(from);
+        let v10_label_store = directory_10::DirectoryLabelStore::new(from);
+        retry_failed_from(&v10_layer_store, &v10_label_store, options, observer).await
+    }
+}
+
+async fn retry_failed_from<S: storage_10::PersistentLayerStore, L: storage_10::LabelStore>(
+    v10_layer_store: &S,
+    v10_label_store: &L,
+    options: &ConversionOptions,
+    observer: &dyn ConversionObserver,
+) -> Result<(), StoreConversionError> {
+    let (from, to, work) = (&options.from[..], &options.to[..], &options.workdir[..]);
+    let labels = options.labels.as_deref();
+    let mut status_path = PathBuf::from(work);
+    status_path.push(STATUS_LOG);
+    if let Err(e) = tokio::fs::metadata(&status_path).await {
+        if e.kind() == io::ErrorKind::NotFound {
+            return Err(StoreConversionError::NothingToRetry(
+                status_path.to_string_lossy().into_owned(),
+            ));
+        }
+        return Err(e.into());
+    }
+    let _locks = lock_conversion(from, to, work).await?;
+    // a retry may use a different mode on purpose, as long as no
+    // converted layer is below a layer converted in another mode
+    let settings = RunManifest::new(options).await?;
+    let recorded = read_run_manifest(work).await?;
+    check_run_manifest(work, &settings, false, options.force).await?;
+
+    let mut status_log = StatusLog::open(work).await?;
+    let roots = find_label_roots(v10_layer_store, v10_label_store, labels, options.verbose).await?;
+    let reachable =
+        find_reachable_layers_from_roots(v10_layer_store, &roots, options.verbose).await?;
+    let order = conversion_order(&reachable);
+    let statuses: HashMap<_, _> = order
+        .iter()
+        .filter_map(|l| status_log.status(*l).map(|s| (*l, s)))
+        .collect();
+    let graph = retry_graph(&reachable, &statuses);
+    let retried = graph[&None].len();
+    if retried == 0 {
+        println!("No failed layers to retry");
+        return Ok(());
+    }
+    println!(
+        "Retrying {retried} failed layers and {} layers below them",
+        conversion_order(&graph).len() - retried
+    );
+
+    let modes = find_layer_modes(v10_layer_store, &roots, options).await?;
+    check_mode_changes(
+        v10_layer_store,
+        &roots,
+        &reachable,
+        &conversion_order(&graph),
+        &modes,
+        options,
+        recorded.as_ref(),
+        &status_log,
+    )
+    .await?;
+
+    // The run covers the whole store, so that `status` can tell how far
+    // along it is.
+    status_log.run_started(order.len()).await?;
+    let space_guard = SpaceGuard::new(to, work, options.min_free_space);
+    let result = convert_reachable_layers(
+        v10_layer_store,
+        options,
+        &mut status_log,
+        &graph,
+        &modes,
+        (!options.skip_space_check).then_some(&space_guard),
+        observer,
+    )
+    .await;
+    let failed = failed_layers(&result);
+    if !failed.is_empty() {
+        report_failure_impact(v10_layer_store, &roots, &reachable, work, &failed).await?;
+    }
+    let (converted, failures) = result?;
+    println!("Converted {} layers", converted.len());
+
+    if !failures.is_empty() {
+        return Err(StoreConversionError::LayerConversionsFailed(failures));
+    }
+    let statuses = get_status_hashmap(work).await?;
+    let unconverted = order
+        .iter()
+        .filter(|l| statuses.get(*l) != Some(&ConversionStatus::Completed))
+        .count();
+    if unconverted != 0 {
+        // an earlier run that stopped at its first failure never got to
+        // these
+        println!(
+            "{unconverted} layers were never attempted. Run convert-store again to convert them"
+        );
+    } else {
+        // the labels were not written yet if the earlier run stopped at
+        // its first failure
+        let label_report =
+            convert_labels(v10_label_store, to, labels, LabelConflictPolicy::Fail, "").await?;
+        if !label_report.conflicts.is_empty() {
+            write_conflict_report(work, &label_report.conflicts).await?;
+            return Err(StoreConversionError::LabelConflicts(label_report.conflicts));
+        }
+        write_version_file(to, StorageVersion::V11).await?;
+        let manifest = write_store_manifest(v10_layer_store, from, to, &order, &converted).await?;
+        println!(
+            "All layers are converted. {STORE_MANIFEST} with {} layers written to `{to}`",
+            manifest.layers.len()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_graph_starts_at_failed_layers() {
+        // 1 -> 2 -> 3 -> 4, and 1 -> 5
+        let reachable = HashMap::from([
+            (None, vec![[1; 5]]),
+            (Some([1; 5]), vec![[2; 5], [5; 5]]),
+            (Some([2; 5]), vec![[3; 5]]),
+            (Some([3; 5]), vec![[4; 5]]),
+        ]);
+        let statuses = HashMap::from([
+            ([1; 5], ConversionStatus::Completed),
+            ([2; 5], ConversionStatus::Error),
+            ([3; 5], ConversionStatus::Error),
+            ([5; 5], ConversionStatus::Error),
+        ]);
+        let graph = retry_graph(&reachable, &statuses);
+        assert_eq!(vec![[2; 5], [5; 5]], graph[&None]);
+        assert_eq!(vec![[3; 5]], graph[&Some([2; 5])]);
+        assert_eq!(vec![[4; 5]], graph[&Some([3; 5])]);
+        assert!(!graph.contains_key(&Some([1; 5])));
+
+        let mut order = conversion_order(&graph);
+        order.sort();
+        assert_eq!(vec![[2; 5], [3; 5], [4; 5], [5; 5]], order);
+    }
+}
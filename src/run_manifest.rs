@@ -0,0 +1,314 @@
+//! A record of the settings a workdir was created with.
+//!
+//! Resuming a conversion reuses the status log and parent maps in the
+//! workdir. That only works if the resumed run converts the same source
+//! into the same destination in the same way, so the settings of the
+//! first run are written to `run.json` in the workdir, and later runs
+//! are checked against them.
+use crate::dataconversion::DatatypeMap;
+use crate::durable::write_file_durably;
+use crate::layer_modes::{LabelRule, ValueMode};
+use crate::options::ConversionOptions;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub const RUN_MANIFEST: &str = "run.json";
+
+#[derive(Error, Debug)]
+pub enum RunManifestError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("could not read {RUN_MANIFEST}: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("the workdir `{work}` was used by a run with different settings ({}). Use --force to resume anyway", .conflicts.join("; "))]
+    Conflict {
+        work: String,
+        conflicts: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RunManifest {
+    pub tool_version: String,
+    pub source: String,
+    /// Tells the source apart from a different store that was put in
+    /// its place.
+    pub source_identity: String,
+    pub destination: String,
+    pub destination_identity: String,
+    pub naive: bool,
+    #[serde(default)]
+    pub auto: bool,
+    /// The databases converted in another mode than `naive`.
+    #[serde(default)]
+    pub naive_overrides: BTreeMap<String, bool>,
+    #[serde(default)]
+    pub label_rules: Vec<LabelRule>,
+    #[serde(default)]
+    pub detect_naive: bool,
+    #[serde(default)]
+    pub datatypes: DatatypeMap,
+    /// The labels listed in the labels file, if one was given.
+    pub labels: Option<Vec<String>>,
+}
+
+/// The device and inode of a file or directory, and the size of a file,
+/// which together tell it apart from anything else put in its place.
+#[cfg(unix)]
+fn identity(path: &Path) -> io::Result<String> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::metadata(path)?;
+    if metadata.is_file() {
+        Ok(format!(
+            "{}:{}:{}",
+            metadata.dev(),
+            metadata.ino(),
+            metadata.len()
+        ))
+    } else {
+        Ok(format!("{}:{}", metadata.dev(), metadata.ino()))
+    }
+}
+
+#[cfg(not(unix))]
+fn identity(path: &Path) -> io::Result<String> {
+    let metadata = std::fs::metadata(path)?;
+    Ok(metadata.len().to_string())
+}
+
+fn canonical(path: &str) -> io::Result<String> {
+    Ok(std::fs::canonicalize(path)?.to_string_lossy().into_owned())
+}
+
+impl RunManifest {
+    /// The settings of the current run. Both the source and the
+    /// destination have to exist.
+    pub async fn new(options: &ConversionOptions) -> io::Result<Self> {
+        let (from, to) = (&options.from[..], &options.to[..]);
+        let labels = match &options.labels {
+            Some(labels) => {
+                let contents = tokio::fs::read_to_string(labels).await?;
+                let mut names: Vec<String> = contents.lines().map(|l| l.to_string()).collect();
+                names.sort();
+                names.dedup();
+                Some(names)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            source: canonical(from)?,
+            source_identity: identity(Path::new(from))?,
+            destination: canonical(to)?,
+            destination_identity: identity(Path::new(to))?,
+            naive: options.naive,
+            auto: options.auto,
+            naive_overrides: options.naive_overrides.clone(),
+            label_rules: options.label_rules.clone(),
+            detect_naive: options.detect_naive,
+            datatypes: options.datatypes.clone(),
+            labels,
+        })
+    }
+
+    /// `options` with the modes and datatype mappings of this run.
+    pub fn with_modes(&self, options: &ConversionOptions) -> ConversionOptions {
+        ConversionOptions {
+            naive: self.naive,
+            auto: self.auto,
+            naive_overrides: self.naive_overrides.clone(),
+            label_rules: self.label_rules.clone(),
+            detect_naive: self.detect_naive,
+            datatypes: self.datatypes.clone(),
+            ..options.clone()
+        }
+    }
+
+    fn value_mode(&self) -> ValueMode {
+        if self.auto {
+            ValueMode::Auto
+        } else if self.naive {
+            ValueMode::Naive
+        } else {
+            ValueMode::Typed
+        }
+    }
+
+    /// The settings in which a run differs from this earlier one.
+    /// `check_mode` is false for runs that are allowed to convert in a
+    /// different mode or with different datatype mappings, such as
+    /// retries.
+    pub fn conflicts(&self, current: &RunManifest, check_mode: bool) -> Vec<String> {
+        let mut conflicts = Vec::new();
+        if self.source != current.source {
+            conflicts.push(format!(
+                "the source was `{}`, not `{}`",
+                self.source, current.source
+            ));
+        } else if self.source_identity != current.source_identity {
+            conflicts.push(format!(
+                "the source `{}` is not the same store anymore",
+                current.source
+            ));
+        }
+        if self.destination != current.destination {
+            conflicts.push(format!(
+                "the destination was `{}`, not `{}`",
+                self.destination, current.destination
+            ));
+        } else if self.destination_identity != current.destination_identity {
+            conflicts.push(format!(
+                "the destination `{}` is not the same store anymore",
+                current.destination
+            ));
+        }
+        if check_mode && self.value_mode() != current.value_mode() {
+            conflicts.push(format!(
+                "the conversion was {}, not {}",
+                self.value_mode(),
+                current.value_mode()
+            ));
+        }
+        if check_mode
+            && (self.naive_overrides != current.naive_overrides
+                || self.label_rules != current.label_rules
+                || self.detect_naive != current.detect_naive)
+        {
+            conflicts.push("the mode of some labels was chosen differently".to_string());
+        }
+        if check_mode && self.datatypes != current.datatypes {
+            conflicts.push("different datatype mappings were used".to_string());
+        }
+        if self.labels != current.labels {
+            conflicts.push("a different label selection was used".to_string());
+        }
+        if self.tool_version != current.tool_version {
+            conflicts.push(format!(
+                "it was made with version {} of this tool, not {}",
+                self.tool_version, current.tool_version
+            ));
+        }
+
+        conflicts
+    }
+}
+
+fn manifest_path(work: &str) -> PathBuf {
+    let mut path = PathBuf::from(work);
+    path.push(RUN_MANIFEST);
+    path
+}
+
+pub async fn read_run_manifest(work: &str) -> Result<Option<RunManifest>, RunManifestError> {
+    match tokio::fs::read(manifest_path(work)).await {
+        Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Check the settings of a run against those recorded in the workdir,
+/// and record them if there are none yet. With `force`, conflicting
+/// settings are only warned about, and the new settings are recorded.
+/// The mode of a run that doesn't check it is never recorded over that
+/// of an earlier run.
+pub async fn check_run_manifest(
+    work: &str,
+    current: &RunManifest,
+    check_mode: bool,
+    force: bool,
+) -> Result<(), RunManifestError> {
+    let mut current = current.clone();
+    if let Some(recorded) = read_run_manifest(work).await? {
+        if !check_mode {
+            current.naive = recorded.naive;
+            current.auto = recorded.auto;
+            current.naive_overrides = recorded.naive_overrides.clone();
+            current.label_rules = recorded.label_rules.clone();
+            current.detect_naive = recorded.detect_naive;
+            current.datatypes = recorded.datatypes.clone();
+        }
+        let conflicts = recorded.conflicts(&current, check_mode);
+        if conflicts.is_empty() {
+            return Ok(());
+        }
+        if !force {
+            return Err(RunManifestError::Conflict {
+                work: work.to_string(),
+                conflicts,
+            });
+        }
+        for conflict in conflicts {
+            eprintln!("WARNING: resuming anyway, but {conflict}");
+        }
+    }
+
+    write_file_durably(&manifest_path(work), &serde_json::to_vec_pretty(&current)?).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> RunManifest {
+        RunManifest {
+            tool_version: "1.0.0".to_string(),
+            source: "/data/storage".to_string(),
+            source_identity: "1:2".to_string(),
+            destination: "/data/storage_11".to_string(),
+            destination_identity: "1:3".to_string(),
+            naive: false,
+            auto: false,
+            naive_overrides: BTreeMap::new(),
+            label_rules: Vec::new(),
+            detect_naive: false,
+            datatypes: DatatypeMap::new(),
+            labels: None,
+        }
+    }
+
+    #[test]
+    fn conflicting_settings() {
+        let recorded = manifest();
+        assert!(recorded.conflicts(&manifest(), true).is_empty());
+
+        let naive = RunManifest {
+            naive: true,
+            ..manifest()
+        };
+        assert_eq!(1, recorded.conflicts(&naive, true).len());
+        assert!(recorded.conflicts(&naive, false).is_empty());
+
+        let auto = RunManifest {
+            auto: true,
+            ..manifest()
+        };
+        assert_eq!(
+            vec!["the conversion was typed, not auto".to_string()],
+            recorded.conflicts(&auto, true)
+        );
+
+        let mixed = RunManifest {
+            naive_overrides: BTreeMap::from([("admin/legacy".to_string(), true)]),
+            ..manifest()
+        };
+        assert_eq!(1, recorded.conflicts(&mixed, true).len());
+        assert!(recorded.conflicts(&mixed, false).is_empty());
+
+        // a different store in the same place
+        let replaced = RunManifest {
+            source_identity: "1:4".to_string(),
+            labels: Some(vec!["admin%2fdb".to_string()]),
+            ..manifest()
+        };
+        assert_eq!(2, recorded.conflicts(&replaced, true).len());
+    }
+}
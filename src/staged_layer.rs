@@ -0,0 +1,271 @@
+//! Building v11 layers on disk rather than in memory.
+//!
+//! The archive layer store of terminus-store keeps every file of a
+//! layer under construction in memory until the layer is finalized.
+//! For large layers, that is several times the size of the layer. So
+//! instead, the files of a layer being converted are written one by one
+//! into a staging directory in the workdir, and only combined into a
+//! `.larch` file at the very end. Both the writing and the combining go
+//! through buffers of a fixed size.
+use terminus_store_10::storage as storage_10;
+use terminus_store_11::storage::archive::ArchiveFilePresenceHeader;
+use terminus_store_11::storage::consts::{self as consts_11, LayerFileEnum};
+use terminus_store_11::storage::directory::FileBackedStore;
+use terminus_store_11::storage::name_to_string;
+use terminus_store_11::structure::LateLogArrayBufBuilder;
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use bytes::buf::UninitSlice;
+use bytes::{BufMut, BytesMut};
+use tokio::fs;
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+const BUFFER_SIZE: usize = 1 << 20;
+
+/// A layer whose files are being written to a staging directory.
+pub struct StagedLayer {
+    id: [u32; 5],
+    dir: PathBuf,
+}
+
+impl StagedLayer {
+    /// Start staging a layer in the workdir. Anything left behind by an
+    /// earlier attempt at the same layer is removed.
+    pub async fn create(work: &str, id: [u32; 5]) -> io::Result<Self> {
+        let mut dir = PathBuf::from(work);
+        dir.push("staging");
+        dir.push(name_to_string(id));
+        if let Err(e) = fs::remove_dir_all(&dir).await {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        fs::create_dir_all(&dir).await?;
+
+        Ok(Self { id, dir })
+    }
+
+    /// Where a file is staged. Different file names can refer to the
+    /// same file in a v11 layer, such as `base_objects.logarray` and
+    /// `child_pos_objects.logarray`, so staged files are named after
+    /// the file type they map to.
+    fn path(&self, name: &str) -> PathBuf {
+        let mut path = self.dir.clone();
+        path.push(format!("{:?}", consts_11::FILENAME_ENUM_MAP[name]));
+        path
+    }
+
+    /// A staged file, for use with the terminus-store builders.
+    pub fn file(&self, name: &str) -> FileBackedStore {
+        FileBackedStore::new(self.path(name))
+    }
+
+    /// A buffer writing through to a staged file.
+    pub fn buffer(&self, name: &str) -> io::Result<FileBuf> {
+        FileBuf::create(&self.path(name))
+    }
+
+    /// Copy a v10 file into the layer unchanged, if it exists.
+    pub async fn copy_from<F: storage_10::FileLoad>(
+        &self,
+        name: &str,
+        input: &F,
+    ) -> io::Result<()> {
+        if !input.exists().await? {
+            return Ok(());
+        }
+        let mut reader = input.open_read().await?;
+        let mut output =
+            BufWriter::with_capacity(BUFFER_SIZE, fs::File::create(self.path(name)).await?);
+        tokio::io::copy(&mut reader, &mut output).await?;
+        output.flush().await
+    }
+
+    fn set_aside_path(&self, name: &str) -> PathBuf {
+        let mut path = self.dir.clone();
+        path.push("set_aside");
+        path.push(format!("{:?}", consts_11::FILENAME_ENUM_MAP[name]));
+        path
+    }
+
+    /// Move a staged file out of the layer, so it can be written anew
+    /// while the old one is still around. Returns whether there was a
+    /// staged file to move.
+    pub async fn set_aside(&self, name: &str) -> io::Result<bool> {
+        let to = self.set_aside_path(name);
+        fs::create_dir_all(to.parent().unwrap()).await?;
+        match fs::rename(self.path(name), to).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// A file that was set aside.
+    pub fn set_aside_file(&self, name: &str) -> FileBackedStore {
+        FileBackedStore::new(self.set_aside_path(name))
+    }
+
+    /// Remove the files that were set aside. This has to happen before
+    /// the layer is finalized.
+    pub async fn remove_set_aside(&self) -> io::Result<()> {
+        let mut dir = self.dir.clone();
+        dir.push("set_aside");
+        match fs::remove_dir_all(dir).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Combine the staged files into a `.larch` file in the v11 store,
+    /// laid out exactly like terminus-store's `finalize` does it, and
+    /// remove the staging directory.
+    pub async fn finalize(self, to: &str) -> io::Result<()> {
+        let file_types: HashMap<String, LayerFileEnum> = consts_11::FILENAME_ENUM_MAP
+            .values()
+            .map(|t| (format!("{t:?}"), *t))
+            .collect();
+        let mut files = Vec::new();
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let file_type = file_types.get(&name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected staged file `{name}`"),
+                )
+            })?;
+            files.push((*file_type, entry.path(), entry.metadata().await?.len()));
+        }
+        files.sort();
+
+        let presence_header =
+            ArchiveFilePresenceHeader::from_present(files.iter().map(|(t, _, _)| *t));
+        let mut offsets = LateLogArrayBufBuilder::new(BytesMut::new());
+        let mut tally = 0;
+        for (_, _, size) in files.iter() {
+            tally += size;
+            offsets.push(tally);
+        }
+        let mut header = BytesMut::new();
+        header.put_u64(presence_header.inner());
+        header.extend(offsets.finalize_header_first());
+
+        let name = name_to_string(self.id);
+        let mut path = PathBuf::from(to);
+        path.push(&name[..3]);
+        fs::create_dir_all(&path).await?;
+        path.push(format!("{name}.larch"));
+        let mut options = fs::OpenOptions::new();
+        options.create_new(true).write(true);
+        let mut output = BufWriter::with_capacity(BUFFER_SIZE, options.open(path).await?);
+        output.write_all(&header).await?;
+        for (_, path, _) in files {
+            let mut input = fs::File::open(path).await?;
+            tokio::io::copy(&mut input, &mut output).await?;
+        }
+        output.flush().await?;
+        output.into_inner().sync_all().await?;
+
+        self.remove().await
+    }
+
+    pub async fn remove(self) -> io::Result<()> {
+        fs::remove_dir_all(&self.dir).await
+    }
+}
+
+/// A `BufMut` that writes through to a file whenever its buffer fills
+/// up, so the dictionary and log array builders of terminus-store,
+/// which only ever append, can write to disk as they go.
+///
+/// `BufMut` has no way of reporting errors, so the first write error
+/// is kept, everything written after it is dropped, and the error is
+/// returned by `finish`.
+pub struct FileBuf {
+    file: std::fs::File,
+    buf: Vec<u8>,
+    error: Option<io::Error>,
+}
+
+impl FileBuf {
+    fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: std::fs::File::create(path)?,
+            buf: Vec::with_capacity(BUFFER_SIZE),
+            error: None,
+        })
+    }
+
+    fn write_buffer(&mut self) {
+        if self.error.is_none() {
+            if let Err(e) = self.file.write_all(&self.buf) {
+                self.error = Some(e);
+            }
+        }
+        self.buf.clear();
+    }
+
+    /// Write out whatever is still buffered.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.write_buffer();
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.file.flush(),
+        }
+    }
+}
+
+unsafe impl BufMut for FileBuf {
+    fn remaining_mut(&self) -> usize {
+        isize::MAX as usize
+    }
+
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        let len = self.buf.len() + cnt;
+        assert!(
+            len <= self.buf.capacity(),
+            "advanced past the end of the buffer"
+        );
+        self.buf.set_len(len);
+    }
+
+    fn chunk_mut(&mut self) -> &mut UninitSlice {
+        if self.buf.len() == self.buf.capacity() {
+            self.write_buffer();
+        }
+        let len = self.buf.len();
+        let capacity = self.buf.capacity();
+        // Safety: the slice is the unused capacity of the buffer, which
+        // `advance_mut` makes sure is never exceeded.
+        unsafe { UninitSlice::from_raw_parts_mut(self.buf.as_mut_ptr().add(len), capacity - len) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_buf_writes_everything() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("file_buf_test_{}", std::process::id()));
+        let mut buf = FileBuf::create(&path).unwrap();
+        let mut expected = Vec::new();
+        for i in 0..(3 * BUFFER_SIZE / 8 + 5) as u64 {
+            buf.put_u64(i);
+            expected.extend(i.to_be_bytes());
+        }
+        let large = vec![1; 3 * BUFFER_SIZE];
+        buf.put_slice(&large);
+        expected.extend(large);
+        buf.finish().unwrap();
+
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(expected, written);
+    }
+}
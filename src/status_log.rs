@@ -0,0 +1,758 @@
+//! The status log in the workdir.
+//!
+//! Every line of `status.log` is a JSON entry followed by a checksum.
+//! A run entry is written whenever a conversion starts, and a layer
+//! entry whenever the conversion of a layer starts, completes or fails.
+//! A resumed run goes by the last status of every layer, while the
+//! `status` subcommand reports on the whole history.
+use terminus_store_10::storage::{name_to_string, string_to_name};
+
+use crate::durable::sync_parent_directory;
+use crate::layer_modes::ValueMode;
+use crate::store_lock::*;
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+pub const STATUS_LOG: &str = "status.log";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConversionStatus {
+    Error,
+    Completed,
+    Started,
+}
+
+impl fmt::Display for ConversionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConversionStatus::Error => write!(f, "Error"),
+            ConversionStatus::Completed => write!(f, "Completed"),
+            ConversionStatus::Started => write!(f, "Started"),
+        }
+    }
+}
+
+impl FromStr for ConversionStatus {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Error" => Ok(ConversionStatus::Error),
+            "Completed" => Ok(ConversionStatus::Completed),
+            "Started" => Ok(ConversionStatus::Started),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unknown conversion status encountered",
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RunEntry {
+    pub run_started: String,
+    /// The number of layers to convert, including those that earlier
+    /// runs already converted.
+    pub layers: usize,
+}
+
+/// Written when a run is interrupted, after the layer that was being
+/// converted was finished.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointEntry {
+    pub interrupted: String,
+    /// The number of layers the run converted.
+    pub converted: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LayerEntry {
+    #[serde(with = "layer_name")]
+    pub layer: [u32; 5],
+    pub status: ConversionStatus,
+    /// Which attempt at converting the layer this is about, starting
+    /// at 1. Entries written by earlier versions have 0 here.
+    pub attempt: u32,
+    /// When the attempt started, or when it ended for a completed or
+    /// failed layer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The mode a completed layer was converted in. Entries written by
+    /// earlier versions don't say.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<ValueMode>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum StatusEntry {
+    Run(RunEntry),
+    Checkpoint(CheckpointEntry),
+    Layer(LayerEntry),
+}
+
+mod layer_name {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(layer: &[u32; 5], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&name_to_string(*layer))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u32; 5], D::Error> {
+        let name = String::deserialize(deserializer)?;
+        parse_layer_name(&name).map_err(serde::de::Error::custom)
+    }
+}
+
+/// `string_to_name` slices the name by bytes, so it panics on names
+/// that are the right length but not ASCII.
+fn parse_layer_name(name: &str) -> io::Result<[u32; 5]> {
+    if !name.is_ascii() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid layer name: {name}"),
+        ));
+    }
+    string_to_name(name)
+}
+
+fn status_path(work: &str) -> PathBuf {
+    let mut path = PathBuf::from(work);
+    path.push(STATUS_LOG);
+    path
+}
+
+fn malformed(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed status log entry: {line}"),
+    )
+}
+
+fn check_checksum(entry: &str, checksum: &str, line: &str) -> io::Result<()> {
+    if u32::from_str_radix(checksum, 16).ok() != Some(crc32fast::hash(entry.as_bytes())) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("status log entry has a wrong checksum: {line}"),
+        ));
+    }
+
+    Ok(())
+}
+
+/// The line written to the status log for an entry. Every line ends in
+/// a checksum, so that a line that was only partially written before a
+/// crash can be recognized.
+fn status_line(entry: &StatusEntry) -> String {
+    let entry = serde_json::to_string(entry).expect("status entries always serialize");
+    let checksum = crc32fast::hash(entry.as_bytes());
+    format!("{entry} {checksum:08x}\n")
+}
+
+fn parse_status_line(line: &str) -> io::Result<StatusEntry> {
+    if line.starts_with('{') {
+        let (entry, checksum) = line.rsplit_once(' ').ok_or_else(|| malformed(line))?;
+        check_checksum(entry, checksum, line)?;
+        return serde_json::from_str(entry).map_err(|_| malformed(line));
+    }
+
+    // Earlier versions wrote just a layer and a status.
+    let elts = line.split(' ').collect::<Vec<&str>>();
+    if elts.len() != 2 {
+        return Err(malformed(line));
+    }
+    let layer = parse_layer_name(elts[0]).map_err(|_| malformed(line))?;
+    let status = ConversionStatus::from_str(elts[1])?;
+
+    Ok(StatusEntry::Layer(LayerEntry {
+        layer,
+        status,
+        attempt: 0,
+        time: None,
+        duration_ms: None,
+        error_kind: None,
+        error: None,
+        mode: None,
+    }))
+}
+
+/// Parse the status log, returning its entries and the length of the
+/// log up to the last valid entry. An invalid last entry is the result
+/// of a crash in the middle of writing it, and is ignored. An invalid
+/// entry anywhere else is an error.
+fn parse_status_log(data: &str) -> io::Result<(Vec<StatusEntry>, usize)> {
+    let mut entries = Vec::new();
+    let mut valid_len = 0;
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (line, is_last) = match rest.find('\n') {
+            Some(pos) => (&rest[..pos], pos + 1 == rest.len()),
+            None => (rest, true),
+        };
+        let complete = line.len() < rest.len();
+        match parse_status_line(line) {
+            Ok(entry) if complete => {
+                entries.push(entry);
+                valid_len += line.len() + 1;
+                rest = &rest[line.len() + 1..];
+            }
+            Err(e) if !is_last => return Err(e),
+            _ => break,
+        }
+    }
+
+    Ok((entries, valid_len))
+}
+
+/// Read the status log without changing it, for reporting on a
+/// conversion that may still be writing to it.
+pub async fn read_status_log(work: &str) -> io::Result<Vec<StatusEntry>> {
+    match fs::read(status_path(work)).await {
+        Ok(data) => Ok(parse_status_log(&String::from_utf8_lossy(&data))?.0),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read the status log before writing to it, dropping a torn last
+/// entry.
+async fn repair_status_log(work: &str) -> io::Result<Vec<StatusEntry>> {
+    let status_path = status_path(work);
+    let data = match fs::read(&status_path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let (entries, valid_len) = parse_status_log(&String::from_utf8_lossy(&data))?;
+    if valid_len < data.len() {
+        // Drop the torn entry, so new entries don't get appended to it.
+        // The layer it was about still has its previous status, and
+        // will be redone if it wasn't completed.
+        eprintln!("ignoring incomplete last entry of the status log");
+        let file = OpenOptions::new().write(true).open(&status_path).await?;
+        file.set_len(valid_len as u64).await?;
+        file.sync_all().await?;
+    }
+
+    Ok(entries)
+}
+
+fn layer_entries(entries: &[StatusEntry]) -> impl Iterator<Item = &LayerEntry> {
+    entries.iter().filter_map(|e| match e {
+        StatusEntry::Layer(entry) => Some(entry),
+        _ => None,
+    })
+}
+
+/// The last status of every layer in the log.
+pub fn latest_statuses(entries: &[StatusEntry]) -> HashMap<[u32; 5], ConversionStatus> {
+    layer_entries(entries)
+        .map(|entry| (entry.layer, entry.status))
+        .collect()
+}
+
+pub async fn get_status_hashmap(work: &str) -> io::Result<HashMap<[u32; 5], ConversionStatus>> {
+    Ok(latest_statuses(&repair_status_log(work).await?))
+}
+
+/// The status log of a conversion, open for appending.
+pub struct StatusLog {
+    file: fs::File,
+    statuses: HashMap<[u32; 5], ConversionStatus>,
+    attempts: HashMap<[u32; 5], u32>,
+    modes: HashMap<[u32; 5], ValueMode>,
+}
+
+impl StatusLog {
+    pub async fn open(work: &str) -> io::Result<Self> {
+        std::fs::create_dir_all(work)?;
+        let entries = repair_status_log(work).await?;
+        let mut attempts = HashMap::new();
+        let mut modes = HashMap::new();
+        for entry in layer_entries(&entries) {
+            if entry.status == ConversionStatus::Started {
+                *attempts.entry(entry.layer).or_default() += 1;
+            }
+            match entry.mode {
+                Some(mode) if entry.status == ConversionStatus::Completed => {
+                    modes.insert(entry.layer, mode);
+                }
+                _ => {
+                    modes.remove(&entry.layer);
+                }
+            }
+        }
+        let path = status_path(work);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        sync_parent_directory(&path).await?;
+
+        Ok(Self {
+            file,
+            statuses: latest_statuses(&entries),
+            attempts,
+            modes,
+        })
+    }
+
+    /// The status of a layer when the log was opened.
+    pub fn status(&self, layer: [u32; 5]) -> Option<ConversionStatus> {
+        self.statuses.get(&layer).copied()
+    }
+
+    /// The mode a layer was converted in, if it is completed and the
+    /// log says.
+    pub fn converted_mode(&self, layer: [u32; 5]) -> Option<ValueMode> {
+        self.modes.get(&layer).copied()
+    }
+
+    async fn write(&mut self, entry: &StatusEntry) -> io::Result<()> {
+        self.file.write_all(status_line(entry).as_bytes()).await?;
+        self.file.flush().await?;
+        self.file.sync_data().await
+    }
+
+    pub async fn run_started(&mut self, layers: usize) -> io::Result<()> {
+        self.write(&StatusEntry::Run(RunEntry {
+            run_started: now(),
+            layers,
+        }))
+        .await
+    }
+
+    pub async fn interrupted(&mut self, converted: usize) -> io::Result<()> {
+        self.write(&StatusEntry::Checkpoint(CheckpointEntry {
+            interrupted: now(),
+            converted,
+        }))
+        .await
+    }
+
+    async fn write_layer(
+        &mut self,
+        layer: [u32; 5],
+        status: ConversionStatus,
+        duration: Option<Duration>,
+        error: Option<(&str, String)>,
+        mode: Option<ValueMode>,
+    ) -> io::Result<()> {
+        let (error_kind, error) = match error {
+            Some((kind, message)) => (Some(kind.to_string()), Some(message)),
+            None => (None, None),
+        };
+        self.write(&StatusEntry::Layer(LayerEntry {
+            layer,
+            status,
+            attempt: self.attempts.get(&layer).copied().unwrap_or(0),
+            time: Some(now()),
+            duration_ms: duration.map(|d| d.as_millis() as u64),
+            error_kind,
+            error,
+            mode,
+        }))
+        .await
+    }
+
+    pub async fn layer_started(&mut self, layer: [u32; 5]) -> io::Result<()> {
+        *self.attempts.entry(layer).or_default() += 1;
+        self.write_layer(layer, ConversionStatus::Started, None, None, None)
+            .await
+    }
+
+    pub async fn layer_completed(
+        &mut self,
+        layer: [u32; 5],
+        duration: Duration,
+        mode: ValueMode,
+    ) -> io::Result<()> {
+        self.write_layer(
+            layer,
+            ConversionStatus::Completed,
+            Some(duration),
+            None,
+            Some(mode),
+        )
+        .await
+    }
+
+    pub async fn layer_failed(
+        &mut self,
+        layer: [u32; 5],
+        duration: Duration,
+        kind: &str,
+        message: String,
+    ) -> io::Result<()> {
+        self.write_layer(
+            layer,
+            ConversionStatus::Error,
+            Some(duration),
+            Some((kind, message)),
+            None,
+        )
+        .await
+    }
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339()
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct LayerSummary {
+    pub layer: String,
+    pub attempts: u32,
+    pub duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct StatusReport {
+    /// The PID of the conversion working in the workdir, if one is
+    /// running. 0 if the PID is unknown.
+    pub running: Option<u32>,
+    pub run_started: Option<String>,
+    /// When the last run was stopped by a signal, if it was.
+    pub run_interrupted: Option<String>,
+    /// The number of layers of the last run, if it was recorded.
+    pub total: Option<usize>,
+    pub completed: usize,
+    pub failed: usize,
+    /// Layers that were started but not finished. When no conversion is
+    /// running, these were interrupted.
+    pub started: usize,
+    pub pending: Option<usize>,
+    pub progress_percent: Option<f64>,
+    pub eta_ms: Option<u64>,
+    pub slowest: Vec<LayerSummary>,
+    pub failures: Vec<LayerSummary>,
+}
+
+/// Summarize the status log of a conversion. The time remaining is
+/// estimated from the average time the layers completed in the last run
+/// took, or in all runs if none completed in the last run yet.
+pub fn status_report(
+    entries: &[StatusEntry],
+    running: Option<u32>,
+    slowest: usize,
+) -> StatusReport {
+    let last_run = entries
+        .iter()
+        .rposition(|e| matches!(e, StatusEntry::Run(_)));
+    let (run_started, total) = match last_run.map(|i| &entries[i]) {
+        Some(StatusEntry::Run(run)) => (Some(run.run_started.clone()), Some(run.layers)),
+        _ => (None, None),
+    };
+    let run_interrupted = entries[last_run.unwrap_or(0)..]
+        .iter()
+        .rev()
+        .find_map(|e| match e {
+            StatusEntry::Checkpoint(checkpoint) => Some(checkpoint.interrupted.clone()),
+            _ => None,
+        });
+
+    let mut latest: HashMap<[u32; 5], &LayerEntry> = HashMap::new();
+    let mut attempts: HashMap<[u32; 5], u32> = HashMap::new();
+    for entry in layer_entries(entries) {
+        if entry.status == ConversionStatus::Started {
+            *attempts.entry(entry.layer).or_default() += 1;
+        }
+        latest.insert(entry.layer, entry);
+    }
+    let summary = |entry: &LayerEntry| LayerSummary {
+        layer: name_to_string(entry.layer),
+        attempts: attempts.get(&entry.layer).copied().unwrap_or(0),
+        duration_ms: entry.duration_ms,
+        error_kind: entry.error_kind.clone(),
+        error: entry.error.clone(),
+    };
+
+    let count = |status| latest.values().filter(|e| e.status == status).count();
+    let completed = count(ConversionStatus::Completed);
+    let failed = count(ConversionStatus::Error);
+    let started = count(ConversionStatus::Started);
+    let pending = total.map(|total| total.saturating_sub(completed + failed + started));
+    let progress_percent = total.map(|total| match total {
+        0 => 100.0,
+        total => (100.0 * completed as f64 / total as f64).min(100.0),
+    });
+
+    let durations = |from: usize| -> Vec<u64> {
+        layer_entries(&entries[from..])
+            .filter(|e| e.status == ConversionStatus::Completed)
+            .filter_map(|e| e.duration_ms)
+            .collect()
+    };
+    let mut completed_durations = durations(last_run.unwrap_or(0));
+    if completed_durations.is_empty() {
+        completed_durations = durations(0);
+    }
+    let remaining = pending.map(|pending| pending + started);
+    let eta_ms = match (remaining, completed_durations.len()) {
+        (Some(remaining), n) if remaining != 0 && n != 0 => {
+            let average = completed_durations.iter().sum::<u64>() / n as u64;
+            Some(average * remaining as u64)
+        }
+        _ => None,
+    };
+
+    let mut finished: Vec<&LayerEntry> = latest
+        .values()
+        .filter(|e| e.duration_ms.is_some())
+        .copied()
+        .collect();
+    finished.sort_by_key(|e| (Reverse(e.duration_ms), e.layer));
+    let mut failures: Vec<&LayerEntry> = latest
+        .values()
+        .filter(|e| e.status == ConversionStatus::Error)
+        .copied()
+        .collect();
+    failures.sort_by_key(|e| e.layer);
+
+    StatusReport {
+        running,
+        run_started,
+        run_interrupted,
+        total,
+        completed,
+        failed,
+        started,
+        pending,
+        progress_percent,
+        eta_ms,
+        slowest: finished.into_iter().take(slowest).map(summary).collect(),
+        failures: failures.into_iter().map(summary).collect(),
+    }
+}
+
+pub fn format_duration(ms: u64) -> String {
+    let seconds = ms / 1000;
+    if seconds == 0 {
+        format!("{ms}ms")
+    } else if seconds < 60 {
+        format!("{}.{}s", seconds, ms % 1000 / 100)
+    } else if seconds < 3600 {
+        format!("{}m {:02}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60)
+    }
+}
+
+fn print_status_report(report: &StatusReport) {
+    match report.running {
+        Some(0) => println!("A conversion is running"),
+        Some(pid) => println!("A conversion is running (pid {pid})"),
+        None => println!("No conversion is running"),
+    }
+    if let Some(started) = &report.run_started {
+        println!("Last run started: {started}");
+    }
+    if let Some(interrupted) = &report.run_interrupted {
+        println!("Last run was interrupted: {interrupted}");
+    }
+    let started = if report.running.is_some() {
+        "in progress"
+    } else {
+        "interrupted"
+    };
+    match (report.total, report.pending) {
+        (Some(total), Some(pending)) => println!(
+            "Layers: {total} total, {} completed, {} failed, {} {started}, {pending} pending",
+            report.completed, report.failed, report.started
+        ),
+        _ => println!(
+            "Layers: {} completed, {} failed, {} {started}",
+            report.completed, report.failed, report.started
+        ),
+    }
+    if let Some(progress) = report.progress_percent {
+        println!("Progress: {progress:.1}%");
+    }
+    if let Some(eta) = report.eta_ms {
+        println!("Estimated time remaining: {}", format_duration(eta));
+    }
+    if !report.slowest.is_empty() {
+        println!("Slowest layers:");
+        for layer in report.slowest.iter() {
+            println!(
+                "  {}  {}",
+                layer.layer,
+                format_duration(layer.duration_ms.unwrap_or(0))
+            );
+        }
+    }
+    if !report.failures.is_empty() {
+        println!("Failed layers:");
+        for layer in report.failures.iter() {
+            println!(
+                "  {}  attempt {}  {}: {}",
+                layer.layer,
+                layer.attempts,
+                layer.error_kind.as_deref().unwrap_or("unknown"),
+                layer.error.as_deref().unwrap_or("")
+            );
+        }
+    }
+}
+
+/// Report on the conversion that uses the given workdir, whether it is
+/// still running or not.
+pub async fn show_status(work: &str, slowest: usize, json: bool) -> io::Result<()> {
+    if fs::metadata(work).await.is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("workdir `{work}` does not exist"),
+        ));
+    }
+    let entries = read_status_log(work).await?;
+    let running = match check_unlocked(Path::new(work)) {
+        Ok(()) => None,
+        Err(LockError::Locked { pid, .. }) => Some(pid.unwrap_or(0)),
+        Err(e) => return Err(io::Error::other(e)),
+    };
+    let report = status_report(&entries, running, slowest);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_status_report(&report);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer_entry(
+        layer: [u32; 5],
+        status: ConversionStatus,
+        duration_ms: Option<u64>,
+    ) -> StatusEntry {
+        StatusEntry::Layer(LayerEntry {
+            layer,
+            status,
+            attempt: 1,
+            time: None,
+            duration_ms,
+            error_kind: (status == ConversionStatus::Error).then(|| "io".to_string()),
+            error: None,
+            mode: None,
+        })
+    }
+
+    #[test]
+    fn status_log_entries_are_checksummed() {
+        let layer1 = [1, 2, 3, 4, 5];
+        let layer2 = [6, 7, 8, 9, 10];
+        let log = format!(
+            "{}{}{}",
+            status_line(&layer_entry(layer1, ConversionStatus::Started, None)),
+            status_line(&layer_entry(layer1, ConversionStatus::Completed, Some(10))),
+            status_line(&layer_entry(layer2, ConversionStatus::Started, None)),
+        );
+        let (entries, valid_len) = parse_status_log(&log).unwrap();
+        let statuses = latest_statuses(&entries);
+        assert_eq!(log.len(), valid_len);
+        assert_eq!(Some(&ConversionStatus::Completed), statuses.get(&layer1));
+        assert_eq!(Some(&ConversionStatus::Started), statuses.get(&layer2));
+
+        // a crash while writing the last entry
+        let completed = status_line(&layer_entry(layer2, ConversionStatus::Completed, Some(10)));
+        let torn = format!("{log}{}", &completed[..completed.len() - 3]);
+        let (entries, valid_len) = parse_status_log(&torn).unwrap();
+        assert_eq!(log.len(), valid_len);
+        assert_eq!(
+            Some(&ConversionStatus::Started),
+            latest_statuses(&entries).get(&layer2)
+        );
+
+        // corruption before the last entry is an error
+        let corrupted = format!("{}{log}", completed.replace("Completed", "Started"));
+        assert!(parse_status_log(&corrupted).is_err());
+
+        // as is a line that isn't an entry at all
+        assert!(parse_status_log(&format!("garbage\n{log}")).is_err());
+        let non_ascii = format!("{} Started\n{log}", "é".repeat(20));
+        assert!(parse_status_log(&non_ascii).is_err());
+    }
+
+    #[test]
+    fn status_log_without_checksums() {
+        let log = "0000000100000002000000030000000400000005 Started\n\
+                   0000000100000002000000030000000400000005 Completed\n";
+        let (entries, valid_len) = parse_status_log(log).unwrap();
+        assert_eq!(log.len(), valid_len);
+        assert_eq!(
+            Some(&ConversionStatus::Completed),
+            latest_statuses(&entries).get(&[1, 2, 3, 4, 5])
+        );
+    }
+
+    #[test]
+    fn report_counts_and_estimates() {
+        let entries = vec![
+            StatusEntry::Run(RunEntry {
+                run_started: "2026-01-01T00:00:00+00:00".to_string(),
+                layers: 5,
+            }),
+            layer_entry([1; 5], ConversionStatus::Started, None),
+            layer_entry([1; 5], ConversionStatus::Completed, Some(2000)),
+            layer_entry([2; 5], ConversionStatus::Started, None),
+            layer_entry([2; 5], ConversionStatus::Completed, Some(4000)),
+            layer_entry([3; 5], ConversionStatus::Started, None),
+            layer_entry([3; 5], ConversionStatus::Error, Some(500)),
+            layer_entry([4; 5], ConversionStatus::Started, None),
+        ];
+        let report = status_report(&entries, None, 2);
+        assert_eq!(Some(5), report.total);
+        assert_eq!(2, report.completed);
+        assert_eq!(1, report.failed);
+        assert_eq!(1, report.started);
+        assert_eq!(Some(1), report.pending);
+        assert_eq!(Some(40.0), report.progress_percent);
+        // two layers left, at an average of 3 seconds each
+        assert_eq!(Some(6000), report.eta_ms);
+        assert_eq!(
+            vec![name_to_string([2; 5]), name_to_string([1; 5])],
+            report
+                .slowest
+                .iter()
+                .map(|l| l.layer.clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, report.failures.len());
+        assert_eq!(Some("io".to_string()), report.failures[0].error_kind);
+    }
+
+    #[test]
+    fn format_durations() {
+        assert_eq!("250ms", format_duration(250));
+        assert_eq!("1.2s", format_duration(1_250));
+        assert_eq!("59.9s", format_duration(59_999));
+        assert_eq!("2m 05s", format_duration(125_000));
+        assert_eq!("1h 01m", format_duration(3_660_000));
+    }
+}
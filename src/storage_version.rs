@@ -0,0 +1,250 @@
+//! Telling which version of TerminusDB wrote a store.
+//!
+//! TerminusDB writes the version of its storage format to
+//! `STORAGE_VERSION` in the storage directory: `1` for TerminusDB 10,
+//! and `2` for TerminusDB 11. The layout of the layers tells the
+//! versions apart as well. TerminusDB 10 keeps every layer in a
+//! directory of its own, under a directory named after the first three
+//! characters of the layer name. TerminusDB 11 keeps every layer in a
+//! single `.larch` file under that same directory, and stores from
+//! before TerminusDB 10 have their layer directories right in the
+//! storage directory.
+use terminus_store_10::storage::string_to_name;
+
+use crate::durable::write_file_durably;
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+pub const STORAGE_VERSION_FILE: &str = "STORAGE_VERSION";
+
+/// The storage versions this tool knows about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StorageVersion {
+    V10,
+    V11,
+}
+
+impl StorageVersion {
+    /// The version stores are converted to.
+    pub const LATEST: Self = StorageVersion::V11;
+
+    /// What TerminusDB writes to `STORAGE_VERSION` for this version.
+    pub fn version_file(self) -> &'static str {
+        match self {
+            StorageVersion::V10 => "1",
+            StorageVersion::V11 => "2",
+        }
+    }
+
+    pub fn from_version_file(contents: &str) -> Option<Self> {
+        match contents.trim() {
+            "1" => Some(StorageVersion::V10),
+            "2" => Some(StorageVersion::V11),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StorageVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            StorageVersion::V10 => "10",
+            StorageVersion::V11 => "11",
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum StorageVersionError {
+    #[error("could not read `{path}`: {source}")]
+    Io { path: String, source: io::Error },
+    #[error("`{path}` has storage version `{version}`, which this tool can't convert. It converts stores of TerminusDB 10, which have storage version 1")]
+    Unsupported { path: String, version: String },
+    #[error("`{0}` has its layers right in the storage directory, like stores from before TerminusDB 10. Upgrade it with TerminusDB 10 first, and convert it after")]
+    BeforeV10(String),
+    #[error("`{path}` is already a TerminusDB {version} store, there is nothing to convert")]
+    AlreadyConverted {
+        path: String,
+        version: StorageVersion,
+    },
+    #[error(
+        "`{0}` has no {STORAGE_VERSION_FILE} file and no layers, so it is not a TerminusDB store"
+    )]
+    NotAStore(String),
+    #[error("there is no way to convert a TerminusDB {from} store into a TerminusDB {to} store")]
+    NoMigration {
+        from: StorageVersion,
+        to: StorageVersion,
+    },
+}
+
+/// What a storage directory, or an archive of one, was found to hold.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreLayout {
+    /// The contents of `STORAGE_VERSION`, if there is one.
+    pub version_file: Option<String>,
+    /// Layer directories under prefix directories, as in TerminusDB 10.
+    pub layer_directories: bool,
+    /// `.larch` layer files, as in TerminusDB 11.
+    pub layer_archives: bool,
+    /// Layer directories right in the storage directory, as in stores
+    /// from before TerminusDB 10.
+    pub unprefixed_layers: bool,
+}
+
+fn is_layer_name(name: &str) -> bool {
+    name.len() == 40 && string_to_name(name).is_ok()
+}
+
+fn is_prefix_name(name: &str) -> bool {
+    name.len() == 3 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Whether a file name is that of a TerminusDB 11 layer file.
+pub fn is_layer_archive_name(name: &str) -> bool {
+    name.strip_suffix(".larch").is_some_and(is_layer_name)
+}
+
+impl StoreLayout {
+    /// Look at the storage directory in `path`. Only as much of the
+    /// store is read as is needed to find its first layer.
+    pub async fn read(path: &str) -> Result<Self, StorageVersionError> {
+        Self::read_dir(Path::new(path))
+            .await
+            .map_err(|source| StorageVersionError::Io {
+                path: path.to_string(),
+                source,
+            })
+    }
+
+    async fn read_dir(path: &Path) -> io::Result<Self> {
+        let mut layout = StoreLayout::default();
+        let mut version_path = PathBuf::from(path);
+        version_path.push(STORAGE_VERSION_FILE);
+        match tokio::fs::read_to_string(version_path).await {
+            Ok(version) => layout.version_file = Some(version),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut prefixes = Vec::new();
+        let mut entries = tokio::fs::read_dir(path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            if is_layer_name(&name) {
+                layout.unprefixed_layers = true;
+            } else if is_prefix_name(&name) {
+                prefixes.push(entry.path());
+            }
+        }
+        for prefix in prefixes {
+            let mut entries = tokio::fs::read_dir(prefix).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if is_layer_archive_name(&name) {
+                    layout.layer_archives = true;
+                } else if is_layer_name(&name) && entry.file_type().await?.is_dir() {
+                    layout.layer_directories = true;
+                }
+            }
+            if layout.layer_archives || layout.layer_directories {
+                break;
+            }
+        }
+
+        Ok(layout)
+    }
+
+    /// The version of the store in `path`, as far as this tool can
+    /// convert it. Stores without a `STORAGE_VERSION` are recognized by
+    /// their layout.
+    pub fn version(&self, path: &str) -> Result<StorageVersion, StorageVersionError> {
+        if let Some(contents) = &self.version_file {
+            return StorageVersion::from_version_file(contents).ok_or_else(|| {
+                StorageVersionError::Unsupported {
+                    path: path.to_string(),
+                    version: contents.trim().to_string(),
+                }
+            });
+        }
+
+        if self.unprefixed_layers {
+            Err(StorageVersionError::BeforeV10(path.to_string()))
+        } else if self.layer_archives {
+            Ok(StorageVersion::V11)
+        } else if self.layer_directories {
+            Ok(StorageVersion::V10)
+        } else {
+            Err(StorageVersionError::NotAStore(path.to_string()))
+        }
+    }
+}
+
+/// Find the version of the storage directory in `path`.
+pub async fn detect_storage_version(path: &str) -> Result<StorageVersion, StorageVersionError> {
+    StoreLayout::read(path).await?.version(path)
+}
+
+/// Write the `STORAGE_VERSION` of a converted store.
+pub async fn write_version_file(to: &str, version: StorageVersion) -> io::Result<()> {
+    let mut path = PathBuf::from(to);
+    path.push(STORAGE_VERSION_FILE);
+    write_file_durably(&path, version.version_file().as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_from_layout() {
+        let v10 = StoreLayout {
+            version_file: Some("1\n".to_string()),
+            layer_directories: true,
+            ..Default::default()
+        };
+        assert_eq!(StorageVersion::V10, v10.version("s").unwrap());
+
+        let unmarked_v10 = StoreLayout {
+            layer_directories: true,
+            ..Default::default()
+        };
+        assert_eq!(StorageVersion::V10, unmarked_v10.version("s").unwrap());
+
+        let unmarked_v11 = StoreLayout {
+            layer_archives: true,
+            ..Default::default()
+        };
+        assert_eq!(StorageVersion::V11, unmarked_v11.version("s").unwrap());
+
+        let old = StoreLayout {
+            unprefixed_layers: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            old.version("s"),
+            Err(StorageVersionError::BeforeV10(_))
+        ));
+
+        let unknown = StoreLayout {
+            version_file: Some("7".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            unknown.version("s"),
+            Err(StorageVersionError::Unsupported { version, .. }) if version == "7"
+        ));
+
+        assert!(matches!(
+            StoreLayout::default().version("s"),
+            Err(StorageVersionError::NotAStore(_))
+        ));
+    }
+}
@@ -0,0 +1,431 @@
+//! Packing a converted v11 store into a single zstd-compressed tar
+//! archive, and unpacking it again on the machine it was shipped to.
+//!
+//! The archive contains the `.larch` layer files, the label files and
+//! `STORAGE_VERSION` (and the store's `MANIFEST.json`, if it has one),
+//! laid out exactly like the storage directory, so
+//! it can also be extracted with plain `tar`. The last entry is a
+//! manifest listing every layer and label, which `unpack` uses to check
+//! the extracted store, and removes once the store checks out.
+use terminus_store_11::storage::{name_to_string, string_to_name};
+
+use crate::convert_labels::parse_label;
+use crate::storage_version::STORAGE_VERSION_FILE;
+use crate::store_manifest::STORE_MANIFEST;
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+pub const ARCHIVE_MANIFEST: &str = "ARCHIVE_MANIFEST.json";
+
+#[derive(Error, Debug)]
+pub enum StoreArchiveError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("could not read archive manifest: {0}")]
+    Manifest(#[from] serde_json::Error),
+    #[error("archive does not contain a {ARCHIVE_MANIFEST}")]
+    MissingManifest,
+    #[error("`{0}` already exists and is not empty")]
+    DestinationNotEmpty(String),
+    #[error("{} files in the extracted store do not match the archive manifest", .0.len())]
+    ManifestMismatch(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ArchivedLayer {
+    pub id: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ArchivedLabel {
+    pub name: String,
+    pub version: u64,
+    pub layer: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    pub storage_version: String,
+    pub layers: Vec<ArchivedLayer>,
+    pub labels: Vec<ArchivedLabel>,
+}
+
+/// Write the v11 store in `store` to a zstd-compressed tar archive.
+pub async fn write_store_archive(
+    store: &str,
+    archive: &str,
+) -> Result<ArchiveManifest, StoreArchiveError> {
+    let store = PathBuf::from(store);
+    let archive = PathBuf::from(archive);
+    tokio::task::spawn_blocking(move || write_archive(&store, &archive))
+        .await
+        .unwrap()
+}
+
+/// Extract an archive written by `write_store_archive` into `to`, and
+/// check the result against the manifest in the archive.
+pub async fn unpack_store_archive(
+    archive: &str,
+    to: &str,
+    verbose: bool,
+) -> Result<(), StoreArchiveError> {
+    let archive = PathBuf::from(archive);
+    let to = PathBuf::from(to);
+    let manifest = tokio::task::spawn_blocking(move || {
+        unpack_archive(&archive, &to)?;
+        let manifest = read_manifest(&to)?;
+        let mismatches = check_extracted_store(&to, &manifest)?;
+        if mismatches.is_empty() {
+            fs::remove_file(to.join(ARCHIVE_MANIFEST))?;
+            Ok(manifest)
+        } else {
+            for mismatch in mismatches.iter() {
+                eprintln!("manifest mismatch: {mismatch}");
+            }
+            Err(StoreArchiveError::ManifestMismatch(mismatches))
+        }
+    })
+    .await
+    .unwrap()?;
+
+    if verbose {
+        for layer in manifest.layers.iter() {
+            println!("layer verified: {}", layer.id);
+        }
+    }
+    println!(
+        "Unpacked {} layers and {} labels",
+        manifest.layers.len(),
+        manifest.labels.len()
+    );
+
+    Ok(())
+}
+
+/// Hashes everything that is read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.hasher.update(&buf[..count]);
+        Ok(count)
+    }
+}
+
+pub fn layer_path(store: &Path, id: &str) -> PathBuf {
+    let mut path = store.to_path_buf();
+    path.push(&id[..3]);
+    path.push(format!("{id}.larch"));
+    path
+}
+
+/// All the finalized layers in a v11 store, sorted by name.
+pub fn store_layers(store: &Path) -> io::Result<Vec<String>> {
+    let mut layers = Vec::new();
+    for direntry in fs::read_dir(store)? {
+        let direntry = direntry?;
+        let name = direntry.file_name().to_string_lossy().into_owned();
+        if name.len() != 3 || !direntry.file_type()?.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(direntry.path())? {
+            let file_name = file?.file_name().to_string_lossy().into_owned();
+            if let Some(id) = file_name.strip_suffix(".larch") {
+                if string_to_name(id).is_ok() {
+                    layers.push(id.to_string());
+                }
+            }
+        }
+    }
+    layers.sort();
+
+    Ok(layers)
+}
+
+/// All label names in a store, sorted.
+pub fn store_labels(store: &Path) -> io::Result<Vec<String>> {
+    let mut labels = Vec::new();
+    for direntry in fs::read_dir(store)? {
+        let direntry = direntry?;
+        let name = direntry.file_name().to_string_lossy().into_owned();
+        if let Some(label) = name.strip_suffix(".label") {
+            if direntry.file_type()?.is_file() {
+                labels.push(label.to_string());
+            }
+        }
+    }
+    labels.sort();
+
+    Ok(labels)
+}
+
+fn archived_label(name: String, contents: &[u8]) -> io::Result<ArchivedLabel> {
+    let state = parse_label(contents)?;
+    Ok(ArchivedLabel {
+        name,
+        version: state.version,
+        layer: state.layer.map(name_to_string),
+    })
+}
+
+fn append_bytes<W: io::Write>(
+    builder: &mut tar::Builder<W>,
+    path: &str,
+    contents: &[u8],
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp() as u64);
+    builder.append_data(&mut header, path, contents)
+}
+
+fn write_archive(store: &Path, archive: &Path) -> Result<ArchiveManifest, StoreArchiveError> {
+    let mut tmp_path = archive.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let file = File::create(&tmp_path)?;
+    let mut builder = tar::Builder::new(zstd::Encoder::new(file, 0)?);
+
+    // Labels go after the layers, so that even an archive that was
+    // only partially extracted never has labels pointing at layers
+    // that are missing.
+    let mut layers = Vec::new();
+    for id in store_layers(store)? {
+        let path = layer_path(store, &id);
+        let file = File::open(&path)?;
+        let metadata = file.metadata()?;
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&metadata);
+        let mut reader = HashingReader {
+            inner: file,
+            hasher: Sha256::new(),
+        };
+        builder.append_data(&mut header, format!("{}/{id}.larch", &id[..3]), &mut reader)?;
+        layers.push(ArchivedLayer {
+            id,
+            size: metadata.len(),
+            sha256: hex::encode(reader.hasher.finalize()),
+        });
+    }
+
+    let mut labels = Vec::new();
+    for name in store_labels(store)? {
+        let mut path = store.to_path_buf();
+        path.push(format!("{name}.label"));
+        let contents = fs::read(path)?;
+        append_bytes(&mut builder, &format!("{name}.label"), &contents)?;
+        labels.push(archived_label(name, &contents)?);
+    }
+
+    let mut manifest_path = store.to_path_buf();
+    manifest_path.push(STORE_MANIFEST);
+    if manifest_path.exists() {
+        append_bytes(&mut builder, STORE_MANIFEST, &fs::read(manifest_path)?)?;
+    }
+
+    let mut version_path = store.to_path_buf();
+    version_path.push(STORAGE_VERSION_FILE);
+    let storage_version = fs::read_to_string(version_path)?;
+    append_bytes(
+        &mut builder,
+        STORAGE_VERSION_FILE,
+        storage_version.as_bytes(),
+    )?;
+
+    let manifest = ArchiveManifest {
+        storage_version,
+        layers,
+        labels,
+    };
+    append_bytes(
+        &mut builder,
+        ARCHIVE_MANIFEST,
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+
+    let file = builder.into_inner()?.finish()?;
+    file.sync_all()?;
+    fs::rename(tmp_path, archive)?;
+
+    Ok(manifest)
+}
+
+fn unpack_archive(archive: &Path, to: &Path) -> Result<(), StoreArchiveError> {
+    match fs::read_dir(to) {
+        Ok(mut entries) => {
+            if entries.next().is_some() {
+                return Err(StoreArchiveError::DestinationNotEmpty(
+                    to.to_string_lossy().into_owned(),
+                ));
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir_all(to)?,
+        Err(e) => return Err(e.into()),
+    }
+
+    let file = File::open(archive)?;
+    let mut archive = tar::Archive::new(zstd::Decoder::new(file)?);
+    archive.unpack(to)?;
+
+    Ok(())
+}
+
+fn read_manifest(store: &Path) -> Result<ArchiveManifest, StoreArchiveError> {
+    let mut path = store.to_path_buf();
+    path.push(ARCHIVE_MANIFEST);
+    match fs::read(path) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(StoreArchiveError::MissingManifest),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut reader = HashingReader {
+        inner: File::open(path)?,
+        hasher: Sha256::new(),
+    };
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(hex::encode(reader.hasher.finalize()))
+}
+
+/// Compare an extracted store with its manifest, returning a
+/// description of every difference.
+fn check_extracted_store(store: &Path, manifest: &ArchiveManifest) -> io::Result<Vec<String>> {
+    let mut mismatches = Vec::new();
+
+    let mut version_path = store.to_path_buf();
+    version_path.push(STORAGE_VERSION_FILE);
+    match fs::read_to_string(version_path) {
+        Ok(version) if version == manifest.storage_version => {}
+        Ok(version) => mismatches.push(format!(
+            "STORAGE_VERSION is {version}, expected {}",
+            manifest.storage_version
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            mismatches.push("STORAGE_VERSION is missing".to_string())
+        }
+        Err(e) => return Err(e),
+    }
+
+    for layer in manifest.layers.iter() {
+        let path = layer_path(store, &layer.id);
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.len() != layer.size => mismatches.push(format!(
+                "layer {} is {} bytes, expected {}",
+                layer.id,
+                metadata.len(),
+                layer.size
+            )),
+            Ok(_) => {
+                if hash_file(&path)? != layer.sha256 {
+                    mismatches.push(format!("layer {} has a different hash", layer.id));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                mismatches.push(format!("layer {} is missing", layer.id))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    for id in store_layers(store)? {
+        if !manifest.layers.iter().any(|l| l.id == id) {
+            mismatches.push(format!("layer {id} is not in the manifest"));
+        }
+    }
+
+    for label in manifest.labels.iter() {
+        let mut path = store.to_path_buf();
+        path.push(format!("{}.label", label.name));
+        match fs::read(path) {
+            Ok(contents) => {
+                let found = archived_label(label.name.clone(), &contents)?;
+                if found.version != label.version || found.layer != label.layer {
+                    mismatches.push(format!("label {} has different contents", label.name));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                mismatches.push(format!("label {} is missing", label.name))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_version::StorageVersion;
+
+    const LAYER: &str = "c79ce872a1851b569f9d81da5f7b916d4e6fde1e";
+
+    fn test_dir(name: &str) -> PathBuf {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("store_archive_{}_{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn unpacked_archives_match_the_store() {
+        let dir = test_dir("round_trip");
+        let store = dir.join("store");
+        fs::create_dir_all(store.join(&LAYER[..3])).unwrap();
+        fs::write(layer_path(&store, LAYER), b"layer contents").unwrap();
+        fs::write(store.join("admin%2fdb.label"), format!("2\n{LAYER}\n")).unwrap();
+        fs::write(
+            store.join(STORAGE_VERSION_FILE),
+            StorageVersion::V11.version_file(),
+        )
+        .unwrap();
+        fs::write(store.join(STORE_MANIFEST), "{}").unwrap();
+        let archive = dir.join("store.tar.zst");
+        let archive = archive.to_str().unwrap();
+
+        let manifest = write_store_archive(store.to_str().unwrap(), archive)
+            .await
+            .unwrap();
+        assert_eq!(1, manifest.layers.len());
+        assert_eq!(Some(LAYER), manifest.labels[0].layer.as_deref());
+
+        let unpacked = dir.join("unpacked");
+        unpack_store_archive(archive, unpacked.to_str().unwrap(), false)
+            .await
+            .unwrap();
+        for file in [
+            format!("{}/{LAYER}.larch", &LAYER[..3]),
+            "admin%2fdb.label".to_string(),
+            STORAGE_VERSION_FILE.to_string(),
+            STORE_MANIFEST.to_string(),
+        ] {
+            assert_eq!(
+                fs::read(store.join(&file)).unwrap(),
+                fs::read(unpacked.join(&file)).unwrap(),
+                "{file}"
+            );
+        }
+        assert!(!unpacked.join(ARCHIVE_MANIFEST).exists());
+
+        assert!(matches!(
+            unpack_store_archive(archive, unpacked.to_str().unwrap(), false).await,
+            Err(StoreArchiveError::DestinationNotEmpty(_))
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+//! Advisory locks that keep two conversions from working on the same
+//! stores or workdir at once.
+//!
+//! A lock is a file in the locked directory, locked with `flock` and
+//! holding the PID of the process that holds it. The lock files are
+//! removed again when the lock is released. The source store is only
+//! read, so nothing is written to it: the directory itself is locked
+//! with `flock` instead, and the PID of the holder goes into a file in
+//! the temporary directory that is named after the source.
+//!
+//! Note that TerminusDB itself does not take these locks, so they can't
+//! keep a running server from writing to its store. `check_server_port`
+//! is there for that.
+use fs2::FileExt;
+use sha2::{Digest, Sha256};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use thiserror::Error;
+
+pub const LOCK_FILE: &str = ".terminusdb-10-to-11.lock";
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error("`{path}` is in use by another conversion{}", holder(.pid))]
+    Locked { path: String, pid: Option<u32> },
+    #[error("something is listening on port {0}. Stop the TerminusDB server before converting its store")]
+    ServerRunning(u16),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+fn holder(pid: &Option<u32>) -> String {
+    match pid {
+        Some(pid) => format!(" (pid {pid})"),
+        None => String::new(),
+    }
+}
+
+/// An exclusive lock on a directory, held until it is released or
+/// dropped.
+pub struct DirectoryLock {
+    /// The lock file, which is removed on release.
+    path: Option<PathBuf>,
+    file: Option<File>,
+}
+
+impl DirectoryLock {
+    /// Lock an existing directory.
+    pub fn acquire(dir: &Path) -> Result<Self, LockError> {
+        let mut path = dir.to_path_buf();
+        path.push(LOCK_FILE);
+        loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            if let Err(e) = file.try_lock_exclusive() {
+                if e.raw_os_error() != fs2::lock_contended_error().raw_os_error() {
+                    return Err(e.into());
+                }
+                return Err(LockError::Locked {
+                    path: dir.to_string_lossy().into_owned(),
+                    pid: read_pid(&mut file),
+                });
+            }
+            // The previous holder may have removed the lock file in
+            // between us opening and locking it, in which case we hold a
+            // lock on a file nobody else will ever see.
+            if !same_file(&file, &path)? {
+                continue;
+            }
+            file.set_len(0)?;
+            file.rewind()?;
+            write!(file, "{}", std::process::id())?;
+            file.sync_data()?;
+
+            return Ok(Self {
+                path: Some(path),
+                file: Some(file),
+            });
+        }
+    }
+
+    /// Lock a directory that is only read from, without writing to it.
+    /// The PID of the holder is kept in the file [`source_pid_file`]
+    /// names instead. If that file can't be written, the lock is still
+    /// held, but other conversions won't know by whom.
+    #[cfg(unix)]
+    pub fn acquire_source(dir: &Path) -> Result<Self, LockError> {
+        let file = File::open(dir)?;
+        let pid_path = source_pid_file(dir)?;
+        if let Err(e) = file.try_lock_exclusive() {
+            if e.raw_os_error() != fs2::lock_contended_error().raw_os_error() {
+                return Err(e.into());
+            }
+            let pid = fs::read_to_string(&pid_path)
+                .ok()
+                .and_then(|contents| contents.trim().parse().ok());
+            return Err(LockError::Locked {
+                path: dir.to_string_lossy().into_owned(),
+                pid,
+            });
+        }
+        let path = fs::write(&pid_path, std::process::id().to_string())
+            .ok()
+            .map(|()| pid_path);
+
+        Ok(Self {
+            path,
+            file: Some(file),
+        })
+    }
+
+    /// Directories can't be opened as files here, so sources are not
+    /// locked.
+    #[cfg(not(unix))]
+    pub fn acquire_source(_dir: &Path) -> Result<Self, LockError> {
+        Ok(Self {
+            path: None,
+            file: None,
+        })
+    }
+
+    /// Remove the lock file and release the lock.
+    pub fn release(mut self) -> io::Result<()> {
+        self.remove()
+    }
+
+    fn remove(&mut self) -> io::Result<()> {
+        if let Some(file) = self.file.take() {
+            // The lock file can already be gone, for instance when the
+            // workdir was cleaned up.
+            if let Some(path) = self.path.as_ref() {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            file.unlock()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for DirectoryLock {
+    fn drop(&mut self) {
+        let _ = self.remove();
+    }
+}
+
+/// Check that no conversion holds the lock on a directory, without
+/// taking it.
+pub fn check_unlocked(dir: &Path) -> Result<(), LockError> {
+    let mut path = dir.to_path_buf();
+    path.push(LOCK_FILE);
+    let mut file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if let Err(e) = FileExt::try_lock_shared(&file) {
+        if e.raw_os_error() != fs2::lock_contended_error().raw_os_error() {
+            return Err(e.into());
+        }
+        return Err(LockError::Locked {
+            path: dir.to_string_lossy().into_owned(),
+            pid: read_pid(&mut file),
+        });
+    }
+    FileExt::unlock(&file)?;
+
+    Ok(())
+}
+
+/// The file holding the PID of the conversion that locked the source
+/// directory `dir`. It is named after a hash of the canonical path of
+/// the directory, so every conversion of the same store finds it.
+fn source_pid_file(dir: &Path) -> io::Result<PathBuf> {
+    let canonical = fs::canonicalize(dir)?;
+    let hash = Sha256::digest(canonical.as_os_str().as_encoded_bytes());
+    let mut path = std::env::temp_dir();
+    path.push(format!(
+        "terminusdb-10-to-11-source-{}.pid",
+        hex::encode(&hash[..8])
+    ));
+
+    Ok(path)
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+#[cfg(unix)]
+fn same_file(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let opened = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(current) => Ok(opened.dev() == current.dev() && opened.ino() == current.ino()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(_file: &File, path: &Path) -> io::Result<bool> {
+    Ok(path.exists())
+}
+
+/// Locks on all directories a conversion works with.
+pub struct StoreLocks(Vec<DirectoryLock>);
+
+impl StoreLocks {
+    /// Lock the `source` directory, if there is one, and every one of
+    /// `dirs`. A directory that is given more than once, for instance a
+    /// workdir that is also the destination, is only locked once.
+    pub fn acquire(source: Option<&str>, dirs: &[&str]) -> Result<Self, LockError> {
+        let mut locked: Vec<PathBuf> = Vec::with_capacity(dirs.len() + 1);
+        let mut locks = Vec::with_capacity(dirs.len() + 1);
+        if let Some(source) = source {
+            let source = Path::new(source);
+            locks.push(DirectoryLock::acquire_source(source)?);
+            locked.push(fs::canonicalize(source)?);
+        }
+        for dir in dirs {
+            let dir = Path::new(dir);
+            let canonical = fs::canonicalize(dir)?;
+            if locked.contains(&canonical) {
+                continue;
+            }
+            locks.push(DirectoryLock::acquire(dir)?);
+            locked.push(canonical);
+        }
+
+        Ok(Self(locks))
+    }
+
+    /// Release all locks. This has to be done before moving any of the
+    /// locked directories.
+    pub fn release(self) -> io::Result<()> {
+        for lock in self.0 {
+            lock.release()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Refuse to continue if anything accepts connections on the given
+/// port on this machine.
+pub fn check_server_port(port: u16) -> Result<(), LockError> {
+    let address = SocketAddr::from(([127, 0, 0, 1], port));
+    match TcpStream::connect_timeout(&address, Duration::from_secs(1)) {
+        Ok(_) => Err(LockError::ServerRunning(port)),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn sources_are_locked_without_writing_to_them() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("store_lock_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (source, work) = (dir.join("source"), dir.join("work"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&work).unwrap();
+        let (source, work) = (source.to_str().unwrap(), work.to_str().unwrap());
+
+        let locks = StoreLocks::acquire(Some(source), &[work]).unwrap();
+        assert_eq!(0, fs::read_dir(source).unwrap().count());
+        assert!(matches!(
+            StoreLocks::acquire(Some(source), &[]),
+            Err(LockError::Locked { pid: Some(pid), .. }) if pid == std::process::id()
+        ));
+        assert!(matches!(
+            check_unlocked(Path::new(work)),
+            Err(LockError::Locked { pid: Some(_), .. })
+        ));
+
+        locks.release().unwrap();
+        assert!(!source_pid_file(Path::new(source)).unwrap().exists());
+        assert!(StoreLocks::acquire(Some(source), &[]).is_ok());
+        assert_eq!(0, fs::read_dir(work).unwrap().count());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
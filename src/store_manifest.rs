@@ -0,0 +1,394 @@
+//! An auditable record of what was migrated into a v11 store.
+//!
+//! After a successful conversion, a `MANIFEST.json` is written into the
+//! v11 store. It lists every converted layer with the sha256 hashes of
+//! its v10 source files and of its v11 `.larch` file, its triple and
+//! dictionary counts, and every label in the store. `check-manifest`
+//! re-hashes the store later on to detect bit rot or tampering.
+use terminus_store_10::storage as storage_10;
+use terminus_store_10::storage::directory as directory_10;
+use terminus_store_11::storage as storage_11;
+use terminus_store_11::storage::archive as archive_11;
+use terminus_store_11::storage::{name_to_string, string_to_name};
+
+use crate::consts::*;
+use crate::convert_labels::parse_label;
+use crate::durable::write_file_durably;
+use crate::store_archive::{hash_file, layer_path, store_labels, store_layers};
+use crate::tar_store::*;
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::io::AsyncReadExt;
+
+pub const STORE_MANIFEST: &str = "MANIFEST.json";
+
+const HASH_BUFFER_SIZE: usize = 1 << 20;
+
+#[derive(Error, Debug)]
+pub enum ManifestError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("could not read {STORE_MANIFEST}: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("`{0}` has no {STORE_MANIFEST}")]
+    Missing(String),
+    #[error("{} differences found between the store and its manifest", .0.len())]
+    Mismatch(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ManifestLayer {
+    pub id: String,
+    pub parent: Option<String>,
+    /// sha256 of every file of the v10 layer, by file name.
+    pub v10_files: BTreeMap<String, String>,
+    pub larch_sha256: String,
+    pub triples_added: usize,
+    pub triples_removed: usize,
+    /// Entries added to the dictionaries by this layer.
+    pub nodes: usize,
+    pub predicates: usize,
+    pub values: usize,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+pub struct ManifestLabel {
+    pub name: String,
+    pub version: u64,
+    pub layer: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StoreManifest {
+    pub tool_version: String,
+    pub source: String,
+    pub created: String,
+    pub layers: Vec<ManifestLayer>,
+    pub labels: Vec<ManifestLabel>,
+}
+
+fn manifest_path(store: &str) -> PathBuf {
+    let mut path = PathBuf::from(store);
+    path.push(STORE_MANIFEST);
+    path
+}
+
+/// Read the manifest of a v11 store, if it has one.
+pub async fn read_store_manifest(store: &str) -> Result<Option<StoreManifest>, ManifestError> {
+    match tokio::fs::read(manifest_path(store)).await {
+        Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The sha256 hash of every file of a v10 layer. The files are read a
+/// buffer at a time, rather than loaded whole.
+pub async fn hash_v10_layer<S: storage_10::PersistentLayerStore>(
+    store: &S,
+    layer: [u32; 5],
+) -> io::Result<BTreeMap<String, String>> {
+    let mut hashes = BTreeMap::new();
+    let mut buf = vec![0; HASH_BUFFER_SIZE];
+    for file in V10_FILENAMES.all() {
+        if storage_10::PersistentLayerStore::file_exists(store, layer, file).await? {
+            let file_handle =
+                storage_10::PersistentLayerStore::get_file(store, layer, file).await?;
+            let mut reader = storage_10::FileLoad::open_read(&file_handle).await?;
+            let mut hasher = Sha256::new();
+            loop {
+                let read = reader.read(&mut buf).await?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buf[..read]);
+            }
+            hashes.insert(file.to_string(), hex::encode(hasher.finalize()));
+        }
+    }
+
+    Ok(hashes)
+}
+
+async fn manifest_layer<S: storage_10::PersistentLayerStore>(
+    v10_layer_store: &S,
+    v11_layer_store: &archive_11::ArchiveLayerStore,
+    to: &str,
+    layer: [u32; 5],
+) -> io::Result<ManifestLayer> {
+    let id = name_to_string(layer);
+    let v11_layer = storage_11::LayerStore::get_layer(v11_layer_store, layer)
+        .await?
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("converted layer {id} not found"),
+            )
+        })?;
+    let larch = layer_path(Path::new(to), &id);
+
+    Ok(ManifestLayer {
+        parent: v11_layer.parent_name().map(name_to_string),
+        v10_files: hash_v10_layer(v10_layer_store, layer).await?,
+        larch_sha256: tokio::task::spawn_blocking(move || hash_file(&larch))
+            .await
+            .unwrap()?,
+        triples_added: v11_layer.internal_triple_layer_addition_count(),
+        triples_removed: v11_layer.internal_triple_layer_removal_count(),
+        nodes: v11_layer.node_dictionary().num_entries(),
+        predicates: v11_layer.predicate_dictionary().num_entries(),
+        values: v11_layer.value_dictionary().num_entries(),
+        id,
+    })
+}
+
+async fn manifest_labels(store: &str) -> io::Result<Vec<ManifestLabel>> {
+    let mut labels = Vec::new();
+    for name in store_labels(Path::new(store))? {
+        let mut path = PathBuf::from(store);
+        path.push(format!("{name}.label"));
+        let state = parse_label(&tokio::fs::read(path).await?)?;
+        labels.push(ManifestLabel {
+            name,
+            version: state.version,
+            layer: state.layer.map(name_to_string),
+        });
+    }
+
+    Ok(labels)
+}
+
+/// Write the manifest for the given layers. Only the layers converted
+/// in this run are hashed. The others keep the entry an existing
+/// manifest has for them, and are only hashed when it has none, such
+/// as layers from an earlier partial conversion. Layers listed in an
+/// existing manifest that are not among the given ones are kept as
+/// long as they are in the store. Labels are always listed as they are
+/// in the store now.
+pub async fn write_store_manifest<S: storage_10::PersistentLayerStore>(
+    v10_layer_store: &S,
+    from: &str,
+    to: &str,
+    layers: &[[u32; 5]],
+    converted: &[[u32; 5]],
+) -> Result<StoreManifest, ManifestError> {
+    let v11_layer_store = archive_11::ArchiveLayerStore::new(to);
+    let converted: HashSet<_> = converted.iter().map(|l| name_to_string(*l)).collect();
+    let mut existing: BTreeMap<_, _> = match read_store_manifest(to).await? {
+        Some(existing) => {
+            let present: HashSet<_> = store_layers(Path::new(to))?.into_iter().collect();
+            existing
+                .layers
+                .into_iter()
+                .filter(|l| !converted.contains(&l.id) && present.contains(&l.id))
+                .map(|l| (l.id.clone(), l))
+                .collect()
+        }
+        None => BTreeMap::new(),
+    };
+    for layer in layers {
+        if let Entry::Vacant(entry) = existing.entry(name_to_string(*layer)) {
+            entry.insert(manifest_layer(v10_layer_store, &v11_layer_store, to, *layer).await?);
+        }
+    }
+    let layers = existing.into_values().collect();
+
+    let manifest = StoreManifest {
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        source: from.to_string(),
+        created: chrono::Utc::now().to_rfc3339(),
+        layers,
+        labels: manifest_labels(to).await?,
+    };
+    write_file_durably(&manifest_path(to), &serde_json::to_vec_pretty(&manifest)?).await?;
+
+    Ok(manifest)
+}
+
+/// Compare a v11 store with its manifest. With `source`, the v10 files
+/// of every layer are checked against the given v10 store as well.
+pub async fn check_store_manifest(
+    store: &str,
+    source: Option<&str>,
+    verbose: bool,
+) -> Result<(), ManifestError> {
+    let manifest = read_store_manifest(store)
+        .await?
+        .ok_or_else(|| ManifestError::Missing(store.to_string()))?;
+
+    let mut mismatches = check_layers(store, &manifest, verbose).await?;
+    if let Some(source) = source {
+        if is_tar_archive(source) {
+            let (v10_layer_store, _) = open_tar_store(source).await?;
+            mismatches.extend(check_sources(&v10_layer_store, &manifest, verbose).await?);
+        } else {
+            let v10_layer_store = directory_10::DirectoryLayerStore::new(source);
+            mismatches.extend(check_sources(&v10_layer_store, &manifest, verbose).await?);
+        }
+    }
+
+    let labels = manifest_labels(store).await?;
+    for label in manifest.labels.iter() {
+        match labels.iter().find(|l| l.name == label.name) {
+            Some(found) if found == label => {}
+            Some(_) => mismatches.push(format!("label {} has different contents", label.name)),
+            None => mismatches.push(format!("label {} is missing", label.name)),
+        }
+    }
+    for label in labels.iter() {
+        if !manifest.labels.iter().any(|l| l.name == label.name) {
+            mismatches.push(format!("label {} is not in the manifest", label.name));
+        }
+    }
+
+    let listed: HashSet<_> = manifest.layers.iter().map(|l| l.id.as_str()).collect();
+    let unlisted = store_layers(Path::new(store))?
+        .into_iter()
+        .filter(|id| !listed.contains(id.as_str()))
+        .count();
+    if unlisted != 0 {
+        // layers written by TerminusDB after the migration
+        println!("{unlisted} layers in the store are not in the manifest");
+    }
+
+    if mismatches.is_empty() {
+        println!(
+            "{} layers and {} labels match the manifest",
+            manifest.layers.len(),
+            manifest.labels.len()
+        );
+        Ok(())
+    } else {
+        for mismatch in mismatches.iter() {
+            eprintln!("manifest mismatch: {mismatch}");
+        }
+        Err(ManifestError::Mismatch(mismatches))
+    }
+}
+
+async fn check_layers(
+    store: &str,
+    manifest: &StoreManifest,
+    verbose: bool,
+) -> io::Result<Vec<String>> {
+    let mut mismatches = Vec::new();
+    for layer in manifest.layers.iter() {
+        let path = layer_path(Path::new(store), &layer.id);
+        let hash = match tokio::task::spawn_blocking(move || hash_file(&path))
+            .await
+            .unwrap()
+        {
+            Ok(hash) => hash,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                mismatches.push(format!("layer {} is missing", layer.id));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if hash != layer.larch_sha256 {
+            mismatches.push(format!("layer {} has a different hash", layer.id));
+        } else if verbose {
+            println!("layer verified: {}", layer.id);
+        }
+    }
+
+    Ok(mismatches)
+}
+
+async fn check_sources<S: storage_10::PersistentLayerStore>(
+    v10_layer_store: &S,
+    manifest: &StoreManifest,
+    verbose: bool,
+) -> io::Result<Vec<String>> {
+    let mut mismatches = Vec::new();
+    for layer in manifest.layers.iter() {
+        let name = string_to_name(&layer.id)?;
+        if !storage_10::PersistentLayerStore::directory_exists(v10_layer_store, name).await? {
+            mismatches.push(format!("source layer {} is missing", layer.id));
+            continue;
+        }
+        let hashes = hash_v10_layer(v10_layer_store, name).await?;
+        if hashes != layer.v10_files {
+            mismatches.push(format!("source layer {} has different files", layer.id));
+        } else if verbose {
+            println!("source layer verified: {}", layer.id);
+        }
+    }
+
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer_modes::ValueMode;
+    use crate::test_store::TestStore;
+
+    #[tokio::test]
+    async fn v10_files_are_hashed_whole() {
+        let store = TestStore::new("manifest_hashes");
+        let (base, _) = store.layers_with_equal_values().await;
+        let v10 = directory_10::DirectoryLayerStore::new(store.path("v10"));
+
+        let hashes = hash_v10_layer(&v10, base).await.unwrap();
+        let id = name_to_string(base);
+        let dir = store.dir.join("v10").join(&id[..3]).join(&id);
+        let mut files = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let entry = entry.unwrap();
+            let name = entry.file_name().into_string().unwrap();
+            let contents = std::fs::read(entry.path()).unwrap();
+            assert_eq!(hex::encode(Sha256::digest(contents)), hashes[&name]);
+            files += 1;
+        }
+        assert_eq!(files, hashes.len());
+    }
+
+    #[tokio::test]
+    async fn only_converted_layers_are_hashed_again() {
+        let store = TestStore::new("manifest_merge");
+        let (base, child) = store.layers_with_equal_values().await;
+        store.convert(base, ValueMode::Auto).await;
+        store.convert(child, ValueMode::Auto).await;
+        let v10 = directory_10::DirectoryLayerStore::new(store.path("v10"));
+        let (from, to) = (store.path("v10"), store.path("v11"));
+
+        // layers that are not in the manifest yet are hashed, whether
+        // they were converted in this run or not
+        let manifest = write_store_manifest(&v10, &from, &to, &[base, child], &[child])
+            .await
+            .unwrap();
+        assert_eq!(2, manifest.layers.len());
+        for layer in manifest.layers.iter() {
+            let larch = layer_path(Path::new(&to), &layer.id);
+            assert_eq!(hash_file(&larch).unwrap(), layer.larch_sha256);
+        }
+
+        // the entries of layers that were not converted again are kept
+        // as they are, and those of converted layers replaced
+        let mut edited = read_store_manifest(&to).await.unwrap().unwrap();
+        for layer in edited.layers.iter_mut() {
+            layer.larch_sha256 = "edited".to_string();
+        }
+        write_file_durably(&manifest_path(&to), &serde_json::to_vec(&edited).unwrap())
+            .await
+            .unwrap();
+        let manifest = write_store_manifest(&v10, &from, &to, &[base, child], &[child])
+            .await
+            .unwrap();
+        let hashes: BTreeMap<_, _> = manifest
+            .layers
+            .iter()
+            .map(|l| (l.id.clone(), l.larch_sha256.clone()))
+            .collect();
+        assert_eq!("edited", hashes[&name_to_string(base)]);
+        assert_ne!("edited", hashes[&name_to_string(child)]);
+    }
+}
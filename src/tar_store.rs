@@ -0,0 +1,680 @@
+//! A read-only v10 store that reads layers and labels straight out of
+//! a tar archive of a v10 storage directory, so that a backup can be
+//! converted without unpacking it first.
+//!
+//! On opening, the archive is read once to build an index of where
+//! each layer file lives. Plain `.tar` archives are then read from by
+//! seeking to the indexed position. Compressed `.tar.gz` archives can't
+//! be seeked in, so all layer files are kept in memory while indexing
+//! and the archive is never decompressed again. Compressed archives
+//! whose layer files take more than [`MAX_DECOMPRESSED_SIZE`] are
+//! refused.
+use terminus_store_10::storage as storage_10;
+use terminus_store_10::storage::{name_to_string, string_to_name, Label};
+
+use crate::convert_labels::parse_label;
+use crate::storage_version::{is_layer_archive_name, StoreLayout, STORAGE_VERSION_FILE};
+
+use std::collections::HashMap;
+use std::io::{self, Read, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+
+/// Files up to this size are kept in the index, so that things like
+/// parent and rollup files can be read without touching the archive.
+const INLINE_FILE_SIZE: u64 = 1024;
+
+/// How many bytes of layer files a compressed archive may hold, as
+/// they are all kept in memory.
+const MAX_DECOMPRESSED_SIZE: u64 = 1 << 30;
+
+/// Whether the given source path should be read as a tar archive
+/// rather than as a storage directory.
+pub fn is_tar_archive(path: &str) -> bool {
+    is_compressed_tar_archive(path) || path.ends_with(".tar")
+}
+
+fn is_compressed_tar_archive(path: &str) -> bool {
+    path.ends_with(".tar.gz") || path.ends_with(".tgz")
+}
+
+struct ArchivedFile {
+    /// Position of the file contents in the archive
+    offset: u64,
+    size: u64,
+    contents: Option<Bytes>,
+}
+
+/// The files of a single layer, by file name.
+type ArchivedLayer = HashMap<String, ArchivedFile>;
+type ArchivedLayers = HashMap<[u32; 5], ArchivedLayer>;
+
+struct TarIndex {
+    path: PathBuf,
+    layers: ArchivedLayers,
+    labels: HashMap<String, Label>,
+    layout: StoreLayout,
+}
+
+#[derive(Clone)]
+pub struct TarLayerStore {
+    index: Arc<TarIndex>,
+}
+
+#[derive(Clone)]
+pub struct TarLabelStore {
+    index: Arc<TarIndex>,
+}
+
+#[derive(Clone)]
+pub struct TarFile {
+    index: Arc<TarIndex>,
+    layer: [u32; 5],
+    name: String,
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "stores read from a tar archive are read-only",
+    )
+}
+
+/// Open a tar archive of a v10 storage directory, indexing its contents.
+pub async fn open_tar_store(path: &str) -> io::Result<(TarLayerStore, TarLabelStore)> {
+    let path = PathBuf::from(path);
+    let compressed = is_compressed_tar_archive(&path.to_string_lossy());
+    let index = tokio::task::spawn_blocking(move || build_index(path, compressed))
+        .await
+        .unwrap()?;
+    let index = Arc::new(index);
+
+    Ok((
+        TarLayerStore {
+            index: index.clone(),
+        },
+        TarLabelStore { index },
+    ))
+}
+
+/// Where a file is in the store, relative to the store root.
+enum StorePath {
+    LayerFile([u32; 5], String),
+    /// A TerminusDB 11 layer file.
+    LayerArchive,
+    /// A file of a layer directory right in the storage directory, as
+    /// in stores from before TerminusDB 10.
+    UnprefixedLayerFile,
+    Label(String),
+    VersionFile,
+    Other,
+}
+
+fn is_hex(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn classify_path(path: &Path) -> (Vec<String>, StorePath) {
+    let mut components: Vec<String> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(c.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    let len = components.len();
+
+    if len >= 3
+        && components[len - 3].len() == 3
+        && components[len - 2].starts_with(&components[len - 3])
+    {
+        if let Ok(layer) = string_to_name(&components[len - 2]) {
+            let file = components.pop().unwrap();
+            components.truncate(len - 3);
+            return (components, StorePath::LayerFile(layer, file));
+        }
+    }
+    // a layer directory under a prefix directory of another layer is
+    // not part of a store
+    let misprefixed = len >= 3 && components[len - 3].len() == 3 && is_hex(&components[len - 3]);
+    if len >= 2 && components[len - 2].len() == 40 && string_to_name(&components[len - 2]).is_ok() {
+        if misprefixed {
+            return (components, StorePath::Other);
+        }
+        components.truncate(len - 2);
+        return (components, StorePath::UnprefixedLayerFile);
+    }
+    if let Some(file) = components.pop() {
+        if let Some(label) = file.strip_suffix(".label") {
+            return (components, StorePath::Label(label.to_string()));
+        }
+        if is_layer_archive_name(&file) {
+            // without the prefix directory
+            components.pop();
+            return (components, StorePath::LayerArchive);
+        }
+        if file == STORAGE_VERSION_FILE {
+            return (components, StorePath::VersionFile);
+        }
+    }
+
+    (components, StorePath::Other)
+}
+
+fn build_index(path: PathBuf, compressed: bool) -> io::Result<TarIndex> {
+    let file = std::fs::File::open(&path)?;
+    let (layers, labels, layout) = if compressed {
+        let mut archive = tar::Archive::new(GzDecoder::new(io::BufReader::new(file)));
+        index_entries(archive.entries()?, Some(MAX_DECOMPRESSED_SIZE))?
+    } else {
+        let mut archive = tar::Archive::new(file);
+        index_entries(archive.entries_with_seek()?, None)?
+    };
+
+    Ok(TarIndex {
+        path,
+        layers,
+        labels,
+        layout,
+    })
+}
+
+/// Index the entries of an archive. With `keep_up_to`, the contents of
+/// all layer files are kept, as long as they don't take more than that
+/// many bytes together.
+fn index_entries<R: Read>(
+    entries: tar::Entries<'_, R>,
+    keep_up_to: Option<u64>,
+) -> io::Result<(ArchivedLayers, HashMap<String, Label>, StoreLayout)> {
+    let mut root = None;
+    let mut layers: ArchivedLayers = HashMap::new();
+    let mut label_candidates = Vec::new();
+    let mut version_candidates = Vec::new();
+    let mut unprefixed_candidates = Vec::new();
+    let mut layout = StoreLayout::default();
+    let mut kept = 0;
+    for entry in entries {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let (parent, store_path) = classify_path(&entry.path()?);
+        match store_path {
+            StorePath::LayerFile(layer, name) => {
+                match &root {
+                    None => root = Some(parent),
+                    Some(root) if *root == parent => {}
+                    Some(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "archive contains layers of more than one store",
+                        ))
+                    }
+                }
+                let offset = entry.raw_file_position();
+                let size = entry.size();
+                let keep = match keep_up_to {
+                    Some(limit) => {
+                        kept += size;
+                        if kept > limit {
+                            return Err(io::Error::new(
+                                io::ErrorKind::Unsupported,
+                                format!(
+                                    "the layers in the archive take more than {limit} bytes \
+                                     decompressed, decompress it and convert the .tar instead"
+                                ),
+                            ));
+                        }
+                        true
+                    }
+                    None => size <= INLINE_FILE_SIZE,
+                };
+                let contents = if keep {
+                    let mut contents = Vec::with_capacity(size as usize);
+                    entry.read_to_end(&mut contents)?;
+                    Some(contents.into())
+                } else {
+                    None
+                };
+                layers.entry(layer).or_default().insert(
+                    name,
+                    ArchivedFile {
+                        offset,
+                        size,
+                        contents,
+                    },
+                );
+            }
+            StorePath::Label(name) => {
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents)?;
+                label_candidates.push((parent, name, contents));
+            }
+            StorePath::VersionFile => {
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents)?;
+                version_candidates.push((parent, contents));
+            }
+            StorePath::LayerArchive => layout.layer_archives = true,
+            StorePath::UnprefixedLayerFile => unprefixed_candidates.push(parent),
+            StorePath::Other => {}
+        }
+    }
+
+    // layer directories without a prefix are only part of the store if
+    // they're right in the storage directory, which is where the layers,
+    // the version file or the labels are
+    let store_root = root.clone().or_else(|| {
+        version_candidates
+            .first()
+            .map(|(parent, _)| parent.clone())
+            .or_else(|| {
+                label_candidates
+                    .first()
+                    .map(|(parent, _, _)| parent.clone())
+            })
+    });
+    layout.unprefixed_layers = unprefixed_candidates
+        .iter()
+        .any(|parent| store_root.is_none() || store_root.as_ref() == Some(parent));
+
+    // label files are only part of the store if they're next to the
+    // layer directories
+    let mut labels = HashMap::new();
+    for (parent, name, contents) in label_candidates {
+        if root.is_none() || root.as_ref() == Some(&parent) {
+            let state = parse_label(&contents)?;
+            labels.insert(
+                name.clone(),
+                Label {
+                    name,
+                    layer: state.layer,
+                    version: state.version,
+                },
+            );
+        }
+    }
+
+    layout.layer_directories = !layers.is_empty();
+    layout.version_file = version_candidates
+        .into_iter()
+        .find(|(parent, _)| root.is_none() || root.as_ref() == Some(parent))
+        .map(|(_, contents)| contents);
+
+    Ok((layers, labels, layout))
+}
+
+impl TarIndex {
+    fn file(&self, layer: [u32; 5], name: &str) -> io::Result<&ArchivedFile> {
+        self.layers
+            .get(&layer)
+            .and_then(|files| files.get(name))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{}/{} is not in the archive", name_to_string(layer), name),
+                )
+            })
+    }
+}
+
+impl TarFile {
+    async fn read_all(&self) -> io::Result<Bytes> {
+        let file = self.index.file(self.layer, &self.name)?;
+        if let Some(contents) = &file.contents {
+            return Ok(contents.clone());
+        }
+
+        let mut archive = tokio::fs::File::open(&self.index.path).await?;
+        archive.seek(SeekFrom::Start(file.offset)).await?;
+        let mut contents = vec![0; file.size as usize];
+        archive.read_exact(&mut contents).await?;
+        Ok(contents.into())
+    }
+}
+
+#[async_trait]
+impl storage_10::FileLoad for TarFile {
+    type Read = Box<dyn AsyncRead + Unpin + Send>;
+
+    async fn exists(&self) -> io::Result<bool> {
+        Ok(self.index.file(self.layer, &self.name).is_ok())
+    }
+
+    async fn size(&self) -> io::Result<usize> {
+        Ok(self.index.file(self.layer, &self.name)?.size as usize)
+    }
+
+    async fn open_read_from(&self, offset: usize) -> io::Result<Self::Read> {
+        let file = self.index.file(self.layer, &self.name)?;
+        if offset as u64 > file.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "cannot read {} from offset {offset}, it is only {} bytes",
+                    self.name, file.size
+                ),
+            ));
+        }
+        if file.contents.is_some() {
+            let contents = self.read_all().await?;
+            return Ok(Box::new(io::Cursor::new(contents.slice(offset..))));
+        }
+
+        let mut archive = tokio::fs::File::open(&self.index.path).await?;
+        archive
+            .seek(SeekFrom::Start(file.offset + offset as u64))
+            .await?;
+        Ok(Box::new(archive.take(file.size - offset as u64)))
+    }
+
+    async fn map(&self) -> io::Result<Bytes> {
+        self.read_all().await
+    }
+}
+
+#[async_trait]
+impl storage_10::FileStore for TarFile {
+    type Write = tokio::fs::File;
+
+    async fn open_write(&self) -> io::Result<Self::Write> {
+        Err(read_only())
+    }
+}
+
+impl TarLayerStore {
+    /// What the archive was found to hold, to check that it is a v10
+    /// store.
+    pub fn layout(&self) -> &StoreLayout {
+        &self.index.layout
+    }
+}
+
+#[async_trait]
+impl storage_10::PersistentLayerStore for TarLayerStore {
+    type File = TarFile;
+
+    async fn directories(&self) -> io::Result<Vec<[u32; 5]>> {
+        Ok(self.index.layers.keys().cloned().collect())
+    }
+
+    async fn create_named_directory(&self, _id: [u32; 5]) -> io::Result<[u32; 5]> {
+        Err(read_only())
+    }
+
+    async fn directory_exists(&self, name: [u32; 5]) -> io::Result<bool> {
+        Ok(self.index.layers.contains_key(&name))
+    }
+
+    async fn get_file(&self, directory: [u32; 5], name: &str) -> io::Result<Self::File> {
+        Ok(TarFile {
+            index: self.index.clone(),
+            layer: directory,
+            name: name.to_string(),
+        })
+    }
+
+    async fn file_exists(&self, directory: [u32; 5], file: &str) -> io::Result<bool> {
+        Ok(self.index.file(directory, file).is_ok())
+    }
+}
+
+#[async_trait]
+impl storage_10::LabelStore for TarLabelStore {
+    async fn labels(&self) -> io::Result<Vec<Label>> {
+        Ok(self.index.labels.values().cloned().collect())
+    }
+
+    async fn create_label(&self, _name: &str) -> io::Result<Label> {
+        Err(read_only())
+    }
+
+    async fn get_label(&self, name: &str) -> io::Result<Option<Label>> {
+        Ok(self.index.labels.get(name).cloned())
+    }
+
+    async fn set_label_option(
+        &self,
+        _label: &Label,
+        _layer: Option<[u32; 5]>,
+    ) -> io::Result<Option<Label>> {
+        Err(read_only())
+    }
+
+    async fn delete_label(&self, _name: &str) -> io::Result<bool> {
+        Err(read_only())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert_layer::{convert_layer_with_stores, LayerSettings};
+    use crate::dataconversion::DatatypeMap;
+    use crate::layer_modes::ValueMode;
+    use crate::observer::SilentObserver;
+    use crate::test_store::TestStore;
+
+    #[test]
+    fn classify_store_paths() {
+        let layer = "c79ce872a1851b569f9d81da5f7b916d4e6fde1e";
+        let (root, path) = classify_path(Path::new(&format!(
+            "./backup/storage/c79/{layer}/node_dictionary_blocks.pfc"
+        )));
+        assert_eq!(vec!["backup", "storage"], root);
+        match path {
+            StorePath::LayerFile(l, file) => {
+                assert_eq!(layer, name_to_string(l));
+                assert_eq!("node_dictionary_blocks.pfc", file);
+            }
+            _ => panic!("expected a layer file"),
+        }
+
+        let (root, path) = classify_path(Path::new("storage/admin%2fdb.label"));
+        assert_eq!(vec!["storage"], root);
+        assert!(matches!(path, StorePath::Label(l) if l == "admin%2fdb"));
+
+        // the prefix directory has to match the layer name
+        let (_, path) = classify_path(Path::new(&format!("abc/{layer}/parent.hex")));
+        assert!(matches!(path, StorePath::Other));
+
+        let (root, path) = classify_path(Path::new(&format!("storage/{layer}/parent.hex")));
+        assert_eq!(vec!["storage"], root);
+        assert!(matches!(path, StorePath::UnprefixedLayerFile));
+
+        let (root, path) = classify_path(Path::new(&format!("storage/c79/{layer}.larch")));
+        assert_eq!(vec!["storage"], root);
+        assert!(matches!(path, StorePath::LayerArchive));
+    }
+
+    /// An archive of a store with a single layer holding a file small
+    /// enough to be kept in the index, and one that is not.
+    fn write_archive(path: &Path, layer: &str, compressed: bool) {
+        let files = [("small.pfc", 10), ("large.pfc", 2 * INLINE_FILE_SIZE)]
+            .map(|(name, size)| (format!("storage/{}/{layer}/{name}", &layer[..3]), size));
+        write_files(path, &files, compressed);
+    }
+
+    /// An archive of files of the given sizes.
+    fn write_files(path: &Path, files: &[(String, u64)], compressed: bool) {
+        let file = std::fs::File::create(path).unwrap();
+        let output: Box<dyn io::Write> = if compressed {
+            Box::new(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            ))
+        } else {
+            Box::new(file)
+        };
+        let mut builder = tar::Builder::new(output);
+        for (name, size) in files {
+            let contents: Vec<u8> = (0..*size).map(|i| i as u8).collect();
+            let mut header = tar::Header::new_gnu();
+            header.set_size(*size);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, &contents[..])
+                .unwrap();
+        }
+        builder.into_inner().unwrap().flush().unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_start_at_most_at_the_end() {
+        let layer = "c79ce872a1851b569f9d81da5f7b916d4e6fde1e";
+        for archive in ["store.tar", "store.tar.gz"] {
+            let mut path = std::env::temp_dir();
+            path.push(format!("tar_store_test_{}_{archive}", std::process::id()));
+            write_archive(&path, layer, archive.ends_with(".gz"));
+            let (store, _) = open_tar_store(path.to_str().unwrap()).await.unwrap();
+
+            for name in ["small.pfc", "large.pfc"] {
+                let file = storage_10::PersistentLayerStore::get_file(
+                    &store,
+                    string_to_name(layer).unwrap(),
+                    name,
+                )
+                .await
+                .unwrap();
+                let size = storage_10::FileLoad::size(&file).await.unwrap();
+
+                let mut rest = Vec::new();
+                let mut reader = storage_10::FileLoad::open_read_from(&file, 5)
+                    .await
+                    .unwrap();
+                reader.read_to_end(&mut rest).await.unwrap();
+                assert_eq!(size - 5, rest.len());
+                assert_eq!(5, rest[0]);
+
+                let mut reader = storage_10::FileLoad::open_read_from(&file, size)
+                    .await
+                    .unwrap();
+                assert_eq!(0, reader.read(&mut [0; 8]).await.unwrap());
+
+                let error = storage_10::FileLoad::open_read_from(&file, size + 1)
+                    .await
+                    .err()
+                    .unwrap();
+                assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+            }
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn unprefixed_layers_are_only_found_in_the_store() {
+        let layer = "c79ce872a1851b569f9d81da5f7b916d4e6fde1e";
+        let other = "0a1b2c3d4e5f60718293a4b5c6d7e8f901234567";
+        let mut path = std::env::temp_dir();
+        path.push(format!("tar_store_unprefixed_{}.tar", std::process::id()));
+        let layer_file = format!("storage/{}/{layer}/parent.hex", &layer[..3]);
+
+        let files = [
+            (layer_file.clone(), 20),
+            (format!("backup/{other}/parent.hex"), 20),
+        ];
+        write_files(&path, &files, false);
+        let (store, _) = open_tar_store(path.to_str().unwrap()).await.unwrap();
+        assert!(!store.layout().unprefixed_layers);
+
+        let files = [
+            (layer_file, 20),
+            (format!("storage/{other}/parent.hex"), 20),
+        ];
+        write_files(&path, &files, false);
+        let (store, _) = open_tar_store(path.to_str().unwrap()).await.unwrap();
+        assert!(store.layout().unprefixed_layers);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn compressed_archives_are_decompressed_once() {
+        let test = TestStore::new("tar_store_decompressed_once");
+        // enough values for dictionaries that are not kept in the index
+        let many: Vec<String> = (0..200u64)
+            .map(|i| format!("{:016x}", i.wrapping_mul(0x9e3779b97f4a7c15)))
+            .collect();
+        let many: Vec<&str> = many.iter().map(|v| &v[..]).collect();
+        let base = test.value_layer(None, &many).await;
+        let middle = test.value_layer(Some(base), &["a"]).await;
+        let top = test.value_layer(Some(middle), &["a", "b"]).await;
+
+        let path = test.dir.join("store.tar.gz");
+        let file = std::fs::File::create(&path).unwrap();
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            file,
+            flate2::Compression::default(),
+        ));
+        builder
+            .append_dir_all("storage", test.dir.join("v10"))
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let (store, _) = open_tar_store(path.to_str().unwrap()).await.unwrap();
+        assert!(store.index.layers[&base]
+            .values()
+            .any(|file| file.size > INLINE_FILE_SIZE));
+        // indexing is the only decompression pass, as any other one would
+        // have to reopen the archive
+        std::fs::remove_file(&path).unwrap();
+        for id in [base, middle, top] {
+            let settings = LayerSettings {
+                mode: ValueMode::Auto,
+                datatypes: &DatatypeMap::new(),
+                rebuild_all_indexes: false,
+            };
+            convert_layer_with_stores(
+                &store,
+                &test.path("v11"),
+                &test.path("work"),
+                settings,
+                id,
+                &SilentObserver,
+            )
+            .await
+            .unwrap();
+        }
+
+        let values = |values: &[&str]| -> Vec<_> {
+            values
+                .iter()
+                .map(|v| ("s".to_string(), "p".to_string(), format!("{v:?}")))
+                .collect()
+        };
+        let mut expected = vec!["a", "b"];
+        expected.extend(many);
+        expected.sort();
+        assert_eq!(values(&expected), test.v11_triples(top).await);
+    }
+
+    #[test]
+    fn compressed_archives_are_refused_above_the_limit() {
+        let layer = "c79ce872a1851b569f9d81da5f7b916d4e6fde1e";
+        let mut path = std::env::temp_dir();
+        path.push(format!("tar_store_limit_{}.tar", std::process::id()));
+        write_archive(&path, layer, false);
+
+        let file = std::fs::File::open(&path).unwrap();
+        let mut archive = tar::Archive::new(file);
+        let error = index_entries(archive.entries().unwrap(), Some(INLINE_FILE_SIZE))
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::Unsupported, error.kind());
+
+        let file = std::fs::File::open(&path).unwrap();
+        let mut archive = tar::Archive::new(file);
+        let (layers, _, _) =
+            index_entries(archive.entries().unwrap(), Some(4 * INLINE_FILE_SIZE)).unwrap();
+        let files = &layers[&string_to_name(layer).unwrap()];
+        assert!(files.values().all(|file| file.contents.is_some()));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+//! Small v10 stores built in a temporary directory, for tests that need
+//! real layers.
+use terminus_store_10::layer::{Layer as _, StringTriple};
+use terminus_store_11::layer::{Layer as _, ObjectType};
+use terminus_store_11::storage::name_to_string;
+use terminus_store_11::store::StoreLayer;
+
+use crate::convert_layer::{convert_layer, LayerSettings};
+use crate::dataconversion::DatatypeMap;
+use crate::layer_modes::ValueMode;
+use crate::observer::{ConversionObserver, SilentObserver};
+
+use std::path::PathBuf;
+
+/// A directory with a v10 store in `v10`, a v11 store in `v11` and a
+/// workdir in `work`, which is removed when dropped.
+pub struct TestStore {
+    pub dir: PathBuf,
+}
+
+impl TestStore {
+    pub fn new(name: &str) -> Self {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("test_store_{}_{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for sub in ["v10", "v11", "work"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+
+        Self { dir }
+    }
+
+    pub fn path(&self, sub: &str) -> String {
+        self.dir.join(sub).to_str().unwrap().to_string()
+    }
+
+    pub fn v10(&self) -> terminus_store_10::store::Store {
+        terminus_store_10::open_directory_store(self.dir.join("v10"))
+    }
+
+    /// A v10 base layer with `foo` both as a plain value and as an
+    /// xsd:string literal, and a child layer on top of it.
+    pub async fn layers_with_equal_values(&self) -> ([u32; 5], [u32; 5]) {
+        let builder = self.v10().create_base_layer().await.unwrap();
+        for (subject, predicate, object) in [
+            (
+                "a",
+                "p",
+                "\"foo\"^^'http://www.w3.org/2001/XMLSchema#string'",
+            ),
+            ("a", "p", "foo"),
+            ("a", "q", "bar"),
+        ] {
+            builder
+                .add_string_triple(StringTriple::new_value(subject, predicate, object))
+                .unwrap();
+        }
+        builder
+            .add_string_triple(StringTriple::new_node("b", "p", "a"))
+            .unwrap();
+        let base = builder.commit().await.unwrap();
+
+        let builder = base.open_write().await.unwrap();
+        builder
+            .add_string_triple(StringTriple::new_value("c", "p", "foo"))
+            .unwrap();
+        builder
+            .add_string_triple(StringTriple::new_node("c", "q", "b"))
+            .unwrap();
+        builder
+            .remove_string_triple(StringTriple::new_value("a", "q", "bar"))
+            .unwrap();
+        let child = builder.commit().await.unwrap();
+
+        (base.name(), child.name())
+    }
+
+    /// A v10 layer on top of `parent` that adds a triple for each of
+    /// `values`.
+    pub async fn value_layer(&self, parent: Option<[u32; 5]>, values: &[&str]) -> [u32; 5] {
+        let builder = match parent {
+            Some(parent) => {
+                let parent = self.v10().get_layer_from_id(parent).await.unwrap().unwrap();
+                parent.open_write().await.unwrap()
+            }
+            None => self.v10().create_base_layer().await.unwrap(),
+        };
+        for value in values {
+            builder
+                .add_string_triple(StringTriple::new_value("s", "p", value))
+                .unwrap();
+        }
+
+        builder.commit().await.unwrap().name()
+    }
+
+    pub async fn convert(&self, id: [u32; 5], mode: ValueMode) {
+        let settings = LayerSettings {
+            mode,
+            datatypes: &DatatypeMap::new(),
+            rebuild_all_indexes: false,
+        };
+        self.convert_with(id, settings, &SilentObserver).await;
+    }
+
+    pub async fn convert_with(
+        &self,
+        id: [u32; 5],
+        settings: LayerSettings<'_>,
+        observer: &dyn ConversionObserver,
+    ) {
+        convert_layer(
+            &self.path("v10"),
+            &self.path("v11"),
+            &self.path("work"),
+            settings,
+            &name_to_string(id),
+            observer,
+        )
+        .await
+        .unwrap();
+    }
+
+    pub async fn v11_layer(&self, id: [u32; 5]) -> StoreLayer {
+        terminus_store_11::open_archive_store(self.dir.join("v11"))
+            .get_layer_from_id(id)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    /// The triples of a converted layer, with values written as Rust
+    /// strings.
+    pub async fn v11_triples(&self, id: [u32; 5]) -> Vec<(String, String, String)> {
+        let layer = self.v11_layer(id).await;
+        let mut triples: Vec<_> = layer
+            .triples()
+            .map(|triple| {
+                let triple = layer.id_triple_to_string(&triple).unwrap();
+                let object = match triple.object {
+                    ObjectType::Node(node) => node,
+                    ObjectType::Value(value) => format!("{:?}", value.as_val::<String, String>()),
+                };
+                (triple.subject, triple.predicate, object)
+            })
+            .collect();
+        triples.sort();
+        triples
+    }
+}
+
+impl Drop for TestStore {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}